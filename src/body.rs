@@ -0,0 +1,114 @@
+//! 消息正文的来源：命令行参数、标准输入（`-`）、--message-file 指定的文件或 --template 指定的模板
+
+use crate::config::AppConfig;
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::PathBuf;
+
+/// 表示从标准输入读取的消息参数
+pub const STDIN_ARG: &str = "-";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodySource {
+    Text(String),
+    /// 读到 EOF
+    Stdin,
+    File(PathBuf),
+    /// [templates] 中的模板名
+    Template(String),
+}
+
+impl BodySource {
+    /// 命令行给出的消息，`-` 表示从标准输入读取
+    pub fn from_arg(message: String) -> Self {
+        if message == STDIN_ARG {
+            BodySource::Stdin
+        } else {
+            BodySource::Text(message)
+        }
+    }
+
+    /// 取得消息正文
+    ///
+    /// 命令行给出的文本与展开后的模板原样返回，超长时在发送时按 user.message_len_policy 处理；
+    /// 标准输入与文件的内容先规范换行，超过 user.max_message_len 时直接报错
+    pub fn read(self, config: &AppConfig) -> Result<String> {
+        let limit = config.user.message_limit();
+        let (raw, origin) = match self {
+            BodySource::Text(text) => return Ok(text),
+            BodySource::Template(name) => return config.expand_template(&name),
+            BodySource::Stdin => {
+                let text = read_utf8(std::io::stdin().lock()).context("Failed to read message from stdin")?;
+                (text, "stdin".to_string())
+            }
+            BodySource::File(path) => {
+                let file = std::fs::File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+                let text = read_utf8(file).with_context(|| format!("Failed to read message from {}", path.display()))?;
+                (text, path.display().to_string())
+            }
+        };
+        let body = normalize(&raw);
+        let len = body.chars().count();
+        if limit.max_len > 0 && len > limit.max_len {
+            return Err(anyhow::anyhow!(
+                "Message from {} is {} characters, longer than user.max_message_len ({}); use send-file to send it as a file",
+                origin,
+                len,
+                limit.max_len
+            ));
+        }
+        Ok(body)
+    }
+}
+
+fn read_utf8(mut reader: impl Read) -> Result<String> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| anyhow::anyhow!("Message is not valid UTF-8"))
+}
+
+/// 把 CRLF 换成 LF，并去掉恰好一个结尾换行
+pub fn normalize(raw: &str) -> String {
+    let text = raw.replace("\r\n", "\n");
+    match text.strip_suffix('\n') {
+        Some(stripped) => stripped.to_string(),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_strips_one_newline_and_crlf() {
+        assert_eq!(normalize("done\n"), "done");
+        assert_eq!(normalize("done\n\n"), "done\n");
+        assert_eq!(normalize("line 1\r\nline 2\r\n"), "line 1\nline 2");
+        assert_eq!(normalize("no newline"), "no newline");
+        assert_eq!(normalize(""), "");
+        assert_eq!(read_utf8(&b"\xff\xfe"[..]).unwrap_err().to_string(), "Message is not valid UTF-8");
+    }
+
+    #[test]
+    fn test_file_body_checked_against_max_len() {
+        let path = std::env::temp_dir().join(format!("lanmsg_body_{}.txt", std::process::id()));
+        std::fs::write(&path, "build 42\r\nall green\r\n").unwrap();
+        let unlimited = AppConfig::default();
+        assert_eq!(BodySource::File(path.clone()).read(&unlimited).unwrap(), "build 42\nall green");
+
+        // 即使策略为截断，文件内容超长也报错
+        let mut limited = AppConfig::default();
+        limited.user.max_message_len = 10;
+        let error = BodySource::File(path.clone()).read(&limited).unwrap_err().to_string();
+        assert!(error.contains("18 characters") && error.contains("send-file"), "{}", error);
+        // 命令行文本与模板留到发送时按策略处理
+        assert_eq!(BodySource::Text("a".repeat(20)).read(&limited).unwrap().len(), 20);
+        limited.templates.insert("long".into(), "b".repeat(20));
+        assert_eq!(BodySource::Template("long".into()).read(&limited).unwrap().len(), 20);
+        assert_eq!(BodySource::from_arg("-".into()), BodySource::Stdin);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(BodySource::File(path).read(&limited).unwrap_err().to_string().starts_with("Failed to open"));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::config::write_atomic;
+use crate::protocol;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 用户缓存文件名，与配置文件放在同一目录
+pub const USER_CACHE_FILE: &str = "users.cache.json";
+
+/// 本机节点 ID 文件名，与配置文件放在同一目录
+pub const NODE_ID_FILE: &str = "node-id";
+
+/// 缓存中的一个用户
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedUser {
+    /// 用户表键（昵称@主机名）
+    pub username: String,
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub login: String,
+    #[serde(default)]
+    pub group: String,
+    /// 对端协议版本字段，用于区分客户端类型
+    #[serde(default)]
+    pub client: String,
+    /// 对端上线报文中的节点 ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    /// 最后活动时间（Unix 秒）
+    pub last_seen: u64,
+}
+
+impl CachedUser {
+    /// 距最后活动的时长
+    pub fn age(&self) -> Duration {
+        let now = unix_now();
+        Duration::from_secs(now.saturating_sub(self.last_seen))
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UserCacheFile {
+    users: Vec<CachedUser>,
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// 读取用户缓存：丢弃超过 max_age 的条目，最多保留最近活动的 max_entries 个
+///
+/// 文件不存在时返回空列表；文件损坏时打印警告并忽略
+pub fn load_users<P: AsRef<Path>>(path: P, max_entries: usize, max_age: Duration) -> Vec<CachedUser> {
+    let path = path.as_ref();
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => return Vec::new(),
+    };
+    let file: UserCacheFile = match serde_json::from_str(&content) {
+        Ok(file) => file,
+        Err(e) => {
+            log::warn!("Ignoring corrupt user cache {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let mut users: Vec<CachedUser> = file
+        .users
+        .into_iter()
+        .filter(|user| user.age() <= max_age)
+        .collect();
+    users.sort_by_key(|user| std::cmp::Reverse(user.last_seen));
+    users.truncate(max_entries);
+    users
+}
+
+/// 保存用户缓存（先写临时文件再改名，避免中途退出留下半个文件）
+pub fn save_users<P: AsRef<Path>>(path: P, users: Vec<CachedUser>) -> Result<()> {
+    let path = path.as_ref();
+    let content = serde_json::to_string_pretty(&UserCacheFile { users })?;
+    write_atomic(path, content.as_bytes())
+}
+
+/// 读取本机节点 ID；文件不存在或内容无效时生成新的 UUID 并写入，之后重启、改主机名都沿用
+pub fn load_or_create_node_id<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    if let Ok(content) = fs::read_to_string(path) {
+        let id = content.trim();
+        if protocol::is_node_id(id) {
+            return Ok(id.to_string());
+        }
+        log::warn!("Ignoring invalid node id in {}, generating a new one", path.display());
+    }
+    let id = new_node_id();
+    write_atomic(path, format!("{}\n", id).as_bytes())?;
+    Ok(id)
+}
+
+/// 随机生成第 4 版 UUID
+pub fn new_node_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, age_secs: u64) -> CachedUser {
+        CachedUser {
+            username: format!("{}@PC", name),
+            addr: "192.168.1.10:2425".parse().unwrap(),
+            login: name.to_string(),
+            group: "dev".to_string(),
+            client: "1".to_string(),
+            node_id: None,
+            last_seen: unix_now() - age_secs,
+        }
+    }
+
+    #[test]
+    fn test_round_trip_applies_age_and_size_caps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_CACHE_FILE);
+        save_users(&path, vec![user("old", 1000), user("alice", 10), user("bob", 20), user("carol", 30)])
+            .unwrap();
+
+        let users = load_users(&path, 2, Duration::from_secs(100));
+        let names: Vec<&str> = users.iter().map(|user| user.login.as_str()).collect();
+        assert_eq!(names, ["alice", "bob"]);
+        assert_eq!(users[0].group, "dev");
+    }
+
+    #[test]
+    fn test_corrupt_cache_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(USER_CACHE_FILE);
+        fs::write(&path, "{ not json").unwrap();
+        assert!(load_users(&path, 10, Duration::from_secs(100)).is_empty());
+        assert!(load_users(dir.path().join("missing.json"), 10, Duration::from_secs(100)).is_empty());
+    }
+
+    #[test]
+    fn test_node_id_is_created_once_and_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(NODE_ID_FILE);
+        let id = load_or_create_node_id(&path).unwrap();
+        assert!(protocol::is_node_id(&id), "{}", id);
+        assert_eq!(load_or_create_node_id(&path).unwrap(), id);
+
+        fs::write(&path, "garbage").unwrap();
+        let replaced = load_or_create_node_id(&path).unwrap();
+        assert_ne!(replaced, id);
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), replaced);
+    }
+}
@@ -0,0 +1,565 @@
+use crate::control::{self, ControlRequest};
+use crate::event::NetEvent;
+use crate::groups;
+use crate::net::IpMsgServer;
+use crate::protocol::{IpMsgPacket, commands};
+use crate::roster::{self, Column};
+use crate::table::{self, TableStyle};
+use anyhow::Result;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// /help 显示的命令列表
+pub const HELP: &str = "\
+/list                          show online users
+/msg <user> <text>             send one private message
+/to <user|all|group:NAME>      change where plain text goes
+/r <text>                      reply to the last sender
+/file <user> <path>            offer a file
+/accept <offer> [file ids...]  receive files from an offer
+/status [away [msg]|back|dnd]  show or change your status
+/block <user>                  block a peer for this session
+/help                          show this help
+/quit                          leave the chat
+//text                         send text that starts with /";
+
+/// 聊天模式下解析后的一行输入
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatInput {
+    /// /quit 或 /exit
+    Quit,
+    /// /msg <用户> <内容>：私聊
+    Msg { recipient: String, text: String },
+    /// /accept <编号> [文件编号...]：接收文件请求中的全部或部分附件
+    Accept { packet_no: u32, file_ids: Vec<u32> },
+    /// /r <内容>：回复最近一次发来消息的用户
+    Reply(String),
+    /// /list：显示用户表
+    List,
+    /// /to <用户|all|group:分组>：切换普通文本的发送对象
+    To(ChatTarget),
+    /// /file <用户> <路径>：发送文件
+    File { recipient: String, path: PathBuf },
+    /// /status [away [离开信息] | back | dnd]：查看或设置本机状态
+    Status(ControlRequest),
+    /// /block <用户>：本次会话中屏蔽对端
+    Block(String),
+    /// /help
+    Help,
+    /// 已知命令的参数不对，内容为用法
+    Usage(&'static str),
+    /// 不认识的命令（不含斜杠）
+    Unknown(String),
+    /// 普通文本：发给当前的发送对象
+    Say(String),
+}
+
+/// 普通文本的发送对象：默认广播，--with 或 /to 切换
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ChatTarget {
+    #[default]
+    All,
+    /// 用户名、用户名@主机名、IP 或 IP:端口
+    User(String),
+    /// 分组的在线用户（带多播标记，分组名逐字匹配）
+    Group(String),
+}
+
+impl ChatTarget {
+    /// 解析 --with 与 /to 的参数：all、group:<分组> 或收件人
+    pub fn parse(arg: &str) -> Self {
+        if arg.eq_ignore_ascii_case("all") {
+            return ChatTarget::All;
+        }
+        match arg.strip_prefix("group:") {
+            Some(name) => ChatTarget::Group(name.to_string()),
+            None => ChatTarget::User(arg.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ChatTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatTarget::All => write!(f, "all"),
+            ChatTarget::User(user) => write!(f, "{}", user),
+            ChatTarget::Group(name) => write!(f, "group:{}", name),
+        }
+    }
+}
+
+/// 统一换行符：CRLF 与行中残留的 \r 都去掉
+pub fn normalize_line(line: &str) -> String {
+    line.replace("\r\n", "\n").replace('\r', "").trim().to_string()
+}
+
+/// 解析一行输入，空行返回 None
+///
+/// 以 / 开头的是命令，不会作为文本发出；以 // 开头的去掉一个斜杠后作为普通文本
+pub fn parse_input(line: &str) -> Option<ChatInput> {
+    let line = normalize_line(line);
+    if line.is_empty() {
+        return None;
+    }
+    if let Some(text) = line.strip_prefix("//") {
+        return Some(ChatInput::Say(format!("/{}", text)));
+    }
+    let Some(command) = line.strip_prefix('/') else {
+        return Some(ChatInput::Say(line));
+    };
+    let (name, rest) = match command.split_once(char::is_whitespace) {
+        Some((name, rest)) => (name, rest.trim()),
+        None => (command, ""),
+    };
+    // 第一个参数与其后的全部内容
+    let two_args = || {
+        rest.split_once(char::is_whitespace)
+            .map(|(first, second)| (first.to_string(), second.trim().to_string()))
+            .filter(|(_, second)| !second.is_empty())
+    };
+
+    let input = match name.to_ascii_lowercase().as_str() {
+        "quit" | "exit" => ChatInput::Quit,
+        "help" | "?" => ChatInput::Help,
+        "list" => ChatInput::List,
+        "msg" => match two_args() {
+            Some((recipient, text)) => ChatInput::Msg { recipient, text },
+            None => ChatInput::Usage("/msg <user> <text>"),
+        },
+        "r" if !rest.is_empty() => ChatInput::Reply(rest.to_string()),
+        "r" => ChatInput::Usage("/r <text>"),
+        "to" if !rest.is_empty() && !rest.contains(char::is_whitespace) => ChatInput::To(ChatTarget::parse(rest)),
+        "to" => ChatInput::Usage("/to <user|all|group:NAME>"),
+        "file" => match two_args() {
+            Some((recipient, path)) => ChatInput::File {
+                recipient,
+                path: PathBuf::from(path),
+            },
+            None => ChatInput::Usage("/file <user> <path>"),
+        },
+        "status" if rest.is_empty() => ChatInput::Status(ControlRequest::Status),
+        "status" => match ControlRequest::parse(rest) {
+            Some(request) => ChatInput::Status(request),
+            None => ChatInput::Usage("/status [away [message] | back | dnd]"),
+        },
+        "block" if !rest.is_empty() => ChatInput::Block(rest.to_string()),
+        "block" => ChatInput::Usage("/block <user>"),
+        "accept" => {
+            let mut numbers = rest.split_whitespace().map(|n| n.trim_start_matches('#').parse::<u32>());
+            match (numbers.next(), numbers.collect::<Result<Vec<_>, _>>()) {
+                (Some(Ok(packet_no)), Ok(file_ids)) => ChatInput::Accept { packet_no, file_ids },
+                _ => ChatInput::Usage("/accept <offer> [file ids...]"),
+            }
+        }
+        _ => ChatInput::Unknown(name.to_string()),
+    };
+    Some(input)
+}
+
+/// 最近一次发来消息的用户（用户名@主机名与地址），/r 回复给他
+#[derive(Debug, Clone, Default)]
+pub struct LastSender(Arc<Mutex<Option<(String, SocketAddr)>>>);
+
+impl LastSender {
+    /// 记录收到的一条消息的发送方
+    pub fn record(&self, sender: &str, addr: SocketAddr) {
+        *self.0.lock().unwrap() = Some((sender.to_string(), addr));
+    }
+
+    /// 最近的发送方的名称，用于提示符
+    pub fn name(&self) -> Option<String> {
+        self.0.lock().unwrap().as_ref().map(|(sender, _)| sender.clone())
+    }
+
+    /// 把 /r 转为发给最近的发送方的私聊；还没有人发来消息时返回 None
+    pub fn reply(&self, text: &str) -> Option<ChatInput> {
+        let (_, addr) = self.0.lock().unwrap().clone()?;
+        Some(ChatInput::Msg {
+            recipient: addr.to_string(),
+            text: text.to_string(),
+        })
+    }
+}
+
+/// 读取一行输入的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadOutcome {
+    Line(String),
+    /// 输入已关闭（EOF）
+    Closed,
+    /// 超过空闲时长没有输入
+    Idle,
+}
+
+/// 读取一行输入；设置了 idle 时，超时无输入返回 Idle（每次调用重新计时）
+pub async fn read_line_with_idle<R>(reader: &mut R, idle: Option<Duration>) -> std::io::Result<ReadOutcome>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    let read = reader.read_line(&mut line);
+    let n = match idle {
+        Some(idle) => match tokio::time::timeout(idle, read).await {
+            Ok(result) => result?,
+            Err(_) => return Ok(ReadOutcome::Idle),
+        },
+        None => read.await?,
+    };
+    Ok(if n == 0 { ReadOutcome::Closed } else { ReadOutcome::Line(line) })
+}
+
+/// 等到 deadline；为 None 时一直等待（用于 select 中可选的定时器）
+pub async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 发送一行聊天输入：普通文本发给 target，/msg 发给指定用户
+pub async fn send_input(server: &IpMsgServer, input: &ChatInput, target: &ChatTarget) -> Result<()> {
+    match (input, target) {
+        (ChatInput::Say(text), ChatTarget::All) => {
+            let packet = server.build_packet(commands::MSG | commands::IPMSG_BROADCASTOPT, text);
+            server.broadcast(&packet).await
+        }
+        (ChatInput::Say(text), ChatTarget::User(recipient)) | (ChatInput::Msg { recipient, text }, _) => {
+            let addr = server.resolve_recipient(recipient).await?;
+            let packet = server.build_packet(commands::MSG, text);
+            server.send_to(&packet, &addr).await
+        }
+        (ChatInput::Say(text), ChatTarget::Group(name)) => {
+            let members = groups::members_of(&server.get_online_users().await, name, false);
+            let results = server.multicast_users(&members, text, None).await;
+            if results.iter().all(|(_, delivery)| delivery.is_failed()) {
+                return Err(anyhow::anyhow!("No online users in group {}", name));
+            }
+            Ok(())
+        }
+        // 其余输入不发送消息，由 run_command 或调用方处理
+        _ => Ok(()),
+    }
+}
+
+/// 执行不发送消息的命令，返回要显示的文本；发送消息、/r、/accept、/file 与 /quit 返回 None，由调用方处理
+///
+/// /to 修改 target；/block 只在本次会话中生效（写入配置文件请用 `lanMsg block`）
+pub async fn run_command(
+    server: &IpMsgServer,
+    input: &ChatInput,
+    target: &mut ChatTarget,
+    style: TableStyle,
+) -> Option<String> {
+    let output = match input {
+        ChatInput::Help => HELP.to_string(),
+        ChatInput::Usage(usage) => format!("[!] Usage: {}", usage),
+        ChatInput::Unknown(name) => {
+            format!("[!] Unknown command /{}; type /help for commands, start with // to send a line beginning with /", name)
+        }
+        ChatInput::List => {
+            let users = server.get_online_users().await;
+            if users.is_empty() {
+                "No online users found".to_string()
+            } else {
+                let table = roster::render(&users, &Column::DEFAULT, style, table::terminal_width());
+                format!("Online users ({}):\n{}", users.len(), table.trim_end())
+            }
+        }
+        ChatInput::To(new_target) => {
+            *target = new_target.clone();
+            format!("Now talking to {}", target)
+        }
+        ChatInput::Status(request) => match control::apply(server, request).await {
+            Ok(status) => status,
+            Err(e) => format!("[!] Failed to set status: {}", e),
+        },
+        ChatInput::Block(peer) => match server.block_peer(peer) {
+            Ok(true) => format!("Blocked {} for this session (use `lanMsg block` to save it)", peer),
+            Ok(false) => format!("{} is already blocked", peer),
+            Err(e) => format!("[!] {}", e),
+        },
+        _ => return None,
+    };
+    Some(output)
+}
+
+/// 收到的消息的发送方式：广播为 `[all]`，多播为 `(multicast)`，其余为 `[private]`
+pub fn delivery_tag(packet: &IpMsgPacket) -> &'static str {
+    if packet.command & commands::IPMSG_MULTICASTOPT != 0 {
+        "(multicast)"
+    } else if packet.command & commands::IPMSG_BROADCASTOPT != 0 {
+        "[all]"
+    } else {
+        "[private]"
+    }
+}
+
+/// 收到的消息的显示行：`[发送方] [all] 消息`
+pub fn incoming_line(packet: &IpMsgPacket) -> String {
+    format!("[{}] {} {}", packet.sender_name, delivery_tag(packet), packet.additional_msg)
+}
+
+/// 事件流中收到的消息的显示行：`时间 [发送方] [all] 消息`；其他事件返回 None
+pub fn event_line(event: &NetEvent, time: &str) -> Option<String> {
+    match event {
+        NetEvent::Packet { packet, .. } if packet.command & 0xff == commands::MSG => {
+            Some(format!("{} {}", time, incoming_line(packet)))
+        }
+        _ => None,
+    }
+}
+
+/// 发送结果的本地回显
+pub fn echo_line(input: &ChatInput, target: &ChatTarget, result: &Result<()>) -> String {
+    match (input, target, result) {
+        (_, _, Err(e)) => format!("[!] Send failed: {}", e),
+        (ChatInput::Say(text), ChatTarget::All, Ok(())) => format!("[you] {}", text),
+        (ChatInput::Say(text), target, Ok(())) => format!("[you -> {}] {}", target, text),
+        (ChatInput::Msg { recipient, text }, _, Ok(())) => format!("[you -> {}] {}", recipient, text),
+        (_, _, Ok(())) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    #[test]
+    fn test_crlf_input_parses_like_lf() {
+        for line in ["/msg bob hi there", "/quit", "hello world"] {
+            let lf = parse_input(&format!("{}\n", line));
+            assert_eq!(parse_input(&format!("{}\r\n", line)), lf);
+            assert_eq!(parse_input(&format!("{}\r", line)), lf);
+        }
+
+        assert_eq!(
+            parse_input("/msg bob hi\r\n"),
+            Some(ChatInput::Msg {
+                recipient: "bob".to_string(),
+                text: "hi".to_string(),
+            })
+        );
+        assert_eq!(parse_input("\r\n"), None);
+    }
+
+    #[test]
+    fn test_broadcast_message_tagged_all() {
+        let message = |command| IpMsgPacket {
+            sender_name: "alice".to_string(),
+            command,
+            additional_msg: "lunch?".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            incoming_line(&message(commands::MSG | commands::IPMSG_BROADCASTOPT)),
+            "[alice] [all] lunch?"
+        );
+        assert_eq!(incoming_line(&message(commands::MSG)), "[alice] [private] lunch?");
+        assert_eq!(
+            incoming_line(&message(commands::MSG | commands::IPMSG_MULTICASTOPT | commands::IPMSG_SENDCHECKOPT)),
+            "[alice] (multicast) lunch?"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_say_is_sent_with_broadcast_option() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+        send_input(&server, &ChatInput::Say("hi all".to_string()), &ChatTarget::All).await.unwrap();
+        let mut buf = [0; 1024];
+        let (len, _) = sink.recv_from(&mut buf).await.unwrap();
+        let command = (commands::MSG | commands::IPMSG_BROADCASTOPT).to_string();
+        assert!(String::from_utf8_lossy(&buf[..len]).ends_with(&format!(":{}:hi all", command)));
+    }
+
+    #[tokio::test]
+    async fn test_two_peers_exchange_chat_lines() {
+        let pair = crate::net::testutil::pair().await;
+        pair.meet().await;
+        let (alice, bob) = (&pair.alice.server, &pair.bob.server);
+        // 先订阅再发送，返回等待下一条消息行的 future
+        let next_line = |server: &IpMsgServer| {
+            let mut events = server.subscribe();
+            async move {
+                loop {
+                    let event = tokio::time::timeout(Duration::from_secs(2), events.recv()).await.unwrap().unwrap();
+                    if let Some(line) = event_line(&event, "12:00:00") {
+                        return line;
+                    }
+                }
+            }
+        };
+
+        // alice 用 --with bob 聊天：普通文本私聊给 bob
+        let incoming = next_line(bob);
+        let target = ChatTarget::parse("bob");
+        let input = parse_input("hi bob\n").unwrap();
+        send_input(alice, &input, &target).await.unwrap();
+        assert_eq!(echo_line(&input, &target, &Ok(())), "[you -> bob] hi bob");
+        assert_eq!(incoming.await, "12:00:00 [alice] [private] hi bob");
+
+        // bob 没有指定对象：广播
+        let incoming = next_line(alice);
+        send_input(bob, &parse_input("hi all").unwrap(), &ChatTarget::All).await.unwrap();
+        assert_eq!(incoming.await, "12:00:00 [bob] [all] hi all");
+    }
+
+    #[test]
+    fn test_slash_commands_parse() {
+        let parse = |line: &str| parse_input(line).unwrap();
+        assert_eq!(parse("/list"), ChatInput::List);
+        assert_eq!(parse("/LIST"), ChatInput::List);
+        assert_eq!(parse("/help"), ChatInput::Help);
+        assert_eq!(parse("/exit"), ChatInput::Quit);
+        assert_eq!(
+            parse("/msg bob@PC-B  see you at 3 "),
+            ChatInput::Msg {
+                recipient: "bob@PC-B".to_string(),
+                text: "see you at 3".to_string(),
+            }
+        );
+        assert_eq!(parse("/msg bob"), ChatInput::Usage("/msg <user> <text>"));
+
+        assert_eq!(parse("/to bob"), ChatInput::To(ChatTarget::User("bob".to_string())));
+        assert_eq!(parse("/to ALL"), ChatInput::To(ChatTarget::All));
+        assert_eq!(parse("/to group:dev"), ChatInput::To(ChatTarget::Group("dev".to_string())));
+        assert_eq!(parse("/to"), ChatInput::Usage("/to <user|all|group:NAME>"));
+        assert_eq!(ChatTarget::Group("dev".to_string()).to_string(), "group:dev");
+
+        assert_eq!(
+            parse("/file bob ./build log.txt"),
+            ChatInput::File {
+                recipient: "bob".to_string(),
+                path: PathBuf::from("./build log.txt"),
+            }
+        );
+        assert_eq!(parse("/file bob"), ChatInput::Usage("/file <user> <path>"));
+
+        assert_eq!(parse("/status"), ChatInput::Status(ControlRequest::Status));
+        assert_eq!(parse("/status away"), ChatInput::Status(ControlRequest::Away(None)));
+        assert_eq!(
+            parse("/status away out to lunch"),
+            ChatInput::Status(ControlRequest::Away(Some("out to lunch".to_string())))
+        );
+        assert_eq!(parse("/status back"), ChatInput::Status(ControlRequest::Back));
+        assert_eq!(parse("/status asleep"), ChatInput::Usage("/status [away [message] | back | dnd]"));
+
+        assert_eq!(parse("/block 10.0.0.0/8"), ChatInput::Block("10.0.0.0/8".to_string()));
+        assert_eq!(parse("/block"), ChatInput::Usage("/block <user>"));
+
+        // 不认识的命令不作为文本发出，// 转义为以 / 开头的文本
+        assert_eq!(parse("/shrug"), ChatInput::Unknown("shrug".to_string()));
+        assert_eq!(parse("//shrug"), ChatInput::Say("/shrug".to_string()));
+        assert_eq!(parse("///"), ChatInput::Say("//".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_chat_commands_end_to_end() {
+        let pair = crate::net::testutil::pair().await;
+        pair.meet().await;
+        let (alice, mut bob) = (&pair.alice.server, pair.bob);
+        let mut target = ChatTarget::All;
+        let run = async |line: &str, target: &mut ChatTarget| {
+            run_command(alice, &parse_input(line).unwrap(), target, TableStyle::Ascii).await
+        };
+
+        assert!(run("/help", &mut target).await.unwrap().contains("/to <user|all|group:NAME>"));
+        assert!(run("/frobnicate", &mut target).await.unwrap().contains("Unknown command /frobnicate"));
+        let list = run("/list", &mut target).await.unwrap();
+        assert!(list.starts_with("Online users (1):") && list.contains("bob"), "{}", list);
+
+        // /to 切换发送对象后普通文本私聊给 bob
+        assert_eq!(run("/to bob", &mut target).await.unwrap(), "Now talking to bob");
+        let say = parse_input("standup?").unwrap();
+        assert_eq!(run_command(alice, &say, &mut target, TableStyle::Ascii).await, None);
+        send_input(alice, &say, &target).await.unwrap();
+        let (received, _) = bob.next_message().await;
+        assert_eq!(received.additional_msg, "standup?");
+        assert_eq!(received.command & commands::IPMSG_BROADCASTOPT, 0);
+
+        let status = run("/status away lunch", &mut target).await.unwrap();
+        assert!(status.starts_with("away (lunch)"), "{}", status);
+
+        assert!(run("/block bob@PC-B", &mut target).await.unwrap().starts_with("Blocked bob@PC-B"));
+        assert!(!alice.peer_filter().accepts(bob.addr.ip(), &["bob@PC-B"]));
+        assert_eq!(run("/block bob@PC-B", &mut target).await.unwrap(), "bob@PC-B is already blocked");
+    }
+
+    #[test]
+    fn test_accept_selects_files() {
+        assert_eq!(
+            parse_input("/accept 1234"),
+            Some(ChatInput::Accept { packet_no: 1234, file_ids: vec![] })
+        );
+        assert_eq!(
+            parse_input("/accept #1234 0 2"),
+            Some(ChatInput::Accept { packet_no: 1234, file_ids: vec![0, 2] })
+        );
+        assert_eq!(parse_input("/accept later"), Some(ChatInput::Usage("/accept <offer> [file ids...]")));
+    }
+
+    #[test]
+    fn test_reply_targets_last_sender() {
+        assert_eq!(parse_input("/r  on my way "), Some(ChatInput::Reply("on my way".to_string())));
+        assert_eq!(parse_input("/r"), Some(ChatInput::Usage("/r <text>")));
+
+        let last = LastSender::default();
+        assert_eq!(last.reply("hi"), None);
+        last.record("alice@PC-A", "192.168.1.5:2425".parse().unwrap());
+        last.record("bob@PC-B", "192.168.1.9:2425".parse().unwrap());
+        assert_eq!(last.name().as_deref(), Some("bob@PC-B"));
+        assert_eq!(
+            last.reply("hi"),
+            Some(ChatInput::Msg {
+                recipient: "192.168.1.9:2425".to_string(),
+                text: "hi".to_string(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_fires_without_input() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut reader = tokio::io::BufReader::new(reader);
+        let idle = Some(Duration::from_millis(100));
+
+        tokio::io::AsyncWriteExt::write_all(&mut writer, b"hi\n").await.unwrap();
+        assert_eq!(
+            read_line_with_idle(&mut reader, idle).await.unwrap(),
+            ReadOutcome::Line("hi\n".to_string())
+        );
+
+        let started = std::time::Instant::now();
+        assert_eq!(read_line_with_idle(&mut reader, idle).await.unwrap(), ReadOutcome::Idle);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        drop(writer);
+        assert_eq!(read_line_with_idle(&mut reader, None).await.unwrap(), ReadOutcome::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_send_result_drives_echo() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+
+        let say = ChatInput::Say("hello".to_string());
+        let result = send_input(&server, &say, &ChatTarget::All).await;
+        assert!(result.is_ok());
+        assert_eq!(echo_line(&say, &ChatTarget::All, &result), "[you] hello");
+
+        let msg = ChatInput::Msg {
+            recipient: "nobody".to_string(),
+            text: "hi".to_string(),
+        };
+        let result = send_input(&server, &msg, &ChatTarget::All).await;
+        assert!(result.is_err());
+        assert!(echo_line(&msg, &ChatTarget::All, &result).starts_with("[!] Send failed"));
+    }
+}
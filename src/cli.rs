@@ -1,4 +1,90 @@
-use clap::{Parser, Subcommand};
+use lanmsg::body::BodySource;
+use lanmsg::config::{ConfigFormat, ConfigOverrides};
+use lanmsg::control::ControlRequest;
+use lanmsg::net::{IPMSG_PORT, ResolveError};
+use lanmsg::relay::RelayFilter;
+use lanmsg::roster::{Column, SortKey};
+use lanmsg::watch::WatchKind;
+use clap::error::ErrorKind;
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+/// 退出码约定（send、send-file、broadcast 及参数错误）
+pub mod exit_code {
+    pub const SUCCESS: u8 = 0;
+    /// 其他错误，如配置有误、文件未被取走
+    pub const FAILURE: u8 = 1;
+    pub const NOT_FOUND: u8 = 2;
+    /// 同名用户不止一个
+    pub const AMBIGUOUS: u8 = 3;
+    /// --wait-ack 重发后仍未确认
+    pub const ACK_TIMEOUT: u8 = 4;
+    /// 绑定端口或发送失败
+    pub const NETWORK: u8 = 5;
+    /// 命令行参数有误（与 sysexits.h 的 EX_USAGE 相同）
+    pub const USAGE: u8 = 64;
+}
+
+const EXIT_CODES_HELP: &str = "退出码：
+  0   成功
+  1   其他错误（如配置有误）
+  2   找不到收件人
+  3   收件人不唯一（同名用户不止一个）
+  4   --wait-ack 重发后仍未收到确认
+  5   网络错误（绑定端口或发送失败）
+  64  命令行参数有误
+多个收件人时取第一个失败的收件人对应的退出码";
+
+/// 带退出码的错误，main 按 code 退出；没有包装的错误按 error_code 分类
+#[derive(Debug)]
+pub struct Failure {
+    pub code: u8,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for Failure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// 为错误标注退出码
+pub trait WithExitCode<T> {
+    fn exit_code(self, code: u8) -> anyhow::Result<T>;
+}
+
+impl<T> WithExitCode<T> for anyhow::Result<T> {
+    fn exit_code(self, code: u8) -> anyhow::Result<T> {
+        self.map_err(|error| Failure { code, error }.into())
+    }
+}
+
+/// 错误对应的退出码：标注过的按标注，收件人解析失败为 2 或 3，其余为 1
+pub fn error_code(error: &anyhow::Error) -> u8 {
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        return failure.code;
+    }
+    match error.downcast_ref::<ResolveError>() {
+        Some(ResolveError::NotFound(_)) => exit_code::NOT_FOUND,
+        Some(ResolveError::Ambiguous { .. }) => exit_code::AMBIGUOUS,
+        None => exit_code::FAILURE,
+    }
+}
+
+/// 输出 clap 的错误并退出：参数错误以 64 退出，--help、--version 以 0 退出
+pub fn usage_error(error: clap::Error) -> ! {
+    let _ = error.print();
+    let code = if error.use_stderr() { exit_code::USAGE } else { exit_code::SUCCESS };
+    std::process::exit(code.into())
+}
 
 #[derive(Parser, Debug)]
 #[command(name = "ipmsg", version = "0.1")]
@@ -6,26 +92,446 @@ pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
 
-    #[arg(short, long, default_value = "anonymous")]
-    pub name: String,
+    /// 用户名（覆盖配置文件中的 user.name）
+    #[arg(short, long)]
+    pub name: Option<String>,
 
-    #[arg(short, long, default_value = "localhost")]
-    pub host: String,
+    /// 登录名（覆盖配置文件中的 user.login）
+    #[arg(long)]
+    pub login: Option<String>,
+
+    /// 主机名（覆盖配置文件中的 user.host）
+    #[arg(short = 'H', long)]
+    pub host: Option<String>,
+
+    /// 所属分组
+    #[arg(long)]
+    pub group: Option<String>,
+
+    /// 监听端口
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// 本地绑定 IP
+    #[arg(long)]
+    pub bind: Option<String>,
+
+    /// 广播地址
+    #[arg(long)]
+    pub broadcast: Option<String>,
+
+    /// 协议报文编码（gbk/utf-8）
+    #[arg(long)]
+    pub encoding: Option<String>,
+
+    /// 只接收不发送：不广播上线/下线，也不自动应答
+    #[arg(long, global = true)]
+    pub passive: bool,
+
+    /// 隐身：不出现在他人的用户列表中，仍接收消息并被动收集在线用户
+    #[arg(long, global = true)]
+    pub stealth: bool,
+
+    /// 配置文件路径（默认依次查找 $LANMSG_CONFIG、平台配置目录、当前目录）
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// 配置方案：叠加配置文件中的 [profile.<名称>]（也可用 LANMSG_PROFILE 指定）
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    /// 只输出警告与错误日志（包括命令行覆盖配置值的提示在内的 info 日志都不输出）
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// 配置有误时不退出，改用默认配置
+    #[arg(long, global = true)]
+    pub ignore_config_errors: bool,
+}
+
+impl Cli {
+    /// send 的收件人与消息，其他命令返回 None；参数有误时以 clap 的格式报错退出
+    pub fn send_parts(&self) -> Option<(Vec<String>, BodySource)> {
+        let Commands::Send {
+            recipients,
+            message,
+            text,
+            message_file,
+            template,
+            ip,
+            group,
+            ..
+        } = &self.command
+        else {
+            return None;
+        };
+        let file = message_file
+            .clone()
+            .map(BodySource::File)
+            .or_else(|| template.clone().map(BodySource::Template));
+        match split_send_args(recipients, message.as_deref(), text, file, *ip, group.is_some()) {
+            Ok(parts) => Some(parts),
+            Err(e) => usage_error(Self::command().error(ErrorKind::ArgumentConflict, e)),
+        }
+    }
+
+    /// 命令行指定的配置覆盖值
+    pub fn overrides(&self) -> ConfigOverrides {
+        ConfigOverrides {
+            name: self.name.clone(),
+            login: self.login.clone(),
+            host: self.host.clone(),
+            group: self.group.clone(),
+            port: self.port,
+            bind_ip: self.bind.clone(),
+            broadcast_ip: self.broadcast.clone(),
+            encoding: self.encoding.clone(),
+            passive: self.passive.then_some(true),
+            stealth: self.stealth.then_some(true),
+            profile: self.profile.clone(),
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    /// 发送消息给指定用户
+    /// 发送消息给一个或多个用户：send <收件人>... -- <消息>、send <收件人>... -m <消息>，或 send <收件人> <消息>
+    #[command(after_help = EXIT_CODES_HELP)]
     Send {
-        recipient: String,
-        message: String,
+        /// 收件人：用户名、用户名@主机名、IP 或 IP:端口；未用 -m 或 -- 给出消息时最后一项为消息
+        recipients: Vec<String>,
+        /// 消息内容
+        #[arg(short, long)]
+        message: Option<String>,
+        /// -- 之后的参数，以空格连接为消息
+        #[arg(last = true)]
+        text: Vec<String>,
+        /// 从文件读取消息（CRLF 换为 LF，去掉一个结尾换行）；消息写作 - 时从标准输入读取
+        #[arg(long, conflicts_with_all = ["message", "text"])]
+        message_file: Option<PathBuf>,
+        /// 发送配置 [templates] 中的模板，{name}、{host} 换成本机昵称与主机名；所有位置参数都是收件人
+        #[arg(long, conflicts_with_all = ["message", "text", "message_file"])]
+        template: Option<String>,
+        /// 以 JSON 输出每个收件人的发送结果
+        #[arg(long)]
+        json: bool,
+        /// 要求对方确认（SENDCHECKOPT），未确认时重发，等到确认或超时再退出
+        #[arg(long)]
+        wait_ack: bool,
+        /// 等待确认的总秒数（默认为 network.timeout_secs）
+        #[arg(long, requires = "wait_ack")]
+        ack_timeout: Option<u64>,
+        /// 不查找用户列表，直接发往该地址（IP 或 IP:端口，默认端口 2425）
+        #[arg(long, value_parser = parse_ip_target, conflicts_with = "group")]
+        ip: Option<SocketAddr>,
+        /// 逐个发给该分组的在线用户（带多播标记，分组名逐字匹配），不含本机与黑名单中的用户
+        #[arg(long)]
+        group: Option<String>,
+        /// --group 改为匹配分组名包含该文本的用户（不区分大小写）
+        #[arg(long, requires = "group")]
+        contains: bool,
+    },
+    /// 发送文件（可一次附带多个文件，对方可全部或部分接收）
+    #[command(allow_missing_positional = true, after_help = EXIT_CODES_HELP)]
+    SendFile {
+        /// 收件人：用户名、用户名@主机名、IP 或 IP:端口
+        #[arg(required_unless_present = "ip", conflicts_with = "ip")]
+        recipient: Option<String>,
+        /// 要发送的文件（目录需要 --as-archive）
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// 把所有文件与目录打包成一个 tar 文件发送
+        #[arg(long)]
+        as_archive: bool,
+        /// 随文件一起发送的消息
+        #[arg(long)]
+        message: Option<String>,
+        /// 等待对方取走文件的秒数（默认为 transfer.offer_ttl_secs）
+        #[arg(long)]
+        serve_timeout: Option<u64>,
+        /// 不查找用户列表，直接发往该地址（IP 或 IP:端口，默认端口 2425）
+        #[arg(long, value_parser = parse_ip_target)]
+        ip: Option<SocketAddr>,
     },
     /// 广播消息给所有人
+    #[command(after_help = EXIT_CODES_HELP)]
     Broadcast {
+        /// 消息内容，- 表示从标准输入读取
+        #[arg(required_unless_present_any = ["message_file", "template"])]
+        message: Option<String>,
+        /// 从文件读取消息
+        #[arg(long, conflicts_with = "message")]
+        message_file: Option<PathBuf>,
+        /// 广播配置 [templates] 中的模板，{name}、{host} 换成本机昵称与主机名
+        #[arg(long, conflicts_with_all = ["message", "message_file"])]
+        template: Option<String>,
+        /// 发送次数（用于压力测试），每次使用新的报文编号
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        repeat: u32,
+        /// 重复发送时相邻两次的间隔（毫秒）
+        #[arg(long, default_value_t = 1000)]
+        interval: u64,
+        /// 要求对端确认，统计确认的已知用户数
+        #[arg(long, conflicts_with = "repeat")]
+        confirm: bool,
+        /// 收集确认的秒数（默认为 network.timeout_secs）
+        #[arg(long, requires = "confirm")]
+        window: Option<u64>,
+        /// 列出确认与未确认的用户
+        #[arg(short, long, requires = "confirm")]
+        verbose: bool,
+        /// 有已知用户未确认时以退出码 4 结束
+        #[arg(long, requires = "confirm")]
+        require_all: bool,
+    },
+    /// 把标准输入的每一行作为一条消息发出（发给用户、分组或广播），读到 EOF 后退出
+    #[command(group(ArgGroup::new("target").required(true).args(["to", "group", "broadcast"])))]
+    Pipe {
+        /// 收件人：用户名、用户名@主机名、IP 或 IP:端口
+        #[arg(long)]
+        to: Option<String>,
+        /// 逐个发给该分组的在线用户（带多播标记，分组名逐字匹配）
+        #[arg(long)]
+        group: Option<String>,
+        /// --group 改为匹配分组名包含该文本的用户（不区分大小写）
+        #[arg(long, requires = "group")]
+        contains: bool,
+        /// 广播给所有人
+        #[arg(long)]
+        broadcast: bool,
+        /// 加在每条消息前的文本，例如 "[CI]"
+        #[arg(long)]
+        prefix: Option<String>,
+        /// 超过 user.max_message_len 的行拆成多条发出（默认按 user.message_len_policy 截断或跳过）
+        #[arg(long)]
+        split: bool,
+    },
+    /// 发送多播消息给一组用户（对方显示为多播而不是私聊）
+    Multicast {
+        /// 收件人列表，逗号分隔，例如 alice,bob@PC-2,192.168.1.9
+        #[arg(value_delimiter = ',', num_args = 1, required = true)]
+        users: Vec<String>,
         message: String,
     },
     /// 列出在线用户
-    List,
+    List {
+        /// 等待应答的秒数（默认为 network.timeout_secs）
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// 用 ASCII 字符画表格（也可设置 debug.ascii_tables）
+        #[arg(long)]
+        ascii: bool,
+        /// 不广播、不等待，直接输出当前用户表（启动时从缓存恢复的用户）
+        #[arg(long, conflicts_with = "timeout")]
+        cached: bool,
+        /// 只列出该分组的用户（逐字匹配，"(no group)" 表示分组为空）
+        #[arg(long)]
+        group: Option<String>,
+        /// 只列出昵称、登录名或主机名包含该文本的用户（不区分大小写）
+        #[arg(long)]
+        filter: Option<String>,
+        /// 排序依据：name、user、host、ip、group 或 last_seen
+        #[arg(long, default_value = "name")]
+        sort: SortKey,
+        /// 降序排列
+        #[arg(long)]
+        desc: bool,
+        /// 输出的列，逗号分隔：name、user、host、ip、port、group、state、note、last_seen
+        #[arg(long, value_delimiter = ',')]
+        columns: Vec<Column>,
+        /// 输出 JSON（字段与顺序同 --columns）
+        #[arg(long)]
+        json: bool,
+    },
+    /// 查询一个对端的客户端版本（GETINFO），加 --absence 同时查询离开信息（GETABSENCEINFO）
+    Query {
+        /// 用户名、用户名@主机名、IP 或 IP:端口
+        user: String,
+        /// 同时查询离开信息
+        #[arg(long)]
+        absence: bool,
+        /// 等待应答的秒数（默认为 network.timeout_secs）
+        #[arg(long)]
+        timeout: Option<u64>,
+    },
+    /// 列出分组及成员数（分组为空的用户归入 "(no group)"）
+    Groups {
+        #[command(subcommand)]
+        action: Option<GroupsAction>,
+        /// 等待应答的秒数（默认为 network.timeout_secs）
+        #[arg(long, global = true)]
+        timeout: Option<u64>,
+        /// 输出 JSON
+        #[arg(long, global = true)]
+        json: bool,
+    },
     /// 启动交互式会话
-    Chat,
-}
\ No newline at end of file
+    Chat {
+        /// 无输入超过该秒数后发送下线通知并退出
+        #[arg(long)]
+        idle_timeout: Option<u64>,
+        /// 普通文本私聊给该用户（用户名、用户名@主机名、IP 或 IP:端口），默认广播
+        #[arg(long)]
+        with: Option<String>,
+    },
+    /// 常驻运行：保持在线，输出收到的消息，处理协议应答，收到 SIGINT/SIGTERM 时下线退出
+    #[command(alias = "recv")]
+    Daemon,
+    /// 查看或设置本机状态：有常驻实例时通过其控制接口设置，否则只广播一次
+    Status {
+        #[command(subcommand)]
+        action: Option<StatusAction>,
+    },
+    /// 以 NDJSON 逐行输出启动之后的事件（消息、上下线、文件请求、送达回执），不回放历史
+    Watch {
+        /// 只输出这些类别，逗号分隔：msg、presence、file、receipt（默认全部）
+        #[arg(long, value_delimiter = ',')]
+        types: Vec<WatchKind>,
+    },
+    /// 自检：验证广播的发送、回环接收与解码
+    Doctor,
+    /// 配置文件管理
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// 屏蔽对端（写入配置文件的 security.blocked）
+    Block {
+        /// IP、CIDR 网段或 用户名@主机名
+        peer: String,
+    },
+    /// 解除屏蔽
+    Unblock {
+        peer: String,
+    },
+    /// 在多个网段之间中继转发报文
+    Relay {
+        /// 网段描述，格式为 绑定IP=广播IP，可重复指定
+        #[arg(long = "segment", required = true, num_args = 1)]
+        segments: Vec<String>,
+        /// 转发范围：presence（仅在线状态）或 all（全部）
+        #[arg(long, default_value = "all")]
+        filter: RelayFilter,
+        /// 记录经中继上下线的用户，并在日志中输出
+        #[arg(long)]
+        learn_users: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GroupsAction {
+    /// 列出分组的成员：昵称、用户名@主机名与离开状态
+    Show {
+        /// 分组名，逐字匹配（"(no group)" 表示分组为空的用户）
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum StatusAction {
+    /// 设为离开，并立即广播状态
+    Away {
+        /// 离开信息（默认为 presence.away_message）
+        #[arg(trailing_var_arg = true)]
+        message: Vec<String>,
+    },
+    /// 恢复在线
+    Back,
+    /// 免打扰
+    Dnd,
+}
+
+/// status 子命令对应的控制请求，不带动作时为查询
+pub fn status_request(action: Option<&StatusAction>) -> ControlRequest {
+    match action {
+        None => ControlRequest::Status,
+        Some(StatusAction::Away { message }) if message.is_empty() => ControlRequest::Away(None),
+        Some(StatusAction::Away { message }) => ControlRequest::Away(Some(message.join(" "))),
+        Some(StatusAction::Back) => ControlRequest::Back,
+        Some(StatusAction::Dnd) => ControlRequest::Dnd,
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigAction {
+    /// 生成默认配置文件（TOML 格式带逐项注释）
+    Init {
+        /// 输出路径，默认与读取配置时的查找结果相同
+        #[arg(long)]
+        path: Option<PathBuf>,
+        /// 文件格式：toml、yaml 或 json（默认按路径的扩展名）
+        #[arg(long)]
+        format: Option<ConfigFormat>,
+        /// 覆盖已存在的文件
+        #[arg(long)]
+        force: bool,
+    },
+    /// 显示合并配置方案、环境变量与命令行后生效的配置
+    Show,
+    /// 把旧版本的配置文件升级到当前布局并写回（原文件另存为 .bak）
+    Migrate,
+}
+
+/// send、send-file 的收件人：--ip 指定的地址（解析时不查找用户列表），否则为位置参数
+pub fn recipient(recipient: Option<&str>, ip: Option<SocketAddr>) -> String {
+    match ip {
+        Some(addr) => addr.to_string(),
+        None => recipient.unwrap_or_default().to_string(),
+    }
+}
+
+/// 拆分 send 的收件人与消息，返回去重后的收件人（保持顺序）
+///
+/// 消息取自 --message-file、-m、-- 之后的参数，或（旧的两参数形式）最后一个位置参数，`-` 表示标准输入；
+/// 指定 --ip 或 --group 时不能再列出收件人
+fn split_send_args(
+    positionals: &[String],
+    message: Option<&str>,
+    text: &[String],
+    file: Option<BodySource>,
+    ip: Option<SocketAddr>,
+    group: bool,
+) -> Result<(Vec<String>, BodySource), String> {
+    let mut recipients = positionals.to_vec();
+    let message = match (file, message, text.is_empty()) {
+        (Some(file), _, _) => file,
+        (None, Some(_), false) => {
+            return Err("Give the message either with -m/--message or after --, not both".into());
+        }
+        (None, Some(message), true) => BodySource::from_arg(message.to_string()),
+        (None, None, false) => BodySource::from_arg(text.join(" ")),
+        (None, None, true) => BodySource::from_arg(recipients.pop().ok_or("No message given")?),
+    };
+    match ip {
+        Some(addr) if recipients.is_empty() => return Ok((vec![addr.to_string()], message)),
+        Some(_) => return Err("--ip cannot be combined with recipients".into()),
+        None => {}
+    }
+    if group && !recipients.is_empty() {
+        return Err("--group cannot be combined with recipients".into());
+    }
+    if group {
+        return Ok((Vec::new(), message));
+    }
+    if recipients.is_empty() {
+        return Err("No recipient given".into());
+    }
+    let mut unique = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        if !unique.contains(&recipient) {
+            unique.push(recipient);
+        }
+    }
+    Ok((unique, message))
+}
+
+/// --ip 的取值：IP 或 IP:端口，未指定端口时使用 2425
+fn parse_ip_target(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    value
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, IPMSG_PORT))
+        .map_err(|_| format!("'{}' is not an IP address or IP:port", value))
+}
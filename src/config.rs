@@ -1,14 +1,41 @@
 use serde::{Deserialize, Serialize};
 use std::{
-    net::{IpAddr, Ipv4Addr},
-    path::Path,
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
     fs,
 };
 use anyhow::{Context, Result};
+use crate::dump::DEFAULT_DUMP_FILE;
+use crate::history::{HISTORY_BACKENDS, HistoryBackend};
+use crate::migrate::{self, CONFIG_VERSION, VERSION_KEY};
+use crate::peer_match::{PeerFilter, PeerPattern, is_valid_peer_key};
+use crate::transfer::{CHECKSUM_ALGORITHMS, ChecksumAlgorithm};
+
+/// 配置文件名
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+/// 查找配置文件时依次尝试的文件名
+pub const CONFIG_FILE_NAMES: &[&str] = &["config.toml", "config.yaml", "config.yml", "config.json"];
+/// 指定配置文件路径的环境变量
+pub const CONFIG_ENV: &str = "LANMSG_CONFIG";
+/// 配置文件不是 UTF-8 且没有 BOM 时改用的编码（默认 gbk）
+pub const CONFIG_ENCODING_ENV: &str = "LANMSG_CONFIG_ENCODING";
+/// 选择配置方案的环境变量
+pub const PROFILE_ENV: &str = "LANMSG_PROFILE";
+/// 配置方案所在的表，例如 [profile.office.user]
+pub const PROFILE_SECTION: &str = "profile";
+/// 显示生效配置时代替密钥的占位符
+pub const REDACTED: &str = "<redacted>";
 
 // 主配置结构
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AppConfig {
+    /// 配置布局版本，缺失时视为 1，加载时迁移到当前版本
+    #[serde(default = "default_config_version")]
+    pub config_version: u32,
+
     #[serde(default)]
     pub network: NetworkConfig,
     
@@ -19,77 +46,604 @@ pub struct AppConfig {
     pub debug: DebugConfig,
     #[serde(default)]
     pub encoding: EncodingConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    #[serde(default)]
+    pub transfer: TransferConfig,
+    #[serde(default)]
+    pub presence: PresenceConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// 消息模板：名称 -> 文本，send/broadcast --template 使用
+    #[serde(default)]
+    pub templates: BTreeMap<String, String>,
+
+    /// 不认识的配置项（例如新版本写入的字段），保存时原样写回
+    #[serde(flatten)]
+    pub extra: toml::Table,
 }
 
 // 网络配置
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NetworkConfig {
     #[serde(default = "default_bind_ip")]
     pub bind_ip: String,
     
     #[serde(default = "default_port")]
     pub port: u16,
+
+    /// 同时监听的多个端口，为空时只监听 port
+    #[serde(default)]
+    pub ports: Vec<u16>,
     
-    #[serde(default = "default_broadcast_ip")]
-    pub broadcast_ip: String,
+    /// 广播目标，每次广播依次发往所有目标；也接受单个字符串或旧键名 broadcast_ip
+    #[serde(default = "default_broadcast_ips", alias = "broadcast_ip", deserialize_with = "one_or_many")]
+    pub broadcast_ips: Vec<String>,
     
     #[serde(default = "default_timeout_secs")]
     pub timeout_secs: u64,
+
+    /// 在线用户表上限，超出时淘汰最久未活动的用户
+    #[serde(default = "default_max_users")]
+    pub max_users: usize,
+
+    /// 报文去重缓存上限，超出时淘汰最早的条目
+    #[serde(default = "default_max_dedup_entries")]
+    pub max_dedup_entries: usize,
+
+    /// 等待应答（消息确认、版本查询等）的条目上限，超出时放弃最早的一条
+    #[serde(default = "default_max_pending_replies")]
+    pub max_pending_replies: usize,
+
+    /// 回复 ANSENTRY 前的最大随机延迟（毫秒），避免大量节点同时应答
+    #[serde(default = "default_ansentry_jitter_ms")]
+    pub ansentry_jitter_ms: u64,
+
+    /// 广播上线后、执行命令前最多等待的时间（毫秒），让对方应答；要找的收件人出现时提前结束
+    #[serde(default = "default_entry_settle_ms")]
+    pub entry_settle_ms: u64,
+
+    /// 启动时首次广播上线通知前的最大随机延迟（毫秒），错开同时启动的大量节点；0 表示不延迟
+    #[serde(default)]
+    pub entry_jitter_ms: u64,
+
+    /// 同一发送方报文乱序时的最长等待时间（毫秒），0 表示不排序
+    #[serde(default)]
+    pub reorder_window_ms: u64,
+
+    /// 退出时保存用户表，启动时恢复（恢复的用户标记为未确认）
+    #[serde(default = "default_true")]
+    pub cache_users: bool,
+
+    /// 超过该时长（秒）未活动的缓存用户不再恢复
+    #[serde(default = "default_user_cache_max_age_secs")]
+    pub user_cache_max_age_secs: u64,
+
+    /// 广播/组播报文的 TTL（1..=255），默认 1 只在本网段内传播
+    #[serde(default = "default_ttl")]
+    pub ttl: u32,
+
+    /// 从单独的临时端口发送，接收套接字不再收到自己发出的广播
+    #[serde(default)]
+    pub separate_send_socket: bool,
+
+    /// 相邻两次广播的最短间隔（毫秒），更快的广播排队依次发出，0 表示不限
+    #[serde(default = "default_min_broadcast_interval_ms")]
+    pub min_broadcast_interval_ms: u64,
+
+    /// 从任意报文（如 MSG）学习发送方并加入用户表，默认只从上线类报文学习
+    #[serde(default)]
+    pub learn_from_any_packet: bool,
+
+    #[serde(flatten)]
+    pub extra: toml::Table,
 }
 
 // 用户配置
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct UserConfig {
+    /// 显示昵称
     #[serde(default = "default_user_name")]
     pub name: String,
+
+    /// 登录名（报文中的用户字段），留空时使用系统用户名
+    #[serde(default)]
+    pub login: String,
     
     #[serde(default = "default_user_host")]
     pub host: String,
     
     #[serde(default)]
     pub auto_login: bool,
+
+    #[serde(default = "default_user_group")]
     pub group: String,
+
+    /// 允许使用的分组，非空时 group 必须是其中之一；为空表示不限制
+    #[serde(default)]
+    pub allowed_groups: Vec<String>,
+
+    /// 状态说明，随上线报文发出，在对方的用户列表中显示
+    #[serde(default)]
+    pub status_note: String,
+
+    /// 报文版本字段中的客户端标识，不能含冒号
+    #[serde(default = "default_client_version")]
+    pub client_version: String,
+
+    /// 发出的消息最多包含的字符数，0 表示不限
+    #[serde(default)]
+    pub max_message_len: usize,
+
+    /// 消息超长时的处理：truncate（截断并加省略号）或 reject（拒绝发送）
+    #[serde(default = "default_message_len_policy")]
+    pub message_len_policy: String,
+
+    #[serde(flatten)]
+    pub extra: toml::Table,
 }
 
 // 编码格式
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct EncodingConfig {
     #[serde(default = "default_gbk")]
     pub protocol: String, // 协议编码 (gbk/utf8)
     #[serde(default = "default_utf8")]
     pub display: String,  // 显示编码
+
+    /// 转义报文固定字段中的冒号与 NUL；标准客户端不认识这种转义，只在所有对端都是 lanMsg 时开启
+    #[serde(default)]
+    pub escape_fields: bool,
+
+    /// 按对端强制使用的协议编码，键为 IP、CIDR 或 用户名@主机名
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub peers: BTreeMap<String, String>,
+
+    #[serde(flatten)]
+    pub extra: toml::Table,
+}
+
+// 安全配置
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SecurityConfig {
+    /// 共享密钥，非空时对收发的报文做 HMAC 签名校验
+    #[serde(default)]
+    pub psk: String,
+
+    /// 黑名单：IP、CIDR 或 用户名@主机名，来自这些对端的报文直接丢弃
+    #[serde(default)]
+    pub blocked: Vec<String>,
+
+    /// 白名单：非空时只接受名单内的对端
+    #[serde(default)]
+    pub allowed: Vec<String>,
+
+    #[serde(flatten)]
+    pub extra: toml::Table,
+}
+
+impl SecurityConfig {
+    /// 黑名单/白名单
+    pub fn peer_filter(&self) -> PeerFilter {
+        PeerFilter::new(&self.blocked, &self.allowed)
+    }
+
+    /// 加入黑名单，已存在时返回 false
+    pub fn block(&mut self, entry: &str) -> Result<bool> {
+        let mut filter = self.peer_filter();
+        let added = filter.block(entry)?;
+        if added {
+            self.blocked.push(entry.to_string());
+        }
+        Ok(added)
+    }
+
+    /// 移出黑名单，不存在时返回 false
+    pub fn unblock(&mut self, entry: &str) -> bool {
+        let before = self.blocked.len();
+        self.blocked.retain(|existing| existing != entry);
+        self.blocked.len() != before
+    }
+}
+
+// 离开状态配置
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PresenceConfig {
+    /// 设置离开且未指定内容时使用的离开信息
+    #[serde(default = "default_away_message")]
+    pub away_message: String,
+
+    /// 离开状态下自动回复收到的消息
+    #[serde(default = "default_true")]
+    pub auto_reply: bool,
+
+    /// 对同一发送方两次自动回复之间的最短间隔（秒）
+    #[serde(default = "default_auto_reply_interval_secs")]
+    pub auto_reply_interval_secs: u64,
+
+    /// 交互模式下无输入超过该秒数后自动设为离开，下次输入时恢复在线；0 表示关闭
+    #[serde(default)]
+    pub auto_away_after_idle_secs: u64,
+
+    /// 隐身：不广播上线、不应答上线通知，只被动收集在线用户；单播消息仍可发出（会暴露给对方）
+    #[serde(default)]
+    pub stealth: bool,
+
+    #[serde(flatten)]
+    pub extra: toml::Table,
+}
+
+// 消息历史配置
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    /// 关闭时不向磁盘写入任何历史记录
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// 存储后端：jsonl 或 sqlite
+    #[serde(default = "default_history_backend")]
+    pub backend: String,
+
+    /// 历史文件路径，留空时使用平台数据目录
+    #[serde(default)]
+    pub path: String,
+
+    /// 最多保留的消息条数，0 表示不限
+    #[serde(default = "default_history_max_messages")]
+    pub max_messages: usize,
+
+    /// 消息保留天数，0 表示不限
+    #[serde(default = "default_history_max_age_days")]
+    pub max_age_days: u64,
+
+    /// 是否保存消息正文，false 时只记录时间与对端
+    #[serde(default = "default_true")]
+    pub store_bodies: bool,
+
+    #[serde(flatten)]
+    pub extra: toml::Table,
+}
+
+// 文件接收配置
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransferConfig {
+    /// 是否接收文件
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+
+    /// 下载目录，留空使用系统下载目录（没有时为 ~/lanmsg-files），需要时自动创建
+    #[serde(default)]
+    pub download_dir: String,
+
+    /// 自动接收这些对端的文件（IP、CIDR 或 用户名@主机名）
+    #[serde(default)]
+    pub auto_accept_from: Vec<String>,
+
+    /// 自动接收的单次文件总大小上限（字节），超过时仍需确认
+    #[serde(default = "default_auto_accept_max_bytes")]
+    pub auto_accept_max_bytes: u64,
+
+    /// 未处理的文件发送请求保留时长（秒）
+    #[serde(default = "default_offer_ttl_secs")]
+    pub offer_ttl_secs: u64,
+
+    /// 未处理的文件发送请求上限，超出时丢弃最早的一条
+    #[serde(default = "default_max_pending_offers")]
+    pub max_pending_offers: usize,
+
+    /// 同时进行的传输数上限
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
+
+    /// 传输速率上限（KB/s），0 表示不限
+    #[serde(default)]
+    pub max_rate_kbps: u64,
+
+    /// 取消传输时保留已接收的部分文件
+    #[serde(default)]
+    pub keep_partial_on_cancel: bool,
+
+    /// 发送文件时计算的校验算法：sha256、crc32 或 none，接收方按附件中的校验值验证
+    #[serde(default = "default_checksum")]
+    pub checksum: String,
+
+    #[serde(flatten)]
+    pub extra: toml::Table,
 }
 
 // 调试配置
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DebugConfig {
     #[serde(default = "default_log_level")]
     pub log_level: String,
     
+    /// 把收发的每个报文写入转储文件
     #[serde(default)]
     pub dump_packets: bool,
+
+    /// 转储文件路径，留空时使用平台数据目录
+    #[serde(default)]
+    pub dump_path: String,
+
+    /// 转储文件超过该大小（字节）时轮转
+    #[serde(default = "default_dump_max_bytes")]
+    pub dump_max_bytes: u64,
+
+    /// 保留的轮转文件个数
+    #[serde(default = "default_dump_keep")]
+    pub dump_keep: usize,
+
+    /// 只接收不发送：不广播上线/下线，也不自动应答
+    #[serde(default)]
+    pub passive: bool,
+
+    /// 内存中保留的最近收到的消息条数
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+
+    /// 表格使用 ASCII 框线（+ - |），用于不支持 Unicode 框线的控制台
+    #[serde(default)]
+    pub ascii_tables: bool,
+
+    /// 事件通道容量：订阅者落后超过该条数时跳过最早的事件
+    #[serde(default = "default_event_channel_capacity")]
+    pub event_channel_capacity: usize,
+
+    #[serde(flatten)]
+    pub extra: toml::Table,
+}
+
+/// 配置文件格式，按扩展名区分，不认识的扩展名按 TOML 处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConfigFormat {
+    #[default]
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// 按文件扩展名判断格式（.toml、.yaml/.yml、.json）
+    pub fn from_path(path: &Path) -> Self {
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "yaml" | "yml" => Self::Yaml,
+            "json" => Self::Json,
+            _ => Self::Toml,
+        }
+    }
+
+    /// 该格式的标准扩展名
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Toml => "toml",
+            Self::Yaml => "yaml",
+            Self::Json => "json",
+        }
+    }
+
+    /// 解析为表，之后的迁移、配置方案与分段反序列化与 TOML 完全相同
+    fn parse_table(self, content: &str) -> Result<toml::Table> {
+        Ok(match self {
+            Self::Toml => toml::from_str(content)?,
+            Self::Yaml if content.trim().is_empty() => toml::Table::new(),
+            Self::Yaml => serde_yaml::from_str(content)?,
+            Self::Json => serde_json::from_str(content)?,
+        })
+    }
+
+    fn serialize(self, config: &AppConfig) -> Result<String> {
+        Ok(match self {
+            Self::Toml => toml::to_string_pretty(config)?,
+            Self::Yaml => serde_yaml::to_string(config)?,
+            Self::Json => serde_json::to_string_pretty(config)? + "\n",
+        })
+    }
+}
+
+impl std::str::FromStr for ConfigFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "toml" => Ok(Self::Toml),
+            "yaml" | "yml" => Ok(Self::Yaml),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow::anyhow!("Unknown config format '{}', expected toml, yaml or json", s)),
+        }
+    }
+}
+
+/// 旧键名改为当前键名（段, 旧键, 新键），在叠加配置方案之前统一，避免同一项以两个键名出现
+const KEY_ALIASES: &[(&str, &str, &str)] = &[("network", "broadcast_ip", "broadcast_ips")];
+
+fn canonicalize_keys(table: &mut toml::Table) {
+    for &(section, alias, key) in KEY_ALIASES {
+        if let Some(toml::Value::Table(section)) = table.get_mut(section)
+            && !section.contains_key(key)
+            && let Some(value) = section.remove(alias)
+        {
+            section.insert(key.to_string(), value);
+        }
+    }
+}
+
+/// 把 overlay 逐层合并到 base：两边都是表时递归合并，否则直接替换
+fn overlay_table(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                overlay_table(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn unknown_profile(profile: &str, table: &toml::Table) -> anyhow::Error {
+    let available: Vec<String> = AppConfig::profiles_in(table).keys().cloned().collect();
+    if available.is_empty() {
+        return anyhow::anyhow!(
+            "Unknown profile '{}': no [{}.<name>] sections are defined",
+            profile,
+            PROFILE_SECTION
+        );
+    }
+    anyhow::anyhow!(
+        "Unknown profile '{}', available profiles: {}",
+        profile,
+        available.join(", ")
+    )
+}
+
+/// 读取配置文件文本：按 BOM 识别 UTF-8/UTF-16 并去掉 BOM，
+/// 没有 BOM 且不是合法 UTF-8 时按 LANMSG_CONFIG_ENCODING（默认 gbk）解码
+pub fn read_config_text(path: &Path) -> Result<String> {
+    let bytes = fs::read(path)?;
+    let fallback = std::env::var(CONFIG_ENCODING_ENV).ok();
+    decode_config_bytes(&bytes, fallback.as_deref())
+        .with_context(|| format!("Failed to decode config file {}", path.display()))
+}
+
+/// 解码配置文件内容，fallback 为无 BOM 且非 UTF-8 时使用的编码名
+pub fn decode_config_bytes(bytes: &[u8], fallback: Option<&str>) -> Result<String> {
+    if let Some((encoding, bom_len)) = encoding_rs::Encoding::for_bom(bytes) {
+        let (text, had_errors) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+        if had_errors {
+            return Err(anyhow::anyhow!("invalid {} content after BOM", encoding.name()));
+        }
+        return Ok(text.into_owned());
+    }
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return Ok(text.to_string());
+    }
+    let label = fallback.unwrap_or("gbk");
+    let encoding = encoding_rs::Encoding::for_label(label.as_bytes())
+        .ok_or_else(|| anyhow::anyhow!("unknown encoding '{}' in ${}", label, CONFIG_ENCODING_ENV))?;
+    let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+    if had_errors {
+        return Err(anyhow::anyhow!(
+            "not valid UTF-8 or {}, set ${} to the file's encoding",
+            encoding.name(),
+            CONFIG_ENCODING_ENV
+        ));
+    }
+    log::warn!("Config file is not UTF-8, decoded as {}", encoding.name());
+    Ok(text.into_owned())
+}
+
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+/// 取出并反序列化一段配置，失败时记录原因并返回默认值
+fn section<T: serde::de::DeserializeOwned + Default>(
+    table: &mut toml::Table,
+    name: &str,
+    failed: &mut Vec<String>,
+) -> T {
+    let Some(value) = table.remove(name) else {
+        return T::default();
+    };
+    match value.try_into() {
+        Ok(section) => section,
+        Err(e) => {
+            failed.push(format!("invalid [{}] section: {}", name, e.message()));
+            T::default()
+        }
+    }
 }
 
 // 默认值函数
+fn default_config_version() -> u32 { CONFIG_VERSION }
 fn default_bind_ip() -> String { "0.0.0.0".to_string() }
 fn default_port() -> u16 { 2425 }
-fn default_broadcast_ip() -> String { "255.255.255.255".to_string() }
+fn default_broadcast_ips() -> Vec<String> { vec!["255.255.255.255".to_string()] }
 fn default_timeout_secs() -> u64 { 3 }
+fn default_max_users() -> usize { 1024 }
+fn default_max_dedup_entries() -> usize { 4096 }
+fn default_max_pending_replies() -> usize { 1024 }
+fn default_ansentry_jitter_ms() -> u64 { 500 }
+fn default_entry_settle_ms() -> u64 { 2000 }
+fn default_min_broadcast_interval_ms() -> u64 { 100 }
+fn default_true() -> bool { true }
+fn default_ttl() -> u32 { 1 }
+fn default_user_cache_max_age_secs() -> u64 { 7 * 24 * 3600 }
+fn default_auto_accept_max_bytes() -> u64 { 10 * 1024 * 1024 }
+fn default_offer_ttl_secs() -> u64 { 600 }
+fn default_max_concurrent() -> usize { 4 }
+fn default_max_pending_offers() -> usize { 64 }
+fn default_checksum() -> String { "sha256".to_string() }
+fn default_away_message() -> String { "I'm away from my desk".to_string() }
+fn default_auto_reply_interval_secs() -> u64 { 300 }
+fn default_history_backend() -> String { "jsonl".to_string() }
+fn default_history_max_messages() -> usize { 10000 }
+fn default_history_max_age_days() -> u64 { 90 }
 fn default_user_name() -> String { "anonymous".to_string() }
 fn default_user_host() -> String { "localhost".to_string() }
 fn default_user_group() -> String { "group".to_string() }
+fn default_client_version() -> String { crate::protocol::PROTOCOL_VERSION.to_string() }
+fn default_message_len_policy() -> String { "truncate".to_string() }
+fn default_dump_max_bytes() -> u64 { 10 * 1024 * 1024 }
+fn default_dump_keep() -> usize { 5 }
+fn default_history_size() -> usize { 100 }
+fn default_event_channel_capacity() -> usize { crate::event::EVENT_CHANNEL_CAPACITY }
 fn default_log_level() -> String { "info".to_string() }
 fn default_gbk() -> String { "gbk".to_string() }
 fn default_utf8() -> String { "utf-8".to_string() }
 
+/// 单个字符串或字符串数组，统一为列表
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct OneOrMany;
+
+    impl<'de> serde::de::Visitor<'de> for OneOrMany {
+        type Value = Vec<String>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string or an array of strings")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, value: &str) -> std::result::Result<Self::Value, E> {
+            Ok(vec![value.to_string()])
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error> {
+            let mut values = Vec::new();
+            while let Some(value) = seq.next_element()? {
+                values.push(value);
+            }
+            Ok(values)
+        }
+    }
+
+    deserializer.deserialize_any(OneOrMany)
+}
+
 // 实现默认配置
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
+            config_version: CONFIG_VERSION,
             network: NetworkConfig::default(),
             user: UserConfig::default(),
             debug: DebugConfig::default(),
             encoding: EncodingConfig::default(),
+            security: SecurityConfig::default(),
+            transfer: TransferConfig::default(),
+            presence: PresenceConfig::default(),
+            history: HistoryConfig::default(),
+            templates: BTreeMap::new(),
+            extra: toml::Table::new(),
         }
     }
 }
@@ -99,8 +653,23 @@ impl Default for NetworkConfig {
         Self {
             bind_ip: default_bind_ip(),
             port: default_port(),
-            broadcast_ip: default_broadcast_ip(),
+            ports: Vec::new(),
+            broadcast_ips: default_broadcast_ips(),
             timeout_secs: default_timeout_secs(),
+            max_users: default_max_users(),
+            max_dedup_entries: default_max_dedup_entries(),
+            max_pending_replies: default_max_pending_replies(),
+            ansentry_jitter_ms: default_ansentry_jitter_ms(),
+            entry_settle_ms: default_entry_settle_ms(),
+            entry_jitter_ms: 0,
+            reorder_window_ms: 0,
+            cache_users: default_true(),
+            user_cache_max_age_secs: default_user_cache_max_age_secs(),
+            ttl: default_ttl(),
+            separate_send_socket: false,
+            min_broadcast_interval_ms: default_min_broadcast_interval_ms(),
+            learn_from_any_packet: false,
+            extra: toml::Table::new(),
         }
     }
 }
@@ -109,9 +678,252 @@ impl Default for UserConfig {
     fn default() -> Self {
         Self {
             name: default_user_name(),
+            login: String::new(),
             host: default_user_host(),
             group: default_user_group(),
+            allowed_groups: Vec::new(),
+            status_note: String::new(),
+            client_version: default_client_version(),
+            max_message_len: 0,
+            message_len_policy: default_message_len_policy(),
             auto_login: false,
+            extra: toml::Table::new(),
+        }
+    }
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            away_message: default_away_message(),
+            auto_reply: default_true(),
+            auto_reply_interval_secs: default_auto_reply_interval_secs(),
+            auto_away_after_idle_secs: 0,
+            stealth: false,
+            extra: toml::Table::new(),
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            backend: default_history_backend(),
+            path: String::new(),
+            max_messages: default_history_max_messages(),
+            max_age_days: default_history_max_age_days(),
+            store_bodies: default_true(),
+            extra: toml::Table::new(),
+        }
+    }
+}
+
+impl HistoryConfig {
+    /// 配置的存储后端，不认识时返回 None
+    pub fn backend(&self) -> Option<HistoryBackend> {
+        HistoryBackend::parse(&self.backend)
+    }
+
+    /// 实际使用的历史文件路径
+    pub fn path(&self) -> PathBuf {
+        if !self.path.is_empty() {
+            return PathBuf::from(&self.path);
+        }
+        let file_name = self.backend().unwrap_or(HistoryBackend::Jsonl).file_name();
+        match dirs::data_dir() {
+            Some(dir) => dir.join("lanmsg").join(file_name),
+            None => PathBuf::from(file_name),
+        }
+    }
+
+    /// 检查历史文件所在目录可写
+    pub fn check_path(&self) -> Option<ConfigError> {
+        let path = self.path();
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        check_writable_dir(
+            "history.path",
+            &path,
+            dir,
+            "a path in a writable directory (or set history.enabled = false)",
+        )
+    }
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_true(),
+            download_dir: String::new(),
+            auto_accept_from: Vec::new(),
+            auto_accept_max_bytes: default_auto_accept_max_bytes(),
+            offer_ttl_secs: default_offer_ttl_secs(),
+            max_pending_offers: default_max_pending_offers(),
+            max_concurrent: default_max_concurrent(),
+            max_rate_kbps: 0,
+            keep_partial_on_cancel: false,
+            checksum: default_checksum(),
+            extra: toml::Table::new(),
+        }
+    }
+}
+
+impl TransferConfig {
+    /// 发送文件时使用的校验算法，none 时为 None
+    pub fn checksum(&self) -> Option<ChecksumAlgorithm> {
+        ChecksumAlgorithm::parse(&self.checksum)
+    }
+
+    /// 实际使用的下载目录
+    pub fn download_dir(&self) -> PathBuf {
+        if !self.download_dir.is_empty() {
+            return PathBuf::from(&self.download_dir);
+        }
+        dirs::download_dir()
+            .or_else(|| dirs::home_dir().map(|home| home.join("lanmsg-files")))
+            .unwrap_or_else(|| PathBuf::from("lanmsg-files"))
+    }
+
+    /// 是否自动接收来自该对端、总大小为 size 的文件
+    pub fn auto_accepts(&self, ip: IpAddr, users: &[&str], size: u64) -> bool {
+        size <= self.auto_accept_max_bytes
+            && self
+                .auto_accept_from
+                .iter()
+                .filter_map(|peer| PeerPattern::parse(peer))
+                .any(|pattern| pattern.matches(ip, users))
+    }
+
+    /// 检查下载目录可写；目录尚不存在时检查最近的已存在上级目录
+    pub fn check_download_dir(&self) -> Option<ConfigError> {
+        let dir = self.download_dir();
+        check_writable_dir(
+            "transfer.download_dir",
+            &dir,
+            &dir,
+            "a writable directory (or set transfer.enabled = false)",
+        )
+    }
+}
+
+/// 检查目录可写；目录尚不存在时检查最近的已存在上级目录，value 为报告错误时显示的取值
+///
+/// 按当前用户的实际权限检查，不在目录中写入任何文件
+fn check_writable_dir(field: &str, value: &Path, dir: &Path, expected: &str) -> Option<ConfigError> {
+    let error = |reason: &str| Some(ConfigError::new(field, value.display(), reason));
+    let Some(existing) = dir.ancestors().find(|path| path.exists()) else {
+        return error("a directory that can be created");
+    };
+    if !existing.is_dir() {
+        return error("a directory, not a file");
+    }
+    if dir_writable(existing) { None } else { error(expected) }
+}
+
+/// 当前用户能否在目录中创建文件
+#[cfg(unix)]
+fn dir_writable(dir: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: path 是存活的、以 NUL 结尾的 C 字符串，access 只读取它
+    unsafe { libc::access(path.as_ptr(), libc::W_OK | libc::X_OK) == 0 }
+}
+
+/// 当前用户能否在目录中创建文件（非 Unix 平台只看只读属性）
+#[cfg(not(unix))]
+fn dir_writable(dir: &Path) -> bool {
+    fs::metadata(dir).is_ok_and(|metadata| !metadata.permissions().readonly())
+}
+
+impl UserConfig {
+    /// 实际使用的登录名：未配置时取系统用户名，都没有时与昵称相同
+    pub fn login(&self) -> String {
+        if !self.login.is_empty() {
+            return self.login.clone();
+        }
+        ["USER", "USERNAME"]
+            .iter()
+            .filter_map(|key| std::env::var(key).ok())
+            .find(|login| !login.is_empty())
+            .unwrap_or_else(|| self.name.clone())
+    }
+
+    /// 发出消息的长度限制，策略不认识时按 truncate 处理
+    pub fn message_limit(&self) -> MessageLimit {
+        MessageLimit {
+            max_len: self.max_message_len,
+            policy: MessageLenPolicy::parse(&self.message_len_policy).unwrap_or(MessageLenPolicy::Truncate),
+        }
+    }
+}
+
+/// 超长消息的处理方式名称
+pub const MESSAGE_LEN_POLICIES: &[&str] = &["truncate", "reject"];
+/// 截断的消息末尾加上的标记
+pub const TRUNCATION_MARKER: &str = "…";
+
+/// 超长消息的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MessageLenPolicy {
+    /// 截断并以省略号结尾
+    #[default]
+    Truncate,
+    /// 拒绝发送
+    Reject,
+}
+
+impl MessageLenPolicy {
+    /// 解析配置中的策略名称，不认识时返回 None
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "truncate" => Some(Self::Truncate),
+            "reject" => Some(Self::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// 发出消息的长度限制（按字符计）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MessageLimit {
+    /// 0 表示不限
+    pub max_len: usize,
+    pub policy: MessageLenPolicy,
+}
+
+impl MessageLimit {
+    /// 按策略处理消息：未超长时为 None，截断时返回截断后的文本（含省略号，总长不超过上限）
+    pub fn apply(&self, text: &str) -> Result<Option<String>> {
+        let len = text.chars().count();
+        if self.max_len == 0 || len <= self.max_len {
+            return Ok(None);
+        }
+        match self.policy {
+            MessageLenPolicy::Truncate => {
+                let keep = self.max_len.saturating_sub(TRUNCATION_MARKER.chars().count());
+                Ok(Some(text.chars().take(keep).collect::<String>() + TRUNCATION_MARKER))
+            }
+            MessageLenPolicy::Reject => Err(anyhow::anyhow!(
+                "Message is {} characters, longer than user.max_message_len ({})",
+                len,
+                self.max_len
+            )),
+        }
+    }
+}
+
+impl DebugConfig {
+    /// 实际使用的转储文件路径
+    pub fn dump_path(&self) -> PathBuf {
+        if !self.dump_path.is_empty() {
+            return PathBuf::from(&self.dump_path);
+        }
+        match dirs::data_dir() {
+            Some(dir) => dir.join("lanmsg").join(DEFAULT_DUMP_FILE),
+            None => PathBuf::from(DEFAULT_DUMP_FILE),
         }
     }
 }
@@ -121,6 +933,14 @@ impl Default for DebugConfig {
         Self {
             log_level: default_log_level(),
             dump_packets: false,
+            dump_path: String::new(),
+            dump_max_bytes: default_dump_max_bytes(),
+            dump_keep: default_dump_keep(),
+            passive: false,
+            history_size: default_history_size(),
+            ascii_tables: false,
+            event_channel_capacity: default_event_channel_capacity(),
+            extra: toml::Table::new(),
         }
     }
 }
@@ -130,67 +950,814 @@ impl Default for EncodingConfig {
         Self{
             protocol: default_gbk(),
             display: default_utf8(),
+            escape_fields: false,
+            peers: BTreeMap::new(),
+            extra: toml::Table::new(),
         }
     }
 }
 
 // 配置方法实现
 impl AppConfig {
-    /// 从文件加载配置
+    /// 从文件加载配置（只解析，不校验取值，校验见 validate）
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::load_profile(path, None)
+    }
+
+    /// 从文件加载配置并叠加指定的配置方案
+    pub fn load_profile(path: impl AsRef<Path>, profile: Option<&str>) -> Result<Self> {
         let path = path.as_ref();
-        let config = match fs::read_to_string(path) {
-            Ok(content) => {
-                let cfg: Self = toml::from_str(&content)
-                    .context("Failed to parse config file")?;
-                
-                if !cfg.network.is_valid() {
-                    eprintln!("Invalid network config, using defaults");
-                    Self::default()
-                } else {
-                    cfg
+        let config = match read_config_text(path) {
+            Ok(content) => Self::parse_profile_as(&content, ConfigFormat::from_path(path), profile)
+                .with_context(|| format!("Failed to parse config file {}", path.display()))?,
+            Err(e) if is_not_found(&e) => {
+                log::info!("Config file not found, using defaults");
+                let config = Self::default();
+                if let Some(profile) = profile {
+                    config.check_profile(profile)?;
                 }
+                config
             }
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                println!("Config file not found, using defaults");
-                Self::default()
-            }
-            Err(e) => return Err(e.into()),
+            Err(e) => return Err(e),
         };
         
         Ok(config)
     }
 
-    /// 获取绑定地址
-    pub fn bind_addr(&self) -> String {
-        format!("{}:{}", self.network.bind_ip, self.network.port)
+    /// 解析配置文本，各段分别反序列化
+    ///
+    /// 某一段有错只报告该段，缺失的字段取默认值，不影响其他段
+    pub fn parse(content: &str) -> Result<Self> {
+        Self::parse_profile(content, None)
     }
 
-    /// 获取广播地址
-    pub fn broadcast_addr(&self) -> String {
-        format!("{}:{}", self.network.broadcast_ip, self.network.port)
+    /// 解析配置文本，指定配置方案时把 [profile.<名称>] 叠加到基础配置上
+    ///
+    /// 叠加按表逐层合并：方案中出现的键覆盖基础配置，未出现的保持不变，数组整体替换
+    pub fn parse_profile(content: &str, profile: Option<&str>) -> Result<Self> {
+        Self::parse_profile_as(content, ConfigFormat::Toml, profile)
     }
-}
 
-impl NetworkConfig {
-    /// 验证网络配置有效性
-    pub fn is_valid(&self) -> bool {
-        let ip_valid = self.bind_ip.parse::<IpAddr>().is_ok() 
-            && self.broadcast_ip.parse::<IpAddr>().is_ok();
-        let port_valid = self.port > 1024 && self.port < 65535;
-        ip_valid && port_valid
+    /// 按指定格式解析配置文本，取默认值与报错方式与 TOML 相同
+    pub fn parse_profile_as(content: &str, format: ConfigFormat, profile: Option<&str>) -> Result<Self> {
+        let mut table = format.parse_table(content)?;
+        for change in migrate::migrate(&mut table)? {
+            log::info!("Config migration {}", change);
+        }
+        if let Some(profile) = profile {
+            let overlay = Self::profiles_in(&table)
+                .get(profile)
+                .cloned()
+                .ok_or_else(|| unknown_profile(profile, &table))?;
+            let toml::Value::Table(mut overlay) = overlay else {
+                return Err(anyhow::anyhow!("[{}.{}] must be a table", PROFILE_SECTION, profile));
+            };
+            canonicalize_keys(&mut table);
+            canonicalize_keys(&mut overlay);
+            overlay_table(&mut table, overlay);
+        }
+        let mut failed = Vec::new();
+        table.remove(VERSION_KEY);
+        let config = Self {
+            config_version: CONFIG_VERSION,
+            network: section(&mut table, "network", &mut failed),
+            user: section(&mut table, "user", &mut failed),
+            debug: section(&mut table, "debug", &mut failed),
+            encoding: section(&mut table, "encoding", &mut failed),
+            security: section(&mut table, "security", &mut failed),
+            transfer: section(&mut table, "transfer", &mut failed),
+            presence: section(&mut table, "presence", &mut failed),
+            history: section(&mut table, "history", &mut failed),
+            templates: section(&mut table, "templates", &mut failed),
+            extra: table,
+        };
+        if !failed.is_empty() {
+            return Err(anyhow::anyhow!(failed.join("; ")));
+        }
+        Ok(config)
     }
-}
 
-// 单元测试
-// #[cfg(test)]
-/*  mod tests {
-    use super::*;
-    use tempfile::NamedTempFile;
-    // use crate::config::fs::write;
-    // use std::fmt::write;
-    // use std::fs::write;
-    // use std::ptr::write;
+    /// 展开消息模板：`{name}`、`{host}` 换成本机昵称与主机名，其他花括号原样保留
+    pub fn expand_template(&self, name: &str) -> Result<String> {
+        let Some(template) = self.templates.get(name) else {
+            if self.templates.is_empty() {
+                return Err(anyhow::anyhow!("Unknown template '{}': no [templates] are defined", name));
+            }
+            let available: Vec<&str> = self.templates.keys().map(String::as_str).collect();
+            return Err(anyhow::anyhow!(
+                "Unknown template '{}', available templates: {}",
+                name,
+                available.join(", ")
+            ));
+        };
+        Ok(template
+            .replace("{name}", &self.user.name)
+            .replace("{host}", &self.user.host))
+    }
+
+    /// 配置文件中定义的配置方案名称
+    pub fn profiles(&self) -> Vec<String> {
+        Self::profiles_in(&self.extra).keys().cloned().collect()
+    }
+
+    fn profiles_in(table: &toml::Table) -> toml::Table {
+        table
+            .get(PROFILE_SECTION)
+            .and_then(|profiles| profiles.as_table())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// 生效配置的 TOML 文本：方案定义已叠加进各段，不再重复输出；已设置的密钥替换为占位符
+    pub fn effective_toml(&self) -> Result<String> {
+        let mut effective = self.clone();
+        effective.extra.remove(PROFILE_SECTION);
+        if !effective.security.psk.is_empty() {
+            effective.security.psk = REDACTED.to_string();
+        }
+        Ok(toml::to_string_pretty(&effective)?)
+    }
+
+    /// 检查配置方案是否存在，不存在时列出可用的方案
+    fn check_profile(&self, profile: &str) -> Result<()> {
+        if self.profiles().iter().any(|name| name == profile) {
+            return Ok(());
+        }
+        Err(unknown_profile(profile, &self.extra))
+    }
+
+    /// 保存配置（格式按扩展名；先写临时文件再改名，中途退出不会截断原文件），目录不存在时自动创建
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let content = ConfigFormat::from_path(path)
+            .serialize(self)
+            .context("Failed to serialize config")?;
+        write_atomic(path, content.as_bytes())
+    }
+
+    /// 把旧版本的配置文件迁移到当前版本并写回，原文件另存为 .bak
+    ///
+    /// 返回已执行迁移的说明，文件已是当前版本时不写入并返回空列表
+    pub fn migrate_file(path: impl AsRef<Path>) -> Result<Vec<String>> {
+        let path = path.as_ref();
+        let content = read_config_text(path)?;
+        let format = ConfigFormat::from_path(path);
+        let mut table = format
+            .parse_table(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        let original = migrate::file_version(&table)?;
+        let applied = migrate::migrate(&mut table)?;
+        if original == CONFIG_VERSION {
+            return Ok(applied);
+        }
+        let config = Self::parse_profile_as(&content, format, None)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        let backup = backup_path(path);
+        fs::copy(path, &backup)
+            .with_context(|| format!("Failed to back up {} to {}", path.display(), backup.display()))?;
+        config.save(path)?;
+        Ok(applied)
+    }
+
+    /// 与文件中的配置不同时才保存，返回是否写入
+    pub fn save_if_changed(&self, path: impl AsRef<Path>) -> Result<bool> {
+        let path = path.as_ref();
+        let format = ConfigFormat::from_path(path);
+        let on_disk = read_config_text(path)
+            .ok()
+            .and_then(|content| Self::parse_profile_as(&content, format, None).ok());
+        if on_disk.as_ref() == Some(self) {
+            return Ok(false);
+        }
+        self.save(path)?;
+        Ok(true)
+    }
+
+    /// 校验全部配置项，返回发现的所有错误
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = self.network.validate();
+        for (field, label) in [
+            ("encoding.protocol", &self.encoding.protocol),
+            ("encoding.display", &self.encoding.display),
+        ] {
+            if encoding_rs::Encoding::for_label(label.as_bytes()).is_none() {
+                errors.push(ConfigError::new(field, label, "an encoding name such as gbk or utf-8"));
+            }
+        }
+        if self.user.client_version.is_empty() || self.user.client_version.contains(':') {
+            errors.push(ConfigError::new(
+                "user.client_version",
+                &self.user.client_version,
+                "a non-empty client identifier without ':'",
+            ));
+        }
+        if MessageLenPolicy::parse(&self.user.message_len_policy).is_none() {
+            errors.push(ConfigError::new(
+                "user.message_len_policy",
+                &self.user.message_len_policy,
+                &format!("one of {}", MESSAGE_LEN_POLICIES.join(", ")),
+            ));
+        }
+        if !self.user.allowed_groups.is_empty() && !self.user.allowed_groups.contains(&self.user.group) {
+            errors.push(ConfigError::new(
+                "user.group",
+                &self.user.group,
+                &format!("one of the allowed groups ({})", self.user.allowed_groups.join(", ")),
+            ));
+        }
+        for (peer, label) in &self.encoding.peers {
+            let field = format!("encoding.peers.\"{}\"", peer);
+            if !is_valid_peer_key(peer) {
+                errors.push(ConfigError::new(&field, peer, "an IP, a CIDR range or user@host as the key"));
+            } else if encoding_rs::Encoding::for_label(label.as_bytes()).is_none() {
+                errors.push(ConfigError::new(&field, label, "an encoding name such as gbk or utf-8"));
+            }
+        }
+        for (name, list) in [
+            ("security.blocked", &self.security.blocked),
+            ("security.allowed", &self.security.allowed),
+        ] {
+            for (index, peer) in list.iter().enumerate() {
+                if !is_valid_peer_key(peer) {
+                    errors.push(ConfigError::new(
+                        &format!("{}[{}]", name, index),
+                        peer,
+                        "an IP, a CIDR range or user@host",
+                    ));
+                }
+            }
+        }
+        for peer in &self.transfer.auto_accept_from {
+            if !is_valid_peer_key(peer) {
+                errors.push(ConfigError::new(
+                    "transfer.auto_accept_from",
+                    peer,
+                    "an IP, a CIDR range or user@host",
+                ));
+            }
+        }
+        if self.transfer.max_concurrent == 0 {
+            errors.push(ConfigError::new("transfer.max_concurrent", 0, "a positive number"));
+        }
+        if self.transfer.max_pending_offers == 0 {
+            errors.push(ConfigError::new("transfer.max_pending_offers", 0, "a positive number"));
+        }
+        if self.debug.event_channel_capacity == 0 {
+            errors.push(ConfigError::new("debug.event_channel_capacity", 0, "a positive number"));
+        }
+        if !CHECKSUM_ALGORITHMS.contains(&self.transfer.checksum.to_ascii_lowercase().as_str()) {
+            errors.push(ConfigError::new(
+                "transfer.checksum",
+                &self.transfer.checksum,
+                &format!("one of {}", CHECKSUM_ALGORITHMS.join(", ")),
+            ));
+        }
+        if self.history.backend().is_none() {
+            errors.push(ConfigError::new(
+                "history.backend",
+                &self.history.backend,
+                &format!("one of {}", HISTORY_BACKENDS.join(", ")),
+            ));
+        }
+        if !LOG_LEVELS.contains(&self.debug.log_level.to_ascii_lowercase().as_str()) {
+            errors.push(ConfigError::new(
+                "debug.log_level",
+                &self.debug.log_level,
+                "one of error, warn, info, debug, trace",
+            ));
+        }
+        errors
+    }
+
+    /// 合并得到最终生效的配置：默认值 < 配置文件 < 环境变量 < 命令行
+    pub fn merged(mut self, env: &ConfigOverrides, cli: &ConfigOverrides) -> Self {
+        env.apply(&mut self);
+        cli.apply(&mut self);
+        self
+    }
+
+    /// 获取绑定地址
+    pub fn bind_addr(&self) -> String {
+        format!("{}:{}", self.network.bind_ip, self.network.port)
+    }
+
+    /// 获取所有广播地址：CIDR 换算为定向广播地址，主机名原样保留（发送时解析）
+    pub fn broadcast_addrs(&self) -> Vec<String> {
+        self.network
+            .broadcast_ips
+            .iter()
+            .map(|value| match BroadcastTarget::parse(value) {
+                Ok(BroadcastTarget::Ip(ip)) => SocketAddr::new(ip, self.network.port).to_string(),
+                _ => format!("{}:{}", value, self.network.port),
+            })
+            .collect()
+    }
+}
+
+impl NetworkConfig {
+    /// 验证网络配置有效性
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_empty()
+    }
+
+    /// 校验网络配置，返回发现的所有错误
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        if self.bind_ip.parse::<IpAddr>().is_err() {
+            errors.push(ConfigError::new("network.bind_ip", &self.bind_ip, "an IPv4 or IPv6 address"));
+        }
+        if self.broadcast_ips.is_empty() {
+            errors.push(ConfigError::new("network.broadcast_ips", "[]", "at least one broadcast address"));
+        }
+        for (index, value) in self.broadcast_ips.iter().enumerate() {
+            if BroadcastTarget::parse(value).is_err() {
+                errors.push(ConfigError::new(
+                    &format!("network.broadcast_ips[{}]", index),
+                    value,
+                    "an IP address, an IPv4 CIDR such as 192.168.10.0/24, or a hostname",
+                ));
+            }
+        }
+        let field = if self.ports.is_empty() { "network.port" } else { "network.ports" };
+        for port in self.listen_ports() {
+            if !(1025..65535).contains(&port) {
+                errors.push(ConfigError::new(field, port, "a port between 1025 and 65534"));
+            }
+        }
+        if !(1..=255).contains(&self.ttl) {
+            errors.push(ConfigError::new("network.ttl", self.ttl, "a value between 1 and 255"));
+        }
+        if self.max_users == 0 {
+            errors.push(ConfigError::new("network.max_users", 0, "a positive number"));
+        }
+        if self.max_dedup_entries == 0 {
+            errors.push(ConfigError::new("network.max_dedup_entries", 0, "a positive number"));
+        }
+        if self.max_pending_replies == 0 {
+            errors.push(ConfigError::new("network.max_pending_replies", 0, "a positive number"));
+        }
+        errors
+    }
+
+    /// 解析所有广播目标
+    pub fn broadcast_targets(&self) -> Result<Vec<BroadcastTarget>> {
+        self.broadcast_ips.iter().map(|value| BroadcastTarget::parse(value)).collect()
+    }
+
+    /// 等待应答的默认超时（发现、列表、消息确认与查询），可按次覆盖
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.timeout_secs)
+    }
+
+    /// 上线后等待对方应答的时间
+    pub fn entry_settle(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.entry_settle_ms)
+    }
+
+    /// 首次上线广播前的随机延迟，在 0..=entry_jitter_ms 毫秒内均匀选取
+    pub fn entry_jitter(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(rand::random_range(0..=self.entry_jitter_ms))
+    }
+
+    /// 实际监听的端口列表（第一个为主端口）
+    pub fn listen_ports(&self) -> Vec<u16> {
+        if self.ports.is_empty() {
+            vec![self.port]
+        } else {
+            self.ports.clone()
+        }
+    }
+}
+
+/// broadcast_ips 中每一项的写法：IP、IPv4 CIDR（换算为定向广播地址）或主机名（发送时解析）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BroadcastTarget {
+    Ip(IpAddr),
+    Host(String),
+}
+
+impl BroadcastTarget {
+    /// 解析广播地址配置，例如 255.255.255.255、192.168.10.0/24 或 lan-gw.example.com
+    pub fn parse(value: &str) -> Result<Self> {
+        if let Ok(ip) = value.parse::<IpAddr>() {
+            return Ok(Self::Ip(ip));
+        }
+        if let Some((network, prefix)) = value.split_once('/') {
+            let network: std::net::Ipv4Addr = network
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid CIDR '{}': expected an IPv4 network", value))?;
+            let prefix: u32 = prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= 32)
+                .ok_or_else(|| anyhow::anyhow!("Invalid CIDR '{}': prefix must be 0..=32", value))?;
+            let host_bits = u32::MAX.checked_shr(prefix).unwrap_or(0);
+            return Ok(Self::Ip(IpAddr::V4((u32::from(network) | host_bits).into())));
+        }
+        if is_hostname(value) {
+            return Ok(Self::Host(value.to_ascii_lowercase()));
+        }
+        Err(anyhow::anyhow!("Invalid broadcast address '{}'", value))
+    }
+}
+
+/// 是否为合法的主机名（字母、数字与连字符组成的标签，以点分隔）
+fn is_hostname(value: &str) -> bool {
+    let value = value.strip_suffix('.').unwrap_or(value);
+    !value.is_empty()
+        && value.len() <= 253
+        && !value.split('.').all(|label| label.bytes().all(|b| b.is_ascii_digit()))
+        && value.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// debug.log_level 可用的取值
+const LOG_LEVELS: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+/// 一个配置项的校验错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// 段.键
+    pub field: String,
+    pub value: String,
+    /// 可接受的取值说明
+    pub expected: String,
+}
+
+impl ConfigError {
+    fn new(field: &str, value: impl fmt::Display, expected: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            value: value.to_string(),
+            expected: expected.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = \"{}\": expected {}", self.field, self.value, self.expected)
+    }
+}
+
+/// 环境变量前缀，例如 LANMSG_NAME、LANMSG_PORT
+pub const ENV_PREFIX: &str = "LANMSG_";
+
+/// 环境变量或命令行提供的覆盖值，None 表示未指定
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigOverrides {
+    pub name: Option<String>,
+    pub login: Option<String>,
+    pub host: Option<String>,
+    pub group: Option<String>,
+    pub port: Option<u16>,
+    pub bind_ip: Option<String>,
+    pub broadcast_ip: Option<String>,
+    pub encoding: Option<String>,
+    pub passive: Option<bool>,
+    pub stealth: Option<bool>,
+    /// 配置方案在解析文件时叠加，不经过 apply
+    pub profile: Option<String>,
+}
+
+impl ConfigOverrides {
+    /// 从 LANMSG_* 环境变量读取
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    /// 按变量名查找覆盖值，便于测试注入
+    pub fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let var = |name: &str| {
+            lookup(&format!("{}{}", ENV_PREFIX, name)).filter(|value| !value.is_empty())
+        };
+        let port = match var("PORT") {
+            Some(port) => Some(
+                port.parse()
+                    .with_context(|| format!("Invalid {}PORT '{}'", ENV_PREFIX, port))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            name: var("NAME"),
+            login: var("LOGIN"),
+            host: var("HOST"),
+            group: var("GROUP"),
+            port,
+            bind_ip: var("BIND"),
+            broadcast_ip: var("BROADCAST"),
+            encoding: var("ENCODING"),
+            passive: None,
+            stealth: None,
+            profile: var("PROFILE"),
+        })
+    }
+
+    /// 命令行覆盖了配置中非默认值的项，每项一行说明；取值相同或配置为默认值时不提示
+    pub fn override_notices(&self, config: &AppConfig) -> Vec<String> {
+        let defaults = AppConfig::default();
+        let text = |value: &String| value.clone();
+        let fields = [
+            ("user.name", self.name.clone(), text(&config.user.name), text(&defaults.user.name)),
+            ("user.login", self.login.clone(), text(&config.user.login), text(&defaults.user.login)),
+            ("user.host", self.host.clone(), text(&config.user.host), text(&defaults.user.host)),
+            ("user.group", self.group.clone(), text(&config.user.group), text(&defaults.user.group)),
+            (
+                "network.port",
+                self.port.map(|port| port.to_string()),
+                config.network.port.to_string(),
+                defaults.network.port.to_string(),
+            ),
+            (
+                "network.bind_ip",
+                self.bind_ip.clone(),
+                text(&config.network.bind_ip),
+                text(&defaults.network.bind_ip),
+            ),
+            (
+                "network.broadcast_ips",
+                self.broadcast_ip.clone(),
+                config.network.broadcast_ips.join(", "),
+                defaults.network.broadcast_ips.join(", "),
+            ),
+            (
+                "encoding.protocol",
+                self.encoding.clone(),
+                text(&config.encoding.protocol),
+                text(&defaults.encoding.protocol),
+            ),
+        ];
+        fields
+            .into_iter()
+            .filter_map(|(field, value, current, default)| {
+                let value = value?;
+                (value != current && current != default)
+                    .then(|| format!("{}: command line '{}' overrides '{}' from the config", field, value, current))
+            })
+            .collect()
+    }
+
+    /// 生效的配置方案：命令行优先于环境变量
+    pub fn select_profile<'a>(env: &'a Self, cli: &'a Self) -> Option<&'a str> {
+        cli.profile.as_deref().or(env.profile.as_deref())
+    }
+
+    /// 将已指定的值写入配置
+    pub fn apply(&self, config: &mut AppConfig) {
+        if let Some(name) = &self.name {
+            config.user.name = name.clone();
+        }
+        if let Some(login) = &self.login {
+            config.user.login = login.clone();
+        }
+        if let Some(host) = &self.host {
+            config.user.host = host.clone();
+        }
+        if let Some(group) = &self.group {
+            config.user.group = group.clone();
+        }
+        if let Some(port) = self.port {
+            // 多端口监听时替换主端口
+            config.network.port = port;
+            if let Some(first) = config.network.ports.first_mut() {
+                *first = port;
+            }
+        }
+        if let Some(bind_ip) = &self.bind_ip {
+            config.network.bind_ip = bind_ip.clone();
+        }
+        if let Some(broadcast_ip) = &self.broadcast_ip {
+            config.network.broadcast_ips = vec![broadcast_ip.clone()];
+        }
+        if let Some(passive) = self.passive {
+            config.debug.passive = passive;
+        }
+        if let Some(stealth) = self.stealth {
+            config.presence.stealth = stealth;
+        }
+        if let Some(encoding) = &self.encoding {
+            config.encoding.protocol = encoding.clone();
+        }
+    }
+}
+
+/// 配置文件路径的来源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// 命令行 --config
+    Cli,
+    /// 环境变量 LANMSG_CONFIG
+    Env,
+    /// 平台配置目录
+    Platform,
+    /// 当前目录
+    CurrentDir,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Cli => write!(f, "--config"),
+            ConfigSource::Env => write!(f, "${}", CONFIG_ENV),
+            ConfigSource::Platform => write!(f, "platform config dir"),
+            ConfigSource::CurrentDir => write!(f, "current directory"),
+        }
+    }
+}
+
+/// 解析得到的配置文件位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigLocation {
+    pub path: PathBuf,
+    pub source: ConfigSource,
+}
+
+impl ConfigLocation {
+    /// 按 --config、环境变量、平台配置目录、当前目录的顺序查找配置文件
+    pub fn resolve(explicit: Option<&Path>) -> Self {
+        Self::resolve_with(
+            explicit,
+            std::env::var_os(CONFIG_ENV),
+            platform_config_dir(),
+            Path::new(""),
+        )
+    }
+
+    /// 查找逻辑本身，便于测试注入环境变量、平台目录与当前目录
+    ///
+    /// 平台目录与当前目录都没有配置文件时返回平台目录下的路径，供保存时使用
+    pub fn resolve_with(
+        explicit: Option<&Path>,
+        env: Option<OsString>,
+        platform_dir: Option<PathBuf>,
+        current_dir: &Path,
+    ) -> Self {
+        if let Some(path) = explicit {
+            return Self::new(path.to_path_buf(), ConfigSource::Cli);
+        }
+        if let Some(path) = env.filter(|path| !path.is_empty()) {
+            return Self::new(PathBuf::from(path), ConfigSource::Env);
+        }
+
+        // 每个目录内按 CONFIG_FILE_NAMES 的顺序查找
+        let find = |dir: &Path| {
+            CONFIG_FILE_NAMES
+                .iter()
+                .map(|name| dir.join(name))
+                .find(|path| path.is_file())
+        };
+        if let Some(path) = platform_dir.as_deref().and_then(find) {
+            return Self::new(path, ConfigSource::Platform);
+        }
+        if let Some(path) = find(current_dir) {
+            return Self::new(path, ConfigSource::CurrentDir);
+        }
+        match platform_dir {
+            Some(dir) => Self::new(dir.join(CONFIG_FILE_NAME), ConfigSource::Platform),
+            None => Self::new(current_dir.join(CONFIG_FILE_NAME), ConfigSource::CurrentDir),
+        }
+    }
+
+    fn new(path: PathBuf, source: ConfigSource) -> Self {
+        Self { path, source }
+    }
+}
+
+/// 平台配置目录：Linux 为 ~/.config/lanmsg，Windows 为 %APPDATA%\\lanmsg，
+/// macOS 为 ~/Library/Application Support/lanmsg
+pub fn platform_config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("lanmsg"))
+}
+
+/// 配置项说明：(段, 键, 说明)，生成示例配置时按此顺序输出
+///
+/// 新增配置字段时须同步在此登记，测试会检查与 AppConfig::default() 一致
+const FIELD_DOCS: &[(&str, &str, &str)] = &[
+    ("network", "bind_ip", "本地绑定 IP"),
+    ("network", "port", "主监听端口"),
+    ("network", "ports", "同时监听的多个端口，为空时只监听 port"),
+    ("network", "broadcast_ips", "广播地址列表，每项为 IP、IPv4 CIDR（如 192.168.10.0/24，换算为定向广播地址）或主机名（发送时解析）；也可写单个字符串"),
+    ("network", "timeout_secs", "网络操作超时（秒）"),
+    ("network", "separate_send_socket", "从单独的临时端口发送，不再收到自己发出的广播"),
+    ("network", "min_broadcast_interval_ms", "相邻两次广播的最短间隔（毫秒），更快的广播排队依次发出，0 表示不限"),
+    ("network", "learn_from_any_packet", "从任意报文（如 MSG）学习发送方并加入用户表，默认只从上线类报文学习"),
+    ("network", "max_users", "在线用户表上限，超出时淘汰最久未活动的用户"),
+    ("network", "max_dedup_entries", "报文去重缓存上限"),
+    ("network", "max_pending_replies", "等待应答的条目上限，超出时放弃最早的一条"),
+    ("network", "ansentry_jitter_ms", "回复上线通知前的最大随机延迟（毫秒）"),
+    ("network", "entry_settle_ms", "广播上线后、执行命令前最多等待的时间（毫秒），要找的收件人出现时提前结束"),
+    ("network", "entry_jitter_ms", "启动时首次广播上线通知前的最大随机延迟（毫秒），0 表示不延迟"),
+    ("network", "reorder_window_ms", "乱序报文的最长等待时间（毫秒），0 表示不排序"),
+    ("network", "cache_users", "退出时保存用户表，下次启动时恢复"),
+    ("network", "user_cache_max_age_secs", "超过该时长（秒）未活动的缓存用户不再恢复"),
+    ("network", "ttl", "广播/组播报文的 TTL（1..=255），1 表示只在本网段内传播"),
+    ("user", "name", "用户名（显示昵称）"),
+    ("user", "login", "登录名（报文中的用户字段），留空时使用系统用户名"),
+    ("user", "host", "主机名"),
+    ("user", "auto_login", "启动时自动上线"),
+    ("user", "group", "所属分组"),
+    ("user", "allowed_groups", "允许使用的分组，非空时 group 必须是其中之一；为空表示不限制"),
+    ("user", "max_message_len", "发出的消息最多包含的字符数，0 表示不限"),
+    ("user", "message_len_policy", "消息超长时的处理：truncate（截断并以省略号结尾）或 reject（拒绝发送）"),
+    ("user", "status_note", "状态说明，随上线报文发出，在对方的用户列表中显示"),
+    ("user", "client_version", "报文版本字段中的客户端标识，不能含冒号"),
+    ("debug", "log_level", "日志级别：error、warn、info、debug 或 trace（日志输出到 stderr，设置 RUST_LOG 时以它为准）"),
+    ("debug", "dump_packets", "把收发的每个报文写入转储文件（每行一个 JSON 记录）"),
+    ("debug", "dump_path", "转储文件路径，留空使用平台数据目录"),
+    ("debug", "dump_max_bytes", "转储文件超过该大小（字节）时轮转"),
+    ("debug", "dump_keep", "保留的轮转文件个数"),
+    ("debug", "passive", "只接收不发送：不广播上线/下线，也不自动应答"),
+    ("debug", "history_size", "内存中保留的最近收到的消息条数"),
+    ("debug", "ascii_tables", "表格使用 ASCII 框线（+ - |），用于不支持 Unicode 框线的控制台"),
+    ("debug", "event_channel_capacity", "事件通道容量：处理慢的订阅者落后超过该条数时跳过最早的事件并输出警告"),
+    ("encoding", "protocol", "协议报文编码（gbk/utf-8）"),
+    ("encoding", "display", "本地显示编码"),
+    ("encoding", "escape_fields", "转义报文固定字段中的冒号与 NUL，标准客户端不认识这种转义，只在所有对端都是 lanMsg 时开启"),
+    ("security", "psk", "共享密钥，非空时签名所有报文并丢弃校验失败的报文"),
+    ("security", "blocked", "黑名单：IP、CIDR 或 用户名@主机名，来自这些对端的报文直接丢弃"),
+    ("security", "allowed", "白名单：非空时只接受名单内的对端"),
+    ("presence", "away_message", "设置离开且未指定内容时使用的离开信息"),
+    ("presence", "auto_reply", "离开状态下自动回复收到的消息"),
+    ("presence", "auto_reply_interval_secs", "对同一发送方两次自动回复之间的最短间隔（秒）"),
+    ("presence", "auto_away_after_idle_secs", "聊天模式下无输入超过该秒数后自动设为离开，0 表示关闭"),
+    ("presence", "stealth", "隐身：不广播上线、不应答上线通知，不出现在对方的用户列表中"),
+    ("history", "enabled", "保存消息历史，关闭时不向磁盘写入任何记录"),
+    ("history", "backend", "存储后端：jsonl 或 sqlite"),
+    ("history", "path", "历史文件路径，留空使用平台数据目录"),
+    ("history", "max_messages", "最多保留的消息条数，0 表示不限"),
+    ("history", "max_age_days", "消息保留天数，0 表示不限"),
+    ("history", "store_bodies", "保存消息正文，关闭时只记录时间与对端"),
+    ("transfer", "enabled", "是否接收文件，开启时启动会检查下载目录可写"),
+    ("transfer", "download_dir", "下载目录，留空使用系统下载目录（没有时为 ~/lanmsg-files），需要时自动创建"),
+    ("transfer", "auto_accept_from", "自动接收这些对端的文件，可写 IP、CIDR 或 用户名@主机名"),
+    ("transfer", "auto_accept_max_bytes", "自动接收的文件总大小上限（字节），超过时仍需确认"),
+    ("transfer", "offer_ttl_secs", "未处理的文件发送请求保留时长（秒）"),
+    ("transfer", "max_pending_offers", "未处理的文件发送请求上限，超出时丢弃最早的一条"),
+    ("transfer", "max_concurrent", "同时进行的传输数上限"),
+    ("transfer", "max_rate_kbps", "传输速率上限（KB/s），0 表示不限"),
+    ("transfer", "keep_partial_on_cancel", "取消传输时保留已接收的部分文件"),
+    ("transfer", "checksum", "发送文件时计算的校验算法：sha256、crc32 或 none，接收方校验不一致时传输失败"),
+];
+
+/// 生成带注释的默认配置文件内容，取值来自 AppConfig::default()
+pub fn default_config_template() -> Result<String> {
+    let defaults = toml::Table::try_from(AppConfig::default())?;
+    let mut out = String::from("# lanMsg 配置文件（由 config init 生成）\n");
+    out.push_str(&format!("\n# 配置布局版本，请勿手动修改\n{} = {}\n", VERSION_KEY, CONFIG_VERSION));
+    let mut section = "";
+    for &(name, key, doc) in FIELD_DOCS {
+        if name != section {
+            section = name;
+            out.push_str(&format!("\n[{}]\n", section));
+        }
+        let value = defaults
+            .get(section)
+            .and_then(|table| table.get(key))
+            .with_context(|| format!("No default for {}.{}", section, key))?;
+        out.push_str(&format!("# {}\n{} = {}\n", doc, key, value));
+    }
+    Ok(out)
+}
+
+/// 按格式生成默认配置文件内容：TOML 带逐项注释，YAML 与 JSON 为默认值本身
+pub fn default_config_template_as(format: ConfigFormat) -> Result<String> {
+    match format {
+        ConfigFormat::Toml => default_config_template(),
+        ConfigFormat::Yaml => Ok(format!(
+            "# lanMsg 配置文件（由 config init 生成）\n{}",
+            format.serialize(&AppConfig::default())?
+        )),
+        ConfigFormat::Json => format.serialize(&AppConfig::default()),
+    }
+}
+
+/// 迁移前原文件的备份路径：在原扩展名后加 .bak
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+/// 原子写文件：写入同目录下的临时文件后改名覆盖
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+// 单元测试
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
     fn test_load_config() {
@@ -198,16 +1765,18 @@ impl NetworkConfig {
             [network]
             bind_ip = "192.168.1.100"
             port = 3000
-            
+
             [user]
             name = "test_user"
             auto_login = true
+            group = "dev"
         "#;
-        
-        let mut file = NamedTempFile::new().unwrap();
-        std::io::write(&mut file, toml_content).unwrap();
-        
-        let config = AppConfig::load(file.path()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, toml_content).unwrap();
+
+        let config = AppConfig::load(&path).unwrap();
         assert_eq!(config.network.bind_ip, "192.168.1.100");
         assert_eq!(config.user.name, "test_user");
         assert!(config.user.auto_login);
@@ -219,6 +1788,781 @@ impl NetworkConfig {
         assert_eq!(config.network.port, 2425);
         assert!(!config.debug.dump_packets);
     }
-}
 
-    */
\ No newline at end of file
+    #[test]
+    fn test_entry_jitter_within_bound() {
+        let mut network = NetworkConfig::default();
+        assert_eq!(network.entry_jitter(), std::time::Duration::ZERO);
+        network.entry_jitter_ms = 250;
+        for _ in 0..100 {
+            assert!(network.entry_jitter() <= std::time::Duration::from_millis(250));
+        }
+    }
+
+    #[test]
+    fn test_expand_template_substitutes_identity() {
+        let config = AppConfig::parse(
+            "[user]\nname = \"alice\"\nhost = \"PC-A\"\n\n[templates]\nbrb = \"{name} on {host}: be right back {soon}\"\nlunch = \"at lunch\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.expand_template("brb").unwrap(), "alice on PC-A: be right back {soon}");
+        assert_eq!(config.expand_template("lunch").unwrap(), "at lunch");
+        assert_eq!(
+            config.expand_template("afk").unwrap_err().to_string(),
+            "Unknown template 'afk', available templates: brb, lunch"
+        );
+        assert!(
+            AppConfig::default()
+                .expand_template("brb")
+                .unwrap_err()
+                .to_string()
+                .contains("no [templates] are defined")
+        );
+    }
+
+    #[test]
+    fn test_template_in_sync_with_defaults() {
+        let defaults = toml::Table::try_from(AppConfig::default()).unwrap();
+        let mut keys: Vec<(String, String)> = Vec::new();
+        for (section, table) in defaults.iter().filter(|(key, _)| *key != VERSION_KEY) {
+            for key in table.as_table().unwrap().keys() {
+                keys.push((section.clone(), key.clone()));
+            }
+        }
+        let mut documented: Vec<(String, String)> = FIELD_DOCS
+            .iter()
+            .map(|(section, key, _)| (section.to_string(), key.to_string()))
+            .collect();
+        keys.sort();
+        documented.sort();
+        assert_eq!(keys, documented);
+
+        let template = default_config_template().unwrap();
+        let parsed: AppConfig = toml::from_str(&template).unwrap();
+        assert_eq!(parsed, AppConfig::default());
+    }
+
+    #[test]
+    fn test_config_resolution_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let platform_dir = dir.path().join("lanmsg");
+        let explicit = dir.path().join("explicit.toml");
+        let env_path = dir.path().join("env.toml");
+        let cwd = dir.path().join("cwd");
+        fs::create_dir(&cwd).unwrap();
+
+        let location = ConfigLocation::resolve_with(
+            Some(&explicit),
+            Some(env_path.clone().into()),
+            Some(platform_dir.clone()),
+            &cwd,
+        );
+        assert_eq!(location, ConfigLocation::new(explicit, ConfigSource::Cli));
+
+        let location = ConfigLocation::resolve_with(
+            None,
+            Some(env_path.clone().into()),
+            Some(platform_dir.clone()),
+            &cwd,
+        );
+        assert_eq!(location, ConfigLocation::new(env_path, ConfigSource::Env));
+
+        // 平台目录没有配置文件时仍返回平台路径，保存时自动创建目录
+        let location = ConfigLocation::resolve_with(None, None, None, &cwd);
+        assert_eq!(location.source, ConfigSource::CurrentDir);
+        let location = ConfigLocation::resolve_with(None, None, Some(platform_dir.clone()), &cwd);
+        let platform_file = platform_dir.join(CONFIG_FILE_NAME);
+        assert_eq!(location.source, ConfigSource::Platform);
+        assert_eq!(location.path, platform_file);
+        AppConfig::default().save(&location.path).unwrap();
+        assert!(platform_file.is_file());
+
+        let location =
+            ConfigLocation::resolve_with(None, Some(OsString::new()), Some(platform_dir.clone()), &cwd);
+        assert_eq!(location, ConfigLocation::new(platform_file.clone(), ConfigSource::Platform));
+
+        // 平台目录优先于当前目录
+        fs::write(cwd.join(CONFIG_FILE_NAME), "").unwrap();
+        let location = ConfigLocation::resolve_with(None, None, Some(platform_dir.clone()), &cwd);
+        assert_eq!(location.source, ConfigSource::Platform);
+        fs::remove_file(&platform_file).unwrap();
+        let location = ConfigLocation::resolve_with(None, None, Some(platform_dir.clone()), &cwd);
+        assert_eq!(location, ConfigLocation::new(cwd.join(CONFIG_FILE_NAME), ConfigSource::CurrentDir));
+
+        // 同一目录内按 toml、yaml、yml、json 的顺序查找
+        fs::write(platform_dir.join("config.json"), "{}").unwrap();
+        let location = ConfigLocation::resolve_with(None, None, Some(platform_dir.clone()), &cwd);
+        assert_eq!(location.path, platform_dir.join("config.json"));
+        fs::write(platform_dir.join("config.yml"), "").unwrap();
+        let location = ConfigLocation::resolve_with(None, None, Some(platform_dir), &cwd);
+        assert_eq!(location.path.file_name().unwrap(), "config.yml");
+    }
+
+    #[test]
+    fn test_ttl_range_validated() {
+        let mut network = NetworkConfig::default();
+        assert!(network.is_valid());
+        for (ttl, valid) in [(0, false), (1, true), (255, true), (256, false)] {
+            network.ttl = ttl;
+            assert_eq!(network.is_valid(), valid, "ttl {}", ttl);
+        }
+    }
+
+    #[test]
+    fn test_override_notices_only_when_values_differ() {
+        let mut file = AppConfig::default();
+        file.user.name = "alice".to_string();
+        file.network.port = 3000;
+
+        let cli = ConfigOverrides {
+            name: Some("bob".to_string()),
+            port: Some(3000),
+            host: Some("PC-B".to_string()),
+            ..Default::default()
+        };
+        // 取值相同的 port 与配置中为默认值的 host 都不提示
+        assert_eq!(
+            cli.override_notices(&file),
+            ["user.name: command line 'bob' overrides 'alice' from the config"]
+        );
+
+        let same = ConfigOverrides {
+            name: Some("alice".to_string()),
+            ..Default::default()
+        };
+        assert!(same.override_notices(&file).is_empty());
+        assert!(ConfigOverrides::default().override_notices(&file).is_empty());
+    }
+
+    #[test]
+    fn test_override_precedence() {
+        let file: AppConfig = toml::from_str(
+            r#"
+            [network]
+            port = 3000
+            [user]
+            name = "from-file"
+            group = "dev"
+            [encoding]
+            protocol = "utf-8"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.network.port, 3000);
+
+        let env = ConfigOverrides::from_vars(|key| match key {
+            "LANMSG_NAME" => Some("from-env".to_string()),
+            "LANMSG_PORT" => Some("4000".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        let cli = ConfigOverrides {
+            name: Some("from-cli".to_string()),
+            ..Default::default()
+        };
+
+        let merged = file.clone().merged(&env, &cli);
+        assert_eq!(merged.user.name, "from-cli");
+        assert_eq!(merged.network.port, 4000);
+        assert_eq!(merged.encoding.protocol, "utf-8");
+        assert_eq!(merged.user.group, "dev");
+
+        let cli = ConfigOverrides {
+            port: Some(5000),
+            encoding: Some("gbk".to_string()),
+            ..Default::default()
+        };
+        let merged = file.clone().merged(&ConfigOverrides::default(), &cli);
+        assert_eq!(merged.user.name, "from-file");
+        assert_eq!(merged.network.port, 5000);
+        assert_eq!(merged.encoding.protocol, "gbk");
+
+        let merged = AppConfig::default().merged(&ConfigOverrides::default(), &ConfigOverrides::default());
+        assert_eq!(merged, AppConfig::default());
+
+        assert!(ConfigOverrides::from_vars(|_| Some("not-a-port".to_string())).is_err());
+    }
+
+    fn fields(errors: &[ConfigError]) -> Vec<&str> {
+        errors.iter().map(|error| error.field.as_str()).collect()
+    }
+
+    #[test]
+    fn test_validate_addresses() {
+        let mut config = AppConfig::default();
+        config.network.bind_ip = "192.168.1".to_string();
+        config.network.broadcast_ips = vec!["10.0.0.0/99".to_string()];
+        let errors = config.validate();
+        assert_eq!(fields(&errors), ["network.bind_ip", "network.broadcast_ips[0]"]);
+        assert_eq!(errors[0].value, "192.168.1");
+    }
+
+    #[test]
+    fn test_validate_ports_and_limits() {
+        let mut config = AppConfig::default();
+        config.network.ports = vec![2425, 80];
+        config.network.ttl = 0;
+        config.network.max_users = 0;
+        config.network.max_dedup_entries = 0;
+        config.network.max_pending_replies = 0;
+        config.debug.event_channel_capacity = 0;
+        let errors = config.validate();
+        assert_eq!(
+            fields(&errors),
+            [
+                "network.ports",
+                "network.ttl",
+                "network.max_users",
+                "network.max_dedup_entries",
+                "network.max_pending_replies",
+                "debug.event_channel_capacity"
+            ]
+        );
+        assert_eq!(errors[0].value, "80");
+
+        // 超出 u16 范围的端口在解析阶段就会报错，并指出字段
+        let error = toml::from_str::<AppConfig>("[network]\nport = 99999\n").unwrap_err();
+        assert!(error.to_string().contains("port"));
+    }
+
+    #[test]
+    fn test_validate_encoding_and_log_level() {
+        let mut config = AppConfig::default();
+        assert!(config.validate().is_empty());
+        config.encoding.protocol = "gbkk".to_string();
+        config.debug.log_level = "verbose".to_string();
+        let errors = config.validate();
+        assert_eq!(fields(&errors), ["encoding.protocol", "debug.log_level"]);
+        assert!(errors[1].to_string().contains("one of error, warn"));
+
+        let mut config = AppConfig::default();
+        config.user.client_version = "acme:2".to_string();
+        assert_eq!(fields(&config.validate()), ["user.client_version"]);
+
+        let mut config = AppConfig::default();
+        config.encoding.peers.insert("192.168.1.0/24".into(), "gbk".into());
+        config.encoding.peers.insert("192.168.1".into(), "gbk".into());
+        config.encoding.peers.insert("carol@LAB-PC".into(), "latin-9000".into());
+        assert_eq!(
+            fields(&config.validate()),
+            ["encoding.peers.\"192.168.1\"", "encoding.peers.\"carol@LAB-PC\""]
+        );
+    }
+
+    #[test]
+    fn test_group_allowlist() {
+        let config = AppConfig::parse("[user]\ngroup = \"dev\"\nallowed_groups = [\"dev\", \"ops\"]\n").unwrap();
+        assert!(config.validate().is_empty());
+
+        // 命令行指定的分组同样受限
+        let overrides = ConfigOverrides {
+            group: Some("sales".to_string()),
+            ..Default::default()
+        };
+        let errors = config.clone().merged(&Default::default(), &overrides).validate();
+        assert_eq!(fields(&errors), ["user.group"]);
+        assert!(errors[0].to_string().contains("allowed groups (dev, ops)"));
+
+        let overrides = ConfigOverrides {
+            group: Some("ops".to_string()),
+            ..Default::default()
+        };
+        assert!(config.merged(&Default::default(), &overrides).validate().is_empty());
+
+        // 为空表示不限制
+        let mut config = AppConfig::default();
+        config.user.group = "anything".to_string();
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_message_len_policy_validated() {
+        let config = AppConfig::parse("[user]\nmax_message_len = 200\nmessage_len_policy = \"Reject\"\n").unwrap();
+        assert!(config.validate().is_empty());
+        assert_eq!(
+            config.user.message_limit(),
+            MessageLimit {
+                max_len: 200,
+                policy: MessageLenPolicy::Reject,
+            }
+        );
+
+        let config = AppConfig::parse("[user]\nmessage_len_policy = \"drop\"\n").unwrap();
+        let errors = config.validate();
+        assert_eq!(fields(&errors), ["user.message_len_policy"]);
+        assert!(errors[0].to_string().contains("truncate, reject"));
+    }
+
+    #[test]
+    fn test_minimal_network_section() {
+        let config = AppConfig::parse("[network]\nport = 3000\n").unwrap();
+        assert_eq!(config.network.port, 3000);
+        assert_eq!(config.user.group, "group");
+        assert_eq!(config.user.name, "anonymous");
+
+        // [user] 中缺少 group 也能解析
+        let config = AppConfig::parse("[user]\nname = \"alice\"\n").unwrap();
+        assert_eq!(config.user.name, "alice");
+        assert_eq!(config.user.group, "group");
+    }
+
+    #[test]
+    fn test_section_errors_name_the_section() {
+        let error = AppConfig::parse("[network]\nport = \"abc\"\n[debug]\ndump_packets = 1\n[user]\nname = \"alice\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("invalid [network] section"), "{}", error);
+        assert!(error.contains("invalid [debug] section"), "{}", error);
+        assert!(!error.contains("[user]"), "{}", error);
+    }
+
+    #[test]
+    fn test_blocklist_persists_across_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        let mut config = AppConfig::default();
+        config.security.allowed = vec!["192.168.1.0/24".to_string()];
+        assert!(config.security.block("192.168.1.66").unwrap());
+        assert!(config.security.block("spammer@EVIL-PC").unwrap());
+        assert!(!config.security.block("192.168.1.66").unwrap());
+        assert!(config.security.block("not a peer").is_err());
+        assert!(config.save_if_changed(&path).unwrap());
+
+        let loaded = AppConfig::load(&path).unwrap();
+        assert_eq!(loaded.security.blocked, ["192.168.1.66", "spammer@EVIL-PC"]);
+        let filter = loaded.security.peer_filter();
+        assert!(!filter.accepts("192.168.1.66".parse().unwrap(), &[]));
+        assert!(filter.accepts("192.168.1.5".parse().unwrap(), &[]));
+        assert!(!filter.accepts("10.0.0.1".parse().unwrap(), &[]));
+
+        let mut loaded = loaded;
+        assert!(loaded.security.unblock("192.168.1.66"));
+        assert!(loaded.save_if_changed(&path).unwrap());
+        assert_eq!(AppConfig::load(&path).unwrap().security.blocked, ["spammer@EVIL-PC"]);
+
+        let mut config = AppConfig::default();
+        config.security.blocked = vec!["10.0.0.1".to_string(), "10.0.0.0/40".to_string()];
+        let errors = config.validate();
+        assert_eq!(fields(&errors), ["security.blocked[1]"]);
+        assert_eq!(errors[0].value, "10.0.0.0/40");
+    }
+
+    #[test]
+    fn test_transfer_settings() {
+        let config = AppConfig::parse(
+            "[transfer]\nauto_accept_from = [\"192.168.1.0/24\", \"carol@LAB-PC\"]\nauto_accept_max_bytes = 1000\n",
+        )
+        .unwrap();
+        let transfer = &config.transfer;
+        assert_eq!(transfer.max_concurrent, 4);
+        assert!(transfer.auto_accepts("192.168.1.9".parse().unwrap(), &[], 1000));
+        assert!(!transfer.auto_accepts("192.168.1.9".parse().unwrap(), &[], 1001));
+        assert!(transfer.auto_accepts("10.0.0.1".parse().unwrap(), &["carol@LAB-PC"], 10));
+        assert!(!transfer.auto_accepts("10.0.0.1".parse().unwrap(), &["dave@LAB-PC"], 10));
+
+        let mut config = AppConfig::default();
+        config.transfer.auto_accept_from = vec!["everyone".to_string()];
+        config.transfer.max_concurrent = 0;
+        config.transfer.max_pending_offers = 0;
+        config.transfer.checksum = "md5".to_string();
+        assert_eq!(
+            fields(&config.validate()),
+            [
+                "transfer.auto_accept_from",
+                "transfer.max_concurrent",
+                "transfer.max_pending_offers",
+                "transfer.checksum"
+            ]
+        );
+        assert_eq!(AppConfig::default().transfer.checksum(), Some(ChecksumAlgorithm::Sha256));
+    }
+
+    #[test]
+    fn test_download_dir_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut transfer = TransferConfig {
+            download_dir: dir.path().join("not/yet/created").display().to_string(),
+            ..Default::default()
+        };
+        assert_eq!(transfer.check_download_dir(), None);
+        // 检查时不创建目录，也不留下任何文件
+        assert!(!dir.path().join("not").exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // 根目录属于 root、权限 0755：其他用户有写权限位也不可写（root 不受权限位限制，跳过）
+        // SAFETY: geteuid 没有参数，总是成功
+        #[cfg(unix)]
+        if unsafe { libc::geteuid() } != 0 {
+            transfer.download_dir = "/lanmsg-downloads".to_string();
+            let error = transfer.check_download_dir().unwrap();
+            assert!(error.expected.contains("writable"));
+        }
+
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        transfer.download_dir = file.display().to_string();
+        let error = transfer.check_download_dir().unwrap();
+        assert_eq!(error.field, "transfer.download_dir");
+        assert!(error.expected.contains("not a file"));
+    }
+
+    #[test]
+    fn test_save_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let mut config = AppConfig::default();
+        config.network.port = 3000;
+        config.user.name = "alice".to_string();
+        config.save(&path).unwrap();
+
+        assert_eq!(AppConfig::load(&path).unwrap(), config);
+        assert!(!config.save_if_changed(&path).unwrap());
+        config.debug.dump_packets = true;
+        assert!(config.save_if_changed(&path).unwrap());
+        assert_eq!(AppConfig::load(&path).unwrap(), config);
+    }
+
+    #[test]
+    fn test_unknown_fields_survive_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+            schema = 7
+
+            [network]
+            port = 3000
+            future_option = "keep me"
+
+            [user]
+            group = "dev"
+
+            [plugins]
+            enabled = ["a", "b"]
+            "#,
+        )
+        .unwrap();
+
+        let mut config = AppConfig::load(&path).unwrap();
+        config.user.name = "alice".to_string();
+        config.save(&path).unwrap();
+
+        let reloaded = AppConfig::load(&path).unwrap();
+        assert_eq!(reloaded, config);
+        assert_eq!(reloaded.user.name, "alice");
+        assert_eq!(reloaded.extra["schema"].as_integer(), Some(7));
+        assert!(reloaded.extra["plugins"].is_table());
+        assert_eq!(
+            reloaded.network.extra["future_option"].as_str(),
+            Some("keep me")
+        );
+    }
+
+    #[test]
+    fn test_profile_overlays_nested_fields() {
+        let content = r#"
+            [network]
+            port = 2425
+            ports = [2425, 2427]
+
+            [user]
+            name = "wei"
+            host = "DESKTOP-01"
+            group = "backend"
+
+            [encoding.peers]
+            "192.168.1.44" = "gbk"
+
+            [profile.makerspace.network]
+            port = 2500
+            ports = [2500]
+
+            [profile.makerspace.user]
+            group = "makers"
+
+            [profile.makerspace.encoding]
+            protocol = "utf-8"
+
+            [profile.makerspace.encoding.peers]
+            "10.0.0.0/8" = "utf-8"
+
+            [profile.office]
+        "#;
+
+        let base = AppConfig::parse(content).unwrap();
+        assert_eq!(base.network.port, 2425);
+        assert_eq!(base.profiles(), ["makerspace", "office"]);
+
+        let maker = AppConfig::parse_profile(content, Some("makerspace")).unwrap();
+        assert_eq!(maker.network.port, 2500);
+        // 数组整体替换，不与基础配置合并
+        assert_eq!(maker.network.ports, [2500]);
+        // 方案未出现的键保持基础配置的值
+        assert_eq!(maker.user.name, "wei");
+        assert_eq!(maker.user.host, "DESKTOP-01");
+        assert_eq!(maker.user.group, "makers");
+        assert_eq!(maker.encoding.protocol, "utf-8");
+        assert_eq!(maker.encoding.display, "utf-8");
+        // 嵌套表逐键合并
+        assert_eq!(maker.encoding.peers.len(), 2);
+        assert_eq!(maker.encoding.peers["192.168.1.44"], "gbk");
+
+        let office = AppConfig::parse_profile(content, Some("office")).unwrap();
+        assert_eq!(office, base);
+
+        let error = AppConfig::parse_profile(content, Some("home")).unwrap_err().to_string();
+        assert!(error.contains("'home'"), "{}", error);
+        assert!(error.contains("makerspace, office"), "{}", error);
+        assert!(AppConfig::parse_profile("", Some("home")).is_err());
+
+        let env = ConfigOverrides::from_vars(|key| {
+            (key == PROFILE_ENV).then(|| "office".to_string())
+        })
+        .unwrap();
+        let cli = ConfigOverrides {
+            profile: Some("makerspace".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ConfigOverrides::select_profile(&env, &ConfigOverrides::default()), Some("office"));
+        assert_eq!(ConfigOverrides::select_profile(&env, &cli), Some("makerspace"));
+    }
+
+    #[test]
+    fn test_effective_toml_round_trip() {
+        let content = r#"
+            [user]
+            name = "wei"
+            allowed_groups = ["backend", "研发部"]
+
+            [security]
+            psk = "s3cret"
+            blocked = ["10.0.0.0/8"]
+
+            [profile.office.network]
+            broadcast_ips = ["192.168.1.255", "10.1.0.0/16"]
+        "#;
+        let mut config = AppConfig::parse_profile(content, Some("office")).unwrap();
+        ConfigOverrides {
+            port: Some(2500),
+            group: Some("backend".to_string()),
+            ..Default::default()
+        }
+        .apply(&mut config);
+
+        let printed = config.effective_toml().unwrap();
+        assert!(!printed.contains("s3cret"), "{}", printed);
+        assert!(!printed.contains("[profile"), "{}", printed);
+
+        let parsed = AppConfig::parse(&printed).unwrap();
+        assert_eq!(parsed.security.psk, REDACTED);
+        let mut expected = config.clone();
+        expected.security.psk = REDACTED.to_string();
+        expected.extra.remove(PROFILE_SECTION);
+        assert_eq!(parsed, expected);
+
+        // 未设置的密钥保持为空
+        let plain = AppConfig::default().effective_toml().unwrap();
+        assert_eq!(AppConfig::parse(&plain).unwrap(), AppConfig::default());
+    }
+
+    #[test]
+    fn test_history_settings() {
+        let config = AppConfig::parse(
+            r#"
+            [history]
+            enabled = true
+            backend = "csv"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(fields(&config.validate()), ["history.backend"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = HistoryConfig::default();
+        assert!(!history.enabled);
+        assert_eq!(history.backend(), Some(HistoryBackend::Jsonl));
+        assert!(history.path().ends_with("history.jsonl"));
+        history.backend = "sqlite".to_string();
+        assert_eq!(history.backend(), Some(HistoryBackend::Sqlite));
+        assert!(history.path().ends_with("history.sqlite3"));
+        let sqlite = AppConfig::parse("[history]\nbackend = \"sqlite\"\n").unwrap();
+        assert!(sqlite.validate().is_empty());
+
+        history.path = dir.path().join("logs/history.db").display().to_string();
+        assert!(history.check_path().is_none());
+        let file = dir.path().join("file");
+        fs::write(&file, b"").unwrap();
+        history.path = file.join("history.db").display().to_string();
+        assert_eq!(history.check_path().unwrap().field, "history.path");
+    }
+
+    #[test]
+    fn test_bom_and_gbk_config_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let mut content = b"\xEF\xBB\xBF".to_vec();
+        content.extend_from_slice("[user]\nname = \"小王\"\n".as_bytes());
+        fs::write(&path, &content).unwrap();
+        assert_eq!(AppConfig::load(&path).unwrap().user.name, "小王");
+
+        let content: Vec<u8> = b"\xFF\xFE"
+            .iter()
+            .copied()
+            .chain("[network]\nport = 3000\n".encode_utf16().flat_map(u16::to_le_bytes))
+            .collect();
+        fs::write(&path, &content).unwrap();
+        assert_eq!(AppConfig::load(&path).unwrap().network.port, 3000);
+
+        let (gbk, _, _) = encoding_rs::GBK.encode("[user]\ngroup = \"研发部\"\n");
+        assert_eq!(decode_config_bytes(&gbk, None).unwrap(), "[user]\ngroup = \"研发部\"\n");
+        assert!(decode_config_bytes(&gbk, Some("no-such-encoding")).is_err());
+    }
+
+    #[test]
+    fn test_migrate_file_writes_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let v1 = include_str!("../tests/fixtures/config_v1.toml");
+        fs::write(&path, v1).unwrap();
+
+        let loaded = AppConfig::load(&path).unwrap();
+        assert_eq!(loaded.config_version, CONFIG_VERSION);
+        assert_eq!(loaded.security.blocked, ["10.0.0.66", "spammer@EVIL-PC"]);
+        assert_eq!(loaded.encoding.protocol, "gbk");
+        assert!(!loaded.extra.contains_key("blocklist"));
+
+        assert_eq!(AppConfig::migrate_file(&path).unwrap().len(), 4);
+        assert_eq!(fs::read_to_string(backup_path(&path)).unwrap(), v1);
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(migrate::file_version(&toml::from_str(&content).unwrap()).unwrap(), CONFIG_VERSION);
+        assert_eq!(AppConfig::load(&path).unwrap(), loaded);
+        assert!(AppConfig::migrate_file(&path).unwrap().is_empty());
+
+        fs::write(&path, "config_version = 99\n").unwrap();
+        let error = format!("{:#}", AppConfig::load(&path).unwrap_err());
+        assert!(error.contains("newer lanMsg"), "{}", error);
+    }
+
+    #[test]
+    fn test_broadcast_target_forms() {
+        let parse = |value: &str| BroadcastTarget::parse(value).unwrap();
+        assert_eq!(parse("255.255.255.255"), BroadcastTarget::Ip("255.255.255.255".parse().unwrap()));
+        assert_eq!(parse("ff02::1"), BroadcastTarget::Ip("ff02::1".parse().unwrap()));
+        assert_eq!(parse("192.168.10.0/24"), BroadcastTarget::Ip("192.168.10.255".parse().unwrap()));
+        assert_eq!(parse("10.1.2.3/16"), BroadcastTarget::Ip("10.1.255.255".parse().unwrap()));
+        assert_eq!(parse("10.1.2.3/32"), BroadcastTarget::Ip("10.1.2.3".parse().unwrap()));
+        assert_eq!(parse("Lan-GW.example.com"), BroadcastTarget::Host("lan-gw.example.com".into()));
+
+        let mut config = AppConfig::default();
+        config.network.broadcast_ips = vec!["192.168.10.0/24".to_string(), "lan-gw.example.com".to_string()];
+        assert!(config.network.is_valid());
+        assert_eq!(config.broadcast_addrs(), ["192.168.10.255:2425", "lan-gw.example.com:2425"]);
+    }
+
+    #[test]
+    fn test_broadcast_ips_single_and_list() {
+        let list = AppConfig::parse("[network]\nbroadcast_ips = [\"192.168.1.255\", \"10.0.0.0/8\"]\n").unwrap();
+        assert_eq!(list.network.broadcast_ips, ["192.168.1.255", "10.0.0.0/8"]);
+        assert_eq!(list.broadcast_addrs(), ["192.168.1.255:2425", "10.255.255.255:2425"]);
+
+        // 旧键名与单个字符串都解析为一项
+        for content in [
+            "[network]\nbroadcast_ip = \"192.168.1.255\"\n",
+            "[network]\nbroadcast_ips = \"192.168.1.255\"\n",
+            "[network]\nbroadcast_ip = [\"192.168.1.255\"]\n",
+        ] {
+            let single = AppConfig::parse(content).unwrap();
+            assert_eq!(single.network.broadcast_ips, ["192.168.1.255"], "{}", content);
+        }
+        let yaml = AppConfig::parse_profile_as("network:\n  broadcast_ip: 192.168.1.255\n", ConfigFormat::Yaml, None).unwrap();
+        assert_eq!(yaml.network.broadcast_ips, ["192.168.1.255"]);
+        assert!(AppConfig::parse("[network]\nbroadcast_ips = 5\n").is_err());
+
+        // 配置方案用另一种键名覆盖时不会重复
+        let content = "[network]\nbroadcast_ip = \"192.168.1.255\"\n[profile.lab.network]\nbroadcast_ips = [\"10.1.255.255\", \"10.2.255.255\"]\n";
+        let lab = AppConfig::parse_profile_as(content, ConfigFormat::Toml, Some("lab")).unwrap();
+        assert_eq!(lab.network.broadcast_ips, ["10.1.255.255", "10.2.255.255"]);
+    }
+
+    #[test]
+    fn test_invalid_broadcast_cidr() {
+        for value in ["192.168.10.0/33", "192.168.10.0/x", "fe80::/64", "300.1.1.0/24", "1.2.3", "-bad-.lan", ""] {
+            assert!(BroadcastTarget::parse(value).is_err(), "{}", value);
+        }
+        let mut network = NetworkConfig {
+            broadcast_ips: vec!["192.168.10.0/24".to_string(), "192.168.10.0/40".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(fields(&network.validate()), ["network.broadcast_ips[1]"]);
+        network.broadcast_ips.pop();
+        assert!(network.validate().is_empty());
+        network.broadcast_ips.clear();
+        assert_eq!(fields(&network.validate()), ["network.broadcast_ips"]);
+    }
+
+    #[test]
+    fn test_toml_yaml_json_load_identically() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [
+            (
+                "config.toml",
+                r#"
+                [network]
+                port = 3000
+                ports = [3000, 3002]
+
+                [user]
+                name = "wei"
+                group = "backend"
+
+                [encoding.peers]
+                "192.168.1.44" = "gbk"
+
+                [security]
+                blocked = ["10.0.0.66"]
+                "#,
+            ),
+            (
+                "config.yaml",
+                "network:\n  port: 3000\n  ports: [3000, 3002]\nuser:\n  name: wei\n  group: backend\n\
+                 encoding:\n  peers:\n    \"192.168.1.44\": gbk\nsecurity:\n  blocked:\n    - 10.0.0.66\n",
+            ),
+            (
+                "config.json",
+                r#"{"network": {"port": 3000, "ports": [3000, 3002]},
+                    "user": {"name": "wei", "group": "backend"},
+                    "encoding": {"peers": {"192.168.1.44": "gbk"}},
+                    "security": {"blocked": ["10.0.0.66"]}}"#,
+            ),
+        ];
+        let loaded: Vec<AppConfig> = files
+            .iter()
+            .map(|(name, content)| {
+                let path = dir.path().join(name);
+                fs::write(&path, content).unwrap();
+                AppConfig::load(&path).unwrap()
+            })
+            .collect();
+        assert_eq!(loaded[0].network.ports, [3000, 3002]);
+        assert_eq!(loaded[0].user.host, "localhost");
+        assert_eq!(loaded[0], loaded[1]);
+        assert_eq!(loaded[0], loaded[2]);
+
+        // 保存按扩展名选择格式，读回的结果不变
+        for name in ["saved.yml", "saved.json"] {
+            let path = dir.path().join(name);
+            loaded[0].save(&path).unwrap();
+            assert_eq!(AppConfig::load(&path).unwrap(), loaded[0]);
+            let template = default_config_template_as(ConfigFormat::from_path(&path)).unwrap();
+            fs::write(&path, template).unwrap();
+            assert_eq!(AppConfig::load(&path).unwrap(), AppConfig::default());
+        }
+
+        // 各格式中有错的段同样只报告该段
+        let path = dir.path().join("bad.json");
+        fs::write(&path, r#"{"network": {"port": "high"}}"#).unwrap();
+        let error = format!("{:#}", AppConfig::load(&path).unwrap_err());
+        assert!(error.contains("invalid [network] section"), "{}", error);
+    }
+}
@@ -0,0 +1,337 @@
+//! 聊天模式的输入行：终端上以原始模式自行编辑输入，其他输出打印在输入行上方，再重绘提示符与未输入完的内容
+//!
+//! 标准输入或标准输出不是终端时不启用，聊天按行读取标准输入
+
+use crate::chat::ReadOutcome;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, queue, terminal};
+use futures::StreamExt;
+use std::io::{IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+use unicode_width::UnicodeWidthChar;
+
+/// 正在编辑的输入行；为 Some 时终端处于原始模式，输入行显示在最后一行
+static LINE: Mutex<Option<Line>> = Mutex::new(None);
+
+struct Line {
+    prompt: String,
+    buffer: LineBuffer,
+}
+
+/// 标准输入与标准输出都是终端时使用行编辑
+pub fn interactive() -> bool {
+    std::io::stdin().is_terminal() && std::io::stdout().is_terminal()
+}
+
+/// 输出内容：正在输入时先清掉输入行并临时退出原始模式，输出后重绘输入行；否则直接输出
+///
+/// 日志也经由这里输出，write 中不能再调用本函数
+pub fn print_above(write: impl FnOnce()) {
+    output(|_| write());
+}
+
+/// 输出一行
+pub fn println(text: &str) {
+    print_above(|| println!("{}", text));
+}
+
+/// 输出一行随时可能到达的通知（收到的消息、文件传输结果等）；不在输入时先换行，免得接在提示符后面
+pub fn notify(text: &str) {
+    output(|editing| {
+        if editing {
+            println!("{}", text);
+        } else {
+            println!("\n{}", text);
+        }
+    });
+}
+
+/// write 的参数表示是否有正在编辑的输入行
+fn output(write: impl FnOnce(bool)) {
+    let line = LINE.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(line) = line.as_ref() else {
+        write(false);
+        return;
+    };
+    let mut stdout = std::io::stdout();
+    let _ = queue!(stdout, cursor::MoveToColumn(0), terminal::Clear(terminal::ClearType::CurrentLine));
+    let _ = stdout.flush();
+    let _ = terminal::disable_raw_mode();
+    write(true);
+    let _ = std::io::stderr().flush();
+    let _ = terminal::enable_raw_mode();
+    draw(line);
+}
+
+/// 显示提示符并读取一行；idle 的含义同 chat::read_line_with_idle
+///
+/// Ctrl-C 与空行上的 Ctrl-D 视为输入关闭。返回或被取消时恢复终端
+pub async fn read_line(prompt: &str, idle: Option<Duration>) -> std::io::Result<ReadOutcome> {
+    let _raw = RawLine::enter(prompt)?;
+    let mut events = EventStream::new();
+    let deadline = idle.map(|idle| tokio::time::Instant::now() + idle);
+    loop {
+        let event = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, events.next()).await {
+                Ok(event) => event,
+                Err(_) => return Ok(ReadOutcome::Idle),
+            },
+            None => events.next().await,
+        };
+        let key = match event.transpose()? {
+            None => return Ok(ReadOutcome::Closed),
+            Some(Event::Key(key)) if key.kind != KeyEventKind::Release => key,
+            Some(Event::Resize(..)) => {
+                with_line(|line| draw(line));
+                continue;
+            }
+            Some(_) => continue,
+        };
+        let edit = with_line(|line| {
+            let edit = line.buffer.apply(&key);
+            match &edit {
+                Edit::Changed => draw(line),
+                Edit::Submit(_) | Edit::Eof | Edit::Interrupt => finish(line),
+                Edit::Unchanged => {}
+            }
+            edit
+        });
+        match edit {
+            Some(Edit::Submit(text)) => return Ok(ReadOutcome::Line(text)),
+            Some(Edit::Eof | Edit::Interrupt) | None => return Ok(ReadOutcome::Closed),
+            Some(Edit::Changed | Edit::Unchanged) => {}
+        }
+    }
+}
+
+fn with_line<T>(f: impl FnOnce(&mut Line) -> T) -> Option<T> {
+    LINE.lock().unwrap_or_else(|e| e.into_inner()).as_mut().map(f)
+}
+
+/// 原始模式下的输入行，离开作用域时恢复终端
+struct RawLine;
+
+impl RawLine {
+    fn enter(prompt: &str) -> std::io::Result<RawLine> {
+        let mut line = LINE.lock().unwrap_or_else(|e| e.into_inner());
+        terminal::enable_raw_mode()?;
+        let active = line.insert(Line {
+            prompt: prompt.to_string(),
+            buffer: LineBuffer::default(),
+        });
+        draw(active);
+        Ok(RawLine)
+    }
+}
+
+impl Drop for RawLine {
+    fn drop(&mut self) {
+        LINE.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// 重绘输入行：光标移到行首并清除，写出提示符与输入中可见的部分，再把光标放回编辑位置
+fn draw(line: &Line) {
+    // 取不到宽度（例如新建的伪终端报告 0 列）时按 80 列
+    let width = match terminal::size() {
+        Ok((columns, _)) if columns > 0 => columns as usize,
+        _ => 80,
+    };
+    let (text, column) = visible(&line.prompt, &line.buffer, width);
+    let mut stdout = std::io::stdout();
+    let _ = queue!(stdout, cursor::MoveToColumn(0), terminal::Clear(terminal::ClearType::CurrentLine));
+    let _ = write!(stdout, "{}", text);
+    let _ = queue!(stdout, cursor::MoveToColumn(column as u16));
+    let _ = stdout.flush();
+}
+
+/// 输入结束：完整显示这一行并换行，留在屏幕上
+fn finish(line: &Line) {
+    let mut stdout = std::io::stdout();
+    let _ = queue!(stdout, cursor::MoveToColumn(0), terminal::Clear(terminal::ClearType::CurrentLine));
+    let _ = write!(stdout, "{}{}\r\n", line.prompt, line.buffer.text());
+    let _ = stdout.flush();
+}
+
+/// 在 width 列内显示的提示符与输入，以及光标所在的列；输入过长时水平滚动，保证光标可见
+fn visible(prompt: &str, buffer: &LineBuffer, width: usize) -> (String, usize) {
+    let prompt_width: usize = prompt.chars().map(char_width).sum();
+    // 最后一列留给光标
+    let room = width.saturating_sub(prompt_width + 1).max(1);
+    let chars = &buffer.chars;
+    let mut start = buffer.cursor;
+    let mut before = 0;
+    while start > 0 && before + char_width(chars[start - 1]) <= room {
+        start -= 1;
+        before += char_width(chars[start]);
+    }
+    let mut shown = String::from(prompt);
+    let mut used = 0;
+    for &c in &chars[start..] {
+        if used + char_width(c) > room {
+            break;
+        }
+        used += char_width(c);
+        shown.push(c);
+    }
+    (shown, prompt_width + before)
+}
+
+fn char_width(c: char) -> usize {
+    c.width().unwrap_or(0)
+}
+
+/// 一次按键对输入行的作用
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    Changed,
+    Unchanged,
+    /// Enter：提交这一行
+    Submit(String),
+    /// 空行上的 Ctrl-D
+    Eof,
+    /// Ctrl-C
+    Interrupt,
+}
+
+/// 输入行的内容与光标位置（以字符计）
+#[derive(Debug, Clone, Default)]
+pub struct LineBuffer {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl LineBuffer {
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// 按键：字符插入到光标处；Backspace/Delete、左右方向键、Home/End，以及 Ctrl-A/E/U/K/W 的常见行编辑
+    pub fn apply(&mut self, key: &KeyEvent) -> Edit {
+        // Windows 上 AltGr 组合出的字符带 Ctrl+Alt，按普通字符处理
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL) && !key.modifiers.contains(KeyModifiers::ALT);
+        let before = (self.chars.len(), self.cursor);
+        match key.code {
+            KeyCode::Enter => return Edit::Submit(self.text()),
+            KeyCode::Char('c') if ctrl => return Edit::Interrupt,
+            KeyCode::Char('d') if ctrl && self.chars.is_empty() => return Edit::Eof,
+            KeyCode::Char('d') if ctrl => self.delete(),
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.chars.len(),
+            KeyCode::Char('u') if ctrl => {
+                self.chars.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char('k') if ctrl => self.chars.truncate(self.cursor),
+            KeyCode::Char('w') if ctrl => self.delete_word(),
+            KeyCode::Char(_) if ctrl || key.modifiers == KeyModifiers::ALT => {}
+            KeyCode::Char(c) => {
+                self.chars.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.chars.remove(self.cursor);
+            }
+            KeyCode::Delete => self.delete(),
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.chars.len(),
+            _ => {}
+        }
+        if (self.chars.len(), self.cursor) == before {
+            Edit::Unchanged
+        } else {
+            Edit::Changed
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    /// 删除光标前的一个词（连同其后的空白）
+    fn delete_word(&mut self) {
+        let mut start = self.cursor;
+        while start > 0 && self.chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        while start > 0 && !self.chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        self.chars.drain(start..self.cursor);
+        self.cursor = start;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(keys: &[KeyEvent]) -> (LineBuffer, Vec<Edit>) {
+        let mut buffer = LineBuffer::default();
+        let edits = keys.iter().map(|key| buffer.apply(key)).collect();
+        (buffer, edits)
+    }
+
+    fn chars(text: &str) -> Vec<KeyEvent> {
+        text.chars().map(|c| KeyEvent::from(KeyCode::Char(c))).collect()
+    }
+
+    fn ctrl(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)
+    }
+
+    #[test]
+    fn test_line_buffer_editing_keys() {
+        let mut keys = chars("hllo");
+        keys.extend([KeyCode::Home, KeyCode::Right].map(KeyEvent::from));
+        keys.extend(chars("e"));
+        keys.push(KeyEvent::from(KeyCode::End));
+        keys.extend(chars(" wrold"));
+        keys.push(ctrl('w'));
+        keys.extend(chars("世界"));
+        keys.push(KeyEvent::from(KeyCode::Backspace));
+        let (buffer, _) = typed(&keys);
+        assert_eq!(buffer.text(), "hello 世");
+        assert_eq!(buffer.cursor(), 7);
+
+        let mut buffer = buffer;
+        assert_eq!(buffer.apply(&KeyEvent::from(KeyCode::Left)), Edit::Changed);
+        assert_eq!(buffer.apply(&ctrl('k')), Edit::Changed);
+        assert_eq!(buffer.text(), "hello ");
+        assert_eq!(buffer.apply(&KeyEvent::from(KeyCode::Delete)), Edit::Unchanged);
+        assert_eq!(buffer.apply(&ctrl('d')), Edit::Unchanged);
+        assert_eq!(buffer.apply(&KeyEvent::from(KeyCode::Enter)), Edit::Submit("hello ".into()));
+        assert_eq!(buffer.apply(&ctrl('u')), Edit::Changed);
+        assert_eq!(buffer.text(), "");
+        assert_eq!(buffer.apply(&ctrl('d')), Edit::Eof);
+        assert_eq!(buffer.apply(&ctrl('c')), Edit::Interrupt);
+    }
+
+    #[test]
+    fn test_visible_scrolls_to_keep_cursor_on_screen() {
+        let (buffer, _) = typed(&chars("hello"));
+        assert_eq!(visible("> ", &buffer, 80), ("> hello".to_string(), 7));
+
+        // 宽度 10：提示符 2 列，光标 1 列，输入最多 7 列
+        let (buffer, _) = typed(&chars("abcdefghij"));
+        assert_eq!(visible("> ", &buffer, 10), ("> defghij".to_string(), 9));
+        let mut buffer = buffer;
+        buffer.apply(&KeyEvent::from(KeyCode::Home));
+        assert_eq!(visible("> ", &buffer, 10), ("> abcdefg".to_string(), 2));
+
+        // 全角字符占两列
+        let (buffer, _) = typed(&chars("你好世界"));
+        assert_eq!(visible("> ", &buffer, 10), ("> 好世界".to_string(), 8));
+    }
+}
@@ -0,0 +1,269 @@
+use crate::net::IpMsgServer;
+use crate::presence::PresenceState;
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// 控制文件名：常驻实例的控制地址与口令，与配置文件放在同一目录
+pub const CONTROL_FILE: &str = "lanmsg.control";
+/// 连接与等待应答的超时
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 发给常驻实例的控制请求，每个连接一行请求、一行应答
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlRequest {
+    /// 查询当前状态
+    Status,
+    /// 设为离开，未指定内容时使用配置的离开信息
+    Away(Option<String>),
+    /// 恢复在线
+    Back,
+    /// 免打扰
+    Dnd,
+}
+
+impl ControlRequest {
+    pub fn encode(&self) -> String {
+        match self {
+            ControlRequest::Status => "status".to_string(),
+            ControlRequest::Away(None) => "away".to_string(),
+            // 离开信息只占一行
+            ControlRequest::Away(Some(message)) => format!("away {}", message.replace(['\r', '\n'], " ")),
+            ControlRequest::Back => "back".to_string(),
+            ControlRequest::Dnd => "dnd".to_string(),
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<Self> {
+        let (verb, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match verb {
+            "status" => Some(ControlRequest::Status),
+            "away" if rest.trim().is_empty() => Some(ControlRequest::Away(None)),
+            "away" => Some(ControlRequest::Away(Some(rest.trim().to_string()))),
+            "back" => Some(ControlRequest::Back),
+            "dnd" => Some(ControlRequest::Dnd),
+            _ => None,
+        }
+    }
+}
+
+/// 状态与持续时长，例如 `away (lunch) for 5m 12s`
+pub fn status_line(state: &PresenceState, elapsed: Duration) -> String {
+    format!("{} for {}", state, format_elapsed(elapsed))
+}
+
+pub(crate) fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// 执行一条控制请求，返回执行后的状态行
+pub async fn apply(server: &IpMsgServer, request: &ControlRequest) -> Result<String> {
+    match request {
+        ControlRequest::Status => {}
+        ControlRequest::Away(message) => server.set_away(message.as_deref()).await?,
+        ControlRequest::Back => server.set_state(PresenceState::Online).await?,
+        ControlRequest::Dnd => server.set_state(PresenceState::DoNotDisturb).await?,
+    }
+    Ok(status_line(&server.state(), server.state_duration()))
+}
+
+/// 常驻实例的控制接口：只监听本机回环地址，地址与口令写入控制文件，退出时删除
+pub struct ControlServer {
+    path: PathBuf,
+    content: String,
+    task: JoinHandle<()>,
+}
+
+impl ControlServer {
+    pub async fn start(path: PathBuf, server: IpMsgServer) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let token = new_token();
+        let content = format!("{} {}\n", listener.local_addr()?, token);
+        write_private(&path, &content).with_context(|| format!("Failed to write {}", path.display()))?;
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (server, token) = (server.clone(), token.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, &server, &token).await {
+                        log::debug!("Control connection failed: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self { path, content, task })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        // 另一个实例接管了控制文件时不删除
+        if fs::read_to_string(&self.path).is_ok_and(|content| content == self.content) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+async fn serve(stream: TcpStream, server: &IpMsgServer, token: &str) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    tokio::time::timeout(CONTROL_TIMEOUT, BufReader::new(reader).read_line(&mut line)).await??;
+    let reply = match line.trim_end().split_once(' ') {
+        Some((given, request)) if given == token => match ControlRequest::parse(request) {
+            Some(request) => match apply(server, &request).await {
+                Ok(status) => format!("ok {}", status),
+                Err(e) => format!("error {}", e),
+            },
+            None => format!("error unknown request '{}'", request),
+        },
+        _ => "error bad token".to_string(),
+    };
+    writer.write_all(format!("{}\n", reply).as_bytes()).await?;
+    Ok(())
+}
+
+/// 向常驻实例发送请求，返回其状态行；没有正在运行的实例时返回 None
+pub async fn request(path: &Path, request: &ControlRequest) -> Result<Option<String>> {
+    let Ok(content) = fs::read_to_string(path) else {
+        return Ok(None);
+    };
+    let Some((addr, token)) = content
+        .trim()
+        .split_once(' ')
+        .and_then(|(addr, token)| Some((addr.parse::<SocketAddr>().ok()?, token)))
+    else {
+        log::warn!("Ignoring malformed control file {}", path.display());
+        return Ok(None);
+    };
+    // 控制文件残留（实例异常退出）时连接会被拒绝
+    let stream = match tokio::time::timeout(CONTROL_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => stream,
+        _ => return Ok(None),
+    };
+    let (reader, mut writer) = stream.into_split();
+    writer
+        .write_all(format!("{} {}\n", token, request.encode()).as_bytes())
+        .await?;
+    let mut reply = String::new();
+    tokio::time::timeout(CONTROL_TIMEOUT, BufReader::new(reader).read_line(&mut reply))
+        .await
+        .context("Timed out waiting for the running instance")??;
+    match reply.trim_end().split_once(' ') {
+        Some(("ok", status)) => Ok(Some(status.to_string())),
+        Some(("error", error)) => Err(anyhow::anyhow!("Running instance refused the request: {}", error)),
+        _ => Err(anyhow::anyhow!("Unexpected reply from the running instance: {:?}", reply)),
+    }
+}
+
+fn new_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// 写入只有当前用户可读的文件
+///
+/// 先删除残留的旧文件，再以 0600 权限新建，写入前不存在其他用户可读的窗口
+fn write_private(path: &Path, content: &str) -> std::io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::protocol::{IpMsgPacket, commands};
+    use tokio::net::UdpSocket;
+
+    #[test]
+    fn test_request_round_trip() {
+        for request in [
+            ControlRequest::Status,
+            ControlRequest::Away(None),
+            ControlRequest::Away(Some("at lunch".to_string())),
+            ControlRequest::Back,
+            ControlRequest::Dnd,
+        ] {
+            assert_eq!(ControlRequest::parse(&request.encode()), Some(request));
+        }
+        assert_eq!(ControlRequest::parse("sleep"), None);
+        assert_eq!(format_elapsed(Duration::from_secs(59)), "59s");
+        assert_eq!(format_elapsed(Duration::from_secs(312)), "5m 12s");
+        assert_eq!(format_elapsed(Duration::from_secs(7380)), "2h 3m");
+    }
+
+    #[test]
+    fn test_control_file_is_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONTROL_FILE);
+        // 上次异常退出残留的、其他用户可读的文件被替换
+        fs::write(&path, "stale").unwrap();
+        let token = new_token();
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, new_token());
+        write_private(&path, &token).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), token);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_status_through_running_instance() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONTROL_FILE);
+        assert_eq!(request(&path, &ControlRequest::Status).await.unwrap(), None);
+
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+        let control = ControlServer::start(path.clone(), server.clone()).await.unwrap();
+
+        let status = request(&path, &ControlRequest::Away(Some("lunch".to_string()))).await.unwrap();
+        assert_eq!(status.as_deref(), Some("away (lunch) for 0s"));
+        // 其他客户端立即收到离开状态
+        let mut buf = [0; 1024];
+        let (len, _) = sink.recv_from(&mut buf).await.unwrap();
+        let refresh = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+        assert_eq!(refresh.command & 0xff, commands::IPMSG_BR_ABSENCE);
+        assert_ne!(refresh.command & commands::IPMSG_ABSENCEOPT, 0);
+        assert_eq!(refresh.additional_msg, "lunch");
+
+        let status = request(&path, &ControlRequest::Back).await.unwrap();
+        assert_eq!(status.as_deref(), Some("online for 0s"));
+
+        // 口令不符的请求被拒绝
+        let addr = fs::read_to_string(&path).unwrap().split(' ').next().unwrap().to_string();
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        stream.write_all(b"guess status\n").await.unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).await.unwrap();
+        assert_eq!(reply, "error bad token\n");
+
+        drop(control);
+        assert!(!path.exists());
+        assert_eq!(request(&path, &ControlRequest::Status).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::protocol::IpMsgPacket;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// 默认去重窗口
+pub const DEFAULT_DEDUP_TTL: Duration = Duration::from_secs(30);
+/// 默认最大缓存条目数
+pub const DEFAULT_DEDUP_CAPACITY: usize = 4096;
+
+/// 去重键：同一身份的同一报文编号视为同一报文
+///
+/// 默认与来源地址无关（中继转发后来源会变化）；需要区分不同主机时可附加来源 IP，
+/// 端口不参与比较，同一主机经多个端口发来的同一报文仍视为重复
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DedupKey {
+    sender_user: String,
+    sender_host: String,
+    packet_no: u32,
+    command: u32,
+    source: Option<IpAddr>,
+}
+
+impl DedupKey {
+    pub fn from_packet(packet: &IpMsgPacket) -> Self {
+        Self {
+            sender_user: packet.sender_user.clone(),
+            sender_host: packet.sender_host.clone(),
+            packet_no: packet.packet_no,
+            command: packet.command,
+            source: None,
+        }
+    }
+
+    /// 附加来源 IP
+    pub fn with_source(mut self, ip: IpAddr) -> Self {
+        self.source = Some(ip);
+        self
+    }
+}
+
+/// 报文去重缓存（按时间窗口过期，超出容量时淘汰最早的条目）
+#[derive(Debug)]
+pub struct DedupCache {
+    ttl: Duration,
+    capacity: usize,
+    seen: HashMap<DedupKey, Instant>,
+    order: VecDeque<(DedupKey, Instant)>,
+    evicted: u64,
+}
+
+impl DedupCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_capacity(ttl, DEFAULT_DEDUP_CAPACITY)
+    }
+
+    pub fn with_capacity(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity: capacity.max(1),
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            evicted: 0,
+        }
+    }
+
+    /// 记录报文，首次出现返回 true，窗口内重复返回 false
+    pub fn check_and_insert(&mut self, key: DedupKey) -> bool {
+        let now = Instant::now();
+        self.purge_expired(now);
+
+        if self.seen.contains_key(&key) {
+            return false;
+        }
+        if self.seen.len() >= self.capacity
+            && let Some((oldest, _)) = self.order.pop_front()
+        {
+            self.seen.remove(&oldest);
+            self.evicted += 1;
+        }
+        self.seen.insert(key.clone(), now);
+        self.order.push_back((key, now));
+        true
+    }
+
+    /// 因容量不足被淘汰的条目数
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// 清理过期条目
+    fn purge_expired(&mut self, now: Instant) {
+        while let Some((_, seen_at)) = self.order.front() {
+            if now.duration_since(*seen_at) < self.ttl {
+                break;
+            }
+            if let Some((key, _)) = self.order.pop_front() {
+                self.seen.remove(&key);
+            }
+        }
+    }
+}
+
+impl Default for DedupCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEDUP_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(packet_no: u32) -> DedupKey {
+        DedupKey::from_packet(&IpMsgPacket {
+            packet_no,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_capacity_evicts_oldest() {
+        let mut cache = DedupCache::with_capacity(DEFAULT_DEDUP_TTL, 2);
+        assert!(cache.check_and_insert(key(1)));
+        assert!(cache.check_and_insert(key(2)));
+        assert!(!cache.check_and_insert(key(2)));
+
+        // 超出容量时淘汰最早的条目，新报文仍被接受
+        assert!(cache.check_and_insert(key(3)));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.evicted(), 1);
+        assert!(cache.check_and_insert(key(1)));
+        assert!(!cache.check_and_insert(key(3)));
+    }
+}
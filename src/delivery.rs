@@ -0,0 +1,118 @@
+//! send 多个收件人时逐个收件人的发送结果：表格与 JSON 输出
+
+use crate::net::{Delivery, SendOutcome};
+use crate::table::{self, TableStyle};
+use serde_json::{Value, json};
+
+/// 汇总：确认、已发出（不等待确认）、未确认、失败的收件人数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Summary {
+    pub acked: usize,
+    pub sent: usize,
+    pub unacked: usize,
+    pub failed: usize,
+}
+
+impl Summary {
+    pub fn of(outcomes: &[SendOutcome]) -> Self {
+        let mut summary = Summary::default();
+        for outcome in outcomes {
+            match outcome.delivery {
+                Delivery::Acked(_) => summary.acked += 1,
+                Delivery::Sent => summary.sent += 1,
+                Delivery::Unacked => summary.unacked += 1,
+                Delivery::Failed(_) => summary.failed += 1,
+            }
+        }
+        summary
+    }
+
+    pub fn total(&self) -> usize {
+        self.acked + self.sent + self.unacked + self.failed
+    }
+
+    /// 所有收件人都失败（没有收件人时也算）
+    pub fn all_failed(&self) -> bool {
+        self.failed == self.total()
+    }
+}
+
+/// 渲染结果表：收件人、地址、结果
+pub fn render(outcomes: &[SendOutcome], style: TableStyle) -> String {
+    let rows: Vec<Vec<String>> = outcomes
+        .iter()
+        .map(|outcome| {
+            vec![
+                outcome.recipient.clone(),
+                outcome.addr.map(|addr| addr.to_string()).unwrap_or_else(|| "-".to_string()),
+                outcome.delivery.to_string(),
+            ]
+        })
+        .collect();
+    table::render(&["Recipient", "Address", "Result"], &[12, 15, 6], &rows, style)
+}
+
+/// 以 JSON 数组输出结果，未解析的地址、未确认的往返时间与成功时的错误为 null
+pub fn to_json(outcomes: &[SendOutcome]) -> serde_json::Result<String> {
+    let rows: Vec<Value> = outcomes
+        .iter()
+        .map(|outcome| {
+            let (rtt_ms, error) = match &outcome.delivery {
+                Delivery::Acked(rtt) => (Some(rtt.as_millis() as u64), None),
+                Delivery::Sent | Delivery::Unacked => (None, None),
+                Delivery::Failed(e) => (None, Some(e.to_string())),
+            };
+            json!({
+                "recipient": outcome.recipient,
+                "addr": outcome.addr.map(|addr| addr.to_string()),
+                "status": outcome.delivery.status(),
+                "rtt_ms": rtt_ms,
+                "error": error,
+            })
+        })
+        .collect();
+    serde_json::to_string_pretty(&rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn outcome(recipient: &str, delivery: Delivery) -> SendOutcome {
+        let addr = (!delivery.is_failed()).then(|| "192.168.1.5:2425".parse().unwrap());
+        SendOutcome {
+            recipient: recipient.into(),
+            addr,
+            delivery,
+        }
+    }
+
+    #[test]
+    fn test_outcomes_table_json_and_summary() {
+        let outcomes = [
+            outcome("alice", Delivery::Acked(Duration::from_millis(12))),
+            outcome("bob", Delivery::Sent),
+            outcome("carol", Delivery::Unacked),
+            outcome("nobody", Delivery::Failed(anyhow::anyhow!("User nobody not found"))),
+        ];
+        let summary = Summary::of(&outcomes);
+        assert_eq!((summary.acked, summary.sent, summary.unacked, summary.failed), (1, 1, 1, 1));
+        assert!(!summary.all_failed());
+        assert!(Summary::of(&outcomes[3..]).all_failed());
+
+        let table = render(&outcomes, TableStyle::Ascii);
+        assert!(table.contains("| alice        | 192.168.1.5:2425 | acked (12 ms)"), "{}", table);
+        assert!(table.contains("| nobody       | -"), "{}", table);
+
+        let json: Value = serde_json::from_str(&to_json(&outcomes).unwrap()).unwrap();
+        assert_eq!(
+            json[0],
+            json!({ "recipient": "alice", "addr": "192.168.1.5:2425", "status": "acked", "rtt_ms": 12, "error": null })
+        );
+        assert_eq!(json[1]["status"], "sent");
+        assert_eq!(json[2]["status"], "unacked");
+        assert_eq!(json[3]["addr"], Value::Null);
+        assert_eq!(json[3]["error"], "User nobody not found");
+    }
+}
@@ -0,0 +1,166 @@
+use crate::config::AppConfig;
+use crate::event::{NetEvent, recv_lossy};
+use crate::net::IpMsgServer;
+use crate::protocol::{IpMsgPacket, commands};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// 单项检查结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    Failed(String),
+    Skipped,
+}
+
+impl CheckStatus {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, CheckStatus::Ok)
+    }
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckStatus::Ok => write!(f, "ok"),
+            CheckStatus::Failed(reason) => write!(f, "FAILED ({})", reason),
+            CheckStatus::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// 自检报告
+#[derive(Debug, Clone)]
+pub struct DiagnosticReport {
+    pub bind_addrs: Vec<SocketAddr>,
+    pub broadcast_target: SocketAddr,
+    pub protocol_encoding: String,
+    pub display_encoding: String,
+    pub send: CheckStatus,
+    pub receive: CheckStatus,
+    pub decode: CheckStatus,
+    pub round_trip: Option<Duration>,
+}
+
+impl DiagnosticReport {
+    /// 发送、接收、解码是否全部成功
+    pub fn is_healthy(&self) -> bool {
+        self.send.is_ok() && self.receive.is_ok() && self.decode.is_ok()
+    }
+}
+
+impl fmt::Display for DiagnosticReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let binds: Vec<String> = self.bind_addrs.iter().map(|addr| addr.to_string()).collect();
+        writeln!(f, "Bind:      {}", binds.join(", "))?;
+        writeln!(f, "Broadcast: {}", self.broadcast_target)?;
+        writeln!(
+            f,
+            "Encoding:  protocol={} display={}",
+            self.protocol_encoding, self.display_encoding
+        )?;
+        writeln!(f, "Send:      {}", self.send)?;
+        match self.round_trip {
+            Some(elapsed) => writeln!(f, "Receive:   {} ({} ms)", self.receive, elapsed.as_millis())?,
+            None => writeln!(f, "Receive:   {}", self.receive)?,
+        }
+        write!(f, "Decode:    {}", self.decode)
+    }
+}
+
+/// 向广播地址发送自检报文并等待回环（服务器须已在监听）
+pub async fn run_diagnostics(
+    server: &IpMsgServer,
+    config: &AppConfig,
+    timeout: Duration,
+) -> DiagnosticReport {
+    let mut report = DiagnosticReport {
+        bind_addrs: server.local_addrs(),
+        broadcast_target: server.broadcast_target(),
+        protocol_encoding: config.encoding.protocol.clone(),
+        display_encoding: config.encoding.display.clone(),
+        send: CheckStatus::Skipped,
+        receive: CheckStatus::Skipped,
+        decode: CheckStatus::Skipped,
+        round_trip: None,
+    };
+
+    let probe = IpMsgPacket {
+        version: config.user.client_version.clone(),
+        packet_no: rand::random(),
+        sender_name: "lanMsg-doctor".to_string(),
+        sender_host: "self-test".to_string(),
+        // NOOP 不会引起对端任何动作
+        command: commands::IPMSG_NOOPERATION,
+        ..Default::default()
+    };
+    let probe_bytes = probe.encode().into_bytes();
+
+    let mut events = server.subscribe();
+    let started = Instant::now();
+    if let Err(e) = server.broadcast(&probe).await {
+        report.send = CheckStatus::Failed(e.to_string());
+        return report;
+    }
+    report.send = CheckStatus::Ok;
+
+    let wait = async {
+        loop {
+            match recv_lossy(&mut events, "Self-test").await {
+                Some(NetEvent::Packet { packet, .. }) if packet.packet_no == probe.packet_no => {
+                    return Ok(());
+                }
+                Some(NetEvent::RawDatagram {
+                    bytes,
+                    error: Some(e),
+                    ..
+                }) if bytes == probe_bytes => return Err(e.to_string()),
+                Some(_) => continue,
+                None => return Err("event channel closed".to_string()),
+            }
+        }
+    };
+
+    match tokio::time::timeout(timeout, wait).await {
+        Ok(Ok(())) => {
+            report.round_trip = Some(started.elapsed());
+            report.receive = CheckStatus::Ok;
+            report.decode = CheckStatus::Ok;
+        }
+        Ok(Err(reason)) => {
+            report.round_trip = Some(started.elapsed());
+            report.receive = CheckStatus::Ok;
+            report.decode = CheckStatus::Failed(reason);
+        }
+        Err(_) => {
+            report.receive = CheckStatus::Failed(format!(
+                "no loopback within {} ms",
+                timeout.as_millis()
+            ));
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_self_loop_succeeds() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        // 回环地址不能广播，直接把广播目标指向自身
+        server.set_broadcast_target(server.local_addr().unwrap()).unwrap();
+        let config = AppConfig::default();
+        let listener = server.clone();
+        let listen_config = Arc::new(config.clone());
+        tokio::spawn(async move { listener.listen(|_, _| {}, listen_config).await });
+
+        let report = run_diagnostics(&server, &config, Duration::from_secs(1)).await;
+        assert!(report.is_healthy(), "{}", report);
+        assert!(report.round_trip.is_some());
+        assert_eq!(report.protocol_encoding, "gbk");
+    }
+}
@@ -0,0 +1,259 @@
+use crate::protocol::{IpMsgPacket, ProtocolError, hex_dump};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// 未配置 debug.dump_path 时的转储文件名
+pub const DEFAULT_DUMP_FILE: &str = "lanmsg-packets.ndjson";
+/// 写入队列长度，写入跟不上时丢弃新记录而不阻塞收发
+const DUMP_QUEUE: usize = 1024;
+
+/// 报文方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DumpDirection {
+    In,
+    Out,
+}
+
+/// 一条转储记录，每行一个 JSON 对象
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpRecord {
+    /// 记录时间（Unix 毫秒）
+    pub time_ms: u64,
+    pub direction: DumpDirection,
+    /// 对端地址（发往广播地址时为广播地址）
+    pub peer: SocketAddr,
+    pub len: usize,
+    /// 原始字节的十六进制
+    pub hex: String,
+    /// 解码结果摘要：命令字、报文编号与发送方
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decoded: Option<String>,
+    /// 解码失败的原因
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DumpRecord {
+    pub fn new(
+        direction: DumpDirection,
+        peer: SocketAddr,
+        bytes: &[u8],
+        decoded: Result<&IpMsgPacket, &ProtocolError>,
+    ) -> Self {
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
+        let (decoded, error) = match decoded {
+            Ok(packet) => (
+                Some(format!(
+                    "cmd={:#x} no={} from={}@{}",
+                    packet.command, packet.packet_no, packet.sender_user, packet.sender_host
+                )),
+                None,
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        Self {
+            time_ms,
+            direction,
+            peer,
+            len: bytes.len(),
+            hex: hex_dump(bytes),
+            decoded,
+            error,
+        }
+    }
+}
+
+/// 按大小轮转的转储文件：超过 max_bytes 时 path 改名为 path.1，原 path.1 改为 path.2，最多保留 keep 个
+#[derive(Debug)]
+pub struct DumpWriter {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: BufWriter<fs::File>,
+    written: u64,
+}
+
+impl DumpWriter {
+    pub fn open(path: impl AsRef<Path>, max_bytes: u64, keep: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let file = open_append(&path)?;
+        let written = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        Ok(Self {
+            path,
+            max_bytes,
+            keep,
+            file: BufWriter::new(file),
+            written,
+        })
+    }
+
+    /// 追加一条记录，写入后超出上限时先轮转（单条记录大于上限时仍完整写入）
+    pub fn write(&mut self, record: &DumpRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        if self.written > 0 && self.written + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated_path(&self.path, self.keep));
+            for index in (1..self.keep).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = BufWriter::new(open_append(&self.path)?);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+/// 第 index 个轮转文件的路径，例如 dump.ndjson.1
+pub fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+fn open_append(path: &Path) -> Result<fs::File> {
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open dump file {}", path.display()))
+}
+
+/// 在后台任务中写转储文件，收发路径只把记录放入队列
+#[derive(Debug, Clone)]
+pub struct PacketDumper {
+    tx: mpsc::Sender<DumpRecord>,
+}
+
+impl PacketDumper {
+    pub fn spawn(mut writer: DumpWriter) -> Self {
+        let (tx, mut rx) = mpsc::channel::<DumpRecord>(DUMP_QUEUE);
+        tokio::spawn(async move {
+            while let Some(record) = rx.recv().await {
+                let mut result = writer.write(&record);
+                // 队列中积压的记录一起写入后再刷盘
+                while result.is_ok()
+                    && let Ok(record) = rx.try_recv()
+                {
+                    result = writer.write(&record);
+                }
+                if let Err(e) = result.and_then(|()| writer.flush()) {
+                    log::warn!("Failed to write packet dump: {}", e);
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// 放入写入队列，队列满时丢弃
+    pub fn record(&self, record: DumpRecord) {
+        if self.tx.try_send(record).is_err() {
+            log::debug!("Packet dump queue full, dropping record");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(no: u32) -> DumpRecord {
+        let packet = IpMsgPacket {
+            packet_no: no,
+            sender_user: "alice".into(),
+            sender_host: "PC".into(),
+            command: 0x20,
+            ..Default::default()
+        };
+        DumpRecord::new(
+            DumpDirection::In,
+            "127.0.0.1:2425".parse().unwrap(),
+            b"1:2:alice:PC:32:hi",
+            Ok(&packet),
+        )
+    }
+
+    #[test]
+    fn test_record_format() {
+        let line = serde_json::to_string(&record(7)).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["direction"], "in");
+        assert_eq!(parsed["peer"], "127.0.0.1:2425");
+        assert_eq!(parsed["len"], 18);
+        assert_eq!(parsed["hex"], hex_dump(b"1:2:alice:PC:32:hi"));
+        assert_eq!(parsed["decoded"], "cmd=0x20 no=7 from=alice@PC");
+        assert!(parsed.get("error").is_none());
+
+        let error = DumpRecord::new(
+            DumpDirection::Out,
+            "127.0.0.1:2425".parse().unwrap(),
+            b"junk",
+            Err(&ProtocolError::TooFewFields { found: 1 }),
+        );
+        let back: DumpRecord = serde_json::from_str(&serde_json::to_string(&error).unwrap()).unwrap();
+        assert_eq!(back, error);
+        assert!(back.error.is_some() && back.decoded.is_none());
+    }
+
+    #[test]
+    fn test_rotation_keeps_n_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.ndjson");
+        let line_len = serde_json::to_string(&record(1)).unwrap().len() as u64 + 1;
+        let mut writer = DumpWriter::open(&path, line_len * 2, 2).unwrap();
+        for no in 0..7 {
+            writer.write(&record(no)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let numbers = |path: &Path| -> Vec<u32> {
+            fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<DumpRecord>(line).unwrap())
+                .map(|record| {
+                    let decoded = record.decoded.unwrap();
+                    let no = decoded.split(' ').find_map(|field| field.strip_prefix("no="));
+                    no.unwrap().parse().unwrap()
+                })
+                .collect()
+        };
+        assert_eq!(numbers(&path), [6]);
+        assert_eq!(numbers(&rotated_path(&path, 1)), [4, 5]);
+        assert_eq!(numbers(&rotated_path(&path, 2)), [2, 3]);
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::protocol::{IpMsgPacket, ProtocolError};
+use serde::Serialize;
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+
+/// 事件通道默认容量（debug.event_channel_capacity）
+///
+/// 事件经 tokio 的 broadcast 通道推送：订阅者落后超过容量时最早的事件被覆盖，
+/// 下一次 recv 返回 Lagged，之后从仍保留的最早事件继续；发送方（监听循环）从不等待订阅者
+pub const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// 接收下一个事件：落后时跳过丢失的事件并输出警告（consumer 用于说明是谁落后），通道关闭时返回 None
+pub async fn recv_lossy(events: &mut broadcast::Receiver<NetEvent>, consumer: &str) -> Option<NetEvent> {
+    loop {
+        match events.recv().await {
+            Ok(event) => return Some(event),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log::warn!("{} fell behind, {} events skipped", consumer, skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// 服务器向订阅者推送的事件
+#[derive(Debug, Clone)]
+pub enum NetEvent {
+    /// 用户加入用户表
+    Joined { username: String, addr: SocketAddr },
+    /// 用户离开用户表（下线或被淘汰）
+    Left { username: String, addr: SocketAddr },
+    /// 收到并解码成功的报文；来源地址与已知用户不符时 suspect 为 true
+    Packet {
+        packet: IpMsgPacket,
+        from: SocketAddr,
+        suspect: bool,
+    },
+    /// 免打扰期间收到的消息，已暂存，恢复在线后以 Packet 事件投递
+    Queued { packet: IpMsgPacket, from: SocketAddr },
+    /// 要求确认的发送有了结果：收到 RECVMSG 时 delivered 为 true，
+    /// 超时（重发时为全部重发之后）或发送失败时为 false
+    SendResult { packet_no: u32, delivered: bool },
+    /// 离开状态下向发送方自动回复了离开信息
+    AutoReplied {
+        username: String,
+        addr: SocketAddr,
+        text: String,
+    },
+    /// 配置文件重新加载：applied 已生效，restart_required 需要重启才能生效
+    ConfigReloaded {
+        applied: Vec<String>,
+        restart_required: Vec<String>,
+    },
+    /// 配置文件重新加载失败，仍使用原配置
+    ConfigReloadFailed { error: String },
+    /// 原始数据报：解码失败时发出；开启 dump_packets 时每个收到的报文都会发出
+    RawDatagram {
+        from: SocketAddr,
+        bytes: Vec<u8>,
+        error: Option<ProtocolError>,
+    },
+}
+
+/// 报文的对外表示，供 watch 等机器可读的输出共用
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PacketView {
+    pub from: SocketAddr,
+    pub packet_no: u32,
+    /// 命令字（低 8 位）
+    pub command: u32,
+    /// 命令选项位（高 24 位）
+    pub options: u32,
+    pub user: String,
+    pub host: String,
+    pub nickname: String,
+    pub group: String,
+    pub text: String,
+}
+
+impl PacketView {
+    pub fn new(packet: &IpMsgPacket, from: SocketAddr) -> Self {
+        Self {
+            from,
+            packet_no: packet.packet_no,
+            command: packet.command & 0xff,
+            options: packet.command & !0xff,
+            user: packet.sender_user.clone(),
+            host: packet.sender_host.clone(),
+            nickname: packet.sender_name.clone(),
+            group: packet.group_name.clone(),
+            text: packet.additional_msg.clone(),
+        }
+    }
+}
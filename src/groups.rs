@@ -0,0 +1,201 @@
+//! 按 IPMsg 分组字段汇总在线用户
+
+use crate::net::OnlineUser;
+use crate::table::{self, TableStyle};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// 分组为空的用户归入的分组的显示名
+pub const NO_GROUP: &str = "(no group)";
+
+/// 一个分组及其成员
+#[derive(Debug, Clone)]
+pub struct Group {
+    /// 分组名原文；为 None 表示分组为空
+    pub name: Option<String>,
+    pub members: Vec<OnlineUser>,
+}
+
+impl Group {
+    /// 显示名
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(NO_GROUP)
+    }
+
+    /// 按名称匹配：逐字比较解码后的原文，不忽略大小写与空白；`(no group)` 匹配分组为空的用户
+    pub fn matches(&self, name: &str) -> bool {
+        match &self.name {
+            Some(group) => group == name,
+            None => name == NO_GROUP,
+        }
+    }
+}
+
+/// 分组匹配的用户：默认逐字匹配（`(no group)` 匹配分组为空的用户），contains 时匹配分组名包含 name 的用户（不区分大小写）
+pub fn members_of(users: &[OnlineUser], name: &str, contains: bool) -> Vec<OnlineUser> {
+    let needle = name.to_lowercase();
+    users
+        .iter()
+        .filter(|user| match (contains, user.group.as_str()) {
+            (true, group) => !group.is_empty() && group.to_lowercase().contains(&needle),
+            (false, "") => name == NO_GROUP,
+            (false, group) => group == name,
+        })
+        .cloned()
+        .collect()
+}
+
+/// 按分组归类（分组名排序，空分组排在最后）
+pub fn group_users(users: &[OnlineUser]) -> Vec<Group> {
+    let mut groups: BTreeMap<(bool, &str), Vec<OnlineUser>> = BTreeMap::new();
+    for user in users {
+        groups
+            .entry((user.group.is_empty(), user.group.as_str()))
+            .or_default()
+            .push(user.clone());
+    }
+    groups
+        .into_iter()
+        .map(|((empty, name), members)| Group {
+            name: (!empty).then(|| name.to_string()),
+            members,
+        })
+        .collect()
+}
+
+/// JSON 输出中的分组摘要
+#[derive(Debug, Serialize)]
+struct GroupSummary<'a> {
+    /// 分组为空时为 null
+    group: Option<&'a str>,
+    members: usize,
+}
+
+/// JSON 输出中的成员
+#[derive(Debug, Serialize)]
+struct MemberView<'a> {
+    nickname: &'a str,
+    user: &'a str,
+    host: &'a str,
+    addr: std::net::SocketAddr,
+    away: bool,
+    note: &'a str,
+}
+
+/// 分组名与成员数
+pub fn render_groups(groups: &[Group], style: TableStyle) -> String {
+    let rows: Vec<Vec<String>> = groups
+        .iter()
+        .map(|group| vec![group.label().to_string(), group.members.len().to_string()])
+        .collect();
+    table::render(&["Group", "Members"], &[12, 7], &rows, style)
+}
+
+/// 分组成员：昵称、用户名@主机名与离开状态
+pub fn render_members(group: &Group, style: TableStyle) -> String {
+    let rows: Vec<Vec<String>> = group
+        .members
+        .iter()
+        .map(|user| {
+            let state = if user.absent { "away" } else { "online" };
+            vec![
+                user.username.clone(),
+                format!("{}@{}", user.login, user.hostname),
+                state.to_string(),
+            ]
+        })
+        .collect();
+    table::render(&["Nickname", "User@Host", "State"], &[12, 16, 6], &rows, style)
+}
+
+pub fn groups_json(groups: &[Group]) -> serde_json::Result<String> {
+    let summaries: Vec<GroupSummary> = groups
+        .iter()
+        .map(|group| GroupSummary {
+            group: group.name.as_deref(),
+            members: group.members.len(),
+        })
+        .collect();
+    serde_json::to_string_pretty(&summaries)
+}
+
+pub fn members_json(group: &Group) -> serde_json::Result<String> {
+    let members: Vec<MemberView> = group
+        .members
+        .iter()
+        .map(|user| MemberView {
+            nickname: &user.username,
+            user: &user.login,
+            host: &user.hostname,
+            addr: user.addr,
+            away: user.absent,
+            note: &user.note,
+        })
+        .collect();
+    serde_json::to_string_pretty(&members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, group: &str, absent: bool) -> OnlineUser {
+        OnlineUser {
+            username: name.into(),
+            hostname: "PC".into(),
+            addr: "192.168.1.5:2425".parse().unwrap(),
+            via_port: 2425,
+            confirmed: true,
+            note: String::new(),
+            login: name.into(),
+            group: group.into(),
+            absent,
+            absence: None,
+            last_seen: std::time::SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn test_group_by_exact_name() {
+        let users = [
+            user("wei", "研发部", false),
+            user("li", "", false),
+            user("zhang", "研发部", true),
+            user("amy", "Dev", false),
+            user("bob", "dev", false),
+        ];
+        let groups = group_users(&users);
+        let labels: Vec<&str> = groups.iter().map(Group::label).collect();
+        assert_eq!(labels, ["Dev", "dev", "研发部", NO_GROUP]);
+
+        let rd = groups.iter().find(|group| group.matches("研发部")).unwrap();
+        assert_eq!(rd.members.len(), 2);
+        assert!(groups.iter().all(|group| !group.matches("研发")));
+        assert_eq!(groups.iter().filter(|group| group.matches("dev")).count(), 1);
+        assert_eq!(groups.last().unwrap().members[0].username, "li");
+        assert!(groups.last().unwrap().matches(NO_GROUP));
+
+        let json: serde_json::Value = serde_json::from_str(&groups_json(&groups).unwrap()).unwrap();
+        assert_eq!(json[2]["group"], "研发部");
+        assert_eq!(json[3]["group"], serde_json::Value::Null);
+        let members: serde_json::Value = serde_json::from_str(&members_json(rd).unwrap()).unwrap();
+        assert_eq!(members[1]["nickname"], "zhang");
+        assert_eq!(members[1]["away"], true);
+    }
+
+    #[test]
+    fn test_members_of_exact_or_contains() {
+        let users = [
+            user("wei", "backend", false),
+            user("amy", "Backend-Ops", false),
+            user("li", "", false),
+            user("zhang", "研发部后端", false),
+        ];
+        let names = |members: Vec<OnlineUser>| members.into_iter().map(|user| user.username).collect::<Vec<_>>();
+        assert_eq!(names(members_of(&users, "backend", false)), ["wei"]);
+        assert_eq!(names(members_of(&users, "backend", true)), ["wei", "amy"]);
+        assert_eq!(names(members_of(&users, "后端", true)), ["zhang"]);
+        assert!(members_of(&users, "后端", false).is_empty());
+        assert_eq!(names(members_of(&users, NO_GROUP, false)), ["li"]);
+    }
+}
@@ -0,0 +1,512 @@
+use crate::cache::unix_now;
+use crate::config::{HistoryConfig, write_atomic};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// history.backend 可用的取值
+pub const HISTORY_BACKENDS: &[&str] = &["jsonl", "sqlite"];
+
+/// 消息历史的存储后端
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryBackend {
+    /// 每条消息一行 JSON
+    Jsonl,
+    /// SQLite 数据库
+    Sqlite,
+}
+
+impl HistoryBackend {
+    /// 解析配置中的后端名称，不认识时返回 None
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "jsonl" => Some(Self::Jsonl),
+            "sqlite" => Some(Self::Sqlite),
+            _ => None,
+        }
+    }
+
+    /// 未配置 history.path 时使用的文件名
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Self::Jsonl => "history.jsonl",
+            Self::Sqlite => "history.sqlite3",
+        }
+    }
+}
+
+/// 消息方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Incoming => "incoming",
+            Self::Outgoing => "outgoing",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "incoming" => Some(Self::Incoming),
+            "outgoing" => Some(Self::Outgoing),
+            _ => None,
+        }
+    }
+}
+
+/// 一条历史记录
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// 记录时间（Unix 秒）
+    pub time: u64,
+    pub direction: Direction,
+    /// 对端（昵称@主机名，广播时为 *）
+    pub peer: String,
+    pub addr: Option<SocketAddr>,
+    /// 消息正文；store_bodies = false 时不保存
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+}
+
+/// 消息历史
+///
+/// 只能通过 open 创建，history.enabled = false 时不会创建，也就不会写任何文件
+#[derive(Debug)]
+pub struct MessageHistory {
+    path: PathBuf,
+    store_bodies: bool,
+    max_messages: usize,
+    max_age_days: u64,
+    store: Mutex<HistoryStore>,
+}
+
+/// 打开的存储及其中的记录概况，用于判断追加后是否需要清理
+#[derive(Debug)]
+struct HistoryStore {
+    sink: Sink,
+    count: usize,
+    // 最早一条记录的时间
+    oldest: Option<u64>,
+}
+
+#[derive(Debug)]
+enum Sink {
+    Jsonl(fs::File),
+    Sqlite(rusqlite::Connection),
+}
+
+impl HistoryStore {
+    fn open(backend: HistoryBackend, path: &Path) -> Result<Self> {
+        match backend {
+            HistoryBackend::Jsonl => {
+                let entries = load_entries(path);
+                let oldest = entries.iter().map(|entry| entry.time).min();
+                Ok(Self {
+                    sink: Sink::Jsonl(open_jsonl(path)?),
+                    count: entries.len(),
+                    oldest,
+                })
+            }
+            HistoryBackend::Sqlite => {
+                let conn = rusqlite::Connection::open(path)
+                    .with_context(|| format!("Failed to open history {}", path.display()))?;
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS messages (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        time INTEGER NOT NULL,
+                        direction TEXT NOT NULL,
+                        peer TEXT NOT NULL,
+                        addr TEXT,
+                        body TEXT
+                    )",
+                )?;
+                let (count, oldest) = conn.query_row("SELECT COUNT(*), MIN(time) FROM messages", [], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?;
+                Ok(Self {
+                    sink: Sink::Sqlite(conn),
+                    count,
+                    oldest,
+                })
+            }
+        }
+    }
+
+    fn append(&mut self, entry: &HistoryEntry) -> Result<()> {
+        match &mut self.sink {
+            Sink::Jsonl(file) => {
+                let mut line = serde_json::to_string(entry)?;
+                line.push('\n');
+                file.write_all(line.as_bytes())?;
+            }
+            Sink::Sqlite(conn) => {
+                conn.execute(
+                    "INSERT INTO messages (time, direction, peer, addr, body) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    rusqlite::params![
+                        entry.time,
+                        entry.direction.as_str(),
+                        entry.peer,
+                        entry.addr.map(|addr| addr.to_string()),
+                        entry.body,
+                    ],
+                )?;
+            }
+        }
+        self.count += 1;
+        self.oldest.get_or_insert(entry.time);
+        Ok(())
+    }
+
+    /// 超出上限一成以上时才清理：条数与保留期限都放宽十分之一，避免到达上限后每次追加都整体重写
+    fn over_limit(&self, now: u64, max_messages: usize, max_age_days: u64) -> bool {
+        let too_many = max_messages > 0 && self.count > max_messages + max_messages / 10;
+        let max_age = max_age_days * 86400;
+        let too_old = max_age_days > 0
+            && self.oldest.is_some_and(|oldest| now.saturating_sub(oldest) > max_age + max_age / 10);
+        too_many || too_old
+    }
+
+    /// 删除超过保留期限或超出条数上限的旧记录（0 表示不限）
+    fn prune(&mut self, path: &Path, max_messages: usize, max_age_days: u64) -> Result<()> {
+        match &mut self.sink {
+            Sink::Jsonl(file) => {
+                let (count, oldest) = prune_jsonl(path, max_messages, max_age_days)?;
+                // 清理会替换文件，之后重新打开才能继续追加
+                *file = open_jsonl(path)?;
+                (self.count, self.oldest) = (count, oldest);
+            }
+            Sink::Sqlite(conn) => {
+                if max_age_days > 0 {
+                    let cutoff = unix_now().saturating_sub(max_age_days * 86400);
+                    conn.execute("DELETE FROM messages WHERE time < ?1", [cutoff])?;
+                }
+                if max_messages > 0 {
+                    conn.execute(
+                        "DELETE FROM messages WHERE id NOT IN (SELECT id FROM messages ORDER BY id DESC LIMIT ?1)",
+                        [max_messages],
+                    )?;
+                }
+                (self.count, self.oldest) = conn.query_row("SELECT COUNT(*), MIN(time) FROM messages", [], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&self, path: &Path) -> Result<Vec<HistoryEntry>> {
+        let Sink::Sqlite(conn) = &self.sink else {
+            return Ok(load_entries(path));
+        };
+        let mut statement = conn.prepare("SELECT time, direction, peer, addr, body FROM messages ORDER BY id")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+        let mut entries = Vec::new();
+        // 与 jsonl 一样跳过无法识别的记录
+        for (time, direction, peer, addr, body) in rows.flatten() {
+            let Some(direction) = Direction::parse(&direction) else {
+                continue;
+            };
+            entries.push(HistoryEntry {
+                time,
+                direction,
+                peer,
+                addr: addr.and_then(|addr| addr.parse().ok()),
+                body,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+fn open_jsonl(path: &Path) -> Result<fs::File> {
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open history {}", path.display()))
+}
+
+impl MessageHistory {
+    /// 按配置打开历史文件，未启用时返回 None
+    ///
+    /// 打开时与每次追加后，记录超出 max_messages 或 max_age_days 一成以上时清理旧记录
+    pub fn open(config: &HistoryConfig) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let backend = config.backend().ok_or_else(|| {
+            anyhow::anyhow!("Unknown history backend '{}'", config.backend)
+        })?;
+
+        let path = config.path();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let mut store = HistoryStore::open(backend, &path)?;
+        if store.over_limit(unix_now(), config.max_messages, config.max_age_days) {
+            store.prune(&path, config.max_messages, config.max_age_days)?;
+        }
+        Ok(Some(Self {
+            path,
+            store_bodies: config.store_bodies,
+            max_messages: config.max_messages,
+            max_age_days: config.max_age_days,
+            store: Mutex::new(store),
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 追加一条记录
+    pub fn record(&self, direction: Direction, peer: &str, addr: Option<SocketAddr>, body: &str) -> Result<()> {
+        let entry = HistoryEntry {
+            time: unix_now(),
+            direction,
+            peer: peer.to_string(),
+            addr,
+            body: self.store_bodies.then(|| body.to_string()),
+        };
+        let mut store = self.store.lock().unwrap();
+        store.append(&entry)?;
+        if store.over_limit(entry.time, self.max_messages, self.max_age_days) {
+            store.prune(&self.path, self.max_messages, self.max_age_days)?;
+        }
+        Ok(())
+    }
+
+    /// 全部记录，按记录顺序排列
+    pub fn entries(&self) -> Result<Vec<HistoryEntry>> {
+        self.store.lock().unwrap().entries(&self.path)
+    }
+}
+
+/// 把 Unix 秒格式化为 `YYYY-MM-DD HH:MM:SS`（UTC），用于输出与日志
+pub fn format_time(secs: u64) -> String {
+    let (days, rest) = (secs / 86400, secs % 86400);
+    // 由 1970-01-01 起的天数换算公历日期（Howard Hinnant 的 civil_from_days）
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+/// 当前时间的 format_time 形式
+pub fn now_string() -> String {
+    format_time(unix_now())
+}
+
+/// 读取历史文件中的全部记录，损坏的行被跳过
+pub fn load_entries(path: impl AsRef<Path>) -> Vec<HistoryEntry> {
+    fs::read_to_string(path)
+        .map(|content| {
+            content
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// 删除 jsonl 文件中超过保留期限或超出条数上限的旧记录（0 表示不限），返回剩下的条数与最早的时间
+fn prune_jsonl(path: &Path, max_messages: usize, max_age_days: u64) -> Result<(usize, Option<u64>)> {
+    if !path.exists() {
+        return Ok((0, None));
+    }
+    let entries = load_entries(path);
+    let total = entries.len();
+    let now = unix_now();
+    let mut kept: Vec<HistoryEntry> = entries
+        .into_iter()
+        .filter(|entry| max_age_days == 0 || now.saturating_sub(entry.time) <= max_age_days * 86400)
+        .collect();
+    if max_messages > 0 && kept.len() > max_messages {
+        kept.drain(..kept.len() - max_messages);
+    }
+    let summary = (kept.len(), kept.iter().map(|entry| entry.time).min());
+    if kept.len() == total {
+        return Ok(summary);
+    }
+    let mut content = String::new();
+    for entry in &kept {
+        content.push_str(&serde_json::to_string(entry)?);
+        content.push('\n');
+    }
+    write_atomic(path, content.as_bytes())?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_in(dir: &Path) -> HistoryConfig {
+        HistoryConfig {
+            enabled: true,
+            path: dir.join("history.jsonl").display().to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn sqlite_in(dir: &Path) -> HistoryConfig {
+        HistoryConfig {
+            backend: "sqlite".to_string(),
+            path: dir.join("history.sqlite3").display().to_string(),
+            ..config_in(dir)
+        }
+    }
+
+    #[test]
+    fn test_disabled_history_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = HistoryConfig {
+            enabled: false,
+            ..config_in(&dir.path().join("nested"))
+        };
+        assert!(MessageHistory::open(&config).unwrap().is_none());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_time(1_791_979_199), "2026-10-14 11:59:59");
+    }
+
+    #[test]
+    fn test_backend_selection() {
+        assert_eq!(HistoryBackend::parse("JSONL"), Some(HistoryBackend::Jsonl));
+        assert_eq!(HistoryBackend::parse("sqlite"), Some(HistoryBackend::Sqlite));
+        assert_eq!(HistoryBackend::parse("csv"), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let addr: SocketAddr = "192.168.1.5:2425".parse().unwrap();
+        let history = MessageHistory::open(&config_in(dir.path())).unwrap().unwrap();
+        history.record(Direction::Incoming, "bob@PC", Some(addr), "hi").unwrap();
+        assert_eq!(load_entries(history.path())[0].body.as_deref(), Some("hi"));
+
+        // 两个后端读回的记录相同，重新打开后仍在
+        let sqlite = MessageHistory::open(&sqlite_in(dir.path())).unwrap().unwrap();
+        sqlite.record(Direction::Incoming, "bob@PC", Some(addr), "hi").unwrap();
+        let mut expected = history.entries().unwrap();
+        expected[0].time = sqlite.entries().unwrap()[0].time;
+        assert_eq!(sqlite.entries().unwrap(), expected);
+        drop(sqlite);
+        let sqlite = MessageHistory::open(&sqlite_in(dir.path())).unwrap().unwrap();
+        assert_eq!(sqlite.entries().unwrap(), expected);
+        // 数据库不是 jsonl 文件
+        assert!(load_entries(sqlite.path()).is_empty());
+
+        let config = HistoryConfig {
+            backend: "csv".to_string(),
+            ..config_in(dir.path())
+        };
+        let error = MessageHistory::open(&config).unwrap_err().to_string();
+        assert!(error.contains("Unknown history backend 'csv'"), "{}", error);
+    }
+
+    #[test]
+    fn test_metadata_only_and_pruning() {
+        let dir = tempfile::tempdir().unwrap();
+        for base in [config_in(dir.path()), sqlite_in(dir.path())] {
+            let config = HistoryConfig {
+                store_bodies: false,
+                max_messages: 2,
+                ..base
+            };
+            let history = MessageHistory::open(&config).unwrap().unwrap();
+            for body in ["one", "two", "three", "four"] {
+                history.record(Direction::Outgoing, "bob@PC", None, body).unwrap();
+            }
+            // 追加时就清理，之后的记录仍写入清理后的存储
+            let entries = history.entries().unwrap();
+            assert_eq!(entries.len(), 2, "{}", config.backend);
+            assert!(entries.iter().all(|entry| entry.body.is_none()));
+            drop(history);
+
+            let history = MessageHistory::open(&config).unwrap().unwrap();
+            assert_eq!(history.entries().unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_pruning_waits_for_slack() {
+        let dir = tempfile::tempdir().unwrap();
+        for base in [config_in(dir.path()), sqlite_in(dir.path())] {
+            let config = HistoryConfig {
+                max_messages: 10,
+                ..base
+            };
+            let history = MessageHistory::open(&config).unwrap().unwrap();
+            // 超出一成以内不清理，超出后一次删回上限
+            for n in 0..11 {
+                history.record(Direction::Outgoing, "bob@PC", None, &n.to_string()).unwrap();
+            }
+            assert_eq!(history.entries().unwrap().len(), 11, "{}", config.backend);
+            history.record(Direction::Outgoing, "bob@PC", None, "11").unwrap();
+            let entries = history.entries().unwrap();
+            assert_eq!(entries.len(), 10);
+            assert_eq!(entries[0].body.as_deref(), Some("2"));
+        }
+    }
+
+    #[test]
+    fn test_expired_entries_pruned_on_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = HistoryConfig {
+            max_age_days: 1,
+            ..config_in(dir.path())
+        };
+        // 过期不到一成的记录打开时还留着，超出之后的下一次追加把它删掉
+        let old = HistoryEntry {
+            time: unix_now() - 86400 - 8640,
+            direction: Direction::Incoming,
+            peer: "bob@PC".into(),
+            addr: None,
+            body: Some("old".into()),
+        };
+        fs::write(config.path(), format!("{}\n", serde_json::to_string(&old).unwrap())).unwrap();
+        let history = MessageHistory::open(&config).unwrap().unwrap();
+        assert_eq!(load_entries(history.path()).len(), 1);
+
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        history.record(Direction::Outgoing, "bob@PC", None, "new").unwrap();
+        history.record(Direction::Outgoing, "bob@PC", None, "newer").unwrap();
+        let bodies: Vec<_> = load_entries(history.path()).into_iter().filter_map(|entry| entry.body).collect();
+        assert_eq!(bodies, ["new", "newer"]);
+    }
+}
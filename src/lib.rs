@@ -0,0 +1,66 @@
+//! lanMsg：兼容飞鸽传书（IPMsg）协议的局域网消息库
+//!
+//! 命令行程序只是这个库的一层薄封装，其他程序也可以直接使用：
+//!
+//! ```
+//! use lanmsg::{IpMsgPacket, IpMsgServer, commands};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await?;
+//! server.set_identity("alice", "PC-A");
+//!
+//! let packet = IpMsgPacket {
+//!     packet_no: 1,
+//!     sender_user: "alice".into(),
+//!     sender_host: "PC-A".into(),
+//!     command: commands::MSG,
+//!     additional_msg: "hello".into(),
+//!     ..Default::default()
+//! };
+//! assert!(packet.encode().ends_with(":1:alice:PC-A:32:hello"));
+//! server.send_to(&packet, &server.local_addr()?).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod body;
+pub mod cache;
+pub mod chat;
+pub mod config;
+pub mod console;
+pub mod control;
+pub mod dedup;
+pub mod delivery;
+pub mod doctor;
+pub mod dump;
+pub mod event;
+pub mod groups;
+pub mod history;
+pub mod logging;
+pub mod migrate;
+pub mod net;
+pub mod peer_encoding;
+pub mod peer_match;
+pub mod pipe;
+pub mod presence;
+pub mod protocol;
+pub mod relay;
+pub mod reload;
+pub mod reorder;
+pub mod roster;
+pub mod security;
+pub mod stats;
+pub mod table;
+pub mod throttle;
+pub mod transfer;
+pub mod watch;
+
+/// 配置
+pub use config::AppConfig;
+/// 服务器事件
+pub use event::NetEvent;
+/// 收发报文的服务器
+pub use net::IpMsgServer;
+/// 报文编解码
+pub use protocol::{IpMsgPacket, PROTOCOL_VERSION, ProtocolError, commands};
@@ -0,0 +1,73 @@
+use crate::console;
+use log::LevelFilter;
+
+/// 优先于 debug.log_level 的环境变量
+pub const LOG_ENV: &str = "RUST_LOG";
+
+/// 初始化日志，输出到 stderr，不干扰 stdout 上的消息与机器可读输出
+///
+/// 设置了 RUST_LOG 时按它过滤，否则先按 info 输出，加载配置后由 set_level 调整
+pub fn init() {
+    let mut builder = pretty_env_logger::formatted_builder();
+    let from_env = rust_log_set();
+    if from_env {
+        builder.parse_filters(&std::env::var(LOG_ENV).unwrap_or_default());
+    } else {
+        // 实际级别由全局上限控制，便于加载配置或热加载时调整
+        builder.filter_level(LevelFilter::Trace);
+    }
+    let logger = builder.build();
+    let filter = logger.filter();
+    // 重复初始化（例如测试中）时保留已有的日志器
+    if log::set_boxed_logger(Box::new(AboveInput(logger))).is_ok() {
+        log::set_max_level(if from_env { filter } else { LevelFilter::Info });
+    }
+}
+
+/// 日志经由 console 输出，聊天时不打乱正在输入的行
+struct AboveInput<L>(L);
+
+impl<L: log::Log> log::Log for AboveInput<L> {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.0.enabled(record.metadata()) {
+            console::print_above(|| self.0.log(record));
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// 按 debug.log_level 设置日志级别；设置了 RUST_LOG 时不生效
+pub fn set_level(level: &str) {
+    if !rust_log_set() {
+        log::set_max_level(level_filter(level));
+    }
+}
+
+/// 解析 debug.log_level，无法识别时为 info
+pub fn level_filter(level: &str) -> LevelFilter {
+    level.parse().unwrap_or(LevelFilter::Info)
+}
+
+fn rust_log_set() -> bool {
+    std::env::var(LOG_ENV).is_ok_and(|filters| !filters.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_filter_parsing() {
+        assert_eq!(level_filter("debug"), LevelFilter::Debug);
+        assert_eq!(level_filter("TRACE"), LevelFilter::Trace);
+        assert_eq!(level_filter("warn"), LevelFilter::Warn);
+        assert_eq!(level_filter("verbose"), LevelFilter::Info);
+    }
+}
@@ -1,190 +1,1211 @@
 mod cli;
-mod config;
-mod net;
-mod protocol;
 
 use anyhow::Result;
 use clap::Parser;
 use cli::Cli;
-use net::IpMsgServer;
-use protocol::{IpMsgPacket, commands};
+use lanmsg::body::BodySource;
+use lanmsg::event::{NetEvent, recv_lossy};
+use lanmsg::protocol::commands;
+use lanmsg::history::{self, Direction, MessageHistory};
+use lanmsg::{cache, chat, config, console, control, delivery, doctor, groups, net, pipe, presence, protocol, relay, reload, roster, table, transfer, watch};
+use cli::{WithExitCode, exit_code};
+use std::process::ExitCode;
 use std::sync::Arc;
-use tokio::io::{self, AsyncBufReadExt};
-use tokio::sync::mpsc;
+use tokio::io;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
-    // let server = IpMsgServer::new().await?;
-    // 1. 加载配置（带回退逻辑）
-    let config = match config::AppConfig::load("config.toml") {
-        Ok(cfg) if cfg.network.is_valid() => cfg,
-        _ => {
-            println!("Using default configuration");
-            config::AppConfig::default()
+async fn main() -> ExitCode {
+    let cli = Cli::try_parse().unwrap_or_else(|e| cli::usage_error(e));
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            let code = cli::error_code(&e);
+            match e.downcast::<cli::Failure>() {
+                Ok(failure) => eprintln!("Error: {:?}", failure.error),
+                Err(e) => eprintln!("Error: {:?}", e),
+            }
+            ExitCode::from(code)
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let send = cli.send_parts();
+    lanmsg::logging::init();
+    if cli.quiet {
+        lanmsg::logging::set_level("warn");
+    }
+
+    let config_location = config::ConfigLocation::resolve(cli.config.as_deref());
+    match &cli.command {
+        cli::Commands::Config { action } if !matches!(action, cli::ConfigAction::Show) => {
+            return run_config_command(action, &config_location);
         }
+        cli::Commands::Block { peer } => return run_block_command(peer, true, &config_location),
+        cli::Commands::Unblock { peer } => return run_block_command(peer, false, &config_location),
+        _ => {}
+    }
+    // 1. 加载配置，合并环境变量与命令行覆盖值，之后只使用合并后的配置
+    log::info!(
+        "Using config {} (from {})",
+        config_location.path.display(),
+        config_location.source
+    );
+    let env_overrides = config::ConfigOverrides::from_env()?;
+    let config = match load_config(&config_location, &env_overrides, &cli) {
+        Ok(config) => config,
+        Err(e) if cli.ignore_config_errors => {
+            log::warn!("{:#}", e);
+            // 放弃的只是配置文件，环境变量与命令行指定的值仍然生效
+            log::warn!("Using default configuration with environment and command-line overrides");
+            config::AppConfig::default().merged(&env_overrides, &cli.overrides())
+        }
+        Err(e) => return Err(e),
     };
+    if !cli.quiet {
+        lanmsg::logging::set_level(&config.debug.log_level);
+    }
+    let profile = config::ConfigOverrides::select_profile(&env_overrides, &cli.overrides())
+        .map(str::to_string);
+    if let Some(profile) = &profile {
+        log::info!("Using profile {}", profile);
+    }
 
+    if let cli::Commands::Config { .. } = &cli.command {
+        print_effective_config(&config, profile.as_deref())?;
+        return Ok(());
+    }
     let config_clone = Arc::new(config.clone());
 
+    // 消息正文可能来自标准输入、文件或模板，在上线之前读取
+    let send = match send {
+        Some((recipients, body)) => Some((recipients, read_body(body, &config)?)),
+        None => None,
+    };
+    let broadcast_body = match &cli.command {
+        cli::Commands::Broadcast {
+            message,
+            message_file,
+            template,
+            ..
+        } => {
+            let body = match (message_file, template) {
+                (Some(path), _) => BodySource::File(path.clone()),
+                (None, Some(name)) => BodySource::Template(name.clone()),
+                (None, None) => BodySource::from_arg(message.clone().unwrap_or_default()),
+            };
+            Some(read_body(body, &config)?)
+        }
+        _ => None,
+    };
+
+    // 有常驻实例时由它设置状态；没有时查询直接返回，设置只广播一次
+    let control_file = config_location.path.with_file_name(control::CONTROL_FILE);
+    let transient_status = if let cli::Commands::Status { action } = &cli.command {
+        let request = cli::status_request(action.as_ref());
+        if let Some(status) = control::request(&control_file, &request).await? {
+            println!("{}", status);
+            return Ok(());
+        }
+        if request == control::ControlRequest::Status {
+            println!("offline (no running lanMsg instance)");
+            return Ok(());
+        }
+        log::warn!("No running lanMsg instance: announcing the state once, it will not be maintained");
+        true
+    } else {
+        false
+    };
+
+    // 中继模式：只转发报文，不广播自身上线
+    if let cli::Commands::Relay {
+        segments,
+        filter,
+        learn_users,
+    } = &cli.command
+    {
+        let segments = segments
+            .iter()
+            .map(|spec| relay::RelaySegment::parse(spec, config.network.port))
+            .collect::<Result<Vec<_>>>()?;
+        let mut relay = relay::Relay::bind(&segments, *filter, config_clone).await?;
+        if *learn_users {
+            // 用户表只用来记录经中继上下线的用户：绑定临时端口，被动模式下不发出任何报文
+            let mut roster_config = config.clone();
+            roster_config.network.port = 0;
+            roster_config.network.ports.clear();
+            roster_config.debug.passive = true;
+            let roster = net::IpMsgServer::from_config(&roster_config).await.exit_code(exit_code::NETWORK)?;
+            let mut events = roster.subscribe();
+            tokio::spawn(async move {
+                while let Some(event) = recv_lossy(&mut events, "Relay roster").await {
+                    match event {
+                        NetEvent::Joined { username, addr } => log::info!("{} ({}) joined via relay", username, addr),
+                        NetEvent::Left { username, addr } => log::info!("{} ({}) left via relay", username, addr),
+                        _ => {}
+                    }
+                }
+            });
+            relay = relay.learn_users(roster);
+        }
+        for addr in relay.local_addrs() {
+            log::info!("Relay segment {}", addr);
+        }
+        return relay.run().await;
+    }
+
     // 2. 初始化服务器（自动处理空地址）
-    let server = net::IpMsgServer::new(Some(config.bind_addr())).await?;
-    println!("Bound to {}", server.bound_addr());
+    let server = net::IpMsgServer::from_config(&config).await.exit_code(exit_code::NETWORK)?;
+    log::info!("Bound to {}", server.bound_addr());
+    server.set_user(&config.user);
+    // 节点 ID 让对端在本机改名或换地址后仍能认出本机
+    match cache::load_or_create_node_id(config_location.path.with_file_name(cache::NODE_ID_FILE)) {
+        Ok(node_id) => server.set_node_id(&node_id),
+        Err(e) => log::warn!("Failed to load node id: {:#}", e),
+    }
+
+    // 常驻的模式提供控制接口，供 status 子命令设置状态
+    let _control = match cli.command {
+        cli::Commands::Daemon | cli::Commands::Chat { .. } | cli::Commands::Watch { .. } => {
+            match control::ControlServer::start(control_file.clone(), server.clone()).await {
+                Ok(control) => Some(control),
+                Err(e) => {
+                    log::warn!("Control interface unavailable: {:#}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    // 恢复上次退出时的用户表
+    let user_cache = config_location.path.with_file_name(cache::USER_CACHE_FILE);
+    if config.network.cache_users {
+        let max_age = std::time::Duration::from_secs(config.network.user_cache_max_age_secs);
+        let restored = server.load_user_cache(&user_cache, max_age).await;
+        if restored > 0 {
+            log::info!("Restored {} cached users", restored);
+        }
+    }
+
+    // 配置文件变化时自动重新加载
+    reload::ConfigWatcher::new(
+        config_location.path.clone(),
+        config.clone(),
+        env_overrides,
+        cli.overrides(),
+    )
+    .spawn(server.clone(), reload::DEFAULT_RELOAD_INTERVAL);
+
+    // 事件输出：配置重新加载、自动回复与解码失败的报文（转储写入文件，见 debug.dump_path）
+    let mut raw_events = server.subscribe();
+    tokio::spawn(async move {
+        while let Some(event) = recv_lossy(&mut raw_events, "Event log").await {
+            match event {
+                NetEvent::ConfigReloaded {
+                    applied,
+                    restart_required,
+                } => {
+                    if !applied.is_empty() {
+                        log::info!("Config reloaded: {}", applied.join(", "));
+                    }
+                    if !restart_required.is_empty() {
+                        log::warn!(
+                            "Restart required for: {}",
+                            restart_required.join(", ")
+                        );
+                    }
+                }
+                NetEvent::AutoReplied { username, addr, .. } => {
+                    log::info!("Auto-replied to {} ({})", username, addr);
+                }
+                NetEvent::ConfigReloadFailed { error } => {
+                    log::warn!("Config reload failed, keeping previous config: {}", error);
+                }
+                NetEvent::RawDatagram {
+                    from,
+                    bytes,
+                    error: Some(e),
+                } => log::debug!(
+                    "Undecodable datagram from {}: {}\nRaw({} bytes): {}",
+                    from,
+                    e,
+                    bytes.len(),
+                    protocol::hex_dump(&bytes)
+                ),
+                _ => {}
+            }
+        }
+    });
+
+    // 消息历史：未启用时不打开也不写入任何文件
+    let history = Arc::new(MessageHistory::open(&config.history)?);
+    let record = {
+        let history = history.clone();
+        move |direction, peer: &str, addr, body: &str| {
+            if let Some(history) = history.as_ref()
+                && let Err(e) = history.record(direction, peer, addr, body)
+            {
+                log::warn!("Failed to write message history: {}", e);
+            }
+        }
+    };
 
     let server_clone = server.clone();
+    let record_incoming = record.clone();
+    // 收到的文件请求，在聊天中用 /accept 接收
+    let offers = Arc::new(transfer::PendingOffers::with_capacity(config_clone.transfer.max_pending_offers));
+    let (offer_server, offer_config, offer_table) = (server.clone(), config_clone.clone(), offers.clone());
+    // 最近一次发来消息的用户，聊天中用 /r 回复
+    let last_sender = chat::LastSender::default();
+    let record_sender = last_sender.clone();
+    // 常驻模式下只输出带时间的消息行，便于 journald 收集
+    let daemon = matches!(cli.command, cli::Commands::Daemon);
+    // watch 模式下 stdout 只输出 NDJSON；在开始接收之前订阅，之后的事件都不会错过
+    let watching = matches!(cli.command, cli::Commands::Watch { .. });
+    let mut watch_events = watching.then(|| server.subscribe());
+    // 聊天模式下收到的消息从事件流输出，同样在开始接收之前订阅
+    let chatting = matches!(cli.command, cli::Commands::Chat { .. });
+    let chat_events = chatting.then(|| server.subscribe());
     // 消息接收线程
     tokio::spawn(async move {
         let _ = server_clone
             .listen(
-                |packet, _| {
-                    println!("\n[{}] {}", packet.sender_name, packet.additional_msg);
+                move |packet, from| {
+                    if let Some(offer) = transfer::FileOffer::from_packet(&packet, from)
+                        && !watching
+                    {
+                        handle_file_offer(&offer_server, &offer_config, &offer_table, offer);
+                    }
+                    let message = packet.command & 0xff == commands::MSG;
+                    if !daemon && !watching && !chatting {
+                        if message {
+                            println!("\n{}", chat::incoming_line(&packet));
+                        } else {
+                            println!("\n[{}] {}", packet.sender_name, packet.additional_msg);
+                        }
+                    } else if daemon && message {
+                        println!(
+                            "{} [{}@{}] {} {}",
+                            history::now_string(),
+                            packet.sender_name,
+                            packet.sender_host,
+                            chat::delivery_tag(&packet),
+                            packet.additional_msg
+                        );
+                    }
+                    if message {
+                        let peer = format!("{}@{}", packet.sender_name, packet.sender_host);
+                        record_sender.record(&peer, from);
+                        record_incoming(Direction::Incoming, &peer, Some(from), &packet.additional_msg);
+                    }
                 },
                 config_clone.clone(),
             )
             .await;
     });
 
+    // list --cached 只读取本地用户表，不上线也不下线
+    let cached_list = matches!(cli.command, cli::Commands::List { cached: true, .. });
+    // list、groups 由 refresh_users 自行广播上线通知并等待应答
+    let refreshing = matches!(
+        cli.command,
+        cli::Commands::List { cached: false, .. } | cli::Commands::Groups { .. }
+    );
+
     // 广播上线通知
-    let entry_packet = IpMsgPacket {
-        version: "lanMsg 0.1".to_string(),
-        packet_no: rand::random(),
-        sender_name: cli.name.clone(),
-        sender_host: cli.host.clone(),
-        command: commands::BR_ENTRY,
-        additional_msg: "".to_string(),
-        group_name: "".to_string(),
-        ..Default::default()
-    };
-    server.broadcast(&entry_packet).await?;
+    let entry_packet = server.presence_packet(commands::BR_ENTRY);
+    if transient_status || cached_list {
+        // 只广播状态变更，不上线也不下线
+    } else if config.debug.passive {
+        log::info!("Passive mode: not announcing presence");
+    } else if config.presence.stealth {
+        log::info!("Stealth mode: not announcing presence");
+    } else {
+        // 大量节点同时启动时错开上线广播
+        let jitter = config.network.entry_jitter();
+        if !jitter.is_zero() {
+            log::debug!("Delaying entry broadcast by {} ms", jitter.as_millis());
+            tokio::time::sleep(jitter).await;
+        }
+        if !refreshing {
+            server.broadcast(&entry_packet).await.exit_code(exit_code::NETWORK)?;
+        }
+    }
 
-    // println!("Fetching online users...");
-    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    // 等待对方应答上线通知，要找的收件人出现时提前开始
+    if !watching && !transient_status && !cached_list && !refreshing {
+        let recipients = match (&cli.command, &send) {
+            (_, Some((recipients, _))) => recipients.clone(),
+            (cli::Commands::SendFile { recipient, ip, .. }, _) => vec![cli::recipient(recipient.as_deref(), *ip)],
+            (cli::Commands::Query { user, .. }, _) => vec![user.clone()],
+            (cli::Commands::Pipe { to: Some(to), .. }, _) => vec![to.clone()],
+            (cli::Commands::Chat { with: Some(with), .. }, _) => match chat::ChatTarget::parse(with) {
+                chat::ChatTarget::User(user) => vec![user],
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        let recipients: Vec<&str> = recipients.iter().map(String::as_str).collect();
+        server.settle_after_entry(config.network.entry_settle(), &recipients).await;
+    }
 
+    // send 未全部成功（或 broadcast --require-all 有未确认的用户）时先照常下线，再带退出码返回
+    let mut send_error = None;
     match cli.command {
-        cli::Commands::Send { recipient, message } => {
-            // 检查 recipient 是否是有效的 IP 地址
-            let addr = if let Ok(ip_addr) = recipient.parse::<std::net::IpAddr>() {
-                // 如果是 IP 地址，直接使用
-                Some(std::net::SocketAddr::new(ip_addr, net::IPMSG_PORT))
+        cli::Commands::Send {
+            group: Some(group),
+            contains,
+            ..
+        } => {
+            let (_, message) = send.unwrap_or_default();
+            let members = groups::members_of(&server.get_online_users().await, &group, contains);
+            let outcomes: Vec<net::SendOutcome> = server
+                .multicast_users(&members, &message, None)
+                .await
+                .into_iter()
+                .map(|(user, delivery)| net::SendOutcome {
+                    recipient: format!("{}@{}", user.username, user.hostname),
+                    addr: Some(user.addr),
+                    delivery,
+                })
+                .collect();
+            if outcomes.is_empty() {
+                let error = anyhow::anyhow!("No online users in group {}", group);
+                send_error = Some(cli::Failure { code: exit_code::NOT_FOUND, error }.into());
             } else {
-                // 否则按用户名查找
-                let recipient_full = format!("{}@{}", recipient, cli.host);
-                server.get_user_addr(&recipient_full).await
-            };
-
-            // let recipient_full = format!("{}@{}", recipient, cli.host);
-            if let Some(addr) = addr {
-                let packet = IpMsgPacket {
-                    version: "lanMsg 0.1".to_string(),
-                    packet_no: rand::random(),
-                    sender_name: cli.name.clone(),
-                    sender_host: cli.host.clone(),
-                    command: commands::MSG,
-                    additional_msg: message,
-                    group_name: "".to_string(),
-                    ..Default::default()
+                let acked = outcomes.iter().filter(|outcome| matches!(outcome.delivery, net::Delivery::Acked(_))).count();
+                let failed = outcomes.iter().filter(|outcome| outcome.delivery.is_failed()).count();
+                for outcome in &outcomes {
+                    println!("{}: {}", outcome.recipient, outcome.delivery);
+                    if !outcome.delivery.is_failed() {
+                        record(Direction::Outgoing, &outcome.recipient, outcome.addr, &message);
+                    }
+                }
+                println!(
+                    "Group {}: {} recipient(s), {} acked, {} sent without ack, {} failed",
+                    group,
+                    outcomes.len(),
+                    acked,
+                    outcomes.len() - acked - failed,
+                    failed
+                );
+                // 与普通 send 相同：发送失败为 5，未确认为 4
+                send_error = send_failure(&outcomes);
+            }
+        }
+        cli::Commands::Send {
+            json,
+            wait_ack,
+            ack_timeout,
+            ..
+        } => {
+            // 收件人可以是 用户名、用户名@主机名、IP 或 IP:端口
+            let (recipients, message) = send.unwrap_or_default();
+            let wait_ack = wait_ack.then(|| ack_timeout.map_or(server.timeout(), std::time::Duration::from_secs));
+            let outcomes = server.send_many(&recipients, &message, wait_ack).await;
+            for outcome in &outcomes {
+                if let (Some(addr), false) = (outcome.addr, outcome.delivery.is_failed()) {
+                    record(Direction::Outgoing, &outcome.recipient, Some(addr), &message);
+                }
+            }
+            if json {
+                println!("{}", delivery::to_json(&outcomes)?);
+            } else if wait_ack.is_some() {
+                let users = server.get_online_users().await;
+                for outcome in &outcomes {
+                    let peer = outcome
+                        .addr
+                        .and_then(|addr| users.iter().find(|user| user.addr == addr))
+                        .map(|user| format!("{}@{}", user.username, user.hostname))
+                        .unwrap_or_else(|| outcome.recipient.clone());
+                    match &outcome.delivery {
+                        net::Delivery::Acked(rtt) => println!("delivered to {} in {}ms", peer, rtt.as_millis()),
+                        net::Delivery::Failed(e) => println!("failed to send to {}: {}", peer, e),
+                        _ => println!("no ack from {} after {} attempts", peer, net::ACK_ATTEMPTS),
+                    }
+                }
+            } else {
+                let style = table::TableStyle::ascii(config.debug.ascii_tables);
+                print!("{}", delivery::render(&outcomes, style));
+            }
+            send_error = send_failure(&outcomes);
+        }
+        cli::Commands::SendFile {
+            recipient,
+            paths,
+            as_archive,
+            message,
+            serve_timeout,
+            ip,
+        } => {
+            let recipient = cli::recipient(recipient.as_deref(), ip);
+            // 路径有误时在发出任何报文之前报错
+            transfer::check_paths(&paths, as_archive)?;
+            let addr = server.resolve_recipient(&recipient).await?;
+            let files = transfer::FileServer::bind(
+                server.send_addr().unwrap_or(server.local_addr()?),
+                server.clone(),
+                Arc::new(config.clone()),
+            )
+            .await?;
+            let mut packet = server.build_packet(
+                commands::MSG | commands::IPMSG_FILEATTACHOPT,
+                message.as_deref().unwrap_or_default(),
+            );
+            let (paths, archive_dir) = if as_archive {
+                let dir = archive_dir(packet.packet_no)?;
+                let mut name = match paths.as_slice() {
+                    [single] => single.canonicalize()?.file_name().unwrap_or_default().to_os_string(),
+                    _ => "files".into(),
                 };
-                server.send_to(&packet, &addr).await?;
+                name.push(".tar");
+                let archive = dir.join(name);
+                transfer::build_archive(&paths, &archive)?;
+                (vec![archive], Some(dir))
             } else {
-                println!("User {} not found", recipient);
-            }
-        }
-        cli::Commands::Broadcast { message } => {
-            let packet = IpMsgPacket {
-                version: "lanMsg 0.1".to_string(),
-                packet_no: rand::random(),
-                sender_name: cli.name.clone(),
-                sender_host: cli.host.clone(),
-                command: commands::MSG,
-                additional_msg: message,
-                group_name: "".to_string(),
-                ..Default::default()
+                (paths, None)
             };
-            server.broadcast(&packet).await?;
+            let attachments = files.offer(packet.packet_no, &paths)?;
+            packet.extension = transfer::encode_attachments(&attachments);
+            // 对方放弃接收时不再等待
+            let mut events = server.subscribe();
+            let release = files.clone();
+            let packet_no = packet.packet_no;
+            tokio::spawn(async move {
+                while let Some(event) = recv_lossy(&mut events, "File release watcher").await {
+                    if let NetEvent::Packet { packet, from, .. } = event
+                        && from.ip() == addr.ip()
+                        && packet.command & 0xff == commands::IPMSG_RELEASEFILES
+                        && packet.additional_msg.trim() == packet_no.to_string()
+                    {
+                        release.release(packet_no);
+                    }
+                }
+            });
+            send_offer(&server, &packet, &addr).await.exit_code(exit_code::NETWORK)?;
+            let names: Vec<&str> = attachments.iter().map(|file| file.name.as_str()).collect();
+            record(Direction::Outgoing, &recipient, Some(addr), &format!("[files] {}", names.join(", ")));
+            let serve_timeout = serve_timeout.unwrap_or(config.transfer.offer_ttl_secs);
+            println!(
+                "Offered {} file(s) to {}, waiting up to {}s for the transfer...",
+                attachments.len(),
+                recipient,
+                serve_timeout
+            );
+            let total = attachments.len();
+            let mut done = 0;
+            let serving = files.serve_until_done(std::time::Duration::from_secs(serve_timeout), |name| {
+                done += 1;
+                println!("[file] {} sent ({}/{})", name, done, total);
+            });
+            let sent = tokio::select! {
+                sent = serving => Some(sent),
+                _ = tokio::signal::ctrl_c() => None,
+            };
+            if let Some(dir) = &archive_dir {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+            // 中断或超时时撤回未被取走的文件
+            if sent != Some(total) {
+                let release = server.build_packet(commands::IPMSG_RELEASEFILES, &packet_no.to_string());
+                if let Err(e) = server.send_to(&release, &addr).await {
+                    log::warn!("Failed to withdraw the file offer: {}", e);
+                }
+            }
+            match sent {
+                Some(sent) if sent == total => println!("Sent {} of {} file(s)", sent, total),
+                Some(sent) => {
+                    return Err(anyhow::anyhow!("Only {} of {} file(s) were picked up", sent, total));
+                }
+                None => return Err(anyhow::anyhow!("Interrupted, file offer withdrawn")),
+            }
         }
-        cli::Commands::List => {
-            // 等待2秒收集响应
-            println!("Fetching online users...");
-            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-            let users = server.get_online_users().await;
-            println!("Online users ({}):", users.len());
-
-            if users.is_empty() {
-                println!("No online users found");
-            } else {
-                println!("┌──────────────┬──────────────┬──────────────┬──────┐");
-                println!(
-                    "│ {:<12} │ {:<12} │ {:<12} │ {:<4} │",
-                    "Username", "Host", "IP", "Port"
+        cli::Commands::Broadcast {
+            confirm: true,
+            window,
+            verbose,
+            require_all,
+            ..
+        } => {
+            let message = broadcast_body.unwrap_or_default();
+            let command = commands::MSG | commands::IPMSG_BROADCASTOPT;
+            let window = window.map(std::time::Duration::from_secs).unwrap_or(server.timeout());
+            let result = server
+                .broadcast_confirmed(command, &message, window)
+                .await
+                .exit_code(exit_code::NETWORK)?;
+            record(Direction::Outgoing, "*", None, &message);
+            let known = result.confirmed.len() + result.unconfirmed.len();
+            println!("confirmed by {} of {} known peers", result.confirmed.len(), known);
+            if verbose {
+                for user in &result.confirmed {
+                    println!("  confirmed        {}@{} ({})", user.username, user.hostname, user.addr);
+                }
+                for user in &result.unconfirmed {
+                    println!("  no confirmation  {}@{} ({})", user.username, user.hostname, user.addr);
+                }
+                for addr in &result.unknown {
+                    println!("  confirmed        (not in roster) {}", addr);
+                }
+            }
+            if require_all && !result.unconfirmed.is_empty() {
+                let error = anyhow::anyhow!(
+                    "{} of {} known peers did not confirm within {} s",
+                    result.unconfirmed.len(),
+                    known,
+                    window.as_secs()
                 );
-                println!("├──────────────┼──────────────┼──────────────┼──────┤");
+                send_error = Some(cli::Failure { code: exit_code::ACK_TIMEOUT, error }.into());
+            }
+        }
+        cli::Commands::Broadcast { repeat, interval, .. } => {
+            let message = broadcast_body.unwrap_or_default();
+            let command = commands::MSG | commands::IPMSG_BROADCASTOPT;
+            let sent = server
+                .broadcast_repeat(command, &message, repeat, std::time::Duration::from_millis(interval))
+                .await
+                .exit_code(exit_code::NETWORK)?;
+            for _ in &sent {
+                record(Direction::Outgoing, "*", None, &message);
+            }
+            if repeat > 1 {
+                println!("Broadcast sent {} time(s)", sent.len());
+            }
+        }
+        cli::Commands::Query { user, absence, timeout } => {
+            let addr = server.resolve_recipient(&user).await?;
+            let timeout = timeout.map(std::time::Duration::from_secs);
+            let info = server.query_peer(&addr, absence, timeout).await?;
+            if info.version.is_none() && info.absence.is_none() {
+                return Err(anyhow::anyhow!(
+                    "No reply from {} ({}) within {} s",
+                    user,
+                    addr,
+                    timeout.unwrap_or(server.timeout()).as_secs()
+                ));
+            }
+            println!("{} ({})", user, addr);
+            println!("  Version: {}", info.version.as_deref().unwrap_or("(no reply)"));
+            if absence {
+                println!("  Absence: {}", info.absence.as_deref().unwrap_or("(no reply)"));
+            }
+        }
+        cli::Commands::Pipe {
+            to,
+            group,
+            contains,
+            broadcast: _,
+            prefix,
+            split,
+        } => {
+            let target = match (to, group) {
+                (Some(to), _) => pipe::PipeTarget::User(to),
+                (_, Some(name)) => pipe::PipeTarget::Group { name, contains },
+                _ => pipe::PipeTarget::Broadcast,
+            };
+            let options = pipe::PipeOptions {
+                prefix,
+                split,
+                interval: std::time::Duration::from_millis(config.network.min_broadcast_interval_ms),
+                limit: config.user.message_limit(),
+            };
+            let input = io::BufReader::new(io::stdin());
+            let report = pipe::run(&server, &target, &options, input, |peer, addr, body| {
+                record(Direction::Outgoing, peer, addr, body)
+            })
+            .await?;
+            log::info!(
+                "Pipe finished: {} line(s), {} message(s) sent, {} truncated, {} split, {} skipped, {} failed",
+                report.lines,
+                report.messages,
+                report.truncated,
+                report.split,
+                report.skipped,
+                report.failed
+            );
+        }
+        cli::Commands::Multicast { users, message } => {
+            for (recipient, result) in server.multicast(&users, &message).await {
+                match result {
+                    Ok(addr) => record(Direction::Outgoing, &recipient, Some(addr), &message),
+                    Err(e) => log::warn!("Multicast to {} failed: {}", recipient, e),
+                }
+            }
+        }
+        cli::Commands::List {
+            timeout,
+            ascii,
+            cached,
+            group,
+            filter,
+            sort,
+            desc,
+            columns,
+            json,
+        } => {
+            let users = if cached {
+                server.get_online_users().await
+            } else {
+                if !json {
+                    println!("Fetching online users...");
+                }
+                server
+                    .refresh_users(timeout.map(std::time::Duration::from_secs))
+                    .await
+            };
+            let query = roster::UserQuery { group, filter, sort, desc };
+            let users = query.apply(users);
+            let columns = if columns.is_empty() { roster::Column::DEFAULT.to_vec() } else { columns };
 
-                for user in users {
-                    println!(
-                        "│ {:<12} │ {:<12} │ {:<12} │ {:<4} │",
-                        user.username, user.hostname, user.ip, user.port
-                    );
+            if json {
+                println!("{}", roster::users_json(&users, &columns)?);
+            } else {
+                println!("Online users ({}):", users.len());
+                if users.is_empty() {
+                    println!("No online users found");
+                } else {
+                    let style = table::TableStyle::ascii(ascii || config.debug.ascii_tables);
+                    print!("{}", roster::render(&users, &columns, style, table::terminal_width()));
+                }
+            }
+        }
+        cli::Commands::Groups { action, timeout, json } => {
+            let users = server
+                .refresh_users(timeout.map(std::time::Duration::from_secs))
+                .await;
+            let all = groups::group_users(&users);
+            let style = table::TableStyle::ascii(config.debug.ascii_tables);
+            match action {
+                None if json => println!("{}", groups::groups_json(&all)?),
+                None => print!("{}", groups::render_groups(&all, style)),
+                Some(cli::GroupsAction::Show { name }) => {
+                    let Some(group) = all.iter().find(|group| group.matches(&name)) else {
+                        return Err(anyhow::anyhow!("No online users in group {}", name));
+                    };
+                    if json {
+                        println!("{}", groups::members_json(group)?);
+                    } else {
+                        print!("{}", groups::render_members(group, style));
+                    }
                 }
-                println!("└──────────────┴──────────────┴──────────────┴──────┘");
             }
         }
-        cli::Commands::Chat => {
-            let (tx, mut rx) = mpsc::channel(100);
+        cli::Commands::Doctor => {
+            let report = doctor::run_diagnostics(&server, &config, server.timeout()).await;
+            println!("{}", report);
+            if !report.is_healthy() {
+                println!("Self-test failed: broadcast may be blocked on this network");
+            }
+        }
+        cli::Commands::Daemon => {
+            // 用户上下线提示；--quiet 时只输出消息
+            if !cli.quiet {
+                let mut events = server.subscribe();
+                let me = format!("{}@{}", config.user.name, config.user.host);
+                tokio::spawn(async move {
+                    while let Some(event) = recv_lossy(&mut events, "Presence output").await {
+                        match event {
+                            NetEvent::Joined { username, .. } | NetEvent::Left { username, .. } if username == me => {}
+                            NetEvent::Joined { username, addr } => {
+                                println!("{} + {} ({})", history::now_string(), username, addr)
+                            }
+                            NetEvent::Left { username, addr } => {
+                                println!("{} - {} ({})", history::now_string(), username, addr)
+                            }
+                            _ => {}
+                        }
+                    }
+                });
+            }
+            log::info!("Running as daemon, press Ctrl-C to stop");
+            shutdown_signal().await;
+            log::info!("Shutting down");
+        }
+        cli::Commands::Status { action } => {
+            let request = cli::status_request(action.as_ref());
+            let before = server.state();
+            let status = control::apply(&server, &request).await?;
+            // 状态未变（例如本来就在线）时也广播一次，让其他客户端刷新
+            if server.state() == before {
+                server.broadcast(&server.presence_packet(commands::IPMSG_BR_ABSENCE)).await?;
+            }
+            println!("{}", status);
+        }
+        cli::Commands::Watch { types } => {
+            let me = format!("{}@{}", config.user.name, config.user.host);
+            let mut events = watch_events.take().expect("subscribed before listening");
+            let mut stdout = std::io::stdout().lock();
+            let shutdown = shutdown_signal();
+            tokio::pin!(shutdown);
+            loop {
+                let event = tokio::select! {
+                    event = recv_lossy(&mut events, "Watch output") => event,
+                    _ = &mut shutdown => break,
+                };
+                let Some(event) = event else {
+                    break;
+                };
+                let Some(event) = watch::WatchEvent::from_event(&event, watch::now_ms()) else {
+                    continue;
+                };
+                if !watch::wanted(&types, event.kind())
+                    || matches!(&event, watch::WatchEvent::Join { user, .. } | watch::WatchEvent::Leave { user, .. } if *user == me)
+                {
+                    continue;
+                }
+                // 每行立即刷新，管道另一端能马上看到
+                let written = std::io::Write::write_all(&mut stdout, (event.to_line()? + "\n").as_bytes())
+                    .and_then(|()| std::io::Write::flush(&mut stdout));
+                if let Err(e) = written {
+                    // 下游关闭了管道（例如 head）时安静退出
+                    log::debug!("Stopped writing events: {}", e);
+                    break;
+                }
+            }
+        }
+        cli::Commands::Relay { .. } => unreachable!("relay mode returns early"),
+        cli::Commands::Config { .. } | cli::Commands::Block { .. } | cli::Commands::Unblock { .. } => {
+            unreachable!("config commands return early")
+        }
+        cli::Commands::Chat { idle_timeout, with } => {
+            let idle = idle_timeout.map(std::time::Duration::from_secs);
+
+            // 收到的消息带时间输出
+            if let Some(mut events) = chat_events {
+                tokio::spawn(async move {
+                    while let Some(event) = recv_lossy(&mut events, "Chat output").await {
+                        if let Some(line) = chat::event_line(&event, &history::now_string()) {
+                            console::notify(&line);
+                        }
+                    }
+                });
+            }
 
-            // 用户输入线程
-            // tokio::spawn(async move {
-            //     let mut stdin = io::BufReader::new(io::stdin());
-            //     loop {
-            //         let mut line = String::new();
-            //         stdin.read_line(&mut line).await.unwrap();
-            //         let _ = tx.send(line.trim().to_string()).await;
-            //     }
-            // });
+            // 普通文本的发送对象，/to 切换
+            let mut target = with.as_deref().map(chat::ChatTarget::parse).unwrap_or_default();
+            let style = table::TableStyle::ascii(config.debug.ascii_tables);
 
-            // 用户输入处理
+            // 用户输入处理：终端上用行编辑，收到的消息打印在输入行上方；否则按行读取标准输入
+            let interactive = console::interactive();
             let mut stdin = io::BufReader::new(io::stdin());
+            let mut auto_away = presence::AutoAway::new(
+                config.presence.auto_away_after_idle_secs,
+                std::time::Instant::now(),
+            );
             loop {
-                print!("> ");
-                let mut input = String::new();
-                stdin.read_line(&mut input).await?;
-                let mut input = input.trim().to_string();
-
-                // 退出命令处理
-                if input.eq_ignore_ascii_case("/quit") || input.eq_ignore_ascii_case("/exit") {
-                    println!("Exiting chat...");
-                    break;
+                let prompt = match (last_sender.name(), &target) {
+                    (Some(name), _) => format!("[/r {}] > ", name),
+                    (None, chat::ChatTarget::All) => "> ".to_string(),
+                    (None, target) => format!("[{}] > ", target),
+                };
+                if !interactive {
+                    print!("{}", prompt);
+                    let _ = std::io::Write::flush(&mut std::io::stdout());
+                }
+                // 等待输入期间到了自动离开的时间就切换为离开，输入继续等待
+                let outcome = {
+                    let read = async {
+                        if interactive {
+                            console::read_line(&prompt, idle).await
+                        } else {
+                            chat::read_line_with_idle(&mut stdin, idle).await
+                        }
+                    };
+                    tokio::pin!(read);
+                    loop {
+                        tokio::select! {
+                            outcome = &mut read => break outcome?,
+                            _ = chat::sleep_until(auto_away.deadline()) => {
+                                if auto_away.poll(std::time::Instant::now(), &server.state()) {
+                                    log::info!("No input, setting presence to away");
+                                    if let Err(e) = server.set_away(None).await {
+                                        log::warn!("Failed to broadcast away state: {}", e);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                };
+                if auto_away.activity(std::time::Instant::now())
+                    && matches!(server.state(), presence::PresenceState::Away { .. })
+                {
+                    log::info!("Input received, back online");
+                    if let Err(e) = server.set_state(presence::PresenceState::Online).await {
+                        log::warn!("Failed to broadcast online state: {}", e);
+                    }
                 }
+                let input = match outcome {
+                    chat::ReadOutcome::Line(line) => line,
+                    chat::ReadOutcome::Closed => break,
+                    chat::ReadOutcome::Idle => {
+                        println!("\nNo input for {}s, leaving chat...", idle_timeout.unwrap_or_default());
+                        break;
+                    }
+                };
+
+                let input = match chat::parse_input(&input) {
+                    Some(chat::ChatInput::Quit) => {
+                        println!("Exiting chat...");
+                        break;
+                    }
+                    Some(chat::ChatInput::Reply(text)) => match last_sender.reply(&text) {
+                        Some(input) => input,
+                        None => {
+                            println!("[!] Nobody has messaged you yet");
+                            continue;
+                        }
+                    },
+                    Some(input) => input,
+                    None => continue,
+                };
 
-                if input.is_empty() {
+                if let Some(output) = chat::run_command(&server, &input, &mut target, style).await {
+                    println!("{}", output);
                     continue;
                 }
+                match &input {
+                    chat::ChatInput::Accept { packet_no, file_ids } => {
+                        let ttl = std::time::Duration::from_secs(config.transfer.offer_ttl_secs);
+                        match offers.take(*packet_no, ttl) {
+                            Some(offer) => receive_files(&server, &config, &offer, file_ids).await,
+                            None => println!("[!] No pending file offer #{}", packet_no),
+                        }
+                        continue;
+                    }
+                    chat::ChatInput::File { recipient, path } => {
+                        if let Err(e) = offer_file_in_chat(&server, &config, recipient, path).await {
+                            println!("[!] File offer failed: {:#}", e);
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
 
-                // let packet = protocol::IpMsgPacket {
-                //     version: 1,
-                //     packet_no: rand::random(),
-                //     sender_name: cli.name.clone(),
-                //     sender_host: cli.host.clone(),
-                //     command: protocol::commands::MSG,
-                //     additional_msg: input.clone(),
-                // };
-                // server.broadcast(&packet, broadcast_addr.clone()).await?;
-                let _ = tx.send(input).await;
+                let result = chat::send_input(&server, &input, &target).await;
+                println!("{}", chat::echo_line(&input, &target, &result));
+                match (&input, &target, &result) {
+                    (chat::ChatInput::Say(text), chat::ChatTarget::All, Ok(())) => {
+                        record(Direction::Outgoing, "*", None, text)
+                    }
+                    (chat::ChatInput::Say(text), target, Ok(())) => {
+                        record(Direction::Outgoing, &target.to_string(), None, text)
+                    }
+                    (chat::ChatInput::Msg { recipient, text }, _, Ok(())) => {
+                        record(Direction::Outgoing, recipient, None, text)
+                    }
+                    _ => {}
+                }
             }
         }
     }
 
     // 发送下线通知
-    let exit_packet = IpMsgPacket {
-        version: "lanMsg 0.1".to_string(),
-        packet_no: rand::random(),
-        sender_name: cli.name.clone(),
-        sender_host: cli.host.clone(),
-        command: commands::BR_EXIT,
-        additional_msg: "".to_string(),
-        group_name: "".to_string(),
-        ..Default::default()
+    let exit_packet = server.build_packet(commands::BR_EXIT, "");
+    if !config.debug.passive && !transient_status && !cached_list {
+        server.broadcast(&exit_packet).await.exit_code(exit_code::NETWORK)?;
+    }
+
+    if config.network.cache_users
+        && let Err(e) = server.save_user_cache(&user_cache).await
+    {
+        log::warn!("Failed to save user cache: {}", e);
+    }
+
+    send_error.map_or(Ok(()), Err)
+}
+
+/// 读取消息正文；模板名不存在属于用法错误
+fn read_body(body: BodySource, config: &config::AppConfig) -> Result<String> {
+    match body {
+        BodySource::Template(_) => body.read(config).exit_code(exit_code::USAGE),
+        body => body.read(config),
+    }
+}
+
+/// 把 send 的逐个收件人结果汇总为错误，全部成功时为 None
+///
+/// 退出码取第一个失败的收件人：找不到为 2、不唯一为 3、未确认为 4、发送失败为 5
+fn send_failure(outcomes: &[net::SendOutcome]) -> Option<anyhow::Error> {
+    let failures: Vec<(u8, String)> = outcomes
+        .iter()
+        .filter_map(|outcome| match &outcome.delivery {
+            net::Delivery::Failed(e) => {
+                let code = match cli::error_code(e) {
+                    exit_code::FAILURE => exit_code::NETWORK,
+                    code => code,
+                };
+                Some((code, format!("{:#}", e)))
+            }
+            net::Delivery::Unacked => Some((
+                exit_code::ACK_TIMEOUT,
+                format!("No ack from {} after {} attempts", outcome.recipient, net::ACK_ATTEMPTS),
+            )),
+            _ => None,
+        })
+        .collect();
+    let (code, _) = failures.first()?;
+    let messages: Vec<&str> = failures.iter().map(|(_, message)| message.as_str()).collect();
+    let error = match messages.as_slice() {
+        [single] => anyhow::anyhow!("{}", single),
+        _ => anyhow::anyhow!(
+            "{} of {} recipient(s) failed: {}",
+            failures.len(),
+            outcomes.len(),
+            messages.join("; ")
+        ),
     };
-    server.broadcast(&exit_packet).await?;
+    Some(cli::Failure { code: *code, error }.into())
+}
 
+/// 等待 SIGINT 或 SIGTERM（非 Unix 平台只有 Ctrl-C）
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                log::warn!("Cannot listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// 打包文件用的临时目录（按报文编号区分）
+fn archive_dir(packet_no: u32) -> Result<std::path::PathBuf> {
+    let dir = std::env::temp_dir().join(format!("lanmsg-send-{}", packet_no));
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// 发出带附件列表的消息并等待对方确认，没有确认时重发（最多 OFFER_SEND_ATTEMPTS 次）
+async fn send_offer(
+    server: &net::IpMsgServer,
+    packet: &protocol::IpMsgPacket,
+    addr: &std::net::SocketAddr,
+) -> Result<()> {
+    const OFFER_SEND_ATTEMPTS: usize = 3;
+    for attempt in 1..=OFFER_SEND_ATTEMPTS {
+        if server.send_checked(packet, addr, None).await?.is_some() {
+            return Ok(());
+        }
+        log::debug!("No acknowledgement for file offer (attempt {})", attempt);
+    }
+    log::warn!("{} did not acknowledge the file offer, serving anyway", addr);
     Ok(())
 }
+
+/// 聊天中的 /file：发出文件请求后在后台等待对方取走，超过 transfer.offer_ttl_secs 未取走的撤回
+async fn offer_file_in_chat(
+    server: &net::IpMsgServer,
+    config: &config::AppConfig,
+    recipient: &str,
+    path: &std::path::Path,
+) -> Result<()> {
+    let paths = vec![path.to_path_buf()];
+    transfer::check_paths(&paths, false)?;
+    let addr = server.resolve_recipient(recipient).await?;
+    let files = transfer::FileServer::bind(
+        server.send_addr().unwrap_or(server.local_addr()?),
+        server.clone(),
+        Arc::new(config.clone()),
+    )
+    .await?;
+    let mut packet = server.build_packet(commands::MSG | commands::IPMSG_FILEATTACHOPT, "");
+    let attachments = files.offer(packet.packet_no, &paths)?;
+    packet.extension = transfer::encode_attachments(&attachments);
+    send_offer(server, &packet, &addr).await?;
+    println!("Offered {} to {}", attachments[0].name, recipient);
+
+    let (server, recipient) = (server.clone(), recipient.to_string());
+    let ttl = std::time::Duration::from_secs(config.transfer.offer_ttl_secs);
+    tokio::spawn(async move {
+        let sent = files
+            .serve_until_done(ttl, |name| console::notify(&format!("[file] {} sent to {}", name, recipient)))
+            .await;
+        if sent == 0 {
+            console::notify(&format!("[file] {} did not pick up the file, offer withdrawn", recipient));
+            let release = server.build_packet(commands::IPMSG_RELEASEFILES, &packet.packet_no.to_string());
+            if let Err(e) = server.send_to(&release, &addr).await {
+                log::warn!("Failed to withdraw the file offer: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// 显示收到的文件请求；在自动接收名单内时直接下载，否则等待 /accept
+fn handle_file_offer(
+    server: &net::IpMsgServer,
+    config: &Arc<config::AppConfig>,
+    offers: &Arc<transfer::PendingOffers>,
+    offer: transfer::FileOffer,
+) {
+    let mut notice = format!("[file] {} offers {} file(s) (#{}):", offer.sender, offer.files.len(), offer.packet_no);
+    for file in &offer.files {
+        notice.push_str(&format!("\n  {}: {} ({})", file.file_id, file.name, file.display_size()));
+    }
+    if !config.transfer.enabled {
+        console::notify(&format!("{}\nFile reception is disabled (transfer.enabled = false)", notice));
+        return;
+    }
+    if config
+        .transfer
+        .auto_accepts(offer.from.ip(), &[offer.sender.as_str()], offer.total_size())
+    {
+        console::notify(&notice);
+        let (server, config) = (server.clone(), config.clone());
+        tokio::spawn(async move { receive_files(&server, &config, &offer, &[]).await });
+        return;
+    }
+    console::notify(&format!("{}\nUse /accept {} [file ids] in chat to receive", notice, offer.packet_no));
+    if let Some(dropped) = offers.insert(offer) {
+        log::warn!(
+            "Too many pending file offers ({}), dropped #{} from {}",
+            config.transfer.max_pending_offers, dropped.packet_no, dropped.sender
+        );
+    }
+}
+
+/// 接收文件请求中选中的附件并逐个报告结果
+async fn receive_files(
+    server: &net::IpMsgServer,
+    config: &config::AppConfig,
+    offer: &transfer::FileOffer,
+    file_ids: &[u32],
+) {
+    match transfer::accept(server, config, offer, file_ids).await {
+        Ok(results) => {
+            for (file, result) in results {
+                match result {
+                    Ok(path) => console::println(&format!("[file] Saved {} to {}", file.name, path.display())),
+                    Err(e) => console::println(&format!("[!] Receiving {} failed: {:#}", file.name, e)),
+                }
+            }
+        }
+        Err(e) => console::println(&format!("[!] {:#}", e)),
+    }
+}
+
+/// 加载并校验配置，列出所有错误
+fn load_config(
+    location: &config::ConfigLocation,
+    env: &config::ConfigOverrides,
+    cli: &Cli,
+) -> Result<config::AppConfig> {
+    let overrides = cli.overrides();
+    let profile = config::ConfigOverrides::select_profile(env, &overrides);
+    let loaded = config::AppConfig::load_profile(&location.path, profile)?;
+    // 命令行覆盖了配置文件或环境变量中设置的值时提示实际生效的取值
+    for notice in overrides.override_notices(&loaded.clone().merged(env, &Default::default())) {
+        log::info!("{}", notice);
+    }
+    let config = loaded.merged(env, &overrides);
+    let mut errors = config.validate();
+    if config.transfer.enabled {
+        errors.extend(config.transfer.check_download_dir());
+    }
+    if config.history.enabled {
+        errors.extend(config.history.check_path());
+    }
+    if errors.is_empty() {
+        return Ok(config);
+    }
+    for error in &errors {
+        log::error!("{}", error);
+    }
+    Err(anyhow::anyhow!(
+        "Invalid configuration in {} ({} errors), fix it or pass --ignore-config-errors",
+        location.path.display(),
+        errors.len()
+    ))
+}
+
+/// 修改配置文件中的黑名单并写回（只改文件内容，不合并环境变量与命令行覆盖值）
+fn run_block_command(peer: &str, block: bool, location: &config::ConfigLocation) -> Result<()> {
+    let mut config = config::AppConfig::load(&location.path)?;
+    let changed = if block {
+        config.security.block(peer)?
+    } else {
+        config.security.unblock(peer)
+    };
+    match (changed, block) {
+        (true, true) => println!("Blocked {}", peer),
+        (true, false) => println!("Unblocked {}", peer),
+        (false, true) => println!("{} is already blocked", peer),
+        (false, false) => println!("{} is not blocked", peer),
+    }
+    if config.save_if_changed(&location.path)? {
+        println!("Saved {}", location.path.display());
+    }
+    Ok(())
+}
+
+/// 显示生效的配置及所用的配置方案
+fn print_effective_config(config: &config::AppConfig, profile: Option<&str>) -> Result<()> {
+    println!("# profile: {}", profile.unwrap_or("(none)"));
+    let profiles = config.profiles();
+    if !profiles.is_empty() {
+        println!("# available profiles: {}", profiles.join(", "));
+    }
+    print!("{}", config.effective_toml()?);
+    Ok(())
+}
+
+/// 配置文件子命令
+fn run_config_command(
+    action: &cli::ConfigAction,
+    location: &config::ConfigLocation,
+) -> Result<()> {
+    match action {
+        cli::ConfigAction::Init { path, format, force } => {
+            // 未指定路径时沿用查找结果，但扩展名随 --format 改变
+            let path = match (path, format) {
+                (Some(path), _) => path.clone(),
+                (None, Some(format)) => location.path.with_extension(format.extension()),
+                (None, None) => location.path.clone(),
+            };
+            let path = &path;
+            let format = format.unwrap_or_else(|| config::ConfigFormat::from_path(path));
+            if path.exists() && !force {
+                return Err(anyhow::anyhow!(
+                    "{} already exists, use --force to overwrite",
+                    path.display()
+                ));
+            }
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)?;
+            }
+            let template = config::default_config_template_as(format)?;
+            config::write_atomic(path, template.as_bytes())?;
+            println!("Wrote default configuration to {}", path.display());
+            Ok(())
+        }
+        cli::ConfigAction::Migrate => {
+            let applied = config::AppConfig::migrate_file(&location.path)?;
+            if applied.is_empty() {
+                println!("{} is already at the current version", location.path.display());
+            } else {
+                for change in &applied {
+                    println!("{}", change);
+                }
+                println!(
+                    "Migrated {} (original saved as {})",
+                    location.path.display(),
+                    config::backup_path(&location.path).display()
+                );
+            }
+            Ok(())
+        }
+        cli::ConfigAction::Show => unreachable!("config show needs the loaded config"),
+    }
+}
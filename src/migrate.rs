@@ -0,0 +1,166 @@
+//! 配置文件版本与迁移
+//!
+//! 配置文件顶层的 config_version 记录布局版本，缺失时视为 1。加载时按顺序
+//! 执行从文件版本到 CONFIG_VERSION 的每一步迁移，新版本写入的文件直接报错。
+
+use anyhow::Result;
+
+/// 当前的配置布局版本
+pub const CONFIG_VERSION: u32 = 2;
+/// 记录布局版本的顶层键
+pub const VERSION_KEY: &str = "config_version";
+
+/// 一步迁移：把 from 版本的布局升级为 from + 1，返回实际做出的改动说明
+struct Migration {
+    from: u32,
+    apply: fn(&mut toml::Table) -> Vec<String>,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    apply: v1_to_v2,
+}];
+
+/// 配置文件的布局版本，缺失时为 1
+pub fn file_version(table: &toml::Table) -> Result<u32> {
+    let Some(value) = table.get(VERSION_KEY) else {
+        return Ok(1);
+    };
+    value
+        .as_integer()
+        .and_then(|version| u32::try_from(version).ok())
+        .filter(|&version| version >= 1)
+        .ok_or_else(|| anyhow::anyhow!("{} must be a positive integer, got {}", VERSION_KEY, value))
+}
+
+/// 把配置升级到当前版本并写入 config_version，返回每项已执行迁移的说明
+pub fn migrate(table: &mut toml::Table) -> Result<Vec<String>> {
+    let version = file_version(table)?;
+    if version > CONFIG_VERSION {
+        return Err(anyhow::anyhow!(
+            "config written by a newer lanMsg ({} = {}, this build supports up to {})",
+            VERSION_KEY,
+            version,
+            CONFIG_VERSION
+        ));
+    }
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|migration| migration.from >= version) {
+        for change in (migration.apply)(table) {
+            applied.push(format!("v{} -> v{}: {}", migration.from, migration.from + 1, change));
+        }
+    }
+    table.insert(VERSION_KEY.to_string(), toml::Value::Integer(CONFIG_VERSION.into()));
+    Ok(applied)
+}
+
+/// v1 -> v2：顶层的 blocklist/allowlist 移入 [security]，编码名改为规范写法（utf8 -> utf-8）
+fn v1_to_v2(table: &mut toml::Table) -> Vec<String> {
+    let mut changes = Vec::new();
+    for (legacy, field) in [("blocklist", "blocked"), ("allowlist", "allowed")] {
+        let Some(toml::Value::Array(entries)) = table.remove(legacy) else {
+            continue;
+        };
+        let security = section_mut(table, "security");
+        let list = security
+            .entry(field)
+            .or_insert_with(|| toml::Value::Array(Vec::new()));
+        if let toml::Value::Array(list) = list {
+            for entry in entries {
+                if !list.contains(&entry) {
+                    list.push(entry);
+                }
+            }
+        }
+        changes.push(format!("moved {} into security.{}", legacy, field));
+    }
+
+    if let Some(toml::Value::Table(encoding)) = table.get_mut("encoding") {
+        for key in ["protocol", "display"] {
+            if let Some(change) = canonicalize_encoding(encoding.get_mut(key)) {
+                changes.push(format!("encoding.{}: {}", key, change));
+            }
+        }
+        if let Some(toml::Value::Table(peers)) = encoding.get_mut("peers") {
+            for (peer, label) in peers.iter_mut() {
+                if let Some(change) = canonicalize_encoding(Some(label)) {
+                    changes.push(format!("encoding.peers.\"{}\": {}", peer, change));
+                }
+            }
+        }
+    }
+    changes
+}
+
+/// 把编码名改为规范写法，返回改动说明；不认识的名称留给校验报告
+fn canonicalize_encoding(value: Option<&mut toml::Value>) -> Option<String> {
+    let toml::Value::String(label) = value? else {
+        return None;
+    };
+    let canonical = encoding_rs::Encoding::for_label(label.trim().as_bytes())?
+        .name()
+        .to_ascii_lowercase();
+    if *label == canonical {
+        return None;
+    }
+    let change = format!("renamed '{}' to '{}'", label, canonical);
+    *label = canonical;
+    Some(change)
+}
+
+fn section_mut<'a>(table: &'a mut toml::Table, name: &str) -> &'a mut toml::Table {
+    let value = table
+        .entry(name)
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    if !value.is_table() {
+        *value = toml::Value::Table(toml::Table::new());
+    }
+    match value {
+        toml::Value::Table(table) => table,
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1: &str = include_str!("../tests/fixtures/config_v1.toml");
+    const V2: &str = include_str!("../tests/fixtures/config_v2.toml");
+
+    #[test]
+    fn test_v1_fixture_migrates_to_v2() {
+        let mut table: toml::Table = toml::from_str(V1).unwrap();
+        assert_eq!(file_version(&table).unwrap(), 1);
+        let applied = migrate(&mut table).unwrap();
+        assert_eq!(
+            applied,
+            [
+                "v1 -> v2: moved blocklist into security.blocked",
+                "v1 -> v2: encoding.protocol: renamed 'GB2312' to 'gbk'",
+                "v1 -> v2: encoding.display: renamed 'utf8' to 'utf-8'",
+                "v1 -> v2: encoding.peers.\"192.168.1.44\": renamed 'UTF8' to 'utf-8'",
+            ]
+        );
+        let expected: toml::Table = toml::from_str(V2).unwrap();
+        assert_eq!(table, expected);
+    }
+
+    #[test]
+    fn test_current_fixture_is_unchanged() {
+        let mut table: toml::Table = toml::from_str(V2).unwrap();
+        assert_eq!(file_version(&table).unwrap(), CONFIG_VERSION);
+        assert!(migrate(&mut table).unwrap().is_empty());
+        assert_eq!(table, toml::from_str::<toml::Table>(V2).unwrap());
+    }
+
+    #[test]
+    fn test_newer_and_invalid_versions_rejected() {
+        let mut newer: toml::Table = toml::from_str("config_version = 99").unwrap();
+        let error = migrate(&mut newer).unwrap_err().to_string();
+        assert!(error.contains("config written by a newer lanMsg"), "{}", error);
+
+        let mut invalid: toml::Table = toml::from_str("config_version = \"two\"").unwrap();
+        assert!(migrate(&mut invalid).is_err());
+    }
+}
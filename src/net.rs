@@ -1,29 +1,407 @@
-use crate::protocol::extract_string_part;
-use crate::config::AppConfig;
-use crate::protocol::{IpMsgPacket, commands};
+use crate::cache::{self, CachedUser};
+use crate::config::{AppConfig, BroadcastTarget, DebugConfig, MessageLimit, NetworkConfig, PresenceConfig, UserConfig};
+use crate::dump::{DumpDirection, DumpRecord, DumpWriter, PacketDumper};
+use crate::dedup::{DEFAULT_DEDUP_TTL, DedupCache, DedupKey};
+use crate::event::{EVENT_CHANNEL_CAPACITY, NetEvent, recv_lossy};
+use crate::peer_encoding::PeerEncodings;
+use crate::peer_match::PeerFilter;
+use crate::presence::{NOT_ABSENT_MESSAGE, PresenceState, PresenceTracker};
+use crate::protocol::{self, IpMsgPacket, ProtocolError, commands};
+use crate::reorder::ReorderBuffer;
+use crate::security::PacketSigner;
+use crate::stats::{ServerStats, StatsSnapshot};
+use crate::throttle::{BroadcastPacer, QUEUE_WARN_THRESHOLD, Slot};
 use anyhow::Result;
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::{TcpListener, UdpSocket};
-use tokio::sync::RwLock;
+use encoding_rs::Encoding;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::UdpSocket;
+use tokio::sync::{Notify, RwLock, broadcast, mpsc, oneshot};
 
 pub const IPMSG_PORT: u16 = 2425;
-const FILE_PORT: u16 = 2426;
+pub const FILE_PORT: u16 = 2426;
+/// 同一新用户在该窗口内只回复一次 ANSENTRY
+const ANSENTRY_SUPPRESS_WINDOW: Duration = Duration::from_secs(5);
+/// broadcast_ip 为主机名时解析结果的缓存时长
+const BROADCAST_RESOLVE_TTL: Duration = Duration::from_secs(60);
+/// 刷新用户表时，超过该时长没有新的应答即视为应答已结束（略大于 ansentry_jitter_ms 的默认值）
+const REFRESH_QUIET: Duration = Duration::from_millis(800);
+
+/// 以主机名配置的广播目标
+#[derive(Debug, Clone)]
+struct BroadcastHost {
+    name: String,
+    /// 上次解析的时间，None 表示尚未解析
+    resolved_at: Option<Instant>,
+}
+
+impl BroadcastHost {
+    fn is_stale(&self) -> bool {
+        self.resolved_at.is_none_or(|at| at.elapsed() >= BROADCAST_RESOLVE_TTL)
+    }
+}
+
+/// 一个广播目标；配置为主机名时 host 记录名称，addr 为上次解析的地址
+#[derive(Debug, Clone)]
+struct BroadcastDest {
+    addr: SocketAddr,
+    host: Option<BroadcastHost>,
+}
 
 #[derive(Debug, Clone)]
 pub struct OnlineUser {
     pub username: String,
     pub hostname: String,
-    pub ip: String,
-    pub port: u16,
+    pub addr: SocketAddr,
+    /// 学习到该用户的本地端口，回复从该端口的套接字发出
+    pub via_port: u16,
+    /// 本次运行中收到过该用户的报文；为 false 表示来自缓存，尚未确认
+    pub confirmed: bool,
+    /// 上线报文中的状态说明（离开时为离开信息）
+    pub note: String,
+    /// 登录名（报文中的用户字段）
+    pub login: String,
+    /// 分组名（按协议编码解码后的原文）
+    pub group: String,
+    /// 处于离开状态
+    pub absent: bool,
+    /// GETABSENCEINFO 查询到的离开信息（对方在线时为 None）
+    pub absence: Option<String>,
+    /// 最近一次收到该用户报文的时间
+    pub last_seen: SystemTime,
+}
+
+impl OnlineUser {
+    pub fn ip(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+}
+
+/// 收件人解析失败的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    NotFound(String),
+    /// 同名用户不止一个，附可用的 用户名@主机名
+    Ambiguous { recipient: String, candidates: Vec<String> },
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::NotFound(recipient) => write!(f, "User {} not found", recipient),
+            ResolveError::Ambiguous { recipient, candidates } => {
+                write!(f, "User {} is ambiguous, use one of: {}", recipient, candidates.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// send_reliable 未收到确认时的发送次数（含第一次）
+pub const ACK_ATTEMPTS: u32 = 3;
+
+/// 发给一个收件人的结果
+#[derive(Debug)]
+pub enum Delivery {
+    /// 对方确认收到，附往返时间
+    Acked(Duration),
+    /// 已发出，不等待确认
+    Sent,
+    /// 已发出（包括重发），超时前没有收到确认
+    Unacked,
+    Failed(anyhow::Error),
+}
+
+impl Delivery {
+    /// acked、sent、unacked 或 failed
+    pub fn status(&self) -> &'static str {
+        match self {
+            Delivery::Acked(_) => "acked",
+            Delivery::Sent => "sent",
+            Delivery::Unacked => "unacked",
+            Delivery::Failed(_) => "failed",
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Delivery::Failed(_))
+    }
+}
+
+/// 按收件人名发送的结果
+#[derive(Debug)]
+pub struct SendOutcome {
+    /// 命令行给出的收件人
+    pub recipient: String,
+    /// 解析到的地址，解析失败时为 None
+    pub addr: Option<SocketAddr>,
+    pub delivery: Delivery,
+}
+
+/// 要求确认的广播在用户表上的结果
+#[derive(Debug, Clone, Default)]
+pub struct BroadcastConfirmation {
+    pub packet_no: u32,
+    /// 窗口内回复了 RECVMSG 的已知用户
+    pub confirmed: Vec<OnlineUser>,
+    /// 未回复的已知用户（对端可能根本不回复确认，不算失败）
+    pub unconfirmed: Vec<OnlineUser>,
+    /// 回复了确认但不在用户表中的地址
+    pub unknown: Vec<SocketAddr>,
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Delivery::Acked(rtt) => write!(f, "acked ({} ms)", rtt.as_millis()),
+            Delivery::Sent => write!(f, "sent"),
+            Delivery::Unacked => write!(f, "no ack"),
+            Delivery::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+/// query 得到的对端信息，未应答的项为 None
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// 客户端版本（SENDINFO）
+    pub version: Option<String>,
+    /// 离开信息（SENDABSENCEINFO）
+    pub absence: Option<String>,
+}
+
+/// 内存中保留的一条收到的消息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedMessage {
+    /// 发送方，用户名@主机名
+    pub sender: String,
+    pub from: SocketAddr,
+    pub packet_no: u32,
+    pub text: String,
+    pub received_at: SystemTime,
+}
+
+/// 用户表条目
+#[derive(Debug, Clone)]
+struct UserEntry {
+    name: String,
+    host: String,
+    addr: SocketAddr,
+    local_port: u16,
+    last_seen: Instant,
+    login: String,
+    group: String,
+    client: String,
+    confirmed: bool,
+    note: String,
+    /// 上线类报文带有离开标志
+    absent: bool,
+    /// SENDABSENCEINFO 中的离开信息，之后收到不带离开标志的上线类报文时清除
+    absence: Option<String>,
+    /// 上线类报文中的节点 ID，有则按它识别同一台机器
+    node_id: Option<String>,
+}
+
+impl UserEntry {
+    /// 报文是否来自登记的地址：地址相同，或同一 IP 经另一个监听端口到达
+    fn is_at(&self, addr: SocketAddr, local_port: u16) -> bool {
+        self.addr == addr || (self.addr.ip() == addr.ip() && self.local_port != local_port)
+    }
+}
+
+/// 节点 ID 为 node_id 的条目的键
+fn node_key(users: &HashMap<String, UserEntry>, node_id: &str) -> Option<String> {
+    users
+        .iter()
+        .find(|(_, entry)| entry.node_id.as_deref() == Some(node_id))
+        .map(|(key, _)| key.clone())
+}
+
+/// 非上线类报文只带登录名：按登录名与主机名找已有条目的键，有多条时优先来源 IP 相同的
+fn login_key(users: &HashMap<String, UserEntry>, packet: &IpMsgPacket, ip: IpAddr) -> Option<String> {
+    users
+        .iter()
+        .filter(|(_, entry)| entry.login == packet.sender_user && entry.host == packet.sender_host)
+        .max_by_key(|(_, entry)| entry.addr.ip() == ip)
+        .map(|(key, _)| key.clone())
+}
+
+/// 报文是否为带离开标志的上线类报文（ABSENCEOPT 与 MSG 的 SENDCHECKOPT 同值，只看上线类报文）
+fn is_absent(packet: &IpMsgPacket) -> bool {
+    protocol::is_presence_command(packet.command) && packet.command & commands::IPMSG_ABSENCEOPT != 0
+}
+
+/// 把 IPv4 映射的 IPv6 地址（双栈套接字上的 `::ffff:192.168.1.5`）还原为 IPv4，其他地址不变
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// 经 IPv6 套接字发往 IPv4 地址时改用映射地址，其余不变
+fn wire_addr(socket: &UdpSocket, addr: SocketAddr) -> SocketAddr {
+    match (socket.local_addr(), addr.ip()) {
+        (Ok(local), IpAddr::V4(ip)) if local.is_ipv6() => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        _ => addr,
+    }
+}
+
+/// 同名用户（用户名@主机名相同、IP 不同，常见于克隆的虚拟机）在用户表中的区分键
+fn collision_key(username: &str, ip: IpAddr) -> String {
+    format!("{}#{}", username, ip)
+}
+
+/// 等待中的应答
+#[derive(Debug)]
+struct PendingReply {
+    ip: IpAddr,
+    command: u32,
+    // RECVMSG 在附加信息中带原报文编号，按编号匹配
+    packet_no: Option<u32>,
+    tx: oneshot::Sender<IpMsgPacket>,
+}
+
+/// 监听端口对应的套接字
+#[derive(Debug, Clone)]
+struct PortSocket {
+    socket: Arc<UdpSocket>,
+    // 该端口上对端使用的端口号，用于广播
+    peer_port: u16,
+}
+
+impl PortSocket {
+    fn local_port(&self) -> u16 {
+        self.socket.local_addr().map(|addr| addr.port()).unwrap_or(0)
+    }
+}
+
+/// 一次广播在各目标上的发送结果
+#[derive(Debug, Clone, Default)]
+pub struct BroadcastSummary {
+    pub results: Vec<(SocketAddr, std::result::Result<(), String>)>,
+}
+
+impl BroadcastSummary {
+    /// 发送成功的目标数
+    pub fn succeeded(&self) -> usize {
+        self.results.iter().filter(|(_, result)| result.is_ok()).count()
+    }
+
+    /// 发送失败的目标及原因
+    pub fn failures(&self) -> impl Iterator<Item = (SocketAddr, &str)> {
+        self.results
+            .iter()
+            .filter_map(|(target, result)| result.as_ref().err().map(|e| (*target, e.as_str())))
+    }
+
+    /// 是否全部成功
+    pub fn is_complete(&self) -> bool {
+        self.succeeded() == self.results.len()
+    }
+}
+
+/// 本机身份，用于服务器自动发出的报文
+#[derive(Debug, Clone)]
+pub struct LocalIdentity {
+    /// 显示昵称
+    pub name: String,
+    pub host: String,
+    /// 登录名，为空时与昵称相同
+    pub login: String,
+    pub group: String,
+    /// 状态说明，随上线报文发出
+    pub status_note: String,
+    /// 报文版本字段中的客户端标识
+    pub client_version: String,
+    /// 随上线类报文发出的节点 ID，为空时不发
+    pub node_id: String,
+}
+
+impl LocalIdentity {
+    fn login(&self) -> &str {
+        if self.login.is_empty() { &self.name } else { &self.login }
+    }
+}
+
+impl Default for LocalIdentity {
+    fn default() -> Self {
+        Self {
+            name: "anonymous".to_string(),
+            host: "localhost".to_string(),
+            login: String::new(),
+            group: String::new(),
+            status_note: String::new(),
+            client_version: protocol::PROTOCOL_VERSION.to_string(),
+            node_id: String::new(),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct IpMsgServer {
-    socket: Arc<UdpSocket>, // 使用 Arc 共享 socket
-    users: Arc<RwLock<HashMap<String, SocketAddr>>>,
+    // 第一个为主套接字，其余为额外监听端口
+    sockets: Vec<PortSocket>,
+    users: Arc<RwLock<HashMap<String, UserEntry>>>,
     default_bind: String,
+    max_users: usize,
+    identity: Arc<std::sync::RwLock<LocalIdentity>>,
+    // 发出消息的长度限制（user.max_message_len）
+    message_limit: Arc<std::sync::RwLock<MessageLimit>>,
+    events: broadcast::Sender<NetEvent>,
+    // 地址变更确认中的用户：用户名 -> 新地址
+    probes: Arc<Mutex<HashMap<String, SocketAddr>>>,
+    // 等待应答的默认超时（network.timeout_secs）
+    timeout: Duration,
+    // 等待中的应答（消息确认、版本查询等），按登记顺序排列
+    pending: Arc<Mutex<Vec<PendingReply>>>,
+    max_pending_replies: usize,
+    // 广播目标（至少一个）；主机名目标在发送前按需重新解析
+    broadcast_targets: Arc<std::sync::RwLock<Vec<BroadcastDest>>>,
+    ansentry_jitter: Duration,
+    // 最近回复过 ANSENTRY 的用户及回复时间
+    answered: Arc<Mutex<HashMap<String, Instant>>>,
+    presence_config: Arc<std::sync::RwLock<PresenceConfig>>,
+    // 最近自动回复过的发送方及回复时间
+    auto_replied: Arc<Mutex<HashMap<String, Instant>>>,
+    dedup: Arc<Mutex<DedupCache>>,
+    presence: Arc<std::sync::RwLock<PresenceTracker>>,
+    // 免打扰期间暂存的消息：(报文, 来源, 是否可疑)
+    dnd_queue: Arc<Mutex<VecDeque<(IpMsgPacket, SocketAddr, bool)>>>,
+    // 通知监听循环投递暂存消息
+    flush: Arc<Notify>,
+    stats: Arc<ServerStats>,
+    // 配置了共享密钥时对报文签名
+    signer: Option<PacketSigner>,
+    ttl: u32,
+    // 被动模式：不发送任何自动应答与状态广播
+    passive: bool,
+    // 从任意报文学习发送方（network.learn_from_any_packet）
+    learn_from_any_packet: bool,
+    // 隐身模式：不广播在线状态、不应答上线通知，发出的报文带 NOADDLISTOPT
+    stealth: Arc<AtomicBool>,
+    // 隐身时是否已提示过单播会暴露本机
+    stealth_revealed: Arc<AtomicBool>,
+    peer_encodings: Arc<std::sync::RwLock<PeerEncodings>>,
+    // 是否转义报文固定字段中的冒号与 NUL
+    escape_fields: bool,
+    peer_filter: Arc<std::sync::RwLock<PeerFilter>>,
+    // 单独的发送套接字（临时端口），设置后所有报文都从它发出
+    send_socket: Option<Arc<UdpSocket>>,
+    // 开启 dump_packets 时写转储文件
+    dumper: Option<PacketDumper>,
+    // 最近收到的消息（debug.history_size 条）
+    recent: Arc<Mutex<VecDeque<ReceivedMessage>>>,
+    history_size: usize,
+    // 广播节流（network.min_broadcast_interval_ms）
+    pacer: Arc<Mutex<BroadcastPacer>>,
 }
 
 impl IpMsgServer {
@@ -31,153 +409,3991 @@ impl IpMsgServer {
     pub async fn new(addr: Option<String>) -> anyhow::Result<Self> {
         let bind_addr = addr.unwrap_or_else(|| format!("0.0.0.0:{}", IPMSG_PORT));
 
+        let network = NetworkConfig::default();
         let socket = Arc::new(UdpSocket::bind(&bind_addr).await?);
         socket.set_broadcast(true)?;
+        apply_ttl(&socket, network.ttl)?;
+        let peer_port = socket.local_addr()?.port();
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
 
         Ok(Self {
-            socket,
+            sockets: vec![PortSocket { socket, peer_port }],
             users: Arc::new(RwLock::new(HashMap::new())),
             default_bind: bind_addr,
+            max_users: network.max_users,
+            identity: Arc::new(std::sync::RwLock::new(LocalIdentity::default())),
+            message_limit: Arc::new(std::sync::RwLock::new(MessageLimit::default())),
+            events,
+            probes: Arc::new(Mutex::new(HashMap::new())),
+            timeout: network.timeout(),
+            pending: Arc::new(Mutex::new(Vec::new())),
+            max_pending_replies: network.max_pending_replies,
+            broadcast_targets: Arc::new(std::sync::RwLock::new(vec![BroadcastDest {
+                addr: SocketAddr::from(([255, 255, 255, 255], IPMSG_PORT)),
+                host: None,
+            }])),
+            ansentry_jitter: Duration::from_millis(network.ansentry_jitter_ms),
+            answered: Arc::new(Mutex::new(HashMap::new())),
+            presence_config: Arc::new(std::sync::RwLock::new(PresenceConfig::default())),
+            auto_replied: Arc::new(Mutex::new(HashMap::new())),
+            dedup: Arc::new(Mutex::new(DedupCache::default())),
+            presence: Arc::new(std::sync::RwLock::new(PresenceTracker::default())),
+            dnd_queue: Arc::new(Mutex::new(VecDeque::new())),
+            flush: Arc::new(Notify::new()),
+            stats: Arc::new(ServerStats::default()),
+            signer: None,
+            ttl: network.ttl,
+            passive: false,
+            learn_from_any_packet: network.learn_from_any_packet,
+            stealth: Arc::new(AtomicBool::new(false)),
+            stealth_revealed: Arc::new(AtomicBool::new(false)),
+            peer_encodings: Arc::new(std::sync::RwLock::new(PeerEncodings::default())),
+            escape_fields: false,
+            peer_filter: Arc::new(std::sync::RwLock::new(PeerFilter::default())),
+            send_socket: None,
+            dumper: None,
+            recent: Arc::new(Mutex::new(VecDeque::new())),
+            history_size: DebugConfig::default().history_size,
+            pacer: Arc::new(Mutex::new(BroadcastPacer::new(Duration::from_millis(
+                network.min_broadcast_interval_ms,
+            )))),
         })
     }
 
+    /// 按配置创建实例（每个监听端口绑定一个套接字）
+    pub async fn from_config(config: &AppConfig) -> anyhow::Result<Self> {
+        let network = &config.network;
+        let ports = network.listen_ports();
+        let mut server = Self::new(Some(format!("{}:{}", network.bind_ip, ports[0]))).await?;
+        server.ttl = network.ttl;
+        apply_ttl(&server.sockets[0].socket, network.ttl)?;
+        for &port in &ports[1..] {
+            let bind: SocketAddr = format!("{}:{}", network.bind_ip, port).parse()?;
+            server.bind_extra_port(bind, port).await?;
+        }
+        server.max_users = network.max_users;
+        server.max_pending_replies = network.max_pending_replies;
+        server.dedup = Arc::new(Mutex::new(DedupCache::with_capacity(
+            DEFAULT_DEDUP_TTL,
+            network.max_dedup_entries,
+        )));
+        server.timeout = network.timeout();
+        server.ansentry_jitter = Duration::from_millis(network.ansentry_jitter_ms);
+        server.pacer = Arc::new(Mutex::new(BroadcastPacer::new(Duration::from_millis(
+            network.min_broadcast_interval_ms,
+        ))));
+        server.passive = config.debug.passive;
+        server.learn_from_any_packet = network.learn_from_any_packet;
+        server.stealth.store(config.presence.stealth, Ordering::Relaxed);
+        server.history_size = config.debug.history_size;
+        server.events = broadcast::channel(config.debug.event_channel_capacity.max(1)).0;
+        server.escape_fields = config.encoding.escape_fields;
+        server.set_peer_encodings(PeerEncodings::from_config(&config.encoding.peers));
+        server.set_peer_filter(config.security.peer_filter());
+        server.set_presence_config(config.presence.clone());
+        if !config.security.psk.is_empty() {
+            server.signer = Some(PacketSigner::new(&config.security.psk));
+        }
+        server.set_broadcast_specs(&network.broadcast_targets()?, ports[0])?;
+        if config.debug.dump_packets {
+            let debug = &config.debug;
+            let writer = DumpWriter::open(debug.dump_path(), debug.dump_max_bytes, debug.dump_keep)?;
+            server.dumper = Some(PacketDumper::spawn(writer));
+        }
+        if network.separate_send_socket {
+            server.bind_send_socket(format!("{}:0", network.bind_ip).parse()?).await?;
+        }
+        Ok(server)
+    }
+
+    /// 绑定单独的发送套接字，之后广播与单播都从它发出
+    ///
+    /// 接收套接字仍在 IPMsg 端口上，自己的广播不会再回环到接收端；
+    /// 对端回复到发送端口的单播报文也会被接收
+    pub(crate) async fn bind_send_socket(&mut self, bind: SocketAddr) -> Result<()> {
+        let socket = UdpSocket::bind(bind).await?;
+        socket.set_broadcast(true)?;
+        apply_ttl(&socket, self.ttl)?;
+        self.send_socket = Some(Arc::new(socket));
+        Ok(())
+    }
+
+    /// 单独的发送套接字的本地地址，未启用时为 None
+    pub fn send_addr(&self) -> Option<SocketAddr> {
+        self.send_socket.as_ref().and_then(|socket| socket.local_addr().ok())
+    }
+
+    /// 额外绑定一个监听端口，peer_port 为该端口上广播使用的目标端口
+    pub(crate) async fn bind_extra_port(&mut self, bind: SocketAddr, peer_port: u16) -> Result<()> {
+        let socket = UdpSocket::bind(bind).await?;
+        socket.set_broadcast(true)?;
+        apply_ttl(&socket, self.ttl)?;
+        self.sockets.push(PortSocket {
+            socket: Arc::new(socket),
+            peer_port,
+        });
+        Ok(())
+    }
+
     /// 获取实际绑定地址
     pub fn bound_addr(&self) -> &str {
         &self.default_bind
     }
 
-    pub async fn broadcast(&self, packet: &IpMsgPacket) -> Result<()> {
-        self.socket
-            .send_to(
-                packet.encode().as_bytes(),
-                format!("255.255.255.255:{}", IPMSG_PORT),
-            )
-            .await?;
+    /// 获取主套接字的本地地址（绑定端口为 0 时可得到实际端口）
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.sockets[0].socket.local_addr()?)
+    }
+
+    /// 所有监听套接字的本地地址
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets
+            .iter()
+            .filter_map(|port_socket| port_socket.socket.local_addr().ok())
+            .collect()
+    }
+
+    /// 主套接字当前的 TTL（IPv6 为跳数限制）
+    pub fn ttl(&self) -> Result<u32> {
+        let socket = socket2::SockRef::from(self.sockets[0].socket.as_ref());
+        if self.local_addr()?.is_ipv4() {
+            Ok(socket.ttl_v4()?)
+        } else {
+            Ok(socket.unicast_hops_v6()?)
+        }
+    }
+
+    /// 主广播目标（第一个）
+    pub fn broadcast_target(&self) -> SocketAddr {
+        self.broadcast_targets.read().unwrap()[0].addr
+    }
+
+    /// 所有广播目标的当前地址
+    pub fn broadcast_targets(&self) -> Vec<SocketAddr> {
+        self.broadcast_targets.read().unwrap().iter().map(|dest| dest.addr).collect()
+    }
+
+    /// 运行时切换为单个广播目标（须与绑定地址属于同一地址族）
+    pub fn set_broadcast_target(&self, addr: SocketAddr) -> Result<()> {
+        self.check_family(addr)?;
+        *self.broadcast_targets.write().unwrap() = vec![BroadcastDest { addr, host: None }];
         Ok(())
     }
 
-    pub async fn send_to(&self, packet: &IpMsgPacket, addr: &SocketAddr) -> Result<()> {
-        self.socket
-            .send_to(packet.encode().as_bytes(), addr)
-            .await?;
+    fn check_family(&self, addr: SocketAddr) -> Result<()> {
+        let local = self.local_addr()?;
+        if local.is_ipv4() != addr.is_ipv4() {
+            return Err(anyhow::anyhow!(
+                "Broadcast target {} does not match bound address family of {}",
+                addr,
+                local
+            ));
+        }
         Ok(())
     }
 
-    pub async fn listen<F>(&self, callback: F, config: Arc<AppConfig>) -> Result<()>
-    where
-        F: Fn(IpMsgPacket, SocketAddr),
-    {
-        let mut buf = [0; 1024];
-        let mut consecutive_errors = 0;
-        const MAX_CONSECUTIVE_ERRORS: u8 = 5;
+    /// 按 broadcast_ips 的一项切换为单个广播目标
+    pub fn set_broadcast_spec(&self, target: &BroadcastTarget, port: u16) -> Result<()> {
+        self.set_broadcast_specs(std::slice::from_ref(target), port)
+    }
 
-        loop {
-            // 1. 接收数据
-            let (len, addr) = match self.socket.recv_from(&mut buf).await {
-                Ok(res) => {
-                    consecutive_errors = 0;
-                    res
+    /// 按 broadcast_ips 的配置切换广播目标；主机名在下次广播时解析，解析之前沿用原位置上的地址
+    ///
+    /// 任一 IP 的地址族与绑定地址不一致时不做任何改动
+    pub fn set_broadcast_specs(&self, targets: &[BroadcastTarget], port: u16) -> Result<()> {
+        if targets.is_empty() {
+            return Err(anyhow::anyhow!("At least one broadcast target is required"));
+        }
+        let current = self.broadcast_targets();
+        let mut dests = Vec::with_capacity(targets.len());
+        for (index, target) in targets.iter().enumerate() {
+            dests.push(match target {
+                BroadcastTarget::Ip(ip) => {
+                    let addr = SocketAddr::new(*ip, port);
+                    self.check_family(addr)?;
+                    BroadcastDest { addr, host: None }
                 }
-                Err(e) => {
-                    consecutive_errors += 1;
-                    eprintln!("[Error] Receive failed ({}): {}", consecutive_errors, e);
-
-                    if consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
-                        eprintln!("[Fatal] Too many errors, shutting down listener");
-                        return Err(e.into());
+                BroadcastTarget::Host(host) => {
+                    let previous = current.get(index).unwrap_or(&current[0]);
+                    BroadcastDest {
+                        addr: SocketAddr::new(previous.ip(), port),
+                        host: Some(BroadcastHost {
+                            name: host.clone(),
+                            resolved_at: None,
+                        }),
                     }
-                    continue;
                 }
+            });
+        }
+        *self.broadcast_targets.write().unwrap() = dests;
+        Ok(())
+    }
+
+    /// 主机名目标的缓存过期时重新解析，失败时保留上次可用的地址
+    async fn refresh_broadcast_hosts(&self) {
+        let stale: Vec<(usize, String, SocketAddr)> = self
+            .broadcast_targets
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, dest)| {
+                let host = dest.host.as_ref().filter(|host| host.is_stale())?;
+                Some((index, host.name.clone(), dest.addr))
+            })
+            .collect();
+        let ipv4 = self.local_addr().map_or(true, |addr| addr.is_ipv4());
+        for (index, host, current) in stale {
+            let resolved = tokio::net::lookup_host((host.as_str(), current.port()))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|mut addrs| {
+                    addrs
+                        .find(|addr| addr.is_ipv4() == ipv4)
+                        .ok_or_else(|| "no address of the bound family".to_string())
+                });
+            let mut targets = self.broadcast_targets.write().unwrap();
+            // 解析期间目标被改掉时放弃这次结果
+            let Some(dest) = targets
+                .get_mut(index)
+                .filter(|dest| dest.host.as_ref().is_some_and(|entry| entry.name == host))
+            else {
+                continue;
             };
-            println!("[Recv] {} bytes from {}", len, addr);
+            if let Some(entry) = dest.host.as_mut() {
+                entry.resolved_at = Some(Instant::now());
+            }
+            match resolved {
+                Ok(addr) => dest.addr = addr,
+                Err(e) => log::warn!(
+                    "Failed to resolve broadcast host {}: {}, keeping {}",
+                    host,
+                    e,
+                    current.ip()
+                ),
+            }
+        }
+    }
 
-            // 1. 根据配置解码原始字节
-            match IpMsgPacket::decode_with_config(&buf[..len], &config) {
-                Ok(packet) => {
-                    println!(
-                        "[Recv] From {}: {}@{} (Cmd: {:#x})",
-                        addr, packet.sender_name, packet.group_name, packet.command
-                    );
-                    self.handle_packet(&packet, &addr).await;
-                    callback(packet, addr);
-                }
-                Err(e) => {
-                    // 调试用：输出原始十六进制
-                    let hex_str = buf[..len]
-                        .iter()
-                        .map(|b| format!("{:02x}", b))
-                        .collect::<String>();
-                    println!(
-                        "[Warn] Decode failed from {}: {}\nRaw({} bytes): {}",
-                        addr, e, len, hex_str
-                    );
-                }
-            }
-
-            // // 1. 提取可打印字符串部分
-            // let string_part = extract_string_part(&buf[..len]);
-            // println!("[Debug] Received ({} bytes): {}", len, string_part);
-
-            // // 2. 尝试解析协议包
-            // match IpMsgPacket::decode(&string_part) {
-            //     Ok(packet) => {
-            //         self.handle_packet(&packet, &addr).await;
-            //         callback(packet, addr);
-            //     }
-            //     Err(e) => {
-            //         println!(
-            //             "[Warn] Decode failed from {}: {} (Raw: {})",
-            //             addr, e, string_part
-            //         );
-            //     }
-            // }
+    /// 设置本机昵称与主机名
+    pub fn set_identity(&self, name: &str, host: &str) {
+        let mut identity = self.identity.write().unwrap();
+        identity.name = name.to_string();
+        identity.host = host.to_string();
+    }
+
+    /// 按用户配置设置本机身份（昵称、登录名、主机名、分组）与消息长度限制
+    pub fn set_user(&self, user: &UserConfig) {
+        *self.message_limit.write().unwrap() = user.message_limit();
+        let mut identity = self.identity.write().unwrap();
+        *identity = LocalIdentity {
+            name: user.name.clone(),
+            host: user.host.clone(),
+            login: user.login(),
+            group: user.group.clone(),
+            status_note: user.status_note.clone(),
+            client_version: user.client_version.clone(),
+            node_id: std::mem::take(&mut identity.node_id),
+        };
+    }
+
+    /// 设置本机节点 ID，之后的上线类报文都带上它，对端据此在改名或换地址后仍认出本机
+    pub fn set_node_id(&self, node_id: &str) {
+        self.identity.write().unwrap().node_id = node_id.to_string();
+    }
+
+    /// 是否为被动模式（只接收，不自动发送）
+    pub fn is_passive(&self) -> bool {
+        self.passive
+    }
+
+    /// 是否为隐身模式
+    pub fn is_stealth(&self) -> bool {
+        self.stealth.load(Ordering::Relaxed)
+    }
+
+    /// 进入或离开隐身模式：进入时广播下线让对端移除本机，离开时正常广播上线
+    pub async fn set_stealth(&self, stealth: bool) -> Result<()> {
+        if stealth == self.is_stealth() {
+            return Ok(());
         }
+        if stealth {
+            let exit = if self.passive {
+                Ok(())
+            } else {
+                self.broadcast(&self.build_packet(commands::BR_EXIT, "")).await
+            };
+            self.stealth.store(true, Ordering::Relaxed);
+            return exit;
+        }
+        self.stealth.store(false, Ordering::Relaxed);
+        self.stealth_revealed.store(false, Ordering::Relaxed);
+        if self.passive {
+            return Ok(());
+        }
+        self.broadcast(&self.presence_packet(commands::BR_ENTRY)).await
     }
 
-    /// 获取当前在线用户（基础版）
-    pub async fn get_online_users_basic(&self) -> Vec<String> {
-        self.users.read().await.keys().cloned().collect()
+    /// 隐身时给要发出的报文加上 NOADDLISTOPT；发出消息会暴露本机，第一次时提示
+    fn stealth_packet(&self, packet: &IpMsgPacket, dest: &SocketAddr) -> Option<IpMsgPacket> {
+        if !self.is_stealth() {
+            return None;
+        }
+        let auto_reply = packet.command & commands::IPMSG_AUTORETOPT != 0;
+        if packet.command & 0xff == commands::MSG
+            && !auto_reply
+            && !self.stealth_revealed.swap(true, Ordering::Relaxed)
+        {
+            log::warn!("Stealth mode: sending to {} reveals this host to the recipient", dest);
+        }
+        Some(IpMsgPacket {
+            command: packet.command | commands::IPMSG_NOADDLISTOPT,
+            ..packet.clone()
+        })
     }
 
-    /// 获取带详细信息的在线用户
-    pub async fn get_online_users(&self) -> Vec<OnlineUser> {
-        self.users
+    /// 对要发出的 MSG 应用长度限制：未超长时返回 None，超长时按策略截断或返回错误
+    fn limited_packet(&self, packet: &IpMsgPacket) -> Result<Option<IpMsgPacket>> {
+        if packet.command & 0xff != commands::MSG {
+            return Ok(None);
+        }
+        let limit = *self.message_limit.read().unwrap();
+        let Some(text) = limit.apply(&packet.additional_msg)? else {
+            return Ok(None);
+        };
+        log::warn!("Message truncated to {} characters (user.max_message_len)", limit.max_len);
+        Ok(Some(IpMsgPacket {
+            additional_msg: text,
+            ..packet.clone()
+        }))
+    }
+
+    /// 替换按对端覆盖的编码表（配置热加载时调用）
+    pub fn set_peer_encodings(&self, peers: PeerEncodings) {
+        *self.peer_encodings.write().unwrap() = peers;
+    }
+
+    /// 替换黑名单/白名单（启动或配置热加载时调用）
+    pub fn set_peer_filter(&self, filter: PeerFilter) {
+        *self.peer_filter.write().unwrap() = filter;
+    }
+
+    /// 运行中加入黑名单，已存在时返回 false
+    pub fn block_peer(&self, entry: &str) -> Result<bool> {
+        self.peer_filter.write().unwrap().block(entry)
+    }
+
+    /// 运行中移出黑名单，不存在时返回 false
+    pub fn unblock_peer(&self, entry: &str) -> bool {
+        self.peer_filter.write().unwrap().unblock(entry)
+    }
+
+    /// 当前的黑名单/白名单
+    pub fn peer_filter(&self) -> PeerFilter {
+        self.peer_filter.read().unwrap().clone()
+    }
+
+    /// 是否接受该报文（按来源 IP 与 登录名/昵称@主机名 匹配名单）
+    fn accepts(&self, packet: &IpMsgPacket, from: IpAddr) -> bool {
+        let login = format!("{}@{}", packet.sender_user, packet.sender_host);
+        let name = format!("{}@{}", packet.sender_name, packet.sender_host);
+        self.peer_filter
             .read()
-            .await
-            .iter()
-            .map(|(name, addr)| {
-                let (username, hostname) = name.split_once('@').unwrap_or(("unknown", "unknown"));
+            .unwrap()
+            .accepts(from, &[login.as_str(), name.as_str()])
+    }
+
+    /// 订阅服务器事件
+    ///
+    /// 处理慢的订阅者落后超过 debug.event_channel_capacity 条时收到 Lagged，可用 recv_lossy 跳过并继续
+    pub fn subscribe(&self) -> broadcast::Receiver<NetEvent> {
+        self.events.subscribe()
+    }
 
-                OnlineUser {
-                    username: username.to_string(),
-                    hostname: hostname.to_string(),
-                    ip: addr.ip().to_string(),
-                    port: addr.port(),
+    /// 等待下一条投递的消息，超时返回 None
+    ///
+    /// 从调用时开始订阅，调用之前已投递的消息不会返回；需要 listen 在运行
+    pub async fn recv_next(&self, timeout: Duration) -> Option<(IpMsgPacket, SocketAddr)> {
+        let mut events = self.subscribe();
+        let next = async {
+            loop {
+                match recv_lossy(&mut events, "recv_next").await? {
+                    NetEvent::Packet { packet, from, .. } if packet.command & 0xff == commands::MSG => {
+                        return Some((packet, from));
+                    }
+                    _ => continue,
                 }
-            })
-            .collect()
+            }
+        };
+        tokio::time::timeout(timeout, next).await.ok().flatten()
     }
 
-    pub async fn get_user_addr(&self, username: &str) -> Option<SocketAddr> {
-        let users = self.users.read().await;
-        users.get(username).cloned()
+    /// 运行统计快照
+    pub async fn stats(&self) -> StatsSnapshot {
+        let users = self.users.read().await.len();
+        let pending_replies = self.pending.lock().unwrap().len();
+        let dedup = self.dedup.lock().unwrap();
+        StatsSnapshot {
+            users,
+            users_evicted: self.stats.users_evicted(),
+            dedup_entries: dedup.len(),
+            dedup_evicted: dedup.evicted(),
+            pending_replies,
+            pending_replies_evicted: self.stats.replies_evicted(),
+            packets_blocked: self.stats.packets_blocked(),
+            broadcasts_throttled: self.stats.broadcasts_throttled(),
+            user_collisions: self.stats.user_collisions(),
+        }
     }
-    // 更新 handle_packet 存储完整用户名
-    async fn handle_packet(&self, packet: &IpMsgPacket, addr: &SocketAddr) {
-        let mut users = self.users.write().await;
-        let username = format!("{}@{}", packet.sender_name, packet.sender_host);
-        let command = packet.command & 0xff;
-        match command {
-            commands::BR_ENTRY => {
-                users.insert(username, *addr);
+
+    /// 推送事件（没有订阅者时直接丢弃）
+    fn emit(&self, event: NetEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// 由服务器之外的组件（如配置热加载）推送事件
+    pub fn notify(&self, event: NetEvent) {
+        self.emit(event);
+    }
+
+    /// 以本机身份构造报文
+    pub fn build_packet(&self, command: u32, additional_msg: &str) -> IpMsgPacket {
+        let identity = self.identity.read().unwrap();
+        let extension = if protocol::carries_node_id(command) && !identity.node_id.is_empty() {
+            format!("{}{}", protocol::NODE_ID_PREFIX, identity.node_id)
+        } else {
+            String::new()
+        };
+        IpMsgPacket {
+            version: identity.client_version.clone(),
+            packet_no: rand::random(),
+            sender_user: identity.login().to_string(),
+            sender_name: identity.name.clone(),
+            sender_host: identity.host.clone(),
+            group_name: identity.group.clone(),
+            command,
+            additional_msg: additional_msg.to_string(),
+            extension,
+            ..Default::default()
+        }
+    }
+
+    /// 当前在线状态
+    pub fn state(&self) -> PresenceState {
+        self.presence.read().unwrap().state().clone()
+    }
+
+    /// 处于当前状态的时长
+    pub fn state_duration(&self) -> Duration {
+        self.presence.read().unwrap().elapsed()
+    }
+
+    /// 切换在线状态并广播状态变更；离开免打扰时投递暂存的消息
+    pub async fn set_state(&self, state: PresenceState) -> Result<()> {
+        let previous = self.presence.write().unwrap().transition(state.clone());
+        if previous == PresenceState::DoNotDisturb && state != PresenceState::DoNotDisturb {
+            self.flush.notify_one();
+        }
+        if previous != state && !self.passive {
+            let refresh = self.presence_packet(commands::IPMSG_BR_ABSENCE);
+            self.broadcast(&refresh).await?;
+        }
+        Ok(())
+    }
+
+    /// 替换离开信息与自动回复设置（启动或配置热加载时调用）
+    pub fn set_presence_config(&self, config: PresenceConfig) {
+        *self.presence_config.write().unwrap() = config;
+    }
+
+    /// 切换为离开状态，未指定内容时使用配置的离开信息
+    pub async fn set_away(&self, message: Option<&str>) -> Result<()> {
+        let message = match message {
+            Some(message) => message.to_string(),
+            None => self.presence_config.read().unwrap().away_message.clone(),
+        };
+        self.set_state(PresenceState::Away { message }).await
+    }
+
+    /// 构造携带当前状态的上线/应答/状态变更报文
+    pub fn presence_packet(&self, command: u32) -> IpMsgPacket {
+        let state = self.state();
+        let text = if state.is_absent() {
+            state.absence_text().to_string()
+        } else {
+            self.identity.read().unwrap().status_note.clone()
+        };
+        self.build_packet(state.apply_to_command(command), &text)
+    }
+
+    /// 在每个监听端口上广播（主端口使用广播目标的端口，其余使用各自的对端端口）
+    ///
+    /// 与上一次广播的间隔小于 min_broadcast_interval_ms 时先排队等待；
+    /// 部分目标失败时打印警告，全部失败时返回错误
+    pub async fn broadcast(&self, packet: &IpMsgPacket) -> Result<()> {
+        let presence = matches!(
+            packet.command & 0xff,
+            commands::BR_ENTRY | commands::BR_EXIT | commands::IPMSG_ANSENTRY | commands::IPMSG_BR_ABSENCE
+        );
+        if presence && self.is_stealth() {
+            log::debug!("Stealth mode: not broadcasting presence");
+            return Ok(());
+        }
+        let limited = self.limited_packet(packet)?;
+        let packet = limited.as_ref().unwrap_or(packet);
+        if !self.pace_broadcast(packet).await {
+            return Ok(());
+        }
+        let summary = self.broadcast_all(packet).await;
+        for (target, error) in summary.failures() {
+            log::warn!("Broadcast to {} failed: {}", target, error);
+        }
+        if summary.succeeded() == 0 {
+            return Err(anyhow::anyhow!("Broadcast failed on all {} targets", summary.results.len()));
+        }
+        Ok(())
+    }
+
+    /// 把同一条消息广播 repeat 次（用于压力测试），相邻两次间隔 interval，
+    /// 每次使用新的报文编号；返回各次的报文编号
+    pub async fn broadcast_repeat(&self, command: u32, message: &str, repeat: u32, interval: Duration) -> Result<Vec<u32>> {
+        let mut packet_nos: Vec<u32> = Vec::new();
+        for i in 0..repeat {
+            if i > 0 {
+                tokio::time::sleep(interval).await;
             }
-            commands::IPMSG_ANSENTRY => {
-                users.insert(username, *addr);
+            let mut packet = self.build_packet(command, message);
+            while packet_nos.contains(&packet.packet_no) {
+                packet.packet_no = rand::random();
+            }
+            self.broadcast(&packet).await?;
+            packet_nos.push(packet.packet_no);
+        }
+        Ok(packet_nos)
+    }
+
+    /// 广播要求确认的消息，在 window 内收集各对端的 RECVMSG，再与当前用户表对照
+    ///
+    /// 需要 listen 在运行
+    pub async fn broadcast_confirmed(&self, command: u32, message: &str, window: Duration) -> Result<BroadcastConfirmation> {
+        // 先订阅，避免漏掉广播后立即到达的确认
+        let mut events = self.subscribe();
+        let packet = self.build_packet(command | commands::IPMSG_SENDCHECKOPT, message);
+        self.broadcast(&packet).await?;
+        let mut acked: Vec<SocketAddr> = Vec::new();
+        let collect = async {
+            // 落后时丢失的确认只会少计，不影响其余的统计
+            while let Some(event) = recv_lossy(&mut events, "Broadcast confirmation").await {
+                if let NetEvent::Packet { packet: reply, from, .. } = event
+                    && reply.command & 0xff == commands::IPMSG_RECVMSG
+                    && reply.additional_msg.trim().parse::<u32>().ok() == Some(packet.packet_no)
+                    && !acked.contains(&from)
+                {
+                    acked.push(from);
+                }
             }
-            commands::BR_EXIT => {
-                users.remove(&username);
+        };
+        let _ = tokio::time::timeout(window, collect).await;
+
+        let (confirmed, unconfirmed): (Vec<OnlineUser>, Vec<OnlineUser>) = self
+            .get_online_users()
+            .await
+            .into_iter()
+            .partition(|user| acked.contains(&user.addr));
+        acked.retain(|addr| !confirmed.iter().any(|user| user.addr == *addr));
+        Ok(BroadcastConfirmation {
+            packet_no: packet.packet_no,
+            confirmed,
+            unconfirmed,
+            unknown: acked,
+        })
+    }
+
+    /// 等待本次广播的发送时机；排队期间被更新的在线状态刷新取代时返回 false
+    async fn pace_broadcast(&self, packet: &IpMsgPacket) -> bool {
+        let presence = packet.command & 0xff == commands::IPMSG_BR_ABSENCE;
+        let (slot, generation, waiting) = {
+            let mut pacer = self.pacer.lock().unwrap();
+            let generation = presence.then(|| pacer.presence_refresh());
+            (pacer.reserve(Instant::now()), generation, pacer.waiting())
+        };
+        let Slot::At(when) = slot else {
+            return true;
+        };
+        self.stats.record_broadcast_throttled();
+        if waiting == QUEUE_WARN_THRESHOLD {
+            log::warn!(
+                "{} broadcasts queued by network.min_broadcast_interval_ms, is something looping?",
+                waiting
+            );
+        }
+        tokio::time::sleep_until(when.into()).await;
+        let mut pacer = self.pacer.lock().unwrap();
+        pacer.finish_waiting();
+        let latest = generation.is_none_or(|generation| pacer.is_latest_presence(generation));
+        if !latest {
+            log::debug!("Skipping presence refresh superseded by a newer one");
+        }
+        latest
+    }
+
+    /// 在所有监听端口上并发广播（不经过节流），返回每个目标的发送结果
+    pub async fn broadcast_all(&self, packet: &IpMsgPacket) -> BroadcastSummary {
+        self.refresh_broadcast_hosts().await;
+        let targets = self.broadcast_targets();
+        let stealthed = self.stealth_packet(packet, &targets[0]);
+        let packet = stealthed.as_ref().unwrap_or(packet);
+        let data = self.wire_bytes(packet, None);
+        let mut dests: Vec<(SocketAddr, &PortSocket)> = Vec::new();
+        for target in &targets {
+            for (index, port_socket) in self.sockets.iter().enumerate() {
+                let dest = if index == 0 {
+                    *target
+                } else {
+                    SocketAddr::new(target.ip(), port_socket.peer_port)
+                };
+                // 多个目标解析到同一地址时只发一次
+                if !dests.iter().any(|(existing, _)| *existing == dest) {
+                    dests.push((dest, port_socket));
+                }
             }
-            _ => {}
         }
+        let sends = dests.into_iter().map(|(dest, port_socket)| {
+            let data = &data;
+            let socket = self.send_socket.as_ref().unwrap_or(&port_socket.socket);
+            async move {
+                let result = check_sent(socket.send_to(data, wire_addr(socket, dest)).await, data.len())
+                    .map_err(|e| e.to_string());
+                if result.is_ok() {
+                    self.dump(DumpDirection::Out, dest, data, Ok(packet));
+                }
+                (dest, result)
+            }
+        });
+        BroadcastSummary {
+            results: futures::future::join_all(sends).await,
+        }
+    }
+
+    /// 单播发送，使用学习到该地址的端口对应的套接字
+    pub async fn send_to(&self, packet: &IpMsgPacket, addr: &SocketAddr) -> Result<()> {
+        let local_port = self
+            .users
+            .read()
+            .await
+            .values()
+            .find(|entry| entry.addr == *addr)
+            .map(|entry| entry.local_port);
+        self.send_via(packet, addr, local_port).await
+    }
+
+    /// 发送请求并等待对端的应答报文，超时返回 None
+    ///
+    /// timeout 为 None 时使用 network.timeout_secs；需要 listen 在运行
+    pub async fn request(
+        &self,
+        packet: &IpMsgPacket,
+        addr: &SocketAddr,
+        reply_command: u32,
+        timeout: Option<Duration>,
+    ) -> Result<Option<IpMsgPacket>> {
+        let (tx, rx) = oneshot::channel();
+        let packet_no = (reply_command == commands::IPMSG_RECVMSG).then_some(packet.packet_no);
+        self.register_pending(PendingReply {
+            ip: addr.ip(),
+            command: reply_command,
+            packet_no,
+            tx,
+        });
+        let sent = self.send_to(packet, addr).await;
+        let reply = match sent {
+            Ok(()) => tokio::time::timeout(timeout.unwrap_or(self.timeout), rx)
+                .await
+                .ok()
+                .and_then(|reply| reply.ok()),
+            Err(_) => None,
+        };
+        // 清理超时或发送失败留下的条目
+        self.pending.lock().unwrap().retain(|pending| !pending.tx.is_closed());
+        sent.map(|()| reply)
+    }
+
+    /// 登记等待中的应答，超出 max_pending_replies 时放弃最早的条目（其请求按未收到应答返回）
+    fn register_pending(&self, reply: PendingReply) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= self.max_pending_replies {
+            pending.retain(|pending| !pending.tx.is_closed());
+        }
+        if !pending.is_empty() && pending.len() >= self.max_pending_replies {
+            // 丢弃发送端后对应的请求立即返回 None
+            let dropped = pending.remove(0);
+            self.stats.record_reply_evicted();
+            log::warn!(
+                "Too many pending replies ({}), gave up waiting for command {:#x} from {}",
+                self.max_pending_replies, dropped.command, dropped.ip
+            );
+        }
+        pending.push(reply);
+    }
+
+    /// 要求对端确认的发送，返回收到 RECVMSG 所用的时间，超时返回 None
+    ///
+    /// 结果同时以 SendResult 事件推送
+    pub async fn send_checked(
+        &self,
+        packet: &IpMsgPacket,
+        addr: &SocketAddr,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Duration>> {
+        let result = self.send_checked_once(packet, addr, timeout).await;
+        self.emit_send_result(packet.packet_no, &result);
+        result
+    }
+
+    fn emit_send_result(&self, packet_no: u32, result: &Result<Option<Duration>>) {
+        self.emit(NetEvent::SendResult {
+            packet_no,
+            delivered: matches!(result, Ok(Some(_))),
+        });
+    }
+
+    /// send_checked 的一次发送，不推送事件
+    async fn send_checked_once(
+        &self,
+        packet: &IpMsgPacket,
+        addr: &SocketAddr,
+        timeout: Option<Duration>,
+    ) -> Result<Option<Duration>> {
+        let mut packet = packet.clone();
+        packet.command |= commands::IPMSG_SENDCHECKOPT;
+        let started = Instant::now();
+        let reply = self
+            .request(&packet, addr, commands::IPMSG_RECVMSG, timeout)
+            .await?;
+        Ok(reply.map(|_| started.elapsed()))
+    }
+
+    /// 要求对端确认的发送，未确认时重发（使用同一报文编号，对端据此去重），共发送 ACK_ATTEMPTS 次
+    ///
+    /// timeout 为总的等待时长，平均分给每次发送；返回从第一次发送到收到 RECVMSG 的时间，始终未确认返回 None
+    /// 结果在重发结束后以一个 SendResult 事件推送
+    pub async fn send_reliable(
+        &self,
+        packet: &IpMsgPacket,
+        addr: &SocketAddr,
+        timeout: Duration,
+    ) -> Result<Option<Duration>> {
+        let result = self.retransmit(packet, addr, timeout).await;
+        self.emit_send_result(packet.packet_no, &result);
+        result
+    }
+
+    async fn retransmit(&self, packet: &IpMsgPacket, addr: &SocketAddr, timeout: Duration) -> Result<Option<Duration>> {
+        let per_attempt = timeout / ACK_ATTEMPTS;
+        let started = Instant::now();
+        for attempt in 1..=ACK_ATTEMPTS {
+            if self.send_checked_once(packet, addr, Some(per_attempt)).await?.is_some() {
+                return Ok(Some(started.elapsed()));
+            }
+            log::debug!("No ack from {} (attempt {} of {})", addr, attempt, ACK_ATTEMPTS);
+        }
+        Ok(None)
+    }
+
+    /// 查询对端的客户端版本，超时返回 None
+    pub async fn query_info(&self, addr: &SocketAddr, timeout: Option<Duration>) -> Result<Option<String>> {
+        let packet = self.build_packet(commands::IPMSG_GETINFO, "");
+        let reply = self
+            .request(&packet, addr, commands::IPMSG_SENDINFO, timeout)
+            .await?;
+        Ok(reply.map(|reply| reply.additional_msg))
+    }
+
+    /// 查询对端的离开信息（在线时为官方客户端的固定文本），超时返回 None
+    pub async fn query_absence(&self, addr: &SocketAddr, timeout: Option<Duration>) -> Result<Option<String>> {
+        let packet = self.build_packet(commands::IPMSG_GETABSENCEINFO, "");
+        let reply = self
+            .request(&packet, addr, commands::IPMSG_SENDABSENCEINFO, timeout)
+            .await?;
+        Ok(reply.map(|reply| reply.additional_msg))
+    }
+
+    /// 同时查询对端的客户端版本与（absence 为 true 时）离开信息
+    pub async fn query_peer(&self, addr: &SocketAddr, absence: bool, timeout: Option<Duration>) -> Result<PeerInfo> {
+        let absence_query = async {
+            if absence {
+                self.query_absence(addr, timeout).await
+            } else {
+                Ok(None)
+            }
+        };
+        let (version, absence) = tokio::join!(self.query_info(addr, timeout), absence_query);
+        Ok(PeerInfo {
+            version: version?,
+            absence: absence?,
+        })
+    }
+
+    /// 收到的报文若是等待中的应答，交给等待方
+    fn resolve_pending(&self, packet: &IpMsgPacket, from: &SocketAddr) {
+        let command = packet.command & 0xff;
+        let mut pending = self.pending.lock().unwrap();
+        let Some(index) = pending.iter().position(|pending| {
+            pending.ip == from.ip()
+                && pending.command == command
+                && pending
+                    .packet_no
+                    .is_none_or(|no| packet.additional_msg.trim().parse() == Ok(no))
+        }) else {
+            return;
+        };
+        let _ = pending.swap_remove(index).tx.send(packet.clone());
+    }
+
+    /// 从指定本地端口的套接字发送（未指定或找不到时使用主套接字）
+    async fn send_via(&self, packet: &IpMsgPacket, addr: &SocketAddr, local_port: Option<u16>) -> Result<()> {
+        let port_socket = local_port
+            .and_then(|port| self.sockets.iter().find(|s| s.local_port() == port))
+            .unwrap_or(&self.sockets[0]);
+        let limited = self.limited_packet(packet)?;
+        let packet = limited.as_ref().unwrap_or(packet);
+        let encoding = self.encoding_for(addr).await;
+        let stealthed = self.stealth_packet(packet, addr);
+        let packet = stealthed.as_ref().unwrap_or(packet);
+        let data = self.wire_bytes(packet, encoding);
+        let socket = self.send_socket.as_ref().unwrap_or(&port_socket.socket);
+        let sent = socket.send_to(&data, wire_addr(socket, *addr)).await;
+        check_sent(sent, data.len())?;
+        self.dump(DumpDirection::Out, *addr, &data, Ok(packet));
+        Ok(())
+    }
+
+    /// 按发往 addr 的方式编码报文（对端编码与签名），供经 TCP 发出的文件请求使用
+    pub(crate) async fn encode_for(&self, packet: &IpMsgPacket, addr: &SocketAddr) -> Vec<u8> {
+        let encoding = self.encoding_for(addr).await;
+        self.wire_bytes(packet, encoding)
+    }
+
+    /// 校验签名并解码经 TCP 收到的报文（文件请求）
+    pub(crate) fn decode_stream(&self, data: &[u8], from: IpAddr, config: &AppConfig) -> Result<IpMsgPacket> {
+        let data = match &self.signer {
+            Some(signer) => signer.verify(data).map_err(|e| anyhow::anyhow!("{}", e))?,
+            None => data,
+        };
+        Ok(self.decode_from(data, from, config)?)
+    }
+
+    /// 开启 dump_packets 时记录一个报文（只入队，不阻塞收发）
+    fn dump(
+        &self,
+        direction: DumpDirection,
+        peer: SocketAddr,
+        bytes: &[u8],
+        decoded: std::result::Result<&IpMsgPacket, &ProtocolError>,
+    ) {
+        if let Some(dumper) = &self.dumper {
+            dumper.record(DumpRecord::new(direction, peer, bytes, decoded));
+        }
+    }
+
+    /// 发往指定地址时按对端覆盖的编码：先按用户名@主机名，再按 IP/网段
+    async fn encoding_for(&self, addr: &SocketAddr) -> Option<&'static Encoding> {
+        let peers = self.peer_encodings.read().unwrap().clone();
+        if peers.is_empty() {
+            return None;
+        }
+        let users = self.users.read().await;
+        users
+            .iter()
+            .filter(|(_, entry)| entry.addr == *addr)
+            .find_map(|(username, entry)| {
+                peers
+                    .for_user(username)
+                    .or_else(|| peers.for_user(&format!("{}@{}", entry.login, entry.host)))
+            })
+            .or_else(|| peers.for_addr(addr.ip()))
+    }
+
+    /// 按对端覆盖的编码解码，未匹配时使用全局编码
+    ///
+    /// 用户名@主机名 的覆盖优先于 IP：先解出发送方，匹配到不同编码时重新解码
+    fn decode_from(&self, data: &[u8], from: IpAddr, config: &AppConfig) -> Result<IpMsgPacket, ProtocolError> {
+        let peers = self.peer_encodings.read().unwrap();
+        let encoding = peers
+            .for_addr(from)
+            .unwrap_or_else(|| protocol::protocol_encoding(config));
+        let packet = IpMsgPacket::decode_with(data, encoding, self.escape_fields)?;
+        let user_encoding = [&packet.sender_user, &packet.sender_name]
+            .iter()
+            .find_map(|user| peers.for_user(&format!("{}@{}", user, packet.sender_host)));
+        match user_encoding {
+            Some(user_encoding) if user_encoding != encoding => {
+                IpMsgPacket::decode_with(data, user_encoding, self.escape_fields)
+            }
+            _ => Ok(packet),
+        }
+    }
+
+    /// 编码报文（指定了对端编码时转码），配置了共享密钥时附加签名
+    fn wire_bytes(&self, packet: &IpMsgPacket, encoding: Option<&'static Encoding>) -> Vec<u8> {
+        let text = packet.encode_escaped(self.escape_fields);
+        let data = match encoding {
+            Some(encoding) => encoding.encode(&text).0.into_owned(),
+            None => text.into_bytes(),
+        };
+        match &self.signer {
+            Some(signer) => signer.sign(&data),
+            None => data,
+        }
+    }
+
+    pub async fn listen<F>(&self, callback: F, config: Arc<AppConfig>) -> Result<()>
+    where
+        F: Fn(IpMsgPacket, SocketAddr),
+    {
+        let mut consecutive_errors = 0;
+        const MAX_CONSECUTIVE_ERRORS: u8 = 5;
+
+        // 每个端口一个接收任务，统一汇入同一处理流程
+        let (tx, mut rx) = mpsc::channel(64);
+        let receivers = self
+            .sockets
+            .iter()
+            .map(|port_socket| (port_socket.socket.clone(), port_socket.local_port()))
+            .chain(self.send_socket.iter().map(|socket| {
+                let port = socket.local_addr().map(|addr| addr.port()).unwrap_or(0);
+                (socket.clone(), port)
+            }));
+        for (socket, local_port) in receivers {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0; protocol::MAX_DATAGRAM];
+                loop {
+                    let received = socket
+                        .recv_from(&mut buf)
+                        .await
+                        .map(|(len, addr)| (buf[..len].to_vec(), canonical_addr(addr)));
+                    if tx.send((received, local_port)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        let mut reorder =
+            ReorderBuffer::new(Duration::from_millis(config.network.reorder_window_ms));
+
+        loop {
+            let reorder_deadline = reorder.next_deadline();
+            let (received, local_port) = tokio::select! {
+                next = rx.recv() => match next {
+                    Some(item) => item,
+                    None => break,
+                },
+                _ = self.flush.notified() => {
+                    self.deliver_queued(&callback);
+                    continue;
+                }
+                _ = async {
+                    match reorder_deadline {
+                        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    for (packet, from, suspect) in reorder.flush_expired(Instant::now()) {
+                        self.deliver(packet, from, suspect, &callback);
+                    }
+                    continue;
+                }
+            };
+
+            // 1. 接收数据
+            let (data, addr) = match received {
+                Ok(res) => {
+                    consecutive_errors = 0;
+                    res
+                }
+                Err(e) => {
+                    consecutive_errors += 1;
+                    log::error!("Receive failed ({}): {}", consecutive_errors, e);
+
+                    if consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
+                        log::error!("Too many errors, shutting down listener");
+                        return Err(e.into());
+                    }
+                    continue;
+                }
+            };
+            // 2. 配置了共享密钥时先校验签名，失败直接丢弃
+            let data = match &self.signer {
+                Some(signer) => match signer.verify(&data) {
+                    Ok(payload) => payload.to_vec(),
+                    Err(e) => {
+                        log::warn!("Dropped packet from {}: {}", addr, e);
+                        continue;
+                    }
+                },
+                None => data,
+            };
+
+            // 3. 根据配置解码原始字节
+            let decoded = self.decode_from(&data, addr.ip(), &config);
+            // 保活报文只在 trace 级别输出
+            let keepalive = matches!(
+                &decoded,
+                Ok(packet) if packet.command & 0xff == commands::IPMSG_NOOPERATION
+            );
+            if keepalive {
+                log::trace!("Keepalive from {} on port {}", addr, local_port);
+            } else {
+                log::debug!("[Recv] {} bytes from {} on port {}", data.len(), addr, local_port);
+            }
+            self.dump(DumpDirection::In, addr, &data, decoded.as_ref());
+            if decoded.is_err() || config.debug.dump_packets {
+                self.emit(NetEvent::RawDatagram {
+                    from: addr,
+                    bytes: data.clone(),
+                    error: decoded.as_ref().err().cloned(),
+                });
+            }
+
+            match decoded {
+                Ok(packet) => {
+                    if !self.accepts(&packet, addr.ip()) {
+                        self.stats.record_packet_blocked();
+                        continue;
+                    }
+                    // 同一报文经多个端口到达时只处理一次
+                    let key = DedupKey::from_packet(&packet).with_source(addr.ip());
+                    if !self.dedup.lock().unwrap().check_and_insert(key) {
+                        // 重发的消息不再投递，但仍要确认：第一次的 RECVMSG 可能已丢失
+                        if packet.command & 0xff == commands::MSG && !self.passive && !self.is_self(&packet) {
+                            self.acknowledge(&packet, addr, local_port);
+                        }
+                        continue;
+                    }
+                    if !keepalive {
+                        log::trace!(
+                            "[Recv] From {}: {}@{} (Cmd: {:#x})",
+                            addr, packet.sender_name, packet.group_name, packet.command
+                        );
+                    }
+                    if let Some(note) = packet.overflow_note() {
+                        log::debug!("{} from {}", note, addr);
+                    }
+                    if !packet.is_compatible() {
+                        log::warn!(
+                            "{} advertises incompatible protocol version '{}'",
+                            addr, packet.version
+                        );
+                    }
+                    let suspect = self.handle_packet_via(&packet, &addr, local_port).await;
+                    for (packet, from, suspect) in
+                        reorder.push((packet, addr, suspect), Instant::now())
+                    {
+                        self.deliver(packet, from, suspect, &callback);
+                    }
+                }
+                Err(e) => {
+                    log::debug!("Decode failed from {}: {}", addr, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 投递报文；免打扰状态下消息先暂存，恢复后再投递
+    fn deliver<F>(&self, packet: IpMsgPacket, from: SocketAddr, suspect: bool, callback: &F)
+    where
+        F: Fn(IpMsgPacket, SocketAddr),
+    {
+        if packet.command & 0xff == commands::MSG && self.state() == PresenceState::DoNotDisturb {
+            self.emit(NetEvent::Queued {
+                packet: packet.clone(),
+                from,
+            });
+            self.dnd_queue.lock().unwrap().push_back((packet, from, suspect));
+            return;
+        }
+        self.emit(NetEvent::Packet {
+            packet: packet.clone(),
+            from,
+            suspect,
+        });
+        if packet.command & 0xff != commands::IPMSG_NOOPERATION {
+            callback(packet, from);
+        }
+    }
+
+    /// 投递免打扰期间暂存的消息
+    fn deliver_queued<F>(&self, callback: &F)
+    where
+        F: Fn(IpMsgPacket, SocketAddr),
+    {
+        let queued: Vec<_> = self.dnd_queue.lock().unwrap().drain(..).collect();
+        for (packet, from, suspect) in queued {
+            self.emit(NetEvent::Packet {
+                packet: packet.clone(),
+                from,
+                suspect,
+            });
+            callback(packet, from);
+        }
+    }
+
+    /// 获取当前在线用户（基础版）
+    pub async fn get_online_users_basic(&self) -> Vec<String> {
+        let mut users: Vec<String> = self.users.read().await.keys().cloned().collect();
+        users.sort();
+        users
+    }
+
+    /// 广播上线通知征集应答，应答平息后返回在线用户
+    ///
+    /// 最多等待 timeout（为 None 时使用 network.timeout_secs）；被动与隐身模式不广播，等到超时为止
+    pub async fn refresh_users(&self, timeout: Option<Duration>) -> Vec<OnlineUser> {
+        let timeout = timeout.unwrap_or(self.timeout);
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut events = self.subscribe();
+        let soliciting = !self.passive && !self.is_stealth();
+        if soliciting
+            && let Err(e) = self.broadcast(&self.presence_packet(commands::BR_ENTRY)).await
+        {
+            log::warn!("Failed to broadcast entry: {}", e);
+        }
+        let quiet = if soliciting { REFRESH_QUIET.min(timeout) } else { timeout };
+        let mut settled = tokio::time::Instant::now() + quiet;
+        loop {
+            match tokio::time::timeout_at(settled.min(deadline), events.recv()).await {
+                Err(_) | Ok(Err(broadcast::error::RecvError::Closed)) => break,
+                // 新用户或新的应答，重新计时
+                Ok(Ok(NetEvent::Joined { .. })) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => {
+                    settled = tokio::time::Instant::now() + quiet;
+                }
+                Ok(Ok(NetEvent::Packet { packet, .. })) if packet.command & 0xff == commands::IPMSG_ANSENTRY => {
+                    settled = tokio::time::Instant::now() + quiet;
+                }
+                Ok(_) => {}
+            }
+        }
+        self.get_online_users().await
+    }
+
+    /// 广播上线通知后等待对方应答，最多等待 settle；指定的收件人都可以解析时提前返回
+    ///
+    /// 返回实际等待的时长
+    pub async fn settle_after_entry(&self, settle: Duration, recipients: &[&str]) -> Duration {
+        let started = Instant::now();
+        let deadline = tokio::time::Instant::from_std(started + settle);
+        let mut events = self.subscribe();
+        loop {
+            if !recipients.is_empty() && self.all_resolvable(recipients).await {
+                break;
+            }
+            match tokio::time::timeout_at(deadline, events.recv()).await {
+                Err(_) | Ok(Err(broadcast::error::RecvError::Closed)) => break,
+                Ok(_) => {}
+            }
+        }
+        started.elapsed()
+    }
+
+    async fn all_resolvable(&self, recipients: &[&str]) -> bool {
+        for recipient in recipients {
+            if self.resolve_recipient(recipient).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// 等待应答的默认超时
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// 获取带详细信息的在线用户（按用户名、主机名排序）
+    pub async fn get_online_users(&self) -> Vec<OnlineUser> {
+        let mut users: Vec<OnlineUser> = self
+            .users
+            .read()
+            .await
+            .values()
+            .map(|entry| OnlineUser {
+                username: entry.name.clone(),
+                hostname: entry.host.clone(),
+                addr: entry.addr,
+                via_port: entry.local_port,
+                confirmed: entry.confirmed,
+                note: entry.note.clone(),
+                login: entry.login.clone(),
+                group: entry.group.clone(),
+                absent: entry.absent,
+                absence: entry.absence.clone(),
+                last_seen: SystemTime::now()
+                    .checked_sub(entry.last_seen.elapsed())
+                    .unwrap_or(SystemTime::UNIX_EPOCH),
+            })
+            .collect();
+        users.sort_by(|a, b| {
+            a.username
+                .cmp(&b.username)
+                .then_with(|| a.hostname.cmp(&b.hostname))
+        });
+        users
+    }
+
+    pub async fn get_user_addr(&self, username: &str) -> Option<SocketAddr> {
+        let users = self.users.read().await;
+        users.get(username).map(|entry| entry.addr)
+    }
+
+    /// 向一组用户发送同一条消息（带多播标记），返回每个收件人的发送结果
+    ///
+    /// 各收件人收到的报文编号相同，对端据此显示为多播而不是私聊
+    pub async fn multicast(&self, recipients: &[String], message: &str) -> Vec<(String, Result<SocketAddr>)> {
+        let packet = self.build_packet(commands::MSG | commands::IPMSG_MULTICASTOPT, message);
+        let mut results = Vec::with_capacity(recipients.len());
+        for recipient in recipients {
+            let result = match self.resolve_recipient(recipient).await {
+                Ok(addr) => self.send_to(&packet, &addr).await.map(|()| addr),
+                Err(e) => Err(e),
+            };
+            results.push((recipient.clone(), result));
+        }
+        results
+    }
+
+    /// 把同一条消息（带多播标记、要求确认）并发地逐个发给用户表中的用户，返回每个收件人的结果
+    ///
+    /// 跳过本机与被黑名单/白名单拒绝的用户；确认的等待时长为 timeout（None 时使用 network.timeout_secs）
+    pub async fn multicast_users(
+        &self,
+        users: &[OnlineUser],
+        message: &str,
+        timeout: Option<Duration>,
+    ) -> Vec<(OnlineUser, Delivery)> {
+        let (login, host) = {
+            let identity = self.identity.read().unwrap();
+            (identity.login().to_string(), identity.host.clone())
+        };
+        let recipients: Vec<&OnlineUser> = {
+            let filter = self.peer_filter.read().unwrap();
+            users
+                .iter()
+                .filter(|user| !(user.login == login && user.hostname == host))
+                .filter(|user| {
+                    let by_login = format!("{}@{}", user.login, user.hostname);
+                    let by_name = format!("{}@{}", user.username, user.hostname);
+                    filter.accepts(user.ip(), &[by_login.as_str(), by_name.as_str()])
+                })
+                .collect()
+        };
+        let packet = self.build_packet(commands::MSG | commands::IPMSG_MULTICASTOPT, message);
+        let sends = recipients.iter().map(|user| {
+            let packet = &packet;
+            async move {
+                let delivery = match self.send_checked(packet, &user.addr, timeout).await {
+                    Ok(Some(rtt)) => Delivery::Acked(rtt),
+                    Ok(None) => Delivery::Unacked,
+                    Err(e) => Delivery::Failed(e),
+                };
+                ((*user).clone(), delivery)
+            }
+        });
+        futures::future::join_all(sends).await
+    }
+
+    /// 把同一条私聊消息并发地发给多个收件人，返回每个收件人的结果（与输入顺序相同）
+    ///
+    /// 每个收件人独立解析，解析失败不影响其他收件人；解析到同一地址的收件人只发一次。
+    /// wait_ack 为 None 时发出即返回，否则逐个收件人经 send_reliable 等待确认（总时长 wait_ack）
+    pub async fn send_many(
+        &self,
+        recipients: &[String],
+        message: &str,
+        wait_ack: Option<Duration>,
+    ) -> Vec<SendOutcome> {
+        let mut resolved: Vec<(String, Result<SocketAddr>)> = Vec::with_capacity(recipients.len());
+        for recipient in recipients {
+            let addr = self.resolve_recipient(recipient).await;
+            if let Ok(addr) = &addr
+                && let Some((first, _)) = resolved.iter().find(|(_, other)| other.as_ref().ok() == Some(addr))
+            {
+                log::info!("{} is the same peer as {}, sending once", recipient, first);
+                continue;
+            }
+            resolved.push((recipient.clone(), addr));
+        }
+        let sends = resolved.into_iter().map(|(recipient, addr)| async move {
+            let addr = match addr {
+                Ok(addr) => addr,
+                Err(e) => {
+                    return SendOutcome {
+                        recipient,
+                        addr: None,
+                        delivery: Delivery::Failed(e),
+                    };
+                }
+            };
+            let packet = self.build_packet(commands::MSG, message);
+            let delivery = match wait_ack {
+                None => match self.send_to(&packet, &addr).await {
+                    Ok(()) => Delivery::Sent,
+                    Err(e) => Delivery::Failed(e),
+                },
+                Some(timeout) => match self.send_reliable(&packet, &addr, timeout).await {
+                    Ok(Some(rtt)) => Delivery::Acked(rtt),
+                    Ok(None) => Delivery::Unacked,
+                    Err(e) => Delivery::Failed(e),
+                },
+            };
+            SendOutcome {
+                recipient,
+                addr: Some(addr),
+                delivery,
+            }
+        });
+        futures::future::join_all(sends).await
+    }
+
+    /// 解析收件人：ip:port、ip（默认端口）、用户名@主机名，或唯一的用户名
+    ///
+    /// 失败时的错误为 ResolveError
+    pub async fn resolve_recipient(&self, recipient: &str) -> Result<SocketAddr> {
+        if let Ok(addr) = recipient.parse::<SocketAddr>() {
+            return Ok(canonical_addr(addr));
+        }
+        if let Ok(ip) = recipient.parse::<std::net::IpAddr>() {
+            return Ok(SocketAddr::new(ip.to_canonical(), IPMSG_PORT));
+        }
+        if recipient.contains('@') {
+            return self
+                .get_user_addr(recipient)
+                .await
+                .ok_or_else(|| ResolveError::NotFound(recipient.to_string()).into());
+        }
+
+        let users = self.users.read().await;
+        let mut matches: Vec<(&String, SocketAddr)> = users
+            .iter()
+            .filter(|(_, entry)| entry.name == recipient)
+            .map(|(name, entry)| (name, entry.addr))
+            .collect();
+        match matches.len() {
+            0 => Err(ResolveError::NotFound(recipient.to_string()).into()),
+            1 => Ok(matches[0].1),
+            _ => {
+                matches.sort();
+                Err(ResolveError::Ambiguous {
+                    recipient: recipient.to_string(),
+                    candidates: matches.iter().map(|(name, _)| name.to_string()).collect(),
+                }
+                .into())
+            }
+        }
+    }
+    /// 处理经主端口收到的报文
+    pub(crate) async fn handle_packet(&self, packet: &IpMsgPacket, addr: &SocketAddr) -> bool {
+        let local_port = self.sockets[0].local_port();
+        self.handle_packet_via(packet, addr, local_port).await
+    }
+
+    /// 处理报文并更新用户表，来源地址与已知用户不符时返回 true（疑似伪造）
+    pub(crate) async fn handle_packet_via(
+        &self,
+        packet: &IpMsgPacket,
+        addr: &SocketAddr,
+        local_port: u16,
+    ) -> bool {
+        let addr = &canonical_addr(*addr);
+        let mut users = self.users.write().await;
+        let mut username = format!("{}@{}", packet.sender_name, packet.sender_host);
+        let command = packet.command & 0xff;
+        // BR_ENTRY/ANSENTRY 带节点 ID 时按它找已有条目：改了昵称或主机名仍是同一条；
+        // 换了地址与其他地址变更一样，先向旧地址确认，不直接迁移
+        let known_node = packet
+            .node_id()
+            .filter(|_| !self.is_self(packet))
+            .and_then(|node_id| node_key(&users, node_id));
+        if let Some(key) = known_node {
+            let entry = &users[&key];
+            if entry.confirmed && !entry.is_at(*addr, local_port) {
+                let known = entry.addr;
+                drop(users);
+                log::warn!(
+                    "{} announced from {}, known address is {} (same node id)",
+                    key, addr, known
+                );
+                if !self.passive {
+                    self.start_probe(key, known, *addr).await;
+                }
+                return true;
+            }
+            username = self.rename_node(&mut users, key, &username, packet);
+        } else if let Some(key) = (!protocol::is_presence_command(command))
+            .then(|| login_key(&users, packet, addr.ip()))
+            .flatten()
+        {
+            // 登录名与昵称不同的用户，非上线类报文里只有登录名，仍归到以昵称登记的条目
+            username = key;
+        } else if users.get(&username).is_some_and(|entry| entry.addr.ip() != addr.ip()) {
+            // 已识别的同名用户按来源 IP 使用区分键
+            let alias = collision_key(&username, addr.ip());
+            if users.contains_key(&alias) {
+                username = alias;
+            }
+        }
+
+        let checked = matches!(
+            command,
+            commands::BR_ENTRY | commands::IPMSG_ANSENTRY | commands::BR_EXIT | commands::MSG
+        );
+        // 缓存恢复的条目尚未确认，新地址直接覆盖旧地址
+        if let Some(entry) = users.get(&username).filter(|entry| checked && entry.confirmed) {
+            let known = entry.addr;
+            let same_peer = entry.is_at(*addr, local_port);
+            let entry_command = matches!(command, commands::BR_ENTRY | commands::IPMSG_ANSENTRY);
+            let collision = !same_peer && entry_command && known.ip() != addr.ip();
+            if !same_peer && !collision {
+                drop(users);
+                log::warn!(
+                    "Suspect packet for {} from {}, known address is {}",
+                    username, addr, known
+                );
+                // 重新上线报文可能是合法的地址变更，先向旧地址确认
+                if !self.passive && entry_command {
+                    self.start_probe(username, known, *addr).await;
+                }
+                return true;
+            }
+            if collision {
+                // 另一个 IP 上线了同名用户：可能是地址变更，也可能是同名的另一台机器。
+                // 先以区分键登记，旧地址不再响应时确认探测会将其并回原条目
+                let alias = collision_key(&username, addr.ip());
+                log::warn!(
+                    "User name collision: {} is at {} and {}, tracking the latter as {}",
+                    username, known, addr, alias
+                );
+                self.stats.record_user_collision();
+                if !self.passive {
+                    self.start_probe(username.clone(), known, *addr).await;
+                }
+                username = alias;
+            } else {
+                // 旧地址仍有响应，取消地址变更
+                self.probes.lock().unwrap().remove(&username);
+            }
+        }
+        if let Some(entry) = users.get_mut(&username)
+            && entry.addr == *addr
+        {
+            entry.confirmed = true;
+            // 已知地址仍有报文（包括对确认探测的 SENDINFO 回复），取消地址变更
+            self.probes.lock().unwrap().remove(&username);
+        }
+        if command == commands::MSG && !self.is_self(packet) {
+            self.remember_message(&username, packet, *addr);
+        }
+        let presence_command = matches!(
+            command,
+            commands::BR_ENTRY
+                | commands::IPMSG_ANSENTRY
+                | commands::BR_EXIT
+                | commands::IPMSG_NOOPERATION
+                | commands::IPMSG_BR_ABSENCE
+        );
+        if self.learn_from_any_packet
+            && !presence_command
+            && packet.command & commands::IPMSG_NOADDLISTOPT == 0
+            && !self.is_self(packet)
+        {
+            self.learn_user(&mut users, username.clone(), packet, *addr, local_port);
+        }
+
+        match command {
+            // 保活：只刷新已知用户的活动时间，不新增用户
+            commands::IPMSG_NOOPERATION => {
+                if let Some(entry) = users.get_mut(&username)
+                    && entry.addr == *addr
+                {
+                    entry.last_seen = Instant::now();
+                }
+            }
+            commands::BR_ENTRY => {
+                self.upsert_user(&mut users, username.clone(), packet, *addr, local_port);
+                if !self.passive && !self.is_stealth() && !self.is_self(packet) {
+                    self.schedule_ansentry(username, *addr, local_port);
+                }
+            }
+            commands::IPMSG_ANSENTRY => {
+                self.upsert_user(&mut users, username, packet, *addr, local_port);
+            }
+            // 状态变更：附加信息在离开时为离开信息，在线时为状态说明；只接受来自登记地址的
+            commands::IPMSG_BR_ABSENCE => {
+                if let Some(entry) = users.get_mut(&username)
+                    && entry.is_at(*addr, local_port)
+                {
+                    entry.last_seen = Instant::now();
+                    entry.note = packet.additional_msg.clone();
+                    entry.absent = is_absent(packet);
+                    entry.absence = entry.absent.then(|| packet.additional_msg.clone());
+                }
+            }
+            // 只接受来自登记地址的下线通知（缓存恢复、尚未确认的条目也一样）
+            commands::BR_EXIT if users.get(&username).is_some_and(|entry| entry.is_at(*addr, local_port)) => {
+                if let Some(entry) = users.remove(&username) {
+                    self.emit(NetEvent::Left {
+                        username,
+                        addr: entry.addr,
+                    });
+                }
+            }
+            commands::MSG if !self.passive && !self.is_self(packet) => {
+                self.acknowledge(packet, *addr, local_port);
+                self.maybe_auto_reply(packet, username, *addr, local_port);
+            }
+            commands::IPMSG_SENDABSENCEINFO => {
+                // 在线时的固定文本不算离开信息
+                if let Some(entry) = users.get_mut(&username)
+                    && entry.addr == *addr
+                {
+                    let text = &packet.additional_msg;
+                    entry.absence = (text != NOT_ABSENT_MESSAGE).then(|| text.clone());
+                }
+                self.resolve_pending(packet, addr);
+            }
+            commands::IPMSG_RECVMSG | commands::IPMSG_SENDINFO => {
+                self.resolve_pending(packet, addr);
+            }
+            commands::IPMSG_GETINFO if !self.passive && !self.is_self(packet) => {
+                let version = self.identity.read().unwrap().client_version.clone();
+                self.reply(self.build_packet(commands::IPMSG_SENDINFO, &version), *addr, local_port);
+            }
+            commands::IPMSG_GETABSENCEINFO if !self.passive && !self.is_self(packet) => {
+                let text = self.state().absence_text().to_string();
+                self.reply(self.build_packet(commands::IPMSG_SENDABSENCEINFO, &text), *addr, local_port);
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// 记入最近消息，超过 history_size 条时丢弃最早的
+    fn remember_message(&self, sender: &str, packet: &IpMsgPacket, from: SocketAddr) {
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_back(ReceivedMessage {
+            sender: sender.to_string(),
+            from,
+            packet_no: packet.packet_no,
+            text: packet.additional_msg.clone(),
+            received_at: SystemTime::now(),
+        });
+        while recent.len() > self.history_size {
+            recent.pop_front();
+        }
+    }
+
+    /// 最近收到的消息快照，按到达顺序排列
+    pub fn recent_messages(&self) -> Vec<ReceivedMessage> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// 插入或刷新用户，超出上限时淘汰最久未活动的条目
+    ///
+    /// 已确认的用户只刷新活动时间和资料，地址变更由确认探测流程处理
+    fn upsert_user(
+        &self,
+        users: &mut HashMap<String, UserEntry>,
+        username: String,
+        packet: &IpMsgPacket,
+        addr: SocketAddr,
+        local_port: u16,
+    ) {
+        if let Some(entry) = users.get_mut(&username) {
+            if !entry.confirmed {
+                entry.addr = addr;
+                entry.local_port = local_port;
+                entry.confirmed = true;
+            }
+            entry.last_seen = Instant::now();
+            entry.login = packet.sender_user.clone();
+            entry.group = packet.group_name.clone();
+            entry.client = packet.version.clone();
+            entry.note = packet.additional_msg.clone();
+            entry.absent = is_absent(packet);
+            if !entry.absent {
+                entry.absence = None;
+            }
+            if let Some(node_id) = packet.node_id() {
+                entry.node_id = Some(node_id.to_string());
+            }
+            return;
+        }
+        if users.len() >= self.max_users {
+            let oldest = users
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_seen)
+                .map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                if let Some(entry) = users.remove(&oldest) {
+                    self.stats.record_user_evicted();
+                    self.emit(NetEvent::Left {
+                        username: oldest.clone(),
+                        addr: entry.addr,
+                    });
+                }
+                log::warn!(
+                    "User table full ({}), evicted {}",
+                    self.max_users, oldest
+                );
+            }
+        }
+        users.insert(
+            username.clone(),
+            UserEntry {
+                name: packet.sender_name.clone(),
+                host: packet.sender_host.clone(),
+                addr,
+                local_port,
+                last_seen: Instant::now(),
+                login: packet.sender_user.clone(),
+                group: packet.group_name.clone(),
+                client: packet.version.clone(),
+                confirmed: true,
+                note: packet.additional_msg.clone(),
+                absent: is_absent(packet),
+                absence: None,
+                node_id: packet.node_id().map(str::to_string),
+            },
+        );
+        self.emit(NetEvent::Joined { username, addr });
+    }
+
+    /// 节点 ID 相同的条目改了昵称或主机名：新键未被占用时把条目移到新键下，返回条目现在的键
+    fn rename_node(
+        &self,
+        users: &mut HashMap<String, UserEntry>,
+        key: String,
+        username: &str,
+        packet: &IpMsgPacket,
+    ) -> String {
+        if key == username || users.contains_key(username) {
+            return key;
+        }
+        let Some(mut entry) = users.remove(&key) else {
+            return key;
+        };
+        log::info!("{} is now known as {} (same node id)", key, username);
+        self.probes.lock().unwrap().remove(&key);
+        self.emit(NetEvent::Left {
+            username: key,
+            addr: entry.addr,
+        });
+        entry.name = packet.sender_name.clone();
+        entry.host = packet.sender_host.clone();
+        let addr = entry.addr;
+        users.insert(username.to_string(), entry);
+        self.emit(NetEvent::Joined {
+            username: username.to_string(),
+            addr,
+        });
+        username.to_string()
+    }
+
+    /// 从非上线类报文学习发送方：已知用户只刷新活动时间，新用户不带分组与状态说明加入
+    fn learn_user(
+        &self,
+        users: &mut HashMap<String, UserEntry>,
+        username: String,
+        packet: &IpMsgPacket,
+        addr: SocketAddr,
+        local_port: u16,
+    ) {
+        if let Some(entry) = users.get_mut(&username) {
+            if entry.addr == addr {
+                entry.last_seen = Instant::now();
+            }
+            return;
+        }
+        let bare = IpMsgPacket {
+            group_name: String::new(),
+            additional_msg: String::new(),
+            extension: String::new(),
+            ..packet.clone()
+        };
+        self.upsert_user(users, username, &bare, addr, local_port);
+    }
+
+    /// 从缓存文件恢复用户表，恢复的条目标记为未确认，返回恢复的数量
+    pub async fn load_user_cache<P: AsRef<Path>>(&self, path: P, max_age: Duration) -> usize {
+        let cached = cache::load_users(path, self.max_users, max_age);
+        let mut users = self.users.write().await;
+        let local_port = self.sockets[0].local_port();
+        let mut restored = 0;
+        for user in cached {
+            if users.len() >= self.max_users || users.contains_key(&user.username) {
+                continue;
+            }
+            let last_seen = Instant::now().checked_sub(user.age()).unwrap_or_else(Instant::now);
+            let (name, host) = user.username.rsplit_once('@').unwrap_or((&user.username, ""));
+            let (name, host) = (name.to_string(), host.to_string());
+            users.insert(
+                user.username,
+                UserEntry {
+                    name,
+                    host,
+                    addr: canonical_addr(user.addr),
+                    local_port,
+                    last_seen,
+                    login: user.login,
+                    group: user.group,
+                    client: user.client,
+                    confirmed: false,
+                    note: String::new(),
+                    absent: false,
+                    absence: None,
+                    node_id: user.node_id.filter(|node_id| protocol::is_node_id(node_id)),
+                },
+            );
+            restored += 1;
+        }
+        restored
+    }
+
+    /// 将用户表写入缓存文件
+    pub async fn save_user_cache<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let now = cache::unix_now();
+        let users = self
+            .users
+            .read()
+            .await
+            .iter()
+            // 同名用户的区分键不写入缓存，下次上线时重新识别
+            .filter(|(username, entry)| **username == format!("{}@{}", entry.name, entry.host))
+            .map(|(username, entry)| CachedUser {
+                username: username.clone(),
+                addr: entry.addr,
+                login: entry.login.clone(),
+                group: entry.group.clone(),
+                client: entry.client.clone(),
+                node_id: entry.node_id.clone(),
+                last_seen: now.saturating_sub(entry.last_seen.elapsed().as_secs()),
+            })
+            .collect();
+        cache::save_users(path, users)
+    }
+
+    /// 是否为本机发出的报文（广播回环）
+    fn is_self(&self, packet: &IpMsgPacket) -> bool {
+        let identity = self.identity.read().unwrap();
+        packet.sender_user == identity.login() && packet.sender_host == identity.host
+    }
+
+    /// 随机延迟后回复 ANSENTRY，窗口内对同一用户只回复一次
+    fn schedule_ansentry(&self, username: String, addr: SocketAddr, local_port: u16) {
+        {
+            let mut answered = self.answered.lock().unwrap();
+            let now = Instant::now();
+            answered.retain(|_, at| now.duration_since(*at) < ANSENTRY_SUPPRESS_WINDOW);
+            if answered.contains_key(&username) {
+                return;
+            }
+            answered.insert(username, now);
+        }
+
+        let max_jitter = self.ansentry_jitter.as_millis() as u64;
+        let delay = Duration::from_millis(rand::random_range(0..=max_jitter));
+        let server = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let reply = server.presence_packet(commands::IPMSG_ANSENTRY);
+            if let Err(e) = server.send_via(&reply, &addr, Some(local_port)).await {
+                log::error!("ANSENTRY to {} failed: {}", addr, e);
+            }
+        });
+    }
+
+    /// 在后台从指定本地端口回复
+    fn reply(&self, packet: IpMsgPacket, addr: SocketAddr, local_port: u16) {
+        let server = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.send_via(&packet, &addr, Some(local_port)).await {
+                log::error!("Reply to {} failed: {}", addr, e);
+            }
+        });
+    }
+
+    /// 消息要求确认时回复 RECVMSG
+    fn acknowledge(&self, packet: &IpMsgPacket, addr: SocketAddr, local_port: u16) {
+        if packet.command & commands::IPMSG_SENDCHECKOPT != 0 {
+            let ack = self.build_packet(commands::IPMSG_RECVMSG, &packet.packet_no.to_string());
+            self.reply(ack, addr, local_port);
+        }
+    }
+
+    /// 离开状态下自动回复离开信息，间隔内对同一发送方只回复一次
+    ///
+    /// 对方的自动回复不再回复，避免两端互相回复
+    fn maybe_auto_reply(&self, packet: &IpMsgPacket, username: String, addr: SocketAddr, local_port: u16) {
+        let PresenceState::Away { message } = self.state() else {
+            return;
+        };
+        if packet.command & commands::IPMSG_AUTORETOPT != 0 {
+            return;
+        }
+        let interval = {
+            let config = self.presence_config.read().unwrap();
+            if !config.auto_reply {
+                return;
+            }
+            Duration::from_secs(config.auto_reply_interval_secs)
+        };
+        {
+            let mut replied = self.auto_replied.lock().unwrap();
+            let now = Instant::now();
+            replied.retain(|_, at| now.duration_since(*at) < interval);
+            if replied.contains_key(&username) {
+                return;
+            }
+            replied.insert(username.clone(), now);
+        }
+
+        let reply = self.build_packet(commands::MSG | commands::IPMSG_AUTORETOPT, &message);
+        let server = self.clone();
+        tokio::spawn(async move {
+            match server.send_via(&reply, &addr, Some(local_port)).await {
+                Ok(()) => server.emit(NetEvent::AutoReplied {
+                    username,
+                    addr,
+                    text: message,
+                }),
+                Err(e) => log::error!("Auto-reply to {} failed: {}", addr, e),
+            }
+        });
+    }
+
+    /// 向旧地址发送确认探测，超时无响应则把用户迁移到新地址
+    ///
+    /// 探测用 GETINFO：对方总会回复 SENDINFO，不像 BR_ENTRY 的应答会被 ANSENTRY_SUPPRESS_WINDOW 压掉
+    async fn start_probe(&self, username: String, old_addr: SocketAddr, new_addr: SocketAddr) {
+        if self
+            .probes
+            .lock()
+            .unwrap()
+            .insert(username.clone(), new_addr)
+            .is_some()
+        {
+            return;
+        }
+
+        let probe = self.build_packet(commands::IPMSG_GETINFO, "");
+        if let Err(e) = self.send_to(&probe, &old_addr).await {
+            log::error!("Probe to {} failed: {}", old_addr, e);
+        }
+
+        let server = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(server.timeout).await;
+            let confirmed = server.probes.lock().unwrap().remove(&username);
+            if confirmed != Some(new_addr) {
+                return;
+            }
+            let mut users = server.users.write().await;
+            if let Some(entry) = users.get_mut(&username) {
+                log::info!(
+                    "{} moved from {} to {}",
+                    username, old_addr, new_addr
+                );
+                entry.addr = new_addr;
+                entry.last_seen = Instant::now();
+            }
+            // 新地址上登记的同名条目其实是同一用户
+            let alias = collision_key(&username, new_addr.ip());
+            if let Some(entry) = users.remove(&alias) {
+                server.emit(NetEvent::Left {
+                    username: alias,
+                    addr: entry.addr,
+                });
+            }
+        });
+    }
+}
+
+/// 检查数据报是否完整发出：发出的字节数少于报文长度时视为截断，返回错误
+pub(crate) fn check_sent(sent: std::io::Result<usize>, len: usize) -> std::io::Result<()> {
+    let sent = sent?;
+    if sent != len {
+        return Err(std::io::Error::other(format!(
+            "short send: {} of {} bytes, datagram truncated",
+            sent, len
+        )));
+    }
+    Ok(())
+}
+
+/// 设置套接字的单播/广播与组播 TTL
+fn apply_ttl(socket: &UdpSocket, ttl: u32) -> Result<()> {
+    let sock = socket2::SockRef::from(socket);
+    if socket.local_addr()?.is_ipv4() {
+        sock.set_ttl_v4(ttl)?;
+        sock.set_multicast_ttl_v4(ttl)?;
+    } else {
+        sock.set_unicast_hops_v6(ttl)?;
+        sock.set_multicast_hops_v6(ttl)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod testutil;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::protocol::ProtocolError;
+
+    fn entry_packet(name: &str) -> IpMsgPacket {
+        IpMsgPacket {
+            sender_name: name.to_string(),
+            sender_host: "PC".to_string(),
+            command: commands::BR_ENTRY,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roster_churn_under_cap_does_not_evict() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.max_users = 2;
+        let addr: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+
+        for _ in 0..3 {
+            server.handle_packet(&entry_packet("alice"), &addr).await;
+            server.handle_packet(&entry_packet("bob"), &addr).await;
+            let mut exit = entry_packet("alice");
+            exit.command = commands::BR_EXIT;
+            server.handle_packet(&exit, &addr).await;
+        }
+
+        let stats = server.stats().await;
+        assert_eq!(stats.users, 1);
+        assert_eq!(stats.users_evicted, 0);
+    }
+
+    #[tokio::test]
+    async fn test_pending_replies_cap_drops_oldest() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.max_pending_replies = 2;
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        // 一直没有应答的请求堆满后，最早的先被放弃，它的等待方立即得到结果
+        let mut waiting: Vec<_> = (0..5)
+            .map(|packet_no| {
+                let (tx, rx) = oneshot::channel();
+                server.register_pending(PendingReply {
+                    ip,
+                    command: commands::IPMSG_RECVMSG,
+                    packet_no: Some(packet_no),
+                    tx,
+                });
+                rx
+            })
+            .collect();
+        for rx in &mut waiting[..3] {
+            assert!(matches!(rx.try_recv(), Err(oneshot::error::TryRecvError::Closed)));
+        }
+        for rx in &mut waiting[3..] {
+            assert!(matches!(rx.try_recv(), Err(oneshot::error::TryRecvError::Empty)));
+        }
+        let stats = server.stats().await;
+        assert_eq!((stats.pending_replies, stats.pending_replies_evicted), (2, 3));
+
+        // 已结束的条目先被清理，不计入淘汰
+        drop(waiting);
+        let (tx, _rx) = oneshot::channel();
+        server.register_pending(PendingReply {
+            ip,
+            command: commands::IPMSG_SENDINFO,
+            packet_no: None,
+            tx,
+        });
+        let stats = server.stats().await;
+        assert_eq!((stats.pending_replies, stats.pending_replies_evicted), (1, 3));
+    }
+
+    #[tokio::test]
+    async fn test_online_users_sorted_by_name_then_host() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let addr: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+        for (name, host) in [("carol", "PC"), ("alice", "PC-B"), ("bob", "PC"), ("alice", "PC-A")] {
+            let mut packet = entry_packet(name);
+            packet.sender_host = host.to_string();
+            server.handle_packet(&packet, &addr).await;
+        }
+
+        let users: Vec<(String, String)> = server
+            .get_online_users()
+            .await
+            .into_iter()
+            .map(|user| (user.username, user.hostname))
+            .collect();
+        let expected = [("alice", "PC-A"), ("alice", "PC-B"), ("bob", "PC"), ("carol", "PC")];
+        assert_eq!(users.len(), expected.len());
+        for ((name, host), (want_name, want_host)) in users.iter().zip(expected) {
+            assert_eq!((name.as_str(), host.as_str()), (want_name, want_host));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_users_restored_unconfirmed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(cache::USER_CACHE_FILE);
+        let cached_addr: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+
+        let before = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        before.handle_packet(&entry_packet("alice"), &cached_addr).await;
+        before.save_user_cache(&path).await.unwrap();
+
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        assert_eq!(server.load_user_cache(&path, Duration::from_secs(60)).await, 1);
+        // 未确认的条目仍可直接发送
+        assert_eq!(server.get_user_addr("alice@PC").await, Some(cached_addr));
+        assert!(!server.get_online_users().await[0].confirmed);
+
+        // 新地址的上线报文直接确认，不视为伪造
+        let new_addr: SocketAddr = "127.0.0.1:2427".parse().unwrap();
+        assert!(!server.handle_packet(&entry_packet("alice"), &new_addr).await);
+        let users = server.get_online_users().await;
+        assert!(users[0].confirmed);
+        assert_eq!(users[0].addr, new_addr);
+    }
+
+    #[tokio::test]
+    async fn test_noop_refreshes_known_user_only() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let addr: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+        server.handle_packet(&entry_packet("alice"), &addr).await;
+        let before = server.users.read().await["alice@PC"].last_seen;
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let mut noop = entry_packet("alice");
+        noop.command = commands::IPMSG_NOOPERATION;
+        server.handle_packet(&noop, &addr).await;
+        assert!(server.users.read().await["alice@PC"].last_seen > before);
+
+        let mut unknown = entry_packet("bob");
+        unknown.command = commands::IPMSG_NOOPERATION;
+        server.handle_packet(&unknown, &addr).await;
+        assert!(server.get_user_addr("bob@PC").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_recipient_forms() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let alice_addr: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+        let bob_a: SocketAddr = "127.0.0.2:2425".parse().unwrap();
+        let bob_b: SocketAddr = "127.0.0.3:2425".parse().unwrap();
+        server.handle_packet(&entry_packet("alice"), &alice_addr).await;
+        let mut bob = entry_packet("bob");
+        bob.sender_host = "PC-A".to_string();
+        server.handle_packet(&bob, &bob_a).await;
+        bob.sender_host = "PC-B".to_string();
+        server.handle_packet(&bob, &bob_b).await;
+
+        assert_eq!(server.resolve_recipient("alice").await.unwrap(), alice_addr);
+        assert_eq!(server.resolve_recipient("bob@PC-B").await.unwrap(), bob_b);
+        assert_eq!(
+            server.resolve_recipient("10.0.0.5:2500").await.unwrap(),
+            "10.0.0.5:2500".parse().unwrap()
+        );
+        assert_eq!(
+            server.resolve_recipient("10.0.0.5").await.unwrap(),
+            SocketAddr::new("10.0.0.5".parse().unwrap(), IPMSG_PORT)
+        );
+        let error = server.resolve_recipient("bob").await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ResolveError>(),
+            Some(&ResolveError::Ambiguous {
+                recipient: "bob".into(),
+                candidates: vec!["bob@PC-A".into(), "bob@PC-B".into()],
+            })
+        );
+        let error = server.resolve_recipient("carol").await.unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&ResolveError::NotFound("carol".into())));
+        assert_eq!(error.to_string(), "User carol not found");
+        assert!(server.resolve_recipient("alice@OTHER").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_configured_ttl_applied() {
+        let mut config = AppConfig::default();
+        config.network.bind_ip = "127.0.0.1".to_string();
+        config.network.ports = vec![0, 0];
+        config.network.ttl = 7;
+        let server = IpMsgServer::from_config(&config).await.unwrap();
+        assert_eq!(server.ttl().unwrap(), 7);
+        for port_socket in &server.sockets {
+            let socket = socket2::SockRef::from(port_socket.socket.as_ref());
+            assert_eq!(socket.ttl_v4().unwrap(), 7);
+            assert_eq!(socket.multicast_ttl_v4().unwrap(), 7);
+        }
+
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        assert_eq!(server.ttl().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_online_user_exposes_socket_addr() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let addr: SocketAddr = "127.0.0.7:2500".parse().unwrap();
+        let mut packet = entry_packet("user@corp");
+        packet.sender_host = "PC-A".to_string();
+        server.handle_packet(&packet, &addr).await;
+
+        let users = server.get_online_users().await;
+        assert_eq!(users[0].addr, addr);
+        assert_eq!(users[0].ip(), addr.ip());
+        assert_eq!(users[0].port(), 2500);
+        // 用户名中的 @ 不影响主机名
+        assert_eq!(users[0].username, "user@corp");
+        assert_eq!(users[0].hostname, "PC-A");
+    }
+
+    #[tokio::test]
+    async fn test_user_table_evicts_least_recently_seen() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.max_users = 2;
+        let addr: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+
+        server.handle_packet(&entry_packet("alice"), &addr).await;
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        server.handle_packet(&entry_packet("bob"), &addr).await;
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        server.handle_packet(&entry_packet("carol"), &addr).await;
+
+        assert!(server.get_user_addr("alice@PC").await.is_none());
+        assert!(server.get_user_addr("bob@PC").await.is_some());
+        assert!(server.get_user_addr("carol@PC").await.is_some());
+        assert_eq!(server.stats().await.users_evicted, 1);
+    }
+
+    async fn spawn_listener() -> (IpMsgServer, SocketAddr) {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let listener = server.clone();
+        tokio::spawn(async move {
+            let _ = listener
+                .listen(|_, _| {}, Arc::new(AppConfig::default()))
+                .await;
+        });
+        (server, addr)
+    }
+
+    #[tokio::test]
+    async fn test_long_message_is_received_whole() {
+        let (server, addr) = spawn_listener().await;
+        let mut events = server.subscribe();
+        let sender = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+
+        // 远超 1 KB 的消息整条收到，不会在接收缓冲区处被截断
+        let text = "0123456789".repeat(600);
+        sender.send_to(&sender.build_packet(commands::MSG, &text), &addr).await.unwrap();
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), events.recv()).await.unwrap().unwrap();
+            if let NetEvent::Packet { packet, .. } = event {
+                assert_eq!(packet.additional_msg, text);
+                break;
+            }
+        }
+    }
+
+    async fn next_packet_event(events: &mut broadcast::Receiver<NetEvent>) -> (u32, bool) {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let NetEvent::Packet { packet, suspect, .. } = event {
+                return (packet.command, suspect);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_recovers() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.events = broadcast::channel(4).0;
+        let addr = server.local_addr().unwrap();
+        let mut slow = server.subscribe();
+        let mut fast = server.subscribe();
+        let listener = server.clone();
+        tokio::spawn(async move {
+            let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+        });
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let noop = |packet_no: u32| format!("1:{}:peer:PC:0:", packet_no).into_bytes();
+        for packet_no in 1..=10 {
+            peer.send_to(&noop(packet_no), addr).await.unwrap();
+            next_packet_event(&mut fast).await;
+        }
+
+        // 容量为 4：最早的 6 个事件被跳过，之后照常接收
+        assert!(matches!(slow.recv().await, Err(broadcast::error::RecvError::Lagged(6))));
+        let Some(NetEvent::Packet { packet, .. }) = recv_lossy(&mut slow, "test").await else {
+            panic!("expected a packet event");
+        };
+        assert_eq!(packet.packet_no, 7);
+
+        // 监听循环不受影响
+        peer.send_to(&noop(11), addr).await.unwrap();
+        let latest = async {
+            loop {
+                if let Some(NetEvent::Packet { packet, .. }) = recv_lossy(&mut slow, "test").await
+                    && packet.packet_no == 11
+                {
+                    return;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), latest).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_forged_exit_is_flagged_and_ignored() {
+        let (server, addr) = spawn_listener().await;
+        let mut events = server.subscribe();
+        let real = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        real.send_to(b"1:1:alice:PC:1:alice\x00dev\x00", addr).await.unwrap();
+        assert_eq!(next_packet_event(&mut events).await, (commands::BR_ENTRY, false));
+
+        forger.send_to(b"1:2:alice:PC:2:alice\x00dev\x00", addr).await.unwrap();
+        assert_eq!(next_packet_event(&mut events).await, (commands::BR_EXIT, true));
+
+        assert_eq!(
+            server.get_user_addr("alice@PC").await,
+            Some(real.local_addr().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_same_name_from_another_ip_is_a_collision() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.passive = true;
+        let first: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+        let second: SocketAddr = "127.0.0.2:2425".parse().unwrap();
+
+        let entry = entry_packet("alice");
+        assert!(!server.handle_packet(&entry, &first).await);
+        assert!(!server.handle_packet(&entry, &second).await);
+        assert_eq!(server.stats().await.user_collisions, 1);
+
+        // 两台机器分别登记，原条目不被覆盖
+        assert_eq!(server.get_user_addr("alice@PC").await, Some(first));
+        assert_eq!(server.get_user_addr("alice@PC#127.0.0.2").await, Some(second));
+        assert!(server.resolve_recipient("alice").await.is_err());
+
+        // 已识别后后续报文按区分键处理，不再计为冲突或疑似伪造
+        assert!(!server.handle_packet(&entry, &second).await);
+        let mut exit = entry_packet("alice");
+        exit.command = commands::BR_EXIT;
+        assert!(!server.handle_packet(&exit, &second).await);
+        assert_eq!(server.stats().await.user_collisions, 1);
+        assert!(server.get_user_addr("alice@PC#127.0.0.2").await.is_none());
+        assert_eq!(server.get_user_addr("alice@PC").await, Some(first));
+    }
+
+    #[tokio::test]
+    async fn test_same_node_id_from_another_ip_updates_one_entry() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.timeout = Duration::from_millis(50);
+        let mut events = server.subscribe();
+        let old_addr = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let first = old_addr.local_addr().unwrap();
+        let second: SocketAddr = "127.0.0.2:2425".parse().unwrap();
+        let node_id = "0b5e2c1a-7f3d-4e8a-9c21-5d6f7a8b9c0d";
+
+        let mut entry = entry_packet("alice");
+        entry.extension = format!("{}{}", protocol::NODE_ID_PREFIX, node_id);
+        assert!(!server.handle_packet(&entry, &first).await);
+        // 重启后换了 IP：不算同名冲突，与其他地址变更一样先向旧地址确认，无人应答才迁移
+        assert!(server.handle_packet(&entry, &second).await);
+        assert_eq!(server.get_user_addr("alice@PC").await, Some(first));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(server.stats().await.user_collisions, 0);
+        assert_eq!(server.get_user_addr("alice@PC").await, Some(second));
+        assert_eq!(server.get_online_users().await.len(), 1);
+
+        // 主机名也改了：条目换用新键
+        let renamed = IpMsgPacket {
+            sender_host: "PC-NEW".into(),
+            ..entry.clone()
+        };
+        assert!(!server.handle_packet(&renamed, &second).await);
+        let users = server.get_online_users().await;
+        assert_eq!(users.len(), 1);
+        assert_eq!((users[0].hostname.as_str(), users[0].addr), ("PC-NEW", second));
+        assert!(server.get_user_addr("alice@PC").await.is_none());
+
+        let joined: Vec<NetEvent> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert!(matches!(&joined[..], [
+            .., NetEvent::Left { username, .. }, NetEvent::Joined { addr, .. },
+        ] if username == "alice@PC" && *addr == second));
+
+        // 不带节点 ID 的同名报文仍按原规则处理
+        server.passive = true;
+        assert!(!server.handle_packet(&entry_packet("bob"), &first).await);
+        assert!(!server.handle_packet(&entry_packet("bob"), &second).await);
+        assert_eq!(server.stats().await.user_collisions, 1);
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_ansentry_with_known_node_id_is_probed() {
+        let mut pair = testutil::pair().await;
+        pair.bob.server.set_node_id("0b5e2c1a-7f3d-4e8a-9c21-5d6f7a8b9c0d");
+        pair.meet().await;
+        pair.alice.server.timeout = Duration::from_millis(300);
+
+        // 冒充者抄来 bob 的节点 ID，从别的地址以另一个名字应答：不迁移、不改名，先探测
+        let mut forged = pair.bob.server.presence_packet(commands::IPMSG_ANSENTRY);
+        forged.sender_name = "mallory".into();
+        let spoofed: SocketAddr = "127.0.0.2:2425".parse().unwrap();
+        assert!(pair.alice.server.handle_packet(&forged, &spoofed).await);
+        assert!(pair.alice.server.probes.lock().unwrap().contains_key("bob@PC-B"));
+        assert!(pair.alice.server.get_user_addr("mallory@PC-B").await.is_none());
+
+        // bob 回复了探测，条目留在原地址
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(pair.alice.server.get_user_addr("bob@PC-B").await, Some(pair.bob.addr));
+        assert_eq!(pair.alice.server.get_online_users().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_exit_with_known_node_id_is_ignored() {
+        let mut pair = testutil::pair().await;
+        pair.bob.server.set_node_id("0b5e2c1a-7f3d-4e8a-9c21-5d6f7a8b9c0d");
+        pair.meet().await;
+        pair.alice.server.passive = true;
+
+        // 带 bob 节点 ID 的下线通知：改名的不会按节点 ID 找到 bob，同名的来自别的地址
+        let spoofed: SocketAddr = "127.0.0.2:2425".parse().unwrap();
+        let mut forged = pair.bob.server.build_packet(commands::BR_EXIT, "");
+        forged.extension = format!("{}{}", protocol::NODE_ID_PREFIX, "0b5e2c1a-7f3d-4e8a-9c21-5d6f7a8b9c0d");
+        let renamed = IpMsgPacket {
+            sender_name: "mallory".into(),
+            ..forged.clone()
+        };
+        pair.alice.server.handle_packet(&renamed, &spoofed).await;
+        assert!(pair.alice.server.handle_packet(&forged, &spoofed).await);
+        assert_eq!(pair.alice.server.get_user_addr("bob@PC-B").await, Some(pair.bob.addr));
+
+        // 尚未确认的条目（缓存恢复）也只接受登记地址发来的下线通知
+        pair.alice.server.users.write().await.get_mut("bob@PC-B").unwrap().confirmed = false;
+        assert!(!pair.alice.server.handle_packet(&forged, &spoofed).await);
+        assert_eq!(pair.alice.server.get_user_addr("bob@PC-B").await, Some(pair.bob.addr));
+        assert!(!pair.alice.server.handle_packet(&forged, &pair.bob.addr).await);
+        assert!(pair.alice.server.get_user_addr("bob@PC-B").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reentry_from_new_address_after_failed_probe() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.timeout = Duration::from_millis(50);
+        let old_addr = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let new_addr: SocketAddr = "127.0.0.1:2999".parse().unwrap();
+
+        let entry = entry_packet("alice");
+        server.handle_packet(&entry, &old_addr.local_addr().unwrap()).await;
+        assert!(server.handle_packet(&entry, &new_addr).await);
+
+        // 旧地址收到确认探测（GETINFO）但不响应，超时后迁移到新地址
+        let mut buf = [0; 1024];
+        let (len, _) = old_addr.recv_from(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..len]).contains(":64:"));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(server.get_user_addr("alice@PC").await, Some(new_addr));
+    }
+
+    #[tokio::test]
+    async fn test_probe_answered_within_ansentry_suppress_window() {
+        let mut pair = testutil::pair().await;
+        pair.meet().await;
+        pair.alice.server.timeout = Duration::from_millis(300);
+
+        // bob 刚应答过 alice 的上线通知，5 秒内不会再应答她的 BR_ENTRY；
+        // 此时冒充 bob 的上线报文触发的探测仍须得到回复
+        let mut forged = pair.bob.server.presence_packet(commands::BR_ENTRY);
+        forged.sender_name = "bob".into();
+        let spoofed: SocketAddr = "127.0.0.1:2999".parse().unwrap();
+        assert!(pair.alice.server.handle_packet(&forged, &spoofed).await);
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(pair.alice.server.get_user_addr("bob@PC-B").await, Some(pair.bob.addr));
+        assert!(pair.alice.server.probes.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_datagram_emits_raw_event() {
+        let (server, addr) = spawn_listener().await;
+        let mut events = server.subscribe();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let garbage = b"not:an:ipmsg".to_vec();
+        peer.send_to(&garbage, addr).await.unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        match event {
+            NetEvent::RawDatagram { from, bytes, error } => {
+                assert_eq!(from, peer.local_addr().unwrap());
+                assert_eq!(bytes, garbage);
+                assert_eq!(error, Some(ProtocolError::TooFewFields { found: 3 }));
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_reports_partial_failure() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        // IPv6 套接字无法发往 IPv4 广播目标，模拟一个出错的网卡
+        server.bind_extra_port("[::1]:0".parse().unwrap(), 2425).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+
+        let summary = server.broadcast_all(&entry_packet("alice")).await;
+        assert_eq!(summary.results.len(), 2);
+        assert_eq!(summary.succeeded(), 1);
+        assert!(!summary.is_complete());
+        assert_eq!(summary.failures().count(), 1);
+        assert_eq!(summary.results[0].0, sink.local_addr().unwrap());
+
+        // 只要有目标成功，broadcast 不返回错误，其余目标照常发出
+        server.broadcast(&entry_packet("alice")).await.unwrap();
+        let mut buf = [0; 1024];
+        for _ in 0..2 {
+            tokio::time::timeout(Duration::from_secs(1), sink.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_broadcast_target_redirects_broadcast() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = receiver.local_addr().unwrap();
+
+        server.set_broadcast_target(target).unwrap();
+        assert_eq!(server.broadcast_target(), target);
+        server.broadcast(&entry_packet("alice")).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let (len, from) = tokio::time::timeout(Duration::from_secs(1), receiver.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, server.local_addr().unwrap());
+        assert!(len > 0);
+
+        // 地址族不一致时拒绝切换，原目标保持不变
+        assert!(server.set_broadcast_target("[::1]:2425".parse().unwrap()).is_err());
+        assert_eq!(server.broadcast_target(), target);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_repeat_uses_fresh_packet_numbers() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+
+        let command = commands::MSG | commands::IPMSG_BROADCASTOPT;
+        let sent = server
+            .broadcast_repeat(command, "soak", 3, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(sent.len(), 3);
+
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        for _ in 0..3 {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(1), sink.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+            assert_eq!(packet.command, command);
+            received.push(packet.packet_no);
+        }
+        assert_eq!(received, sent);
+        received.sort_unstable();
+        received.dedup();
+        assert_eq!(received.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_confirmed_against_roster() {
+        let mut pair = testutil::pair().await;
+        pair.meet().await;
+        // carol 在用户表中但不回复确认
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut passive = pair.alice.server.clone();
+        passive.passive = true;
+        passive.handle_packet(&entry_packet("carol"), &silent.local_addr().unwrap()).await;
+
+        let command = commands::MSG | commands::IPMSG_BROADCASTOPT;
+        let result = pair
+            .alice
+            .server
+            .broadcast_confirmed(command, "fire drill", Duration::from_millis(300))
+            .await
+            .unwrap();
+        let names = |users: &[OnlineUser]| users.iter().map(|user| user.username.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&result.confirmed), ["bob"]);
+        assert_eq!(names(&result.unconfirmed), ["carol"]);
+        assert!(result.unknown.is_empty());
+
+        let (received, _) = pair.bob.next_message().await;
+        assert_eq!(received.packet_no, result.packet_no);
+        assert_eq!(received.command, command | commands::IPMSG_SENDCHECKOPT);
+    }
+
+    #[tokio::test]
+    async fn test_acked_send_emits_send_result() {
+        let pair = testutil::pair().await;
+        pair.meet().await;
+        let alice = &pair.alice.server;
+        let mut events = alice.subscribe();
+        let mut next_result = async || loop {
+            match tokio::time::timeout(Duration::from_secs(2), events.recv()).await.unwrap().unwrap() {
+                NetEvent::SendResult { packet_no, delivered } => return (packet_no, delivered),
+                _ => continue,
+            }
+        };
+
+        let packet = alice.build_packet(commands::MSG, "delivered?");
+        assert!(alice.send_checked(&packet, &pair.bob.addr, None).await.unwrap().is_some());
+        assert_eq!(next_result().await, (packet.packet_no, true));
+
+        // 重发全部未确认只推送一次
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let packet = alice.build_packet(commands::MSG, "anyone?");
+        let rtt = alice
+            .send_reliable(&packet, &silent.local_addr().unwrap(), Duration::from_millis(150))
+            .await
+            .unwrap();
+        assert!(rtt.is_none());
+        assert_eq!(next_result().await, (packet.packet_no, false));
+        while let Ok(event) = events.try_recv() {
+            assert!(!matches!(event, NetEvent::SendResult { .. }), "{:?}", event);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ipv4_mapped_source_stored_as_ipv4() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.passive = true;
+        let mapped: SocketAddr = "[::ffff:192.168.1.5]:2425".parse().unwrap();
+        let plain: SocketAddr = "192.168.1.5:2425".parse().unwrap();
+        server.handle_packet(&entry_packet("alice"), &mapped).await;
+
+        let users = server.get_online_users().await;
+        assert_eq!(users[0].addr, plain);
+        assert_eq!(users[0].addr.to_string(), "192.168.1.5:2425");
+        assert_eq!(server.get_user_addr("alice@PC").await, Some(plain));
+        // 之后经 IPv4 到达的报文是同一用户，不算地址冲突
+        assert!(!server.handle_packet(&entry_packet("alice"), &plain).await);
+        assert_eq!(server.get_online_users().await.len(), 1);
+        assert_eq!(server.resolve_recipient("::ffff:192.168.1.5").await.unwrap(), plain);
+        assert_eq!(canonical_addr("[::1]:2425".parse().unwrap()).to_string(), "[::1]:2425");
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_reaches_every_target() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = first.local_addr().unwrap().port();
+        let second_addr = second.local_addr().unwrap();
+
+        let targets = [
+            BroadcastTarget::Ip("127.0.0.1".parse().unwrap()),
+            BroadcastTarget::Host("localhost".into()),
+        ];
+        server.set_broadcast_specs(&targets, port).unwrap();
+        let summary = server.broadcast_all(&entry_packet("alice")).await;
+        // localhost 解析为同一地址，只发一次
+        assert_eq!(summary.results.len(), 1);
+        let mut buf = [0; 1024];
+        tokio::time::timeout(Duration::from_secs(1), first.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        *server.broadcast_targets.write().unwrap() = vec![
+            BroadcastDest {
+                addr: first.local_addr().unwrap(),
+                host: None,
+            },
+            BroadcastDest {
+                addr: second_addr,
+                host: None,
+            },
+        ];
+        let summary = server.broadcast_all(&entry_packet("alice")).await;
+        assert!(summary.is_complete());
+        assert_eq!(server.broadcast_targets(), [first.local_addr().unwrap(), second_addr]);
+        for receiver in [&first, &second] {
+            tokio::time::timeout(Duration::from_secs(1), receiver.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        // 任一目标地址族不符时整体不变
+        let mixed = [
+            BroadcastTarget::Ip("127.0.0.1".parse().unwrap()),
+            BroadcastTarget::Ip("ff02::1".parse().unwrap()),
+        ];
+        assert!(server.set_broadcast_specs(&mixed, port).is_err());
+        assert_eq!(server.broadcast_targets(), [first.local_addr().unwrap(), second_addr]);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_host_resolved_at_send_time() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = receiver.local_addr().unwrap();
+
+        let host = BroadcastTarget::Host("localhost".into());
+        server.set_broadcast_spec(&host, target.port()).unwrap();
+        server.broadcast(&entry_packet("alice")).await.unwrap();
+        assert_eq!(server.broadcast_target(), target);
+        let mut buf = [0; 1024];
+        tokio::time::timeout(Duration::from_secs(1), receiver.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // 解析失败时沿用上次可用的地址
+        let missing = BroadcastTarget::Host("no-such-host.invalid".into());
+        server.set_broadcast_spec(&missing, target.port()).unwrap();
+        server.broadcast(&entry_packet("alice")).await.unwrap();
+        assert_eq!(server.broadcast_target(), target);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_entry_yields_single_ansentry() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.ansentry_jitter = Duration::from_millis(20);
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        let entry = entry_packet("alice");
+        server.handle_packet(&entry, &peer_addr).await;
+        server.handle_packet(&entry, &peer_addr).await;
+
+        let config = AppConfig::default();
+        let mut buf = [0; 1024];
+        let mut replies = 0;
+        while let Ok(Ok((len, _))) =
+            tokio::time::timeout(Duration::from_millis(200), peer.recv_from(&mut buf)).await
+        {
+            let packet = IpMsgPacket::decode_with_config(&buf[..len], &config).unwrap();
+            if packet.command == commands::IPMSG_ANSENTRY {
+                replies += 1;
+            }
+        }
+        assert_eq!(replies, 1);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_ports_share_one_registry() {
+        let receiver_a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver_b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let extra_bind = "127.0.0.1:0".parse().unwrap();
+        let peer_port_b = receiver_b.local_addr().unwrap().port();
+        server.bind_extra_port(extra_bind, peer_port_b).await.unwrap();
+        server.set_broadcast_target(receiver_a.local_addr().unwrap()).unwrap();
+        let ports = server.local_addrs();
+        assert_eq!(ports.len(), 2);
+
+        let mut events = server.subscribe();
+        let listener = server.clone();
+        tokio::spawn(async move {
+            let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+        });
+
+        // 同一节点在两个端口上各发一次同一报文，只计一个用户、只处理一次
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let entry = b"1:10:alice:PC:1:alice\x00dev\x00";
+        peer.send_to(entry, ports[1]).await.unwrap();
+        assert_eq!(next_packet_event(&mut events).await, (commands::BR_ENTRY, false));
+        peer.send_to(entry, ports[0]).await.unwrap();
+        // 不同报文编号经另一端口到达，也不视为伪造
+        peer.send_to(b"1:11:alice:PC:1:alice\x00dev\x00", ports[0]).await.unwrap();
+        assert_eq!(next_packet_event(&mut events).await, (commands::BR_ENTRY, false));
+
+        let users = server.get_online_users().await;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].via_port, ports[1].port());
+
+        // 回复从学习到该用户的端口发出
+        let mut buf = [0; 1024];
+        let peer_addr = peer.local_addr().unwrap();
+        while tokio::time::timeout(Duration::from_millis(100), peer.recv_from(&mut buf))
+            .await
+            .is_ok()
+        {}
+        server.send_to(&entry_packet("me"), &peer_addr).await.unwrap();
+        let (_, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, ports[1]);
+
+        // 广播在每个端口上各发一次
+        server.broadcast(&entry_packet("me")).await.unwrap();
+        let (_, from_a) = receiver_a.recv_from(&mut buf).await.unwrap();
+        let (_, from_b) = receiver_b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from_a, ports[0]);
+        assert_eq!(from_b, ports[1]);
+    }
+
+    #[tokio::test]
+    async fn test_reorder_window_delivers_swapped_messages_in_order() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut config = AppConfig::default();
+        config.network.reorder_window_ms = 200;
+
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let listener = server.clone();
+        let sink_list = delivered.clone();
+        tokio::spawn(async move {
+            let _ = listener
+                .listen(
+                    move |packet, _| sink_list.lock().unwrap().push(packet.packet_no),
+                    Arc::new(config),
+                )
+                .await;
+        });
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for packet in [&b"1:10:bob:PC:32:question"[..], b"1:12:bob:PC:32:answer", b"1:11:bob:PC:32:more"] {
+            peer.send_to(packet, addr).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*delivered.lock().unwrap(), [10, 11, 12]);
+    }
+
+    #[tokio::test]
+    async fn test_psk_drops_unsigned_packets() {
+        let mut config = AppConfig::default();
+        config.security.psk = "secret".to_string();
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.signer = Some(PacketSigner::new(&config.security.psk));
+        let addr = server.local_addr().unwrap();
+        let mut events = server.subscribe();
+        let listener = server.clone();
+        tokio::spawn(async move { listener.listen(|_, _| {}, Arc::new(config)).await });
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"1:1:alice:PC:32:forged", addr).await.unwrap();
+        let signed = PacketSigner::new("secret").sign(b"1:2:alice:PC:32:genuine");
+        peer.send_to(&signed, addr).await.unwrap();
+
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let NetEvent::Packet { packet, .. } = event {
+                assert_eq!(packet.packet_no, 2);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signed_long_message_verifies() {
+        let mut config = AppConfig::default();
+        config.security.psk = "secret".to_string();
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.signer = Some(PacketSigner::new(&config.security.psk));
+        let addr = server.local_addr().unwrap();
+        let mut events = server.subscribe();
+        let listener = server.clone();
+        tokio::spawn(async move { listener.listen(|_, _| {}, Arc::new(config)).await });
+
+        // 签名附在报文末尾：超过 1 KB 的消息整条收到后签名仍然有效
+        let mut sender = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        sender.signer = Some(PacketSigner::new("secret"));
+        let text = "signed ".repeat(400) + "end";
+        let packet = sender.build_packet(commands::MSG, &text);
+        sender.send_to(&packet, &addr).await.unwrap();
+
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let NetEvent::Packet { packet: received, .. } = event {
+                assert_eq!((received.packet_no, received.additional_msg), (packet.packet_no, text));
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dnd_queue_flushes_when_back_online() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut events = server.subscribe();
+
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let listener = server.clone();
+        let sink_list = delivered.clone();
+        tokio::spawn(async move {
+            let _ = listener
+                .listen(
+                    move |packet, _| sink_list.lock().unwrap().push(packet.packet_no),
+                    Arc::new(AppConfig::default()),
+                )
+                .await;
+        });
+
+        server.set_state(PresenceState::DoNotDisturb).await.unwrap();
+        let mut buf = [0; 1024];
+        let (len, _) = sink.recv_from(&mut buf).await.unwrap();
+        let refresh = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+        assert_eq!(refresh.command & 0xff, commands::IPMSG_BR_ABSENCE);
+        assert_ne!(refresh.command & commands::IPMSG_ABSENCEOPT, 0);
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"1:42:bob:PC:32:hello", addr).await.unwrap();
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let NetEvent::Queued { packet, .. } = event {
+                assert_eq!(packet.packet_no, 42);
+                break;
+            }
+        }
+        assert!(delivered.lock().unwrap().is_empty());
+
+        server.set_state(PresenceState::Online).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*delivered.lock().unwrap(), vec![42]);
+        assert_eq!(server.state(), PresenceState::Online);
+    }
+
+    #[tokio::test]
+    async fn test_passive_mode_sends_nothing() {
+        let mut config = AppConfig::default();
+        config.debug.passive = true;
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.passive = config.debug.passive;
+        server.ansentry_jitter = Duration::ZERO;
+        let addr = server.local_addr().unwrap();
+        let mut events = server.subscribe();
+        let listener = server.clone();
+        tokio::spawn(async move {
+            let _ = listener.listen(|_, _| {}, Arc::new(config)).await;
+        });
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"1:1:alice:PC:1:alice\x00dev\x00", addr).await.unwrap();
+        peer.send_to(b"1:2:alice:PC:112:", addr).await.unwrap();
+        // 仍然接收并记录报文
+        assert_eq!(next_packet_event(&mut events).await.0, commands::BR_ENTRY);
+        assert_eq!(
+            next_packet_event(&mut events).await.0,
+            commands::IPMSG_GETABSENCEINFO
+        );
+        assert_eq!(server.get_online_users().await.len(), 1);
+
+        let mut buf = [0; 1024];
+        let reply = tokio::time::timeout(Duration::from_millis(300), peer.recv_from(&mut buf)).await;
+        assert!(reply.is_err(), "passive server must not reply");
+    }
+
+    #[tokio::test]
+    async fn test_learn_from_any_packet_toggle() {
+        let message = IpMsgPacket {
+            sender_name: "bob".into(),
+            sender_user: "bob".into(),
+            sender_host: "PC".into(),
+            command: commands::MSG,
+            additional_msg: "hi".into(),
+            ..Default::default()
+        };
+        let peer: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.passive = true;
+        server.handle_packet(&message, &peer).await;
+        assert!(server.get_online_users().await.is_empty());
+
+        server.learn_from_any_packet = true;
+        server.handle_packet(&message, &peer).await;
+        let users = server.get_online_users().await;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].addr, peer);
+        // 消息正文不会被当作状态说明
+        assert_eq!(users[0].note, "");
+    }
+
+    #[tokio::test]
+    async fn test_absence_refresh_updates_roster() {
+        let pair = testutil::pair().await;
+        pair.meet().await;
+        let bob_status = |server: IpMsgServer| async move {
+            let users = server.get_online_users().await;
+            let bob = users.iter().find(|user| user.username == "bob").unwrap();
+            (bob.absent, bob.note.clone(), bob.absence.clone())
+        };
+
+        // 伪造的状态变更不改动已知用户
+        let mut forged = pair.bob.server.presence_packet(commands::IPMSG_BR_ABSENCE);
+        forged.command |= commands::IPMSG_ABSENCEOPT;
+        forged.additional_msg = "gone for good".into();
+        let spoofed: SocketAddr = "127.0.0.2:2425".parse().unwrap();
+        pair.alice.server.handle_packet(&forged, &spoofed).await;
+        assert_eq!(bob_status(pair.alice.server.clone()).await, (false, String::new(), None));
+
+        let away = PresenceState::Away { message: "at lunch".into() };
+        pair.bob.server.set_state(away).await.unwrap();
+        let expected = (true, "at lunch".to_string(), Some("at lunch".to_string()));
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while bob_status(pair.alice.server.clone()).await != expected {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        pair.bob.server.set_state(PresenceState::Online).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while bob_status(pair.alice.server.clone()).await != (false, String::new(), None) {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_messages_match_entry_by_login() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.passive = true;
+        server.learn_from_any_packet = true;
+        let peer: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+        // 登录名 bob、昵称 Bobby：上线报文带昵称，消息只带登录名
+        let mut entry = entry_packet("Bobby");
+        entry.sender_user = "bob".into();
+        server.handle_packet(&entry, &peer).await;
+        let message = IpMsgPacket {
+            sender_name: "bob".into(),
+            sender_user: "bob".into(),
+            sender_host: "PC".into(),
+            command: commands::MSG,
+            additional_msg: "hi".into(),
+            ..Default::default()
+        };
+        assert!(!server.handle_packet(&message, &peer).await);
+        let users = server.get_online_users().await;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].username, "Bobby");
+        assert_eq!(users[0].login, "bob");
+        assert_eq!(server.recent_messages()[0].sender, "Bobby@PC");
+
+        // 其他地址冒用登录名发来的消息按已知条目检查
+        let spoofer: SocketAddr = "127.0.0.9:2425".parse().unwrap();
+        assert!(server.handle_packet(&message, &spoofer).await);
+        assert_eq!(server.get_online_users().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_entry_settle_waits_for_recipient() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.passive = true;
+
+        // 没有应答时等满设定的时长
+        let waited = server.settle_after_entry(Duration::from_millis(300), &["carol"]).await;
+        assert!(waited >= Duration::from_millis(300) && waited < Duration::from_secs(2), "{:?}", waited);
+        let waited = server.settle_after_entry(Duration::from_millis(200), &[]).await;
+        assert!(waited >= Duration::from_millis(200));
+
+        // 收件人出现后立即返回
+        let entry = IpMsgPacket {
+            sender_name: "carol".into(),
+            sender_user: "carol".into(),
+            sender_host: "PC-C".into(),
+            command: commands::IPMSG_ANSENTRY,
+            ..Default::default()
+        };
+        let peer: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+        let answering = server.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            answering.handle_packet(&entry, &peer).await;
+        });
+        let waited = server.settle_after_entry(Duration::from_secs(5), &["carol"]).await;
+        assert!(waited < Duration::from_secs(2), "{:?}", waited);
+        assert_eq!(server.settle_after_entry(Duration::from_secs(5), &["127.0.0.1"]).await.as_secs(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stealth_mode_lurks_until_left() {
+        let mut config = AppConfig::default();
+        config.presence.stealth = true;
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.stealth.store(config.presence.stealth, Ordering::Relaxed);
+        server.ansentry_jitter = Duration::ZERO;
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut events = server.subscribe();
+        let listener = server.clone();
+        tokio::spawn(async move {
+            let _ = listener.listen(|_, _| {}, Arc::new(config)).await;
+        });
+
+        // 不广播在线状态，也不应答上线通知，但仍收集在线用户
+        server.broadcast(&server.presence_packet(commands::BR_ENTRY)).await.unwrap();
+        server.set_away(Some("lurking")).await.unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"1:1:alice:PC:1:alice\x00dev\x00", addr).await.unwrap();
+        assert_eq!(next_packet_event(&mut events).await.0, commands::BR_ENTRY);
+        assert_eq!(server.get_online_users().await.len(), 1);
+        let mut buf = [0; 1024];
+        let silent = tokio::time::timeout(Duration::from_millis(300), peer.recv_from(&mut buf)).await;
+        assert!(silent.is_err(), "stealth server must not answer BR_ENTRY");
+        assert!(tokio::time::timeout(Duration::from_millis(50), sink.recv_from(&mut buf)).await.is_err());
+
+        // 单播消息照常发出，带 NOADDLISTOPT
+        server.send_to(&server.build_packet(commands::MSG, "psst"), &peer.local_addr().unwrap()).await.unwrap();
+        let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+        let message = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+        assert_eq!(message.command, commands::MSG | commands::IPMSG_NOADDLISTOPT);
+
+        // 离开隐身后正常广播上线
+        server.set_stealth(false).await.unwrap();
+        let (len, _) = sink.recv_from(&mut buf).await.unwrap();
+        let entry = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+        assert_eq!(entry.command & 0xff, commands::BR_ENTRY);
+        assert_eq!(entry.command & commands::IPMSG_NOADDLISTOPT, 0);
+    }
+
+    #[tokio::test]
+    async fn test_peer_encoding_override_beats_global() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let mut config = AppConfig::default();
+        config.encoding.protocol = "utf-8".into();
+        let peer: IpAddr = "192.168.1.44".parse().unwrap();
+        let gbk = encoding_rs::GBK.encode("1:1:alice:PC:32:你好").0.into_owned();
+        let utf8 = "1:1:alice:PC:32:你好".as_bytes();
+
+        // 全局 utf-8 无法正确解出 GBK 报文
+        let packet = server.decode_from(&gbk, peer, &config).unwrap();
+        assert_ne!(packet.additional_msg, "你好");
+
+        config.encoding.peers.insert("192.168.1.0/24".into(), "gbk".into());
+        server.set_peer_encodings(PeerEncodings::from_config(&config.encoding.peers));
+        assert_eq!(server.decode_from(&gbk, peer, &config).unwrap().additional_msg, "你好");
+
+        // 用户名@主机名 优先于网段
+        config.encoding.peers.insert("alice@PC".into(), "utf-8".into());
+        server.set_peer_encodings(PeerEncodings::from_config(&config.encoding.peers));
+        assert_eq!(server.decode_from(utf8, peer, &config).unwrap().additional_msg, "你好");
+
+        // 发送时同样使用覆盖的编码
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+        server.set_peer_encodings(PeerEncodings::from_config(&BTreeMap::from([(
+            "127.0.0.1".to_string(),
+            "gbk".to_string(),
+        )])));
+        let mut packet = server.build_packet(commands::MSG, "你好");
+        packet.group_name.clear();
+        server.send_to(&packet, &receiver_addr).await.unwrap();
+        let mut buf = [0; 1024];
+        let (len, _) = receiver.recv_from(&mut buf).await.unwrap();
+        assert!(buf[..len].ends_with(&encoding_rs::GBK.encode("你好").0));
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_drops_unlisted_peers() {
+        let (server, addr) = spawn_listener().await;
+        server.set_peer_filter(PeerFilter::new(&[], &["carol@LAB-PC".to_string()]));
+        let mut events = server.subscribe();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"1:1:alice:PC:1:alice\x00dev\x00", addr).await.unwrap();
+        peer.send_to(b"1:2:carol:LAB-PC:1:carol\x00dev\x00", addr).await.unwrap();
+        assert_eq!(next_packet_event(&mut events).await.0, commands::BR_ENTRY);
+
+        let users = server.get_online_users().await;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].hostname, "LAB-PC");
+        assert_eq!(server.stats().await.packets_blocked, 1);
+
+        assert!(server.block_peer("127.0.0.1").unwrap());
+        peer.send_to(b"1:3:carol:LAB-PC:32:hi", addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.stats().await.packets_blocked, 2);
+    }
+
+    #[tokio::test]
+    async fn test_user_config_sets_login_and_name() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let user = UserConfig {
+            name: "Zhang San".into(),
+            login: "zhangsan".into(),
+            host: "PC-1".into(),
+            group: "dev".into(),
+            ..Default::default()
+        };
+        server.set_user(&user);
+
+        let entry = server.presence_packet(commands::BR_ENTRY);
+        assert_eq!(entry.sender_user, "zhangsan");
+        assert_eq!(entry.sender_name, "Zhang San");
+        assert_eq!(entry.group_name, "dev");
+        assert!(entry.encode().ends_with(":zhangsan:PC-1:1:Zhang San\0dev"));
+        assert!(server.is_self(&entry));
+    }
+
+    #[tokio::test]
+    async fn test_configured_client_version_is_sent() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+        server.set_user(&UserConfig {
+            client_version: "acme-fleet 2.3".into(),
+            ..Default::default()
+        });
+
+        server.broadcast(&server.presence_packet(commands::BR_ENTRY)).await.unwrap();
+        server.send_to(&server.build_packet(commands::MSG, "hi"), &sink.local_addr().unwrap()).await.unwrap();
+        let mut buf = [0; 1024];
+        for _ in 0..2 {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(1), sink.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(buf[..len].starts_with(b"acme-fleet 2.3:"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_getabsenceinfo_answered_from_state() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+        server
+            .set_state(PresenceState::Away {
+                message: "lunch".into(),
+            })
+            .await
+            .unwrap();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = IpMsgPacket {
+            sender_name: "bob".into(),
+            sender_host: "PC".into(),
+            command: commands::IPMSG_GETABSENCEINFO,
+            ..Default::default()
+        };
+        server.handle_packet(&request, &peer.local_addr().unwrap()).await;
+
+        let mut buf = [0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), peer.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+        assert_eq!(reply.command, commands::IPMSG_SENDABSENCEINFO);
+        assert_eq!(reply.additional_msg, "lunch");
+    }
+
+    #[tokio::test]
+    async fn test_rapid_broadcasts_are_paced_not_lost() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let interval = Duration::from_millis(10);
+        server.pacer = Arc::new(Mutex::new(BroadcastPacer::new(interval)));
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+
+        let started = Instant::now();
+        let sends = (1..=50).map(|n| {
+            let server = server.clone();
+            async move {
+                let packet = IpMsgPacket {
+                    packet_no: n,
+                    command: commands::MSG,
+                    additional_msg: format!("flood {}", n),
+                    ..Default::default()
+                };
+                server.broadcast(&packet).await
+            }
+        });
+        for result in futures::future::join_all(sends).await {
+            result.unwrap();
+        }
+        assert!(started.elapsed() >= interval * 49, "{:?}", started.elapsed());
+
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while received.len() < 50 {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(1), sink.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+            received.push(packet.packet_no);
+        }
+        received.sort();
+        assert_eq!(received, (1..=50).collect::<Vec<_>>());
+        assert_eq!(server.stats().await.broadcasts_throttled, 49);
+
+        // 排队中的在线状态刷新只发出最新的一次
+        tokio::time::sleep(interval).await;
+        let refreshes = (0..3).map(|_| {
+            let server = server.clone();
+            async move {
+                server.broadcast(&server.presence_packet(commands::IPMSG_BR_ABSENCE)).await
+            }
+        });
+        for result in futures::future::join_all(refreshes).await {
+            result.unwrap();
+        }
+        let mut refreshes = 0;
+        while tokio::time::timeout(Duration::from_millis(100), sink.recv_from(&mut buf)).await.is_ok() {
+            refreshes += 1;
+        }
+        assert_eq!(refreshes, 2);
+    }
+
+    #[test]
+    fn test_short_send_is_an_error() {
+        assert!(check_sent(Ok(1400), 1400).is_ok());
+        let error = check_sent(Ok(1024), 1400).unwrap_err();
+        assert!(error.to_string().contains("short send: 1024 of 1400 bytes"), "{}", error);
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            check_sent(Err(refused), 1400).unwrap_err().kind(),
+            std::io::ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recent_messages_keep_latest() {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.history_size = 3;
+        let peer: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+        for n in 1..=5 {
+            let message = IpMsgPacket {
+                packet_no: n,
+                sender_name: "bob".into(),
+                sender_user: "bob".into(),
+                sender_host: "PC".into(),
+                command: commands::MSG,
+                additional_msg: format!("message {}", n),
+                ..Default::default()
+            };
+            server.handle_packet(&message, &peer).await;
+        }
+        let recent = server.recent_messages();
+        let texts: Vec<&str> = recent.iter().map(|message| message.text.as_str()).collect();
+        assert_eq!(texts, ["message 3", "message 4", "message 5"]);
+        assert_eq!(recent[0].sender, "bob@PC");
+        assert_eq!(recent[0].from, peer);
+    }
+
+    #[tokio::test]
+    async fn test_query_peer_returns_version_and_absence() {
+        let alice = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let bob = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        bob.set_user(&UserConfig {
+            name: "bob".into(),
+            host: "PC-B".into(),
+            client_version: "lanMsg-test 1.2".into(),
+            ..Default::default()
+        });
+        bob.set_state(PresenceState::Away {
+            message: "in a meeting".into(),
+        })
+        .await
+        .unwrap();
+        for server in [&alice, &bob] {
+            let listener = server.clone();
+            tokio::spawn(async move {
+                let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+            });
+        }
+
+        let bob_addr = bob.local_addr().unwrap();
+        let info = alice
+            .query_peer(&bob_addr, true, Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        assert_eq!(info.version.as_deref(), Some("lanMsg-test 1.2"));
+        assert_eq!(info.absence.as_deref(), Some("in a meeting"));
+
+        let info = alice
+            .query_peer(&bob_addr, false, Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        assert_eq!(info.absence, None);
+
+        // 没有应答的地址两项都为 None
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let info = alice
+            .query_peer(&silent.local_addr().unwrap(), true, Some(Duration::from_millis(50)))
+            .await
+            .unwrap();
+        assert_eq!(info, PeerInfo::default());
+    }
+
+    #[tokio::test]
+    async fn test_absence_info_stored_on_user() {
+        let config = PresenceConfig {
+            away_message: "back at 14:00".into(),
+            ..Default::default()
+        };
+        let alice = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        alice.set_identity("alice", "PC-A");
+        let bob = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        bob.set_identity("bob", "PC-B");
+        bob.set_state(PresenceState::Away {
+            message: config.away_message.clone(),
+        })
+        .await
+        .unwrap();
+        for server in [&alice, &bob] {
+            let listener = server.clone();
+            tokio::spawn(async move {
+                let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+            });
+        }
+        let bob_addr = bob.local_addr().unwrap();
+        let mut passive = alice.clone();
+        passive.passive = true;
+        let entry = bob.presence_packet(commands::IPMSG_ANSENTRY);
+        passive.handle_packet(&entry, &bob_addr).await;
+        assert_eq!(alice.get_online_users().await[0].absence, None);
+
+        let absence = alice.query_absence(&bob_addr, Some(Duration::from_secs(1))).await.unwrap();
+        assert_eq!(absence.as_deref(), Some("back at 14:00"));
+        let users = alice.get_online_users().await;
+        assert!(users[0].absent);
+        assert_eq!(users[0].absence.as_deref(), Some("back at 14:00"));
+
+        // 对方回到在线后清除
+        bob.set_state(PresenceState::Online).await.unwrap();
+        passive
+            .handle_packet(&bob.presence_packet(commands::IPMSG_ANSENTRY), &bob_addr)
+            .await;
+        assert_eq!(alice.get_online_users().await[0].absence, None);
+        let absence = alice.query_absence(&bob_addr, Some(Duration::from_secs(1))).await.unwrap();
+        assert_eq!(absence.as_deref(), Some(NOT_ABSENT_MESSAGE));
+        assert_eq!(alice.get_online_users().await[0].absence, None);
+    }
+
+    #[tokio::test]
+    async fn test_getinfo_answered_with_client_version() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.set_user(&UserConfig {
+            client_version: "acme-fleet 2.3".into(),
+            ..Default::default()
+        });
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = IpMsgPacket {
+            sender_name: "bob".into(),
+            sender_host: "PC".into(),
+            command: commands::IPMSG_GETINFO,
+            ..Default::default()
+        };
+        server.handle_packet(&request, &peer.local_addr().unwrap()).await;
+
+        let mut buf = [0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), peer.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+        assert_eq!(reply.command, commands::IPMSG_SENDINFO);
+        assert_eq!(reply.additional_msg, "acme-fleet 2.3");
+    }
+
+    #[tokio::test]
+    async fn test_own_absence_query_not_answered() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.set_identity("alice", "PC-A");
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        // 回环收到自己广播的查询：不应答
+        let own = server.build_packet(commands::IPMSG_GETABSENCEINFO, "");
+        server.handle_packet(&own, &peer_addr).await;
+        let mut buf = [0; 1024];
+        let reply = tokio::time::timeout(Duration::from_millis(300), peer.recv_from(&mut buf)).await;
+        assert!(reply.is_err(), "own query must not be answered");
+
+        let request = IpMsgPacket {
+            sender_user: "bob".into(),
+            sender_host: "PC-B".into(),
+            command: commands::IPMSG_GETABSENCEINFO,
+            ..Default::default()
+        };
+        server.handle_packet(&request, &peer_addr).await;
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), peer.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+        assert_eq!(reply.command, commands::IPMSG_SENDABSENCEINFO);
+        assert_eq!(reply.additional_msg, NOT_ABSENT_MESSAGE);
+    }
+
+    #[tokio::test]
+    async fn test_auto_reply_once_per_interval() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+        server.set_presence_config(PresenceConfig {
+            away_message: "out for lunch".into(),
+            ..Default::default()
+        });
+        server.set_away(None).await.unwrap();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let message = IpMsgPacket {
+            sender_name: "bob".into(),
+            sender_host: "PC".into(),
+            command: commands::MSG,
+            additional_msg: "are you there?".into(),
+            ..Default::default()
+        };
+        server.handle_packet(&message, &peer.local_addr().unwrap()).await;
+        server.handle_packet(&message, &peer.local_addr().unwrap()).await;
+
+        let mut buf = [0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), peer.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+        assert_eq!(reply.command, commands::MSG | commands::IPMSG_AUTORETOPT);
+        assert_eq!(reply.additional_msg, "out for lunch");
+        // 间隔内的第二条消息不再回复
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), peer.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+
+        // 对方的自动回复不再回复
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let auto = IpMsgPacket {
+            sender_name: "carol".into(),
+            command: commands::MSG | commands::IPMSG_AUTORETOPT,
+            ..message
+        };
+        server.handle_packet(&auto, &other.local_addr().unwrap()).await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), other.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recv_next_returns_message_or_times_out() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        assert!(server.recv_next(Duration::from_millis(100)).await.is_none());
+
+        let waiter = {
+            let server = server.clone();
+            tokio::spawn(async move { server.recv_next(Duration::from_secs(2)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let from: SocketAddr = "127.0.0.1:2425".parse().unwrap();
+        let entry = IpMsgPacket {
+            sender_name: "bob".into(),
+            command: commands::BR_ENTRY,
+            ..Default::default()
+        };
+        let message = IpMsgPacket {
+            command: commands::MSG,
+            additional_msg: "ping".into(),
+            ..entry.clone()
+        };
+        // 非消息报文被跳过
+        server.deliver(entry, from, false, &|_, _| {});
+        server.deliver(message, from, false, &|_, _| {});
+
+        let (packet, addr) = waiter.await.unwrap().unwrap();
+        assert_eq!(packet.additional_msg, "ping");
+        assert_eq!(addr, from);
+    }
+
+    #[tokio::test]
+    async fn test_separate_send_socket() {
+        let mut config = AppConfig::default();
+        config.network.bind_ip = "127.0.0.1".to_string();
+        config.network.port = 0;
+        let server = IpMsgServer::from_config(&config).await.unwrap();
+        assert!(server.send_addr().is_none());
+
+        config.network.separate_send_socket = true;
+        let server = IpMsgServer::from_config(&config).await.unwrap();
+        let send_addr = server.send_addr().unwrap();
+        assert_ne!(send_addr, server.local_addr().unwrap());
+
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+        server.broadcast(&server.build_packet(commands::BR_ENTRY, "")).await.unwrap();
+        let mut buf = [0; 1024];
+        let (_, from) = tokio::time::timeout(Duration::from_secs(1), sink.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, send_addr);
+
+        // 回复到发送端口的报文同样被接收
+        let mut events = server.subscribe();
+        let listener = server.clone();
+        tokio::spawn(async move {
+            let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+        });
+        sink.send_to(b"1:1:bob:PC:3:bob\x00", send_addr).await.unwrap();
+        assert_eq!(next_packet_event(&mut events).await.0, commands::IPMSG_ANSENTRY);
+    }
+
+    #[tokio::test]
+    async fn test_overlong_message_truncated_or_rejected() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sink_addr = sink.local_addr().unwrap();
+        let mut user = UserConfig {
+            max_message_len: 8,
+            ..Default::default()
+        };
+        server.set_user(&user);
+        let mut buf = [0; 1024];
+
+        let long = server.build_packet(commands::MSG, "你好，这是一条很长的消息");
+        server.send_to(&long, &sink_addr).await.unwrap();
+        let (len, _) = sink.recv_from(&mut buf).await.unwrap();
+        let sent = String::from_utf8_lossy(&buf[..len]).to_string();
+        assert!(sent.ends_with(":你好，这是一条…"), "{}", sent);
+
+        // 未超长的消息与非 MSG 报文不受影响
+        server.send_to(&server.build_packet(commands::MSG, "short"), &sink_addr).await.unwrap();
+        let (len, _) = sink.recv_from(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..len]).ends_with(":short"));
+
+        user.message_len_policy = "reject".to_string();
+        server.set_user(&user);
+        let error = server.send_to(&long, &sink_addr).await.unwrap_err().to_string();
+        assert!(error.contains("max_message_len (8)"), "{}", error);
+        server.set_broadcast_target(sink_addr).unwrap();
+        assert!(server.broadcast(&long).await.is_err());
+        let info = server.build_packet(commands::IPMSG_SENDINFO, "a very long client version string");
+        server.send_to(&info, &sink_addr).await.unwrap();
+        // 被拒绝的消息没有发出，下一个收到的是 SENDINFO
+        let (len, _) = sink.recv_from(&mut buf).await.unwrap();
+        let received = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+        assert_eq!(received.command, commands::IPMSG_SENDINFO);
+    }
+
+    #[tokio::test]
+    async fn test_multicast_to_group_skips_self_and_blocked() {
+        let alice = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        alice.set_identity("alice", "PC-A");
+        let bob = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        bob.set_identity("bob", "PC-B");
+        let mut bob_events = bob.subscribe();
+        for server in [&alice, &bob] {
+            let listener = server.clone();
+            tokio::spawn(async move {
+                let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+            });
+        }
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let blocked = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let entry = |name: &str, host: &str, group: &str| IpMsgPacket {
+            sender_user: name.to_string(),
+            sender_name: name.to_string(),
+            sender_host: host.to_string(),
+            group_name: group.to_string(),
+            command: commands::BR_ENTRY,
+            ..Default::default()
+        };
+        let mut alice_passive = alice.clone();
+        alice_passive.passive = true;
+        for (packet, addr) in [
+            (entry("bob", "PC-B", "backend"), bob.local_addr().unwrap()),
+            (entry("carol", "PC-C", "backend"), silent.local_addr().unwrap()),
+            (entry("dave", "PC-D", "backend"), blocked.local_addr().unwrap()),
+            (entry("erin", "PC-E", "frontend"), "127.0.0.1:9".parse().unwrap()),
+            (entry("alice", "PC-A", "backend"), alice.local_addr().unwrap()),
+        ] {
+            alice_passive.handle_packet(&packet, &addr).await;
+        }
+        alice.block_peer("dave@PC-D").unwrap();
+
+        let members = crate::groups::members_of(&alice.get_online_users().await, "backend", false);
+        assert_eq!(members.len(), 4);
+        let results = alice
+            .multicast_users(&members, "standup in 5", Some(Duration::from_millis(300)))
+            .await;
+        let summary: Vec<(String, String)> = results
+            .iter()
+            .map(|(user, delivery)| (user.username.clone(), delivery.to_string()))
+            .collect();
+        assert_eq!(summary.len(), 2, "{:?}", summary);
+        assert_eq!(summary[0].0, "bob");
+        assert!(summary[0].1.starts_with("acked"), "{:?}", summary);
+        assert_eq!(summary[1], ("carol".to_string(), "no ack".to_string()));
+
+        let received = loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), bob_events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let NetEvent::Packet { packet, .. } = event
+                && packet.command & 0xff == commands::MSG
+            {
+                break packet;
+            }
+        };
+        assert_ne!(received.command & commands::IPMSG_MULTICASTOPT, 0);
+        assert_eq!(received.additional_msg, "standup in 5");
+        let mut buf = [0; 1024];
+        assert!(blocked.try_recv_from(&mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_many_reports_each_recipient() {
+        let alice = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let bob = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        bob.set_identity("bob", "PC-B");
+        for server in [&alice, &bob] {
+            let listener = server.clone();
+            tokio::spawn(async move {
+                let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+            });
+        }
+        let bob_addr = bob.local_addr().unwrap();
+        let mut passive = alice.clone();
+        passive.passive = true;
+        passive.handle_packet(&entry_packet("bob"), &bob_addr).await;
+
+        let recipients = ["bob".to_string(), "nobody".to_string(), bob_addr.to_string()];
+        let outcomes = alice
+            .send_many(&recipients, "deploy done", Some(Duration::from_secs(1)))
+            .await;
+        // bob 与其地址是同一个对端，只发一次；找不到的收件人不影响其他人
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes[0].recipient, "bob");
+        assert_eq!(outcomes[0].addr, Some(bob_addr));
+        assert_eq!(outcomes[0].delivery.status(), "acked");
+        assert_eq!(outcomes[1].recipient, "nobody");
+        assert_eq!(outcomes[1].addr, None);
+        assert!(outcomes[1].delivery.to_string().contains("not found"));
+    }
+
+    #[tokio::test]
+    async fn test_send_reliable_retries_until_timeout() {
+        let alice = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        alice.set_identity("alice", "PC-A");
+        let bob = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        bob.set_identity("bob", "PC-B");
+        for server in [&alice, &bob] {
+            let listener = server.clone();
+            tokio::spawn(async move {
+                let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+            });
+        }
+        let packet = alice.build_packet(commands::MSG, "deploy done");
+        let rtt = alice
+            .send_reliable(&packet, &bob.local_addr().unwrap(), Duration::from_secs(3))
+            .await
+            .unwrap();
+        assert!(rtt.unwrap() < Duration::from_secs(1));
+
+        // 不应答的对端：重发 ACK_ATTEMPTS 次（报文编号不变）后返回 None
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let started = Instant::now();
+        let rtt = alice
+            .send_reliable(&packet, &silent.local_addr().unwrap(), Duration::from_millis(150))
+            .await
+            .unwrap();
+        assert_eq!(rtt, None);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        let mut buf = [0; 1024];
+        for _ in 0..ACK_ATTEMPTS {
+            let (len, _) = silent.try_recv_from(&mut buf).unwrap();
+            let received = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+            assert_eq!(received.packet_no, packet.packet_no);
+            assert_ne!(received.command & commands::IPMSG_SENDCHECKOPT, 0);
+        }
+        assert!(silent.try_recv_from(&mut buf).is_err());
+        assert!(alice.pending.lock().unwrap().is_empty());
+
+        // 不等待确认时只发一次
+        let outcomes = alice
+            .send_many(&[silent.local_addr().unwrap().to_string()], "fire and forget", None)
+            .await;
+        assert_eq!(outcomes[0].delivery.to_string(), "sent");
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), silent.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let received = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+        assert_eq!(received.command & commands::IPMSG_SENDCHECKOPT, 0);
+    }
+
+    #[tokio::test]
+    async fn test_retry_recovers_lost_ack() {
+        let alice = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        alice.set_identity("alice", "PC-A");
+        let bob = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        bob.set_identity("bob", "PC-B");
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let listener = bob.clone();
+        let sink_list = delivered.clone();
+        tokio::spawn(async move {
+            let _ = listener
+                .listen(
+                    move |packet, _| sink_list.lock().unwrap().push(packet.additional_msg),
+                    Arc::new(AppConfig::default()),
+                )
+                .await;
+        });
+        let listener = alice.clone();
+        tokio::spawn(async move {
+            let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+        });
+
+        // 中间转发，丢掉 bob 的第一个 RECVMSG
+        let proxy = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let proxy_addr = proxy.local_addr().unwrap();
+        let bob_addr = bob.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; protocol::MAX_DATAGRAM];
+            let mut sender = None;
+            let mut acks = 0;
+            while let Ok((len, from)) = proxy.recv_from(&mut buf).await {
+                if from == bob_addr {
+                    acks += 1;
+                    if acks > 1 && let Some(sender) = sender {
+                        let _ = proxy.send_to(&buf[..len], sender).await;
+                    }
+                } else {
+                    sender = Some(from);
+                    let _ = proxy.send_to(&buf[..len], bob_addr).await;
+                }
+            }
+        });
+
+        let outcomes = alice
+            .send_many(&[proxy_addr.to_string()], "deploy done", Some(Duration::from_secs(3)))
+            .await;
+        assert!(matches!(outcomes[0].delivery, Delivery::Acked(_)), "{}", outcomes[0].delivery);
+        // 重发的消息只投递一次
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(*delivered.lock().unwrap(), vec!["deploy done".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_direct_send_without_presence() {
+        let alice = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let bob = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        bob.set_identity("bob", "PC-B");
+        let mut events = bob.subscribe();
+        for server in [&alice, &bob] {
+            let listener = server.clone();
+            tokio::spawn(async move {
+                let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+            });
+        }
+
+        // 双方都没有广播上线，alice 的用户表为空，按 --ip 给出的地址直接发送
+        assert!(alice.get_online_users().await.is_empty());
+        let target = bob.local_addr().unwrap().to_string();
+        let addr = alice.resolve_recipient(&target).await.unwrap();
+        let message = alice.build_packet(commands::MSG, "hello over ip");
+        let rtt = alice.send_checked(&message, &addr, Some(Duration::from_secs(1))).await.unwrap();
+        assert!(rtt.is_some());
+
+        let received = loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let NetEvent::Packet { packet, suspect, .. } = event
+                && packet.command & 0xff == commands::MSG
+            {
+                assert!(!suspect);
+                break packet;
+            }
+        };
+        assert_eq!(received.additional_msg, "hello over ip");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_returns_once_replies_settle() {
+        let alice = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let mut bob = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        bob.ansentry_jitter = Duration::ZERO;
+        bob.set_identity("bob", "PC-B");
+        for server in [&alice, &bob] {
+            let listener = server.clone();
+            tokio::spawn(async move {
+                let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+            });
+        }
+
+        // refresh 自行广播上线通知，bob 应答后不必等满超时
+        alice.set_broadcast_target(bob.local_addr().unwrap()).unwrap();
+        let started = Instant::now();
+        let users = alice.refresh_users(Some(Duration::from_secs(10))).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let bob_entry = users.iter().find(|user| user.username == "bob").unwrap();
+        assert_eq!(bob_entry.addr, bob.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_configured_timeout_bounds_waits() {
+        let mut alice = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        alice.timeout = Duration::from_millis(200);
+        let mut bob = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        bob.ansentry_jitter = Duration::ZERO;
+        bob.set_identity("bob", "PC-B");
+        let bob_addr = bob.local_addr().unwrap();
+        for server in [&alice, &bob] {
+            let listener = server.clone();
+            tokio::spawn(async move {
+                let _ = listener.listen(|_, _| {}, Arc::new(AppConfig::default())).await;
+            });
+        }
+
+        alice.set_broadcast_target(bob_addr).unwrap();
+        alice.broadcast(&alice.presence_packet(commands::BR_ENTRY)).await.unwrap();
+        let started = Instant::now();
+        let users = alice.refresh_users(None).await;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(1));
+        assert!(users.iter().any(|user| user.username == "bob"));
+
+        let message = alice.build_packet(commands::MSG, "hello");
+        let rtt = alice.send_checked(&message, &bob_addr, None).await.unwrap();
+        assert!(rtt.unwrap() < Duration::from_millis(200));
+
+        // 无应答时按配置的超时返回，单次调用可覆盖
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let started = Instant::now();
+        assert!(alice.send_checked(&message, &silent_addr, None).await.unwrap().is_none());
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(1));
+        let started = Instant::now();
+        let info = alice
+            .query_info(&silent_addr, Some(Duration::from_millis(50)))
+            .await
+            .unwrap();
+        assert!(info.is_none());
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert!(alice.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_multicast_reaches_each_member() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let mut members = Vec::new();
+        for name in ["bob", "carol"] {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            server
+                .handle_packet(&entry_packet(name), &socket.local_addr().unwrap())
+                .await;
+            members.push(socket);
+        }
+
+        let recipients = ["bob".to_string(), "carol".to_string(), "nobody".to_string()];
+        let results = server.multicast(&recipients, "standup").await;
+        assert!(results[0].1.is_ok() && results[1].1.is_ok());
+        assert!(results[2].1.is_err());
+
+        let mut packet_nos = Vec::new();
+        for socket in &members {
+            let mut buf = [0; 1024];
+            // 跳过对上线报文的 ANSENTRY 应答
+            let packet = loop {
+                let (len, _) = tokio::time::timeout(Duration::from_secs(2), socket.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                let packet = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+                if packet.command & 0xff == commands::MSG {
+                    break packet;
+                }
+            };
+            assert_ne!(packet.command & commands::IPMSG_MULTICASTOPT, 0);
+            assert_eq!(packet.additional_msg, "standup");
+            packet_nos.push(packet.packet_no);
+        }
+        assert_eq!(packet_nos[0], packet_nos[1]);
+    }
+
+    #[tokio::test]
+    async fn test_status_note_round_trips_into_user_entry() {
+        let alice = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        alice.set_user(&UserConfig {
+            name: "alice".into(),
+            host: "PC-A".into(),
+            group: "dev".into(),
+            status_note: "on call: 10:00-18:00".into(),
+            ..Default::default()
+        });
+        let entry = alice.presence_packet(commands::BR_ENTRY);
+        let decoded = IpMsgPacket::decode_with_config(
+            &alice.wire_bytes(&entry, None),
+            &AppConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(decoded.group_name, "dev");
+        assert_eq!(decoded.additional_msg, "on call: 10:00-18:00");
+
+        let bob = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        bob.handle_packet(&decoded, &"127.0.0.1:2425".parse().unwrap()).await;
+        let users = bob.get_online_users().await;
+        assert_eq!(users[0].note, "on call: 10:00-18:00");
+
+        // 离开时改为携带离开信息
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        alice.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+        alice
+            .set_state(PresenceState::Away {
+                message: "lunch".into(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(alice.presence_packet(commands::BR_ENTRY).additional_msg, "lunch");
     }
 }
@@ -0,0 +1,110 @@
+//! 测试用：在回环地址上互相连通的两个服务器
+//!
+//! alice（alice@PC-A）与 bob（bob@PC-B）各自绑定临时端口，广播目标指向对方，
+//! 应答上线通知不加随机延迟；收到的 MSG 报文转发到各自的 messages 通道
+
+use super::IpMsgServer;
+use crate::config::AppConfig;
+use crate::protocol::{IpMsgPacket, commands};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// 互相发现的最长等待时间
+const MEET_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub(crate) struct Peer {
+    pub server: IpMsgServer,
+    pub addr: SocketAddr,
+    /// 收到的 MSG 报文与来源地址
+    pub messages: mpsc::UnboundedReceiver<(IpMsgPacket, SocketAddr)>,
+}
+
+impl Peer {
+    async fn start(name: &str, host: &str) -> Peer {
+        let mut server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        server.set_identity(name, host);
+        server.ansentry_jitter = Duration::ZERO;
+        let addr = server.local_addr().unwrap();
+        let (tx, messages) = mpsc::unbounded_channel();
+        let listener = server.clone();
+        // 与服务器发出报文的编码一致
+        let mut config = AppConfig::default();
+        config.encoding.protocol = "utf8".into();
+        tokio::spawn(async move {
+            let callback = move |packet: IpMsgPacket, from: SocketAddr| {
+                if packet.command & 0xff == commands::MSG {
+                    let _ = tx.send((packet, from));
+                }
+            };
+            let _ = listener.listen(callback, Arc::new(config)).await;
+        });
+        Peer { server, addr, messages }
+    }
+
+    /// 等待下一条收到的消息
+    pub async fn next_message(&mut self) -> (IpMsgPacket, SocketAddr) {
+        tokio::time::timeout(MEET_TIMEOUT, self.messages.recv())
+            .await
+            .expect("no message received")
+            .expect("listener stopped")
+    }
+}
+
+pub(crate) struct Pair {
+    pub alice: Peer,
+    pub bob: Peer,
+}
+
+/// 启动两个互为广播目标的服务器
+pub(crate) async fn pair() -> Pair {
+    let alice = Peer::start("alice", "PC-A").await;
+    let bob = Peer::start("bob", "PC-B").await;
+    alice.server.set_broadcast_target(bob.addr).unwrap();
+    bob.server.set_broadcast_target(alice.addr).unwrap();
+    Pair { alice, bob }
+}
+
+impl Pair {
+    /// alice 广播上线通知，等到双方的用户表中都有对方
+    pub async fn meet(&self) {
+        let entry = self.alice.server.presence_packet(commands::BR_ENTRY);
+        self.alice.server.broadcast(&entry).await.unwrap();
+        let met = async {
+            while self.alice.server.get_user_addr("bob@PC-B").await.is_none()
+                || self.bob.server.get_user_addr("alice@PC-A").await.is_none()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(MEET_TIMEOUT, met).await.expect("peers did not meet");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pair_delivers_message_end_to_end() {
+        let mut pair = pair().await;
+        pair.meet().await;
+        assert_eq!(pair.alice.server.resolve_recipient("bob").await.unwrap(), pair.bob.addr);
+
+        let packet = pair.alice.server.build_packet(commands::MSG, "你好，bob");
+        let rtt = pair
+            .alice
+            .server
+            .send_checked(&packet, &pair.bob.addr, Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        assert!(rtt.is_some());
+
+        let (received, from) = pair.bob.next_message().await;
+        assert_eq!(from, pair.alice.addr);
+        assert_eq!(received.sender_name, "alice");
+        assert_eq!(received.packet_no, packet.packet_no);
+        assert_eq!(received.additional_msg, "你好，bob");
+    }
+}
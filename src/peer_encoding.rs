@@ -0,0 +1,67 @@
+use crate::peer_match::PeerPattern;
+use encoding_rs::Encoding;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// 按对端强制使用的协议编码（encoding.peers）
+///
+/// 匹配优先级：用户名@主机名 > 最长前缀的 IP/网段，未匹配时使用全局编码
+#[derive(Debug, Clone, Default)]
+pub struct PeerEncodings {
+    rules: Vec<(PeerPattern, &'static Encoding)>,
+}
+
+impl PeerEncodings {
+    /// 由配置表构造，无法识别的条目被忽略（由配置校验报告）
+    pub fn from_config(peers: &BTreeMap<String, String>) -> Self {
+        let rules = peers
+            .iter()
+            .filter_map(|(key, label)| {
+                Some((PeerPattern::parse(key)?, Encoding::for_label(label.as_bytes())?))
+            })
+            .collect();
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// 按 IP 或网段查找
+    pub fn for_addr(&self, ip: IpAddr) -> Option<&'static Encoding> {
+        self.rules
+            .iter()
+            .filter(|(pattern, _)| pattern.contains(ip))
+            .max_by_key(|(pattern, _)| pattern.specificity())
+            .map(|(_, encoding)| *encoding)
+    }
+
+    /// 按 用户名@主机名 查找
+    pub fn for_user(&self, user: &str) -> Option<&'static Encoding> {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.is_user(user))
+            .map(|(_, encoding)| *encoding)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{GBK, UTF_8};
+
+    #[test]
+    fn test_user_beats_cidr_and_longest_prefix_wins() {
+        let peers = PeerEncodings::from_config(&BTreeMap::from([
+            ("192.168.1.0/24".to_string(), "utf-8".to_string()),
+            ("192.168.1.44".to_string(), "gbk".to_string()),
+            ("carol@LAB-PC".to_string(), "utf-8".to_string()),
+            ("bad key".to_string(), "gbk".to_string()),
+        ]));
+        assert_eq!(peers.for_addr("192.168.1.44".parse().unwrap()), Some(GBK));
+        assert_eq!(peers.for_addr("192.168.1.45".parse().unwrap()), Some(UTF_8));
+        assert_eq!(peers.for_addr("10.0.0.1".parse().unwrap()), None);
+        assert_eq!(peers.for_user("carol@LAB-PC"), Some(UTF_8));
+        assert_eq!(peers.for_user("dave@LAB-PC"), None);
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::Result;
+use std::net::IpAddr;
+
+/// 配置中指定对端的方式：IP、CIDR 网段或 用户名@主机名
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerPattern {
+    /// 用户名@主机名
+    User(String),
+    /// IP 或 CIDR 网段（单个 IP 视为最长前缀）
+    Net { network: IpAddr, prefix: u8 },
+}
+
+impl PeerPattern {
+    /// 解析配置中的对端写法，无法识别时返回 None
+    pub fn parse(key: &str) -> Option<Self> {
+        if key.contains('@') {
+            return Some(Self::User(key.to_string()));
+        }
+        let (ip, prefix) = match key.split_once('/') {
+            Some((ip, prefix)) => (ip.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (key.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return None;
+        }
+        Some(Self::Net {
+            network: ip,
+            prefix,
+        })
+    }
+
+    /// IP 是否落在该网段内
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let Self::Net { network, prefix } = *self else {
+            return false;
+        };
+        match (network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// 是否为指定的 用户名@主机名
+    pub fn is_user(&self, user: &str) -> bool {
+        matches!(self, Self::User(name) if name == user)
+    }
+
+    /// 同时匹配多条规则时用于取最具体的一条（前缀越长越具体）
+    pub fn specificity(&self) -> u8 {
+        match self {
+            Self::Net { prefix, .. } => *prefix,
+            Self::User(_) => 0,
+        }
+    }
+
+    /// IP 或任一 用户名@主机名 命中即匹配
+    pub fn matches(&self, ip: IpAddr, users: &[&str]) -> bool {
+        self.contains(ip) || users.iter().any(|user| self.is_user(user))
+    }
+}
+
+/// 检查配置中的对端写法是否为 IP、CIDR 或 用户名@主机名
+pub fn is_valid_peer_key(key: &str) -> bool {
+    PeerPattern::parse(key).is_some()
+}
+
+/// 黑名单与白名单；白名单非空时只接受名单内的对端，黑名单优先
+#[derive(Debug, Clone, Default)]
+pub struct PeerFilter {
+    blocked: Vec<(String, PeerPattern)>,
+    allowed: Vec<(String, PeerPattern)>,
+}
+
+impl PeerFilter {
+    /// 由配置中的名单构造，无法识别的条目被忽略（由配置校验报告）
+    pub fn new(blocked: &[String], allowed: &[String]) -> Self {
+        let parse = |entries: &[String]| {
+            entries
+                .iter()
+                .filter_map(|entry| Some((entry.clone(), PeerPattern::parse(entry)?)))
+                .collect()
+        };
+        Self {
+            blocked: parse(blocked),
+            allowed: parse(allowed),
+        }
+    }
+
+    /// 是否接受来自该对端的报文
+    pub fn accepts(&self, ip: IpAddr, users: &[&str]) -> bool {
+        let hit = |list: &[(String, PeerPattern)]| {
+            list.iter().any(|(_, pattern)| pattern.matches(ip, users))
+        };
+        !hit(&self.blocked) && (self.allowed.is_empty() || hit(&self.allowed))
+    }
+
+    /// 加入黑名单，已存在时返回 false
+    pub fn block(&mut self, entry: &str) -> Result<bool> {
+        let pattern = PeerPattern::parse(entry)
+            .ok_or_else(|| anyhow::anyhow!("Invalid peer '{}': expected an IP, a CIDR range or user@host", entry))?;
+        if self.blocked.iter().any(|(existing, _)| existing == entry) {
+            return Ok(false);
+        }
+        self.blocked.push((entry.to_string(), pattern));
+        Ok(true)
+    }
+
+    /// 移出黑名单，不存在时返回 false
+    pub fn unblock(&mut self, entry: &str) -> bool {
+        let before = self.blocked.len();
+        self.blocked.retain(|(existing, _)| existing != entry);
+        self.blocked.len() != before
+    }
+
+    /// 黑名单条目（配置中的写法）
+    pub fn blocked(&self) -> Vec<String> {
+        self.blocked.iter().map(|(entry, _)| entry.clone()).collect()
+    }
+
+    /// 白名单条目（配置中的写法）
+    pub fn allowed(&self) -> Vec<String> {
+        self.allowed.iter().map(|(entry, _)| entry.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_match_forms() {
+        let lan = PeerPattern::parse("192.168.1.0/24").unwrap();
+        assert!(lan.contains("192.168.1.200".parse().unwrap()));
+        assert!(!lan.contains("192.168.2.1".parse().unwrap()));
+        assert!(!lan.contains("::1".parse().unwrap()));
+        assert!(PeerPattern::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+
+        let carol = PeerPattern::parse("carol@LAB-PC").unwrap();
+        assert!(carol.matches("10.0.0.1".parse().unwrap(), &["carol@LAB-PC"]));
+        assert!(!carol.matches("10.0.0.1".parse().unwrap(), &["dave@LAB-PC"]));
+
+        assert!(is_valid_peer_key("10.0.0.0/8"));
+        assert!(is_valid_peer_key("fe80::/10"));
+        assert!(!is_valid_peer_key("10.0.0.0/33"));
+        assert!(!is_valid_peer_key("bad key"));
+    }
+
+    #[test]
+    fn test_allowlist_only_mode() {
+        let lan: IpAddr = "192.168.1.5".parse().unwrap();
+        let other: IpAddr = "10.0.0.66".parse().unwrap();
+
+        let mut filter = PeerFilter::default();
+        assert!(filter.accepts(other, &[]));
+        assert!(filter.block("10.0.0.66").unwrap());
+        assert!(!filter.block("10.0.0.66").unwrap());
+        assert!(filter.block("nonsense").is_err());
+        assert!(!filter.accepts(other, &[]));
+        assert!(filter.unblock("10.0.0.66"));
+        assert!(filter.accepts(other, &[]));
+
+        let filter = PeerFilter::new(
+            &["spammer@EVIL-PC".to_string()],
+            &["192.168.1.0/24".to_string(), "carol@LAB-PC".to_string()],
+        );
+        assert!(filter.accepts(lan, &["bob@PC"]));
+        assert!(filter.accepts(other, &["carol@LAB-PC"]));
+        assert!(!filter.accepts(other, &["dave@PC"]));
+        // 黑名单优先于白名单
+        assert!(!filter.accepts(lan, &["spammer@EVIL-PC"]));
+    }
+}
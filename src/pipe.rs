@@ -0,0 +1,280 @@
+//! pipe：把标准输入的每一行作为一条消息发出（发给用户、分组或广播），读到 EOF 后退出
+
+use crate::config::{MessageLenPolicy, MessageLimit, TRUNCATION_MARKER};
+use crate::groups;
+use crate::net::{Delivery, IpMsgServer};
+use crate::protocol::commands;
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// 读入但尚未发出的行数上限；发送跟不上时读取随之暂停，不会无限缓存
+pub const PIPE_QUEUE_LINES: usize = 64;
+
+/// 消息发往哪里
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipeTarget {
+    /// 用户名、用户名@主机名、IP 或 IP:端口，每行重新解析
+    User(String),
+    /// 逐个发给分组的在线用户（带多播标记），每行重新取成员
+    Group { name: String, contains: bool },
+    Broadcast,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PipeOptions {
+    /// 加在每条消息前（以空格分隔）
+    pub prefix: Option<String>,
+    /// 超长的行拆成多条消息，而不是按策略截断或跳过
+    pub split: bool,
+    /// 相邻两条消息的最小间隔（network.min_broadcast_interval_ms）
+    pub interval: Duration,
+    pub limit: MessageLimit,
+}
+
+/// 一行按长度限制处理后的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Prepared {
+    Whole(String),
+    Truncated(String),
+    Split(Vec<String>),
+    /// 策略为 reject 且没有 --split，整行跳过
+    Rejected,
+}
+
+impl Prepared {
+    /// 要发出的消息
+    pub fn messages(&self) -> Vec<String> {
+        match self {
+            Prepared::Whole(text) | Prepared::Truncated(text) => vec![text.clone()],
+            Prepared::Split(parts) => parts.clone(),
+            Prepared::Rejected => Vec::new(),
+        }
+    }
+}
+
+/// 统计：读入的行（不含空行）、发出的消息、截断、拆分、跳过的行与发送失败的消息
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PipeReport {
+    pub lines: usize,
+    pub messages: usize,
+    pub truncated: usize,
+    pub split: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// 加上前缀并按长度限制处理一行；拆分时每段都带前缀
+pub fn prepare(line: &str, prefix: Option<&str>, limit: &MessageLimit, split: bool) -> Prepared {
+    let with_prefix = |text: &str| match prefix {
+        Some(prefix) => format!("{} {}", prefix, text),
+        None => text.to_string(),
+    };
+    let text = with_prefix(line);
+    let len = text.chars().count();
+    if limit.max_len == 0 || len <= limit.max_len {
+        return Prepared::Whole(text);
+    }
+    if split {
+        let prefix_len = prefix.map_or(0, |prefix| prefix.chars().count() + 1);
+        let chunk = limit.max_len.saturating_sub(prefix_len).max(1);
+        let chars: Vec<char> = line.chars().collect();
+        return Prepared::Split(
+            chars
+                .chunks(chunk)
+                .map(|part| with_prefix(&part.iter().collect::<String>()))
+                .collect(),
+        );
+    }
+    match limit.policy {
+        MessageLenPolicy::Truncate => {
+            let keep = limit.max_len.saturating_sub(TRUNCATION_MARKER.chars().count());
+            Prepared::Truncated(text.chars().take(keep).collect::<String>() + TRUNCATION_MARKER)
+        }
+        MessageLenPolicy::Reject => Prepared::Rejected,
+    }
+}
+
+/// 逐行读取 input 并发出，读到 EOF 且已读入的行全部发出后返回
+///
+/// 每条成功发出的消息调用一次 on_sent(对端, 地址, 正文)；单条发送失败只记录警告并继续
+pub async fn run<R>(
+    server: &IpMsgServer,
+    target: &PipeTarget,
+    options: &PipeOptions,
+    input: R,
+    mut on_sent: impl FnMut(&str, Option<SocketAddr>, &str),
+) -> Result<PipeReport>
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel::<String>(PIPE_QUEUE_LINES);
+    let reader = tokio::spawn(read_lines(input, tx));
+
+    let mut report = PipeReport::default();
+    let mut next_send = Instant::now();
+    while let Some(line) = rx.recv().await {
+        report.lines += 1;
+        let prepared = prepare(&line, options.prefix.as_deref(), &options.limit, options.split);
+        match &prepared {
+            Prepared::Truncated(_) => {
+                report.truncated += 1;
+                log::warn!(
+                    "Line {} truncated to {} characters (user.max_message_len)",
+                    report.lines,
+                    options.limit.max_len
+                );
+            }
+            Prepared::Split(parts) => {
+                report.split += 1;
+                log::warn!(
+                    "Line {} split into {} messages (user.max_message_len)",
+                    report.lines,
+                    parts.len()
+                );
+            }
+            Prepared::Rejected => {
+                report.skipped += 1;
+                log::warn!(
+                    "Line {} skipped: longer than user.max_message_len ({}); use --split to send it in parts",
+                    report.lines,
+                    options.limit.max_len
+                );
+            }
+            Prepared::Whole(_) => {}
+        }
+        for message in prepared.messages() {
+            tokio::time::sleep_until(next_send).await;
+            next_send = Instant::now() + options.interval;
+            match send_one(server, target, &message, &mut on_sent).await {
+                Ok(()) => report.messages += 1,
+                Err(e) => {
+                    report.failed += 1;
+                    log::warn!("Line {} not sent: {:#}", report.lines, e);
+                }
+            }
+        }
+    }
+    reader.await??;
+    Ok(report)
+}
+
+/// 按行读入并放入队列；队列满时等待，从而暂停读取。去掉行尾的 CR，跳过空行，非 UTF-8 的字节按替换字符处理
+async fn read_lines<R>(mut input: R, tx: mpsc::Sender<String>) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if input.read_until(b'\n', &mut buf).await? == 0 {
+            return Ok(());
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches(['\n', '\r']);
+        if line.is_empty() {
+            continue;
+        }
+        if tx.send(line.to_string()).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+async fn send_one(
+    server: &IpMsgServer,
+    target: &PipeTarget,
+    message: &str,
+    on_sent: &mut impl FnMut(&str, Option<SocketAddr>, &str),
+) -> Result<()> {
+    match target {
+        PipeTarget::User(recipient) => {
+            let addr = server.resolve_recipient(recipient).await?;
+            server.send_to(&server.build_packet(commands::MSG, message), &addr).await?;
+            on_sent(recipient, Some(addr), message);
+        }
+        PipeTarget::Group { name, contains } => {
+            let members = groups::members_of(&server.get_online_users().await, name, *contains);
+            if members.is_empty() {
+                return Err(anyhow::anyhow!("No online users in group {}", name));
+            }
+            for (user, delivery) in server.multicast_users(&members, message, None).await {
+                let peer = format!("{}@{}", user.username, user.hostname);
+                match delivery {
+                    Delivery::Failed(e) => log::warn!("Send to {} failed: {:#}", peer, e),
+                    _ => on_sent(&peer, Some(user.addr), message),
+                }
+            }
+        }
+        PipeTarget::Broadcast => {
+            let command = commands::MSG | commands::IPMSG_BROADCASTOPT;
+            server.broadcast(&server.build_packet(command, message)).await?;
+            on_sent("*", None, message);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::protocol::IpMsgPacket;
+    use tokio::net::UdpSocket;
+
+    #[test]
+    fn test_prepare_applies_prefix_and_length_policy() {
+        let limit = MessageLimit {
+            max_len: 10,
+            policy: MessageLenPolicy::Truncate,
+        };
+        assert_eq!(prepare("ok", Some("[CI]"), &limit, false), Prepared::Whole("[CI] ok".into()));
+        assert_eq!(prepare("build failed", None, &limit, false), Prepared::Truncated("build fai…".into()));
+        assert_eq!(
+            prepare("abcdefghij", Some("[CI]"), &limit, true),
+            Prepared::Split(vec!["[CI] abcde".into(), "[CI] fghij".into()])
+        );
+        let reject = MessageLimit {
+            max_len: 10,
+            policy: MessageLenPolicy::Reject,
+        };
+        assert_eq!(prepare("build failed", None, &reject, false), Prepared::Rejected);
+        assert_eq!(prepare("build failed", None, &reject, true).messages(), ["build fail", "ed"]);
+        assert_eq!(prepare("build failed", None, &MessageLimit::default(), false).messages(), ["build failed"]);
+    }
+
+    #[tokio::test]
+    async fn test_pipe_broadcasts_each_line_until_eof() {
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_broadcast_target(sink.local_addr().unwrap()).unwrap();
+        let options = PipeOptions {
+            prefix: Some("[CI]".into()),
+            interval: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let mut sent = Vec::new();
+        let input: &'static [u8] = b"step 1\n\nstep 2\r\nstep 3";
+        let report = run(&server, &PipeTarget::Broadcast, &options, input, |peer, _, body| {
+            sent.push(format!("{} {}", peer, body))
+        })
+        .await
+        .unwrap();
+        assert_eq!((report.lines, report.messages, report.failed), (3, 3, 0));
+        assert_eq!(sent, ["* [CI] step 1", "* [CI] step 2", "* [CI] step 3"]);
+
+        let mut buf = [0; 1024];
+        for expected in ["[CI] step 1", "[CI] step 2", "[CI] step 3"] {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(1), sink.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = IpMsgPacket::decode_with_config(&buf[..len], &AppConfig::default()).unwrap();
+            assert_eq!(packet.command, commands::MSG | commands::IPMSG_BROADCASTOPT);
+            assert_eq!(packet.additional_msg, expected);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::protocol::commands;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// 免打扰状态对外显示的离开信息
+pub const DND_MESSAGE: &str = "Do not disturb";
+/// 在线状态下回复 GETABSENCEINFO 的内容（与官方客户端一致）
+pub const NOT_ABSENT_MESSAGE: &str = "Not absence mode";
+
+/// 本机在线状态
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PresenceState {
+    #[default]
+    Online,
+    Away {
+        message: String,
+    },
+    DoNotDisturb,
+}
+
+impl PresenceState {
+    /// 是否需要在报文中携带离开标志
+    pub fn is_absent(&self) -> bool {
+        !matches!(self, PresenceState::Online)
+    }
+
+    /// 对外显示的离开信息
+    pub fn absence_text(&self) -> &str {
+        match self {
+            PresenceState::Online => NOT_ABSENT_MESSAGE,
+            PresenceState::Away { message } => message,
+            PresenceState::DoNotDisturb => DND_MESSAGE,
+        }
+    }
+
+    /// 为上线/应答/状态变更报文附加离开标志
+    pub fn apply_to_command(&self, command: u32) -> u32 {
+        if self.is_absent() {
+            command | commands::IPMSG_ABSENCEOPT
+        } else {
+            command & !commands::IPMSG_ABSENCEOPT
+        }
+    }
+}
+
+impl fmt::Display for PresenceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresenceState::Online => write!(f, "online"),
+            PresenceState::Away { message } if message.is_empty() => write!(f, "away"),
+            PresenceState::Away { message } => write!(f, "away ({})", message),
+            PresenceState::DoNotDisturb => write!(f, "do not disturb"),
+        }
+    }
+}
+
+/// 当前状态及进入该状态的时间
+#[derive(Debug, Clone)]
+pub(crate) struct PresenceTracker {
+    state: PresenceState,
+    since: Instant,
+}
+
+impl PresenceTracker {
+    pub(crate) fn state(&self) -> &PresenceState {
+        &self.state
+    }
+
+    /// 处于当前状态的时长
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.since.elapsed()
+    }
+
+    /// 切换状态，返回之前的状态
+    pub(crate) fn transition(&mut self, state: PresenceState) -> PresenceState {
+        self.since = Instant::now();
+        std::mem::replace(&mut self.state, state)
+    }
+}
+
+impl Default for PresenceTracker {
+    fn default() -> Self {
+        Self {
+            state: PresenceState::Online,
+            since: Instant::now(),
+        }
+    }
+}
+
+/// 空闲自动离开：用户输入或发出消息时重新计时，超时后切换为离开，下次输入时恢复在线
+///
+/// 时间由调用方传入，便于测试；after 为 None 时不做任何事
+#[derive(Debug, Clone)]
+pub struct AutoAway {
+    after: Option<Duration>,
+    last_activity: Instant,
+    /// 当前的离开状态是否由本计时器设置
+    engaged: bool,
+}
+
+impl AutoAway {
+    /// after_secs 为 0 时关闭
+    pub fn new(after_secs: u64, now: Instant) -> Self {
+        Self {
+            after: (after_secs > 0).then(|| Duration::from_secs(after_secs)),
+            last_activity: now,
+            engaged: false,
+        }
+    }
+
+    /// 下一次需要检查的时间；关闭或已自动离开时为 None
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.after {
+            Some(after) if !self.engaged => Some(self.last_activity + after),
+            _ => None,
+        }
+    }
+
+    /// 记录一次用户活动；返回 true 表示之前是自动离开，应恢复为在线
+    pub fn activity(&mut self, now: Instant) -> bool {
+        self.last_activity = now;
+        std::mem::take(&mut self.engaged)
+    }
+
+    /// 检查是否到了自动离开的时间；只在当前为在线状态时触发（手动设置的离开与免打扰不受影响）
+    pub fn poll(&mut self, now: Instant, state: &PresenceState) -> bool {
+        let due = self.deadline().is_some_and(|deadline| now >= deadline);
+        if due && *state == PresenceState::Online {
+            self.engaged = true;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_away_transitions_and_restores() {
+        let start = Instant::now();
+        let mut tracker = PresenceTracker::default();
+        let mut auto = AutoAway::new(60, start);
+        let away = PresenceState::Away { message: "brb".into() };
+
+        assert!(!auto.poll(start + Duration::from_secs(59), tracker.state()));
+        // 输入重新计时
+        assert!(!auto.activity(start + Duration::from_secs(30)));
+        assert!(!auto.poll(start + Duration::from_secs(60), tracker.state()));
+        assert!(auto.poll(start + Duration::from_secs(90), tracker.state()));
+        tracker.transition(away.clone());
+        assert_eq!(auto.deadline(), None);
+        assert!(!auto.poll(start + Duration::from_secs(500), tracker.state()));
+
+        // 下次输入时恢复在线，并重新计时
+        assert!(auto.activity(start + Duration::from_secs(600)));
+        tracker.transition(PresenceState::Online);
+        assert_eq!(auto.deadline(), Some(start + Duration::from_secs(660)));
+        assert!(!auto.activity(start + Duration::from_secs(601)));
+
+        // 手动设置的离开不会被自动恢复
+        tracker.transition(PresenceState::DoNotDisturb);
+        assert!(!auto.poll(start + Duration::from_secs(2000), tracker.state()));
+        assert!(!auto.activity(start + Duration::from_secs(2001)));
+    }
+
+    #[test]
+    fn test_auto_away_disabled_when_zero() {
+        let start = Instant::now();
+        let mut auto = AutoAway::new(0, start);
+        assert_eq!(auto.deadline(), None);
+        assert!(!auto.poll(start + Duration::from_secs(86400), &PresenceState::Online));
+        assert!(!auto.activity(start));
+    }
+}
@@ -1,10 +1,44 @@
-use crate::config::EncodingConfig;
 use crate::config::AppConfig;
-use encoding_rs::{GBK, UTF_8};
+use encoding_rs::{Encoding, GBK, UTF_8};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// 本客户端发送的版本字段
+pub const PROTOCOL_VERSION: &str = "lanMsg 0.1";
+/// 上线类报文扩展部分中节点 ID 的前缀，其他客户端会忽略这一段
+pub const NODE_ID_PREFIX: &str = "UUID:";
+/// 兼容的 IPMsg 协议主版本号
+pub const PROTOCOL_MAJOR: u32 = 1;
+/// 一个 UDP 报文（IPv4）最多能携带的字节数，接收缓冲区按此分配，长消息不会被截断
+pub const MAX_DATAGRAM: usize = 65507;
+
+/// 报文解码错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// 冒号分隔的字段不足六个
+    TooFewFields { found: usize },
+    /// 报文编号不是合法数字
+    InvalidPacketNo(String),
+    /// 命令字不是合法数字
+    InvalidCommand(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::TooFewFields { found } => {
+                write!(f, "Invalid packet format: expected 6 fields, found {}", found)
+            }
+            ProtocolError::InvalidPacketNo(value) => write!(f, "Invalid packet number '{}'", value),
+            ProtocolError::InvalidCommand(value) => write!(f, "Invalid command '{}'", value),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
 
 /// IPMsg 报文格式
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IpMsgPacket {
     pub version: String,
     pub packet_no: u32,
@@ -14,23 +48,66 @@ pub struct IpMsgPacket {
     pub sender_name: String,
     pub group_name: String,
     pub additional_msg: String,
+    /// 附加信息中的扩展部分：非上线类报文为第一个 NUL 之后（例如文件附件列表），上线类报文为第三个 NUL 之后（例如节点 ID）
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub extension: String,
+    /// 从版本字段解析出的协议主版本号（无法识别时为 None）
+    #[serde(skip)]
+    pub version_major: Option<u32>,
+    /// 规范化前的原始昵称（调试用）
+    #[serde(skip)]
+    pub raw_sender_name: String,
+    /// 规范化前的原始主机名（调试用）
+    #[serde(skip)]
+    pub raw_sender_host: String,
+    /// 附加信息中未转义的冒号个数（超出六个字段的部分已并回附加信息）
+    #[serde(skip)]
+    pub overflow_fields: usize,
 }
 
 impl IpMsgPacket {
-    /// 编码为协议字符串
+    /// 编码为标准协议字符串（固定字段原样写出，与 encoding.escape_fields 的默认值一致）
     pub fn encode(&self) -> String {
+        self.encode_escaped(false)
+    }
+
+    /// 编码为协议字符串，escape 为 false 时固定字段原样写出（兼容不转义的对端）
+    ///
+    /// 用户字段写登录名；上线类报文的附加信息为 `昵称\0分组[\0附加信息[\0扩展]]`
+    pub fn encode_escaped(&self, escape: bool) -> String {
+        let field = |value: &str| if escape { escape_field(value) } else { value.to_string() };
         format!(
             "{}:{}:{}:{}:{}:{}",
             self.version,
             self.packet_no,
-            self.sender_name,
-            self.sender_host,
+            field(&self.sender_user),
+            field(&self.sender_host),
             self.command,
-            self.additional_msg
+            self.payload()
         )
     }
 
-    /// 从字符串解析
+    /// 附加信息字段的内容
+    fn payload(&self) -> String {
+        if !is_presence_command(self.command) {
+            if self.extension.is_empty() {
+                return self.additional_msg.clone();
+            }
+            return format!("{}\0{}\0", self.additional_msg, self.extension);
+        }
+        let mut payload = format!("{}\0{}", self.sender_name, self.group_name);
+        if !self.additional_msg.is_empty() || !self.extension.is_empty() {
+            payload.push('\0');
+            payload.push_str(&self.additional_msg);
+        }
+        if !self.extension.is_empty() {
+            payload.push('\0');
+            payload.push_str(&self.extension);
+        }
+        payload
+    }
+
+    // 从字符串解析
     // pub fn decode(s: &str) -> anyhow::Result<Self> {
     //     // 先清理可能的垃圾数据
     //     let clean_str = s.split('\0').next().unwrap_or(s).trim();
@@ -53,110 +130,253 @@ impl IpMsgPacket {
 
     /// 数据打包
     pub fn encode_with_config(&self, config: &AppConfig) -> Vec<u8> {
-        let additional = if self.group_name.is_empty() {
-            self.additional_msg.clone()
-        } else {
-            format!("{}\x00{}", self.sender_name, self.group_name)
-        };
-        
-        let packet_str = format!(
-            "{}:{}:{}:{}:{}:{}",
-            self.version,
-            self.packet_no,
-            "aaMsg".to_string(),
-            // self.sender_name,
-            self.sender_host,
-            self.command,
-            additional
-        );
-        
+        let packet_str = self.encode_escaped(config.encoding.escape_fields);
         // 根据配置选择编码
-        let encoder = match config.encoding.protocol.as_str() {
-            "gbk" => GBK,
-            _ => UTF_8,
-        };
-        
-        encoder.encode(&packet_str).0.to_vec()
+        protocol_encoding(config).encode(&packet_str).0.to_vec()
     }
 
     /// 增强版协议包解码
-    pub fn decode_with_config(data: &[u8], config: &AppConfig) -> anyhow::Result<IpMsgPacket> {
-        // 先尝试完整解码
-        let decoder = match config.encoding.protocol.as_str() {
-            "gbk" => GBK,
-            _ => UTF_8,
-        };
+    pub fn decode_with_config(data: &[u8], config: &AppConfig) -> Result<IpMsgPacket, ProtocolError> {
+        Self::decode_with(data, protocol_encoding(config), config.encoding.escape_fields)
+    }
 
+    /// 使用指定编码解码（例如按对端覆盖的编码），unescape 为 true 时还原固定字段中的转义
+    pub fn decode_with(
+        data: &[u8],
+        decoder: &'static Encoding,
+        unescape: bool,
+    ) -> Result<IpMsgPacket, ProtocolError> {
+        // 先尝试完整解码
         let (cow, _, had_errors) = decoder.decode(data);
         if had_errors {
             // 回退到提取可打印部分
-            let fallback_str = extract_string_part2(data, config);
-            return Self::decode_fallback(&fallback_str);
+            let fallback_str = extract_string_part2(data, decoder);
+            let mut packet = Self::decode_fallback(&fallback_str)?;
+            if unescape {
+                packet.unescape_fields();
+            }
+            packet.default_display_name();
+            return Ok(packet);
         }
 
         let s = cow.trim();
-        Self::parse_packet_str(s)
+        let mut packet = Self::parse_packet_str(s)?;
+        if unescape {
+            packet.unescape_fields();
+        }
+        packet.default_display_name();
+        Ok(packet)
+    }
+
+    /// 没有昵称（非上线类报文或对方未设置）时以登录名显示
+    fn default_display_name(&mut self) {
+        if self.sender_name.is_empty() {
+            self.sender_name = normalize_field(&self.sender_user);
+        }
+    }
+
+    /// 按命令字拆分附加信息：上线类报文为 昵称\0分组\0附加信息\0扩展，其余只取正文
+    fn apply_payload(&mut self, payload: &str) {
+        if is_presence_command(self.command) {
+            let mut split_iter = payload.splitn(4, '\0');
+            self.sender_name = split_iter.next().unwrap_or_default().to_string();
+            self.group_name = split_iter.next().unwrap_or_default().to_string();
+            self.additional_msg = split_iter.next().unwrap_or_default().to_string();
+            self.extension = split_iter.next().unwrap_or_default().trim_end_matches('\0').to_string();
+            // 状态说明（如 10:00-18:00）与节点 ID 扩展中的冒号都属于正常内容
+            self.overflow_fields = 0;
+        } else {
+            let (body, extension) = payload.split_once('\0').unwrap_or((payload, ""));
+            self.additional_msg = body.to_string();
+            // 扩展部分（附件列表）本身以冒号分隔，不计入多余字段
+            self.overflow_fields = body.matches(':').count();
+            self.extension = extension.trim_end_matches('\0').to_string();
+        }
+    }
+
+    /// 还原固定字段（登录名、主机名）中的转义
+    fn unescape_fields(&mut self) {
+        self.sender_user = unescape_field(&self.sender_user);
+        self.raw_sender_host = unescape_field(&self.raw_sender_host);
+        self.sender_host = normalize_field(&self.raw_sender_host);
     }
 
     /// 回退解析（当完整解码失败时使用）
-    fn decode_fallback(s: &str) -> anyhow::Result<IpMsgPacket> {
+    fn decode_fallback(s: &str) -> Result<IpMsgPacket, ProtocolError> {
         // 尝试提取基本字段
-        let parts: Vec<&str> = s.split(':').collect();
+        let parts: Vec<&str> = s.splitn(6, ':').collect();
         if parts.len() < 6 {
-            return Err(anyhow::anyhow!("Invalid packet format"));
+            return Err(ProtocolError::TooFewFields { found: parts.len() });
         }
 
-        Ok(IpMsgPacket {
+        let mut packet = IpMsgPacket {
             version: parts[0].to_string(),
             packet_no: parts[1].parse().unwrap_or(0),
             sender_user: parts[2].to_string(),
             sender_host: parts[3].to_string(),
             command: parts[4].parse().unwrap_or(0),
-            sender_name: parts[5].split('\0').next().unwrap_or("").to_string(),
-            group_name: parts[5].split('\0').next().unwrap_or("").to_string(),
-            additional_msg: parts[5].split('\0').next().unwrap_or("").to_string(),
-        })
+            ..Default::default()
+        };
+        packet.apply_payload(parts[5]);
+        packet.version_major = parse_version_major(&packet.version);
+        packet.normalize_identity();
+        Ok(packet)
     }
 
     /// 核心解析逻辑
-    fn parse_packet_str(s: &str) -> anyhow::Result<IpMsgPacket> {
-        let parts: Vec<&str> = s.split(':').collect();
+    fn parse_packet_str(s: &str) -> Result<IpMsgPacket, ProtocolError> {
+        // 附加信息中可能含有未转义的冒号，只切前五个字段，其余整体作为附加信息
+        let parts: Vec<&str> = s.splitn(6, ':').collect();
         if parts.len() < 6 {
-            return Err(anyhow::anyhow!("Invalid packet format"));
+            return Err(ProtocolError::TooFewFields { found: parts.len() });
         }
 
-        let mut additional = parts[5];
-        let mut split_iter = additional.split('\x00');
-        let mut group = "";
-        let mut name = "";
-    
-        name = split_iter.next().unwrap_or_default();
-        group = split_iter.next().unwrap_or_default();
-        additional = split_iter.next().unwrap_or_default();
-
-        // if let Some(pos) = additional.find('\0') {
-        //     group = &additional[pos + 1..];
-        //     additional = &additional[..pos];
-        // }
-
-        Ok(IpMsgPacket {
+        let mut packet = IpMsgPacket {
             version: parts[0].to_string(),
-            packet_no: parts[1].parse()?,
+            packet_no: parts[1]
+                .parse()
+                .map_err(|_| ProtocolError::InvalidPacketNo(parts[1].to_string()))?,
             sender_user: parts[2].to_string(),
             sender_host: parts[3].to_string(),
-            command: parts[4].parse()?,
-            sender_name: name.to_string(),
-            group_name: group.to_string(),
-            additional_msg: additional.to_string(),
-            
+            command: parts[4]
+                .parse()
+                .map_err(|_| ProtocolError::InvalidCommand(parts[4].to_string()))?,
+            ..Default::default()
+        };
+        packet.apply_payload(parts[5]);
+        packet.version_major = parse_version_major(&packet.version);
+        packet.normalize_identity();
+        Ok(packet)
+    }
+
+    /// 附加信息含有多余冒号时的调试说明
+    pub fn overflow_note(&self) -> Option<String> {
+        (self.overflow_fields > 0).then(|| {
+            format!(
+                "packet {} has {} extra ':' field(s), reattached to the message body",
+                self.packet_no, self.overflow_fields
+            )
         })
     }
+
+    /// BR_ENTRY/ANSENTRY 扩展部分中的节点 ID（按行查找 `UUID:` 前缀，格式不对时忽略）
+    pub fn node_id(&self) -> Option<&str> {
+        if !carries_node_id(self.command) {
+            return None;
+        }
+        self.extension
+            .lines()
+            .filter_map(|line| line.strip_prefix(NODE_ID_PREFIX))
+            .find(|id| is_node_id(id))
+    }
+
+    /// 对方的协议主版本是否与本机兼容（无法识别时视为兼容）
+    pub fn is_compatible(&self) -> bool {
+        self.version_major.is_none_or(|major| major == PROTOCOL_MAJOR)
+    }
+
+    /// 规范化昵称与主机名，保留原始值供调试
+    fn normalize_identity(&mut self) {
+        self.raw_sender_name = std::mem::take(&mut self.sender_name);
+        self.raw_sender_host = std::mem::take(&mut self.sender_host);
+        self.sender_name = normalize_field(&self.raw_sender_name);
+        self.sender_host = normalize_field(&self.raw_sender_host);
+    }
+}
+
+/// 是否为合法的节点 ID（`8-4-4-4-12` 位十六进制的 UUID）
+pub fn is_node_id(id: &str) -> bool {
+    let groups: Vec<&str> = id.split('-').collect();
+    groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+        && groups.iter().all(|group| group.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// 携带节点 ID 的报文：只有上线通知与其应答，下线、状态变更等报文不据此识别对端
+pub fn carries_node_id(command: u32) -> bool {
+    matches!(command & 0xff, commands::BR_ENTRY | commands::IPMSG_ANSENTRY)
+}
+
+/// 上线类报文（保活、上线、下线、应答、状态变更）的附加信息携带昵称与分组
+pub fn is_presence_command(command: u32) -> bool {
+    (command & 0xff) < 0x10
+}
+
+/// 转义固定字段：`\` 写作 `\\`，冒号写作 `\c`，NUL 写作 `\0`
+pub fn escape_field(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ':' => escaped.push_str("\\c"),
+            '\0' => escaped.push_str("\\0"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 还原 escape_field 的转义，无法识别的转义原样保留
+pub fn unescape_field(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('c') => unescaped.push(':'),
+            Some('0') => unescaped.push('\0'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// 解析版本字段中的协议主版本号
+///
+/// 标准客户端以数字开头（如 `1`、`1_iptux 0.76`），lanMsg 自身的版本字段视为当前协议版本
+pub(crate) fn parse_version_major(version: &str) -> Option<u32> {
+    let digits: String = version.chars().take_while(|c| c.is_ascii_digit()).collect();
+    if !digits.is_empty() {
+        return digits.parse().ok();
+    }
+    if version.starts_with("lanMsg") {
+        return Some(PROTOCOL_MAJOR);
+    }
+    None
+}
+
+/// 清理身份字段：去除 NUL 与控制字符，空白折叠为单个空格并去掉首尾空白
+pub(crate) fn normalize_field(raw: &str) -> String {
+    let mut result = String::with_capacity(raw.len());
+    let mut pending_space = false;
+    for c in raw.chars() {
+        if c.is_whitespace() {
+            pending_space = true;
+        } else if c.is_control() {
+            // NUL 等控制字符直接丢弃
+            continue;
+        } else {
+            if pending_space && !result.is_empty() {
+                result.push(' ');
+            }
+            pending_space = false;
+            result.push(c);
+        }
+    }
+    result
 }
 
 impl Default for IpMsgPacket {
     fn default() -> Self {
         Self {
-            version: "lanMsg 0.1".to_string(),
+            version: PROTOCOL_VERSION.to_string(),
             packet_no: 0,
             sender_user: "default_user".to_string(),  // 默认值
             sender_host: String::new(),
@@ -164,67 +384,95 @@ impl Default for IpMsgPacket {
             sender_name: String::new(),
             group_name: String::new(),
             additional_msg: String::new(),
+            extension: String::new(),
+            version_major: None,
+            raw_sender_name: String::new(),
+            raw_sender_host: String::new(),
+            overflow_fields: 0,
         }
     }
 }
 
 /// 命令常量
 pub mod commands {
+    pub const IPMSG_NOOPERATION: u32 = 0x00000000; // 保活，不做任何动作
     pub const BR_ENTRY: u32 = 0x00000001; // 上线通知
     pub const BR_EXIT: u32 = 0x00000002; // 下线通知
     pub const IPMSG_ANSENTRY: u32 = 0x00000003; //通报新上线
     pub const IPMSG_BR_ABSENCE: u32 = 0x00000004; //更改为离开状态
     pub const MSG: u32 = 0x00000020; // 文本消息
-    pub const FILE: u32 = 0x00000060; // 文件传输
+    pub const IPMSG_RECVMSG: u32 = 0x00000021; // 消息已收到（附加信息为原报文编号）
+    pub const IPMSG_GETINFO: u32 = 0x00000040; // 查询客户端版本
+    pub const IPMSG_SENDINFO: u32 = 0x00000041; // 回复客户端版本
+    pub const FILE: u32 = 0x00000060; // 文件传输：经 TCP 请求附件数据（IPMSG_GETFILEDATA）
+    pub const IPMSG_RELEASEFILES: u32 = 0x00000061; // 放弃接收附件（附加信息为原报文编号）
+    pub const IPMSG_GETABSENCEINFO: u32 = 0x00000070; // 查询离开信息
+    pub const IPMSG_SENDABSENCEINFO: u32 = 0x00000071; // 回复离开信息
+
+    // 选项位（与命令字按位或）
+    pub const IPMSG_ABSENCEOPT: u32 = 0x00000100; // 离开状态（用于上线类命令）
+    pub const IPMSG_SENDCHECKOPT: u32 = 0x00000100; // 要求回复 RECVMSG（用于 MSG）
+    pub const IPMSG_BROADCASTOPT: u32 = 0x00000400; // 发给所有人（广播）
+    pub const IPMSG_MULTICASTOPT: u32 = 0x00000800; // 发给指定的一组用户
+    pub const IPMSG_NOADDLISTOPT: u32 = 0x00080000; // 不要把发送方加入用户列表
+    pub const IPMSG_AUTORETOPT: u32 = 0x00002000; // 自动回复，对方不应再自动回复
+    pub const IPMSG_FILEATTACHOPT: u32 = 0x00200000; // 消息带有文件附件列表（用于 MSG）
+}
+
+/// 将原始字节格式化为十六进制字符串
+pub fn hex_dump(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 /// 从字节流中提取可打印字符串部分
-pub(crate) fn extract_string_part(data: &[u8]) -> String {
+pub fn extract_string_part(data: &[u8]) -> String {
     let mut result = String::with_capacity(data.len());
     for &byte in data {
         if byte.is_ascii_graphic() || byte == b' ' {
             result.push(byte as char);
-        } else if byte == b'\0' {
-            break; // 遇到空字符停止
         } else {
-            break; // 遇到非可打印ASCII字符停止
+            break; // 遇到空字符或非可打印ASCII字符停止
         }
     }
     result.trim().to_string()
 }
 
-/// 改进的字符串提取函数（支持GBK双字节字符）
-pub(crate) fn extract_string_part2(data: &[u8], config: &AppConfig) -> String {
+/// 配置的全局协议编码
+pub fn protocol_encoding(config: &AppConfig) -> &'static Encoding {
     match config.encoding.protocol.as_str() {
-        "gbk" => {
-            // GBK模式：尝试解码整个字节流
-            let (cow, _, _) = GBK.decode(data);
-            let s = cow.trim();
-            // 找到第一个非法字符位置
-            s.chars()
-                .take_while(|&c| c != '\0' && !c.is_control())
-                .collect()
-        }
-        _ => {
-            // UTF-8模式：传统ASCII处理
-            let mut result = String::with_capacity(data.len());
-            for &byte in data {
-                if byte.is_ascii_graphic() || byte == b' ' {
-                    result.push(byte as char);
-                } else if byte == b'\0' {
-                    break;
-                } else {
-                    break;
-                }
+        "gbk" => GBK,
+        _ => UTF_8,
+    }
+}
+
+/// 改进的字符串提取函数（支持GBK双字节字符）
+pub(crate) fn extract_string_part2(data: &[u8], encoding: &'static Encoding) -> String {
+    if encoding == GBK {
+        // GBK模式：尝试解码整个字节流
+        let (cow, _, _) = GBK.decode(data);
+        let s = cow.trim();
+        // 找到第一个非法字符位置
+        s.chars()
+            .take_while(|&c| c != '\0' && !c.is_control())
+            .collect()
+    } else {
+        // UTF-8模式：传统ASCII处理
+        let mut result = String::with_capacity(data.len());
+        for &byte in data {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                result.push(byte as char);
+            } else {
+                break;
             }
-            result.trim().to_string()
         }
+        result.trim().to_string()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::EncodingConfig;
 
     #[test]
     fn test_extract_string() {
@@ -241,7 +489,7 @@ mod tests {
         // assert_eq!(extract_string_part(b"Text\xFFMore"), "Text");
 
         let config = AppConfig {
-            encoding: EncodingConfig { protocol: "gbk".into(), display: "utf-8".into() },
+            encoding: EncodingConfig { protocol: "gbk".into(), display: "utf-8".into(), ..Default::default() },
             ..Default::default()
         };
 
@@ -258,4 +506,143 @@ mod tests {
         assert_eq!(packet.sender_name, "张三");
         assert_eq!(packet.group_name, "开发组");
     }
+
+    #[test]
+    fn test_normalize_identity() {
+        let config = AppConfig {
+            encoding: EncodingConfig { protocol: "utf-8".into(), display: "utf-8".into(), ..Default::default() },
+            ..Default::default()
+        };
+
+        let data = b"1:7:pc-usr: PC-1\t :1:al\tice  \x00dev\x00";
+        let packet = IpMsgPacket::decode_with_config(data, &config).unwrap();
+
+        assert_eq!(packet.sender_name, "al ice");
+        assert_eq!(packet.sender_host, "PC-1");
+        assert_eq!(packet.raw_sender_name, "al\tice  ");
+        assert_eq!(packet.raw_sender_host, " PC-1\t ");
+        assert_eq!(normalize_field("bob\x00\x00\x01 "), "bob");
+    }
+
+    #[test]
+    fn test_version_compatibility() {
+        let config = AppConfig::default();
+
+        let packet = IpMsgPacket::decode_with_config(b"1_iptux 0.76:1:u:h:32:hi", &config).unwrap();
+        assert_eq!(packet.version_major, Some(1));
+        assert!(packet.is_compatible());
+
+        let packet = IpMsgPacket::decode_with_config(b"lanMsg 0.1:1:u:h:32:hi", &config).unwrap();
+        assert!(packet.is_compatible());
+
+        let packet = IpMsgPacket::decode_with_config(b"2:1:u:h:32:hi", &config).unwrap();
+        assert_eq!(packet.version_major, Some(2));
+        assert!(!packet.is_compatible());
+    }
+
+    #[test]
+    fn test_colon_in_fixed_fields_round_trips() {
+        let packet = IpMsgPacket {
+            packet_no: 9,
+            sender_user: "a:b".into(),
+            sender_host: "host:1\\x".into(),
+            command: commands::MSG,
+            additional_msg: "hi".into(),
+            ..Default::default()
+        };
+        let wire = packet.encode_escaped(true);
+        assert_eq!(wire.matches(':').count(), 5);
+
+        let mut config = AppConfig::default();
+        config.encoding.escape_fields = true;
+        let decoded = IpMsgPacket::decode_with_config(wire.as_bytes(), &config).unwrap();
+        assert_eq!(decoded.sender_user, "a:b");
+        assert_eq!(decoded.sender_host, "host:1\\x");
+        assert_eq!(decoded.command, commands::MSG);
+
+        // 默认（标准客户端）：不转义也不还原，反斜杠原样保留
+        let standard = AppConfig::default();
+        assert!(!standard.encoding.escape_fields);
+        let decoded = IpMsgPacket::decode_with_config(b"1:1:a\\cb:PC:32:hi", &standard).unwrap();
+        assert_eq!(decoded.sender_user, "a\\cb");
+        assert_eq!(IpMsgPacket::decode_with_config(b"1:1:a\\cb:PC\\1:32:hi", &standard).unwrap().sender_host, "PC\\1");
+        assert_eq!(unescape_field(&escape_field("x\0y:z\\")), "x\0y:z\\");
+    }
+
+    #[test]
+    fn test_overflow_fields_reattached_to_body() {
+        let config = AppConfig::default();
+        let packet =
+            IpMsgPacket::decode_with_config(b"1:5:alice:PC:32:meet at 10:30", &config)
+                .unwrap();
+        assert_eq!(packet.sender_user, "alice");
+        assert_eq!(packet.command, commands::MSG);
+        assert_eq!(packet.additional_msg, "meet at 10:30");
+        assert_eq!(packet.overflow_fields, 1);
+        assert!(packet.overflow_note().unwrap().contains("1 extra ':'"));
+
+        let packet = IpMsgPacket::decode_with_config(b"1:6:alice:PC:32:hi", &config).unwrap();
+        assert_eq!(packet.overflow_note(), None);
+
+        // 上线类报文的状态说明与节点 ID 扩展里的冒号不算多余字段
+        let entry = b"1:7:alice:PC:1:alice\x00dev\x00in 10:00-18:00\x00UUID:0b5e2c1a";
+        let packet = IpMsgPacket::decode_with_config(entry, &config).unwrap();
+        assert_eq!(packet.additional_msg, "in 10:00-18:00");
+        assert_eq!(packet.overflow_note(), None);
+    }
+
+    #[test]
+    fn test_login_and_display_name_round_trip() {
+        let config = AppConfig {
+            encoding: EncodingConfig { protocol: "utf-8".into(), ..Default::default() },
+            ..Default::default()
+        };
+        let entry = IpMsgPacket {
+            packet_no: 3,
+            sender_user: "zhangsan".into(),
+            sender_name: "张三".into(),
+            sender_host: "PC-1".into(),
+            group_name: "开发组".into(),
+            command: commands::BR_ENTRY,
+            ..Default::default()
+        };
+        let decoded = IpMsgPacket::decode_with_config(&entry.encode_with_config(&config), &config).unwrap();
+        assert_eq!(decoded.sender_user, "zhangsan");
+        assert_eq!(decoded.sender_name, "张三");
+        assert_eq!(decoded.group_name, "开发组");
+        assert_eq!(decoded.node_id(), None);
+
+        // 上线报文的扩展部分携带节点 ID，状态说明为空时也保留位置
+        let id = "0b5e2c1a-7f3d-4e8a-9c21-5d6f7a8b9c0d";
+        let with_id = IpMsgPacket {
+            extension: format!("{}{}", NODE_ID_PREFIX, id),
+            ..entry.clone()
+        };
+        let encoded = with_id.encode_with_config(&config);
+        assert!(String::from_utf8_lossy(&encoded).ends_with(&format!("开发组\0\0UUID:{}", id)));
+        let decoded = IpMsgPacket::decode_with_config(&encoded, &config).unwrap();
+        assert_eq!((decoded.additional_msg.as_str(), decoded.node_id()), ("", Some(id)));
+        let invalid = IpMsgPacket {
+            extension: "UUID:not-a-uuid".into(),
+            ..entry.clone()
+        };
+        assert_eq!(invalid.node_id(), None);
+        // 只有上线通知与应答按节点 ID 识别对端
+        let exit = IpMsgPacket {
+            command: commands::BR_EXIT,
+            ..with_id.clone()
+        };
+        assert_eq!(exit.node_id(), None);
+
+        // 消息报文的附加信息只有正文，显示名退回登录名
+        let msg = IpMsgPacket {
+            command: commands::MSG,
+            additional_msg: "你好".into(),
+            ..entry
+        };
+        let decoded = IpMsgPacket::decode_with_config(&msg.encode_with_config(&config), &config).unwrap();
+        assert_eq!(decoded.sender_user, "zhangsan");
+        assert_eq!(decoded.sender_name, "zhangsan");
+        assert_eq!(decoded.additional_msg, "你好");
+    }
 }
@@ -0,0 +1,421 @@
+use crate::config::AppConfig;
+use crate::dedup::{DedupCache, DedupKey};
+use crate::net::{IpMsgServer, check_sent};
+use crate::protocol::{IpMsgPacket, MAX_DATAGRAM, commands};
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+
+/// 中继转发范围
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayFilter {
+    /// 仅转发上线/下线/离开等在线状态报文
+    Presence,
+    /// 转发全部报文
+    All,
+}
+
+impl RelayFilter {
+    /// 判断该报文是否需要转发
+    pub fn allows(&self, packet: &IpMsgPacket) -> bool {
+        match self {
+            RelayFilter::All => true,
+            RelayFilter::Presence => matches!(
+                packet.command & 0xff,
+                commands::BR_ENTRY
+                    | commands::BR_EXIT
+                    | commands::IPMSG_ANSENTRY
+                    | commands::IPMSG_BR_ABSENCE
+            ),
+        }
+    }
+}
+
+impl FromStr for RelayFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "presence" => Ok(RelayFilter::Presence),
+            "all" => Ok(RelayFilter::All),
+            other => Err(anyhow::anyhow!(
+                "Unknown relay filter '{}', expected presence or all",
+                other
+            )),
+        }
+    }
+}
+
+/// 中继网段：本地绑定地址与该网段的广播地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelaySegment {
+    pub bind: SocketAddr,
+    pub broadcast: SocketAddr,
+}
+
+impl RelaySegment {
+    /// 解析 `绑定IP=广播IP` 形式的网段描述
+    pub fn parse(spec: &str, port: u16) -> Result<Self> {
+        let (bind, broadcast) = spec
+            .split_once('=')
+            .with_context(|| format!("Invalid segment '{}', expected BIND_IP=BROADCAST_IP", spec))?;
+        let bind: IpAddr = bind.trim().parse().context("Invalid segment bind address")?;
+        let broadcast: IpAddr = broadcast
+            .trim()
+            .parse()
+            .context("Invalid segment broadcast address")?;
+        Ok(Self {
+            bind: SocketAddr::new(bind, port),
+            broadcast: SocketAddr::new(broadcast, port),
+        })
+    }
+}
+
+/// 子网中继：把一个网段收到的报文原样广播到其他网段
+///
+/// 所有网段共用一个绑定在 0.0.0.0 上的套接字（绑定到单播地址收不到广播），
+/// 按 IP_PKTINFO 给出的目的地址与接收地址判断报文来自哪个网段
+#[derive(Clone)]
+pub struct Relay {
+    socket: Arc<UdpSocket>,
+    segments: Vec<RelaySegment>,
+    filter: RelayFilter,
+    dedup: Arc<Mutex<DedupCache>>,
+    roster: Option<IpMsgServer>,
+    config: Arc<AppConfig>,
+}
+
+impl Relay {
+    /// 在各网段共同的端口上绑定 0.0.0.0（网段须为 IPv4 且端口相同）
+    pub async fn bind(
+        segments: &[RelaySegment],
+        filter: RelayFilter,
+        config: Arc<AppConfig>,
+    ) -> Result<Self> {
+        if segments.len() < 2 {
+            return Err(anyhow::anyhow!("Relay needs at least two segments"));
+        }
+        if let Some(segment) = segments.iter().find(|segment| !segment.bind.is_ipv4() || !segment.broadcast.is_ipv4()) {
+            return Err(anyhow::anyhow!("Relay segment {} is not IPv4", segment.bind));
+        }
+        let port = segments[0].bind.port();
+        if segments.iter().any(|segment| segment.bind.port() != port) {
+            return Err(anyhow::anyhow!("Relay segments must share one port"));
+        }
+
+        let bind = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+        let socket = UdpSocket::bind(bind)
+            .await
+            .with_context(|| format!("Failed to bind relay socket {}", bind))?;
+        socket.set_broadcast(true)?;
+        pktinfo::enable(&socket).context("Failed to enable IP_PKTINFO on the relay socket")?;
+
+        Ok(Self {
+            socket: Arc::new(socket),
+            segments: segments.to_vec(),
+            filter,
+            dedup: Arc::new(Mutex::new(DedupCache::default())),
+            roster: None,
+            config,
+        })
+    }
+
+    /// 将中继转发的用户同时记录到指定服务器的用户表中
+    pub fn learn_users(mut self, server: IpMsgServer) -> Self {
+        self.roster = Some(server);
+        self
+    }
+
+    /// 各网段的地址（绑定 IP 与实际端口），发往这里的单播报文归入该网段
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        let port = self.socket.local_addr().map_or(0, |addr| addr.port());
+        self.segments
+            .iter()
+            .map(|segment| SocketAddr::new(segment.bind.ip(), port))
+            .collect()
+    }
+
+    /// 运行中继，直到套接字出错
+    pub async fn run(&self) -> Result<()> {
+        let own_addrs = self.local_addrs();
+        let mut buf = vec![0; MAX_DATAGRAM];
+
+        loop {
+            let (len, addr, info) = pktinfo::recv(&self.socket, &mut buf).await?;
+            // 忽略自己转发出去后回环的报文
+            if own_addrs.contains(&addr) {
+                continue;
+            }
+            let Some(index) = info.and_then(|info| self.segment_of(info)) else {
+                log::debug!("Relay dropped a packet from {} that matches no segment ({:?})", addr, info);
+                continue;
+            };
+
+            let data = &buf[..len];
+            let packet = match IpMsgPacket::decode_with_config(data, &self.config) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    log::warn!("Relay decode failed from {}: {}", addr, e);
+                    continue;
+                }
+            };
+
+            if !self.filter.allows(&packet) {
+                continue;
+            }
+            // 去重：同一报文经其他中继绕回时不再转发
+            if !self.dedup.lock().unwrap().check_and_insert(DedupKey::from_packet(&packet)) {
+                continue;
+            }
+
+            self.forward(index, data).await;
+
+            if let Some(server) = &self.roster {
+                server.handle_packet(&packet, &addr).await;
+            }
+        }
+    }
+
+    /// 报文所属的网段：目的地址是网段的绑定地址或广播地址；
+    /// 否则（如发往 255.255.255.255）按接收它的本机地址匹配
+    fn segment_of(&self, info: pktinfo::PacketInfo) -> Option<usize> {
+        let dest = IpAddr::V4(info.dest);
+        self.segments
+            .iter()
+            .position(|segment| segment.bind.ip() == dest || segment.broadcast.ip() == dest)
+            .or_else(|| {
+                self.segments
+                    .iter()
+                    .position(|segment| segment.bind.ip() == IpAddr::V4(info.local))
+            })
+    }
+
+    /// 将原始字节转发到除来源外的所有网段
+    async fn forward(&self, from_index: usize, data: &[u8]) {
+        for (index, segment) in self.segments.iter().enumerate() {
+            if index == from_index {
+                continue;
+            }
+            if let Err(e) = check_sent(self.socket.send_to(data, segment.broadcast).await, data.len()) {
+                log::error!("Relay to {} failed: {}", segment.broadcast, e);
+            }
+        }
+    }
+}
+
+/// 接收时取得报文的目的地址（IP_PKTINFO），目前只支持 Linux
+mod pktinfo {
+    use std::io;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::net::UdpSocket;
+
+    /// 报文的目的地址与接收它的本机地址
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PacketInfo {
+        /// IP 头中的目的地址（广播报文为广播地址）
+        pub dest: Ipv4Addr,
+        /// 接收接口上的本机地址
+        pub local: Ipv4Addr,
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn enable(socket: &UdpSocket) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        let on: libc::c_int = 1;
+        // SAFETY: 套接字有效，选项值指向一个存活的 c_int
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_PKTINFO,
+                (&on as *const libc::c_int).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn enable(_socket: &UdpSocket) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "relay needs IP_PKTINFO, which is only supported on Linux"))
+    }
+
+    /// 接收一个报文及其 IP_PKTINFO（没有附带时为 None）
+    pub async fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<PacketInfo>)> {
+        socket.async_io(tokio::io::Interest::READABLE, || recv_now(socket, buf)).await
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_now(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<PacketInfo>)> {
+        use std::os::fd::AsRawFd;
+        // SAFETY: 以下结构都是可以全零初始化的 C 结构；recvmsg 只写入 msghdr 指向的、在此期间存活的缓冲区，
+        // CMSG_* 只在内核填写的 msg_controllen 范围内遍历
+        unsafe {
+            let mut name: libc::sockaddr_in = std::mem::zeroed();
+            let mut iov = libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            };
+            // 按 cmsghdr 对齐的控制消息缓冲区
+            let mut control = [0u64; 8];
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_name = (&mut name as *mut libc::sockaddr_in).cast();
+            msg.msg_namelen = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+            let len = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut info = None;
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::IPPROTO_IP && (*cmsg).cmsg_type == libc::IP_PKTINFO {
+                    let pktinfo = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<libc::in_pktinfo>());
+                    info = Some(PacketInfo {
+                        dest: Ipv4Addr::from(u32::from_be(pktinfo.ipi_addr.s_addr)),
+                        local: Ipv4Addr::from(u32::from_be(pktinfo.ipi_spec_dst.s_addr)),
+                    });
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+            let from = SocketAddr::from((Ipv4Addr::from(u32::from_be(name.sin_addr.s_addr)), u16::from_be(name.sin_port)));
+            Ok((len as usize, from, info))
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn recv_now(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<PacketInfo>)> {
+        let (len, from) = socket.try_recv_from(buf)?;
+        Ok((len, from, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn recv_timeout(socket: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        match tokio::time::timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await {
+            Ok(Ok((len, _))) => Some(buf[..len].to_vec()),
+            _ => None,
+        }
+    }
+
+    /// 模拟两个回环"网段"（127.0.0.1 与 127.0.0.2）：各有一个普通节点，中继在两者之间转发
+    async fn setup(filter: RelayFilter) -> (Relay, UdpSocket, UdpSocket) {
+        let peer_a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_b = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let segments = [
+            RelaySegment {
+                bind: "127.0.0.1:0".parse().unwrap(),
+                broadcast: peer_a.local_addr().unwrap(),
+            },
+            RelaySegment {
+                bind: "127.0.0.2:0".parse().unwrap(),
+                broadcast: peer_b.local_addr().unwrap(),
+            },
+        ];
+        let config = AppConfig::default();
+        let relay = Relay::bind(&segments, filter, Arc::new(config)).await.unwrap();
+        (relay, peer_a, peer_b)
+    }
+
+    #[tokio::test]
+    async fn test_relay_forwards_original_bytes_once() {
+        let (relay, peer_a, peer_b) = setup(RelayFilter::All).await;
+        let relay_b = relay.local_addrs()[1];
+        let runner = relay.clone();
+        tokio::spawn(async move { runner.run().await });
+
+        let data = b"1:100:alice:PC-A:1:alice\x00dev\x00".to_vec();
+        peer_b.send_to(&data, relay_b).await.unwrap();
+        assert_eq!(recv_timeout(&peer_a).await, Some(data.clone()));
+
+        // 同一报文再次到达（例如经另一中继绕回）不会被重复转发
+        peer_b.send_to(&data, relay_b).await.unwrap();
+        assert_eq!(recv_timeout(&peer_a).await, None);
+
+        // 超过 1 KB 的报文原样转发，不被截断
+        let long = format!("1:101:alice:PC-A:32:{}", "x".repeat(8000)).into_bytes();
+        peer_b.send_to(&long, relay_b).await.unwrap();
+        assert_eq!(recv_timeout(&peer_a).await, Some(long));
+    }
+
+    #[tokio::test]
+    async fn test_relay_presence_filter() {
+        let (relay, peer_a, peer_b) = setup(RelayFilter::Presence).await;
+        let relay_b = relay.local_addrs()[1];
+        let runner = relay.clone();
+        tokio::spawn(async move { runner.run().await });
+
+        peer_b.send_to(b"1:200:alice:PC-A:32:hello", relay_b).await.unwrap();
+        assert_eq!(recv_timeout(&peer_a).await, None);
+
+        peer_b.send_to(b"1:201:alice:PC-A:1:alice\x00dev\x00", relay_b).await.unwrap();
+        assert!(recv_timeout(&peer_a).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_relay_receives_real_broadcasts() {
+        // 网段 A 是 127.0.0.0/8（回环上的广播地址 127.255.255.255），网段 B 由 127.0.0.3 上的节点代替
+        let port = std::net::UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+        let peer_a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer_a.set_broadcast(true).unwrap();
+        let peer_b = UdpSocket::bind("127.0.0.3:0").await.unwrap();
+        let segments = [
+            RelaySegment::parse("127.0.0.1=127.255.255.255", port).unwrap(),
+            RelaySegment {
+                bind: SocketAddr::from(([127, 0, 0, 2], port)),
+                broadcast: peer_b.local_addr().unwrap(),
+            },
+        ];
+        let relay = Relay::bind(&segments, RelayFilter::All, Arc::new(AppConfig::default()))
+            .await
+            .unwrap();
+        tokio::spawn(async move { relay.run().await });
+
+        // 真正的广播报文归入网段 A，转发到网段 B
+        let data = b"1:400:alice:PC-A:1:alice\x00dev\x00".to_vec();
+        peer_a.send_to(&data, ("127.255.255.255", port)).await.unwrap();
+        assert_eq!(recv_timeout(&peer_b).await, Some(data));
+
+        // 不属于任何网段的目的地址不转发
+        let stray = b"1:401:alice:PC-A:1:alice\x00dev\x00";
+        peer_a.send_to(stray, ("127.0.0.9", port)).await.unwrap();
+        assert_eq!(recv_timeout(&peer_b).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_relay_roster_learning_is_opt_in() {
+        let entry = b"1:300:alice:PC-A:1:alice\x00dev\x00";
+
+        // 默认不把转发的用户写入中继自身的用户表
+        let (relay, peer_a, peer_b) = setup(RelayFilter::All).await;
+        let server = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let relay_b = relay.local_addrs()[1];
+        tokio::spawn(async move { relay.run().await });
+        peer_b.send_to(entry, relay_b).await.unwrap();
+        assert!(recv_timeout(&peer_a).await.is_some());
+        assert!(server.get_online_users().await.is_empty());
+
+        let (relay, peer_a, peer_b) = setup(RelayFilter::All).await;
+        let relay = relay.learn_users(server.clone());
+        let relay_b = relay.local_addrs()[1];
+        tokio::spawn(async move { relay.run().await });
+        peer_b.send_to(entry, relay_b).await.unwrap();
+        assert!(recv_timeout(&peer_a).await.is_some());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(server.get_online_users().await.len(), 1);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::config::{AppConfig, ConfigFormat, ConfigOverrides, read_config_text};
+use crate::event::NetEvent;
+use crate::net::IpMsgServer;
+use crate::peer_encoding::PeerEncodings;
+use crate::protocol::commands;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// 默认检查配置文件修改时间的间隔
+pub const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// 运行中可以直接生效的配置项，其余变更需要重启
+const RELOADABLE: &[&str] = &[
+    "user.name",
+    "user.login",
+    "user.host",
+    "user.group",
+    "user.status_note",
+    "user.client_version",
+    "network.broadcast_ips",
+    "encoding.peers",
+    "security.blocked",
+    "security.allowed",
+    "debug.log_level",
+    "presence.away_message",
+    "presence.auto_reply",
+    "presence.auto_reply_interval_secs",
+    "presence.stealth",
+];
+
+/// 两份配置之间的差异
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigDelta {
+    /// 可以直接生效的配置项（段.键）
+    pub applied: Vec<String>,
+    /// 需要重启才能生效的配置项
+    pub restart_required: Vec<String>,
+}
+
+impl ConfigDelta {
+    /// 比较两份配置，按是否可热加载分类
+    pub fn between(old: &AppConfig, new: &AppConfig) -> Self {
+        let old = flatten(old);
+        let new = flatten(new);
+        let mut delta = Self::default();
+        let keys: std::collections::BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+        for key in keys {
+            // 当前方案的取值已叠加到各段中，方案定义本身的变化不用报告
+            if old.get(key) == new.get(key) || key.starts_with("profile.") {
+                continue;
+            }
+            if RELOADABLE.contains(&key.as_str()) {
+                delta.applied.push(key.clone());
+            } else {
+                delta.restart_required.push(key.clone());
+            }
+        }
+        delta
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.restart_required.is_empty()
+    }
+
+    fn changed(&self, key: &str) -> bool {
+        self.applied.iter().any(|applied| applied == key)
+    }
+}
+
+/// 展开为 段.键 -> 值
+fn flatten(config: &AppConfig) -> BTreeMap<String, toml::Value> {
+    let mut out = BTreeMap::new();
+    let table = toml::Table::try_from(config).unwrap_or_default();
+    for (section, value) in table {
+        match value {
+            toml::Value::Table(fields) => {
+                for (key, value) in fields {
+                    out.insert(format!("{}.{}", section, key), value);
+                }
+            }
+            value => {
+                out.insert(section, value);
+            }
+        }
+    }
+    out
+}
+
+/// 轮询配置文件修改时间，变化时重新加载并应用
+pub struct ConfigWatcher {
+    path: PathBuf,
+    env: ConfigOverrides,
+    cli: ConfigOverrides,
+    current: AppConfig,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    /// current 为启动时已合并覆盖值的配置
+    pub fn new(path: PathBuf, current: AppConfig, env: ConfigOverrides, cli: ConfigOverrides) -> Self {
+        let modified = modified_time(&path);
+        Self {
+            path,
+            env,
+            cli,
+            current,
+            modified,
+        }
+    }
+
+    /// 当前生效的配置
+    pub fn current(&self) -> &AppConfig {
+        &self.current
+    }
+
+    /// 文件有变化时重新加载，返回差异；文件未变化返回 Ok(None)
+    ///
+    /// 加载或校验失败时保留原配置并返回错误
+    pub fn poll(&mut self) -> Result<Option<ConfigDelta>> {
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return Ok(None);
+        }
+        self.modified = modified;
+
+        let content = read_config_text(&self.path)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let profile = ConfigOverrides::select_profile(&self.env, &self.cli);
+        let loaded = AppConfig::parse_profile_as(&content, ConfigFormat::from_path(&self.path), profile)
+            .context("Failed to parse config file")?;
+        let merged = loaded.merged(&self.env, &self.cli);
+        if let Some(error) = merged.validate().first() {
+            return Err(anyhow::anyhow!("Invalid config: {}", error));
+        }
+
+        let delta = ConfigDelta::between(&self.current, &merged);
+        self.current = merged;
+        Ok(Some(delta))
+    }
+
+    /// 把可热加载的变更应用到服务器
+    pub async fn apply(&self, server: &IpMsgServer, delta: &ConfigDelta) -> Result<()> {
+        let config = &self.current;
+        if delta.changed("network.broadcast_ips") {
+            let targets = config.network.broadcast_targets()?;
+            server.set_broadcast_specs(&targets, server.broadcast_target().port())?;
+        }
+        if delta.changed("encoding.peers") {
+            server.set_peer_encodings(PeerEncodings::from_config(&config.encoding.peers));
+        }
+        if delta.changed("security.blocked") || delta.changed("security.allowed") {
+            server.set_peer_filter(config.security.peer_filter());
+        }
+        if delta.changed("debug.log_level") {
+            crate::logging::set_level(&config.debug.log_level);
+        }
+        if delta.applied.iter().any(|key| key.starts_with("presence.")) {
+            server.set_presence_config(config.presence.clone());
+        }
+        if delta.changed("presence.stealth") {
+            server.set_stealth(config.presence.stealth).await?;
+        }
+        if ["user.name", "user.login", "user.host", "user.group", "user.status_note", "user.client_version"]
+            .iter()
+            .any(|key| delta.changed(key))
+        {
+            server.set_user(&config.user);
+            if server.is_passive() {
+                return Ok(());
+            }
+            server.broadcast(&server.presence_packet(commands::BR_ENTRY)).await?;
+        }
+        Ok(())
+    }
+
+    /// 在后台定期检查，结果以事件形式推送
+    pub fn spawn(mut self, server: IpMsgServer, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let event = match self.poll() {
+                    Ok(None) => continue,
+                    Ok(Some(delta)) if delta.is_empty() => continue,
+                    Ok(Some(delta)) => match self.apply(&server, &delta).await {
+                        Ok(()) => NetEvent::ConfigReloaded {
+                            applied: delta.applied,
+                            restart_required: delta.restart_required,
+                        },
+                        Err(e) => NetEvent::ConfigReloadFailed {
+                            error: e.to_string(),
+                        },
+                    },
+                    Err(e) => NetEvent::ConfigReloadFailed {
+                        error: format!("{:#}", e),
+                    },
+                };
+                server.notify(event);
+            }
+        })
+    }
+}
+
+fn modified_time(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_classifies_reloadable_fields() {
+        let old = AppConfig::default();
+        let mut new = old.clone();
+        new.user.name = "alice".to_string();
+        new.network.broadcast_ips = vec!["192.168.1.255".to_string()];
+        new.network.port = 3000;
+        new.network.bind_ip = "127.0.0.1".to_string();
+        new.encoding.peers.insert("192.168.1.44".to_string(), "gbk".to_string());
+
+        let delta = ConfigDelta::between(&old, &new);
+        assert_eq!(delta.applied, ["encoding.peers", "network.broadcast_ips", "user.name"]);
+        assert_eq!(delta.restart_required, ["network.bind_ip", "network.port"]);
+        assert!(ConfigDelta::between(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_previous_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "[user]\nname = \"alice\"\ngroup = \"dev\"\n").unwrap();
+        let initial: AppConfig = toml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let cli = ConfigOverrides {
+            host: Some("PC-A".to_string()),
+            ..Default::default()
+        };
+        let mut watcher = ConfigWatcher::new(
+            path.clone(),
+            initial.clone().merged(&ConfigOverrides::default(), &cli),
+            ConfigOverrides::default(),
+            cli,
+        );
+        assert!(watcher.poll().unwrap().is_none());
+
+        // 修改时间精度可能较粗，直接清空记录的时间以模拟文件变化
+        fs::write(&path, "[user]\nname = \"bob\"\ngroup = \"dev\"\n").unwrap();
+        watcher.modified = None;
+        let delta = watcher.poll().unwrap().unwrap();
+        assert_eq!(delta.applied, ["user.name"]);
+        assert_eq!(watcher.current().user.name, "bob");
+        // 命令行覆盖值在重新加载后仍然生效
+        assert_eq!(watcher.current().user.host, "PC-A");
+
+        fs::write(&path, "[network\nbroken").unwrap();
+        watcher.modified = None;
+        assert!(watcher.poll().is_err());
+        assert_eq!(watcher.current().user.name, "bob");
+    }
+}
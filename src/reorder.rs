@@ -0,0 +1,202 @@
+use crate::protocol::IpMsgPacket;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// 编号跳跃超过该值时认为对端使用随机报文编号，不再排序
+const MAX_SEQUENCE_GAP: u32 = 32;
+/// 最多跟踪的发送方数量，超出时清理没有待发报文的发送方
+const MAX_TRACKED_SENDERS: usize = 1024;
+
+/// 等待投递的报文：报文、来源地址、是否疑似伪造
+pub type Delivery = (IpMsgPacket, SocketAddr, bool);
+
+#[derive(Debug, Default)]
+struct SenderState {
+    last: Option<u32>,
+    random: bool,
+    held: BTreeMap<u32, Delivery>,
+    held_since: Option<Instant>,
+}
+
+impl SenderState {
+    /// 依次取出从 last 开始连续的报文
+    fn drain_consecutive(&mut self, out: &mut Vec<Delivery>) {
+        while let Some(last) = self.last {
+            match self.held.remove(&last.wrapping_add(1)) {
+                Some(delivery) => {
+                    self.last = Some(last.wrapping_add(1));
+                    out.push(delivery);
+                }
+                None => break,
+            }
+        }
+        if self.held.is_empty() {
+            self.held_since = None;
+        }
+    }
+
+    /// 放弃等待，按编号顺序取出全部暂存报文
+    fn release_all(&mut self, out: &mut Vec<Delivery>) {
+        let held = std::mem::take(&mut self.held);
+        if let Some(&max) = held.keys().next_back() {
+            self.last = Some(max);
+        }
+        out.extend(held.into_values());
+        self.held_since = None;
+    }
+}
+
+/// 按发送方排序的投递缓冲
+///
+/// 发现某个发送方的报文编号出现缺口时，后到的报文最多暂存 window，
+/// 缺口补齐后按序放出，超时则直接放出。报文编号看起来是随机数的发送方不经过缓冲。
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    window: Duration,
+    senders: HashMap<String, SenderState>,
+}
+
+impl ReorderBuffer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            senders: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    /// 收到报文，返回现在可以投递的报文（按顺序）
+    pub fn push(&mut self, delivery: Delivery, now: Instant) -> Vec<Delivery> {
+        if !self.is_enabled() {
+            return vec![delivery];
+        }
+
+        let packet_no = delivery.0.packet_no;
+        let key = format!("{}@{}", delivery.0.sender_user, delivery.0.sender_host);
+        if !self.senders.contains_key(&key) && self.senders.len() >= MAX_TRACKED_SENDERS {
+            self.senders.retain(|_, state| !state.held.is_empty());
+        }
+        let state = self.senders.entry(key).or_default();
+        let mut out = Vec::new();
+
+        if state.random {
+            out.push(delivery);
+            return out;
+        }
+
+        let Some(last) = state.last else {
+            state.last = Some(packet_no);
+            out.push(delivery);
+            return out;
+        };
+
+        let ahead = packet_no.wrapping_sub(last);
+        let behind = last.wrapping_sub(packet_no);
+        if ahead == 1 {
+            state.last = Some(packet_no);
+            out.push(delivery);
+            state.drain_consecutive(&mut out);
+        } else if ahead > 1 && ahead <= MAX_SEQUENCE_GAP {
+            state.held.insert(packet_no, delivery);
+            state.held_since.get_or_insert(now);
+        } else if behind <= MAX_SEQUENCE_GAP {
+            // 迟到或重发的旧报文直接投递
+            out.push(delivery);
+        } else {
+            state.random = true;
+            state.release_all(&mut out);
+            out.push(delivery);
+        }
+        out
+    }
+
+    /// 放出等待超时的报文
+    pub fn flush_expired(&mut self, now: Instant) -> Vec<Delivery> {
+        let mut out = Vec::new();
+        for state in self.senders.values_mut() {
+            if let Some(since) = state.held_since
+                && now.duration_since(since) >= self.window
+            {
+                state.release_all(&mut out);
+            }
+        }
+        out
+    }
+
+    /// 最近一次需要放出暂存报文的时间
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.senders
+            .values()
+            .filter_map(|state| state.held_since)
+            .min()
+            .map(|since| since + self.window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(200);
+
+    fn message(packet_no: u32) -> Delivery {
+        let packet = IpMsgPacket {
+            packet_no,
+            sender_user: "alice".to_string(),
+            sender_host: "PC".to_string(),
+            ..Default::default()
+        };
+        (packet, "127.0.0.1:2425".parse().unwrap(), false)
+    }
+
+    fn numbers(deliveries: &[Delivery]) -> Vec<u32> {
+        deliveries.iter().map(|(packet, _, _)| packet.packet_no).collect()
+    }
+
+    #[test]
+    fn test_swapped_pair_is_delivered_in_order() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        let now = Instant::now();
+        assert_eq!(numbers(&buffer.push(message(10), now)), [10]);
+        assert!(buffer.push(message(12), now).is_empty());
+        assert!(buffer.next_deadline().is_some());
+        assert_eq!(numbers(&buffer.push(message(11), now)), [11, 12]);
+        assert!(buffer.next_deadline().is_none());
+    }
+
+    #[test]
+    fn test_gap_is_flushed_on_timeout() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        let now = Instant::now();
+        buffer.push(message(10), now);
+        assert!(buffer.push(message(13), now).is_empty());
+        assert!(buffer.push(message(12), now).is_empty());
+        assert!(buffer.flush_expired(now + WINDOW / 2).is_empty());
+        assert_eq!(numbers(&buffer.flush_expired(now + WINDOW)), [12, 13]);
+        // 超时放出后继续按新的编号排序
+        assert_eq!(numbers(&buffer.push(message(14), now + WINDOW)), [14]);
+    }
+
+    #[test]
+    fn test_in_order_traffic_is_not_delayed() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        let now = Instant::now();
+        for packet_no in 1..=5 {
+            assert_eq!(numbers(&buffer.push(message(packet_no), now)), [packet_no]);
+        }
+        assert!(buffer.next_deadline().is_none());
+    }
+
+    #[test]
+    fn test_random_packet_numbers_bypass_buffer() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        let now = Instant::now();
+        for packet_no in [3_000_000, 17, 2_000_000_000, 42] {
+            assert_eq!(numbers(&buffer.push(message(packet_no), now)), [packet_no]);
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! 在线用户的筛选、排序与列选择（list 命令）
+
+use crate::control::format_elapsed;
+use crate::groups::NO_GROUP;
+use crate::net::OnlineUser;
+use crate::table::{self, TableStyle};
+use anyhow::Result;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 备注列最多显示的字符数
+const NOTE_WIDTH: usize = 16;
+
+/// 用户表中可选的列，名称同时用作 JSON 字段名
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    /// 昵称
+    Name,
+    /// 登录名
+    User,
+    Host,
+    Ip,
+    Port,
+    Group,
+    /// online，或缓存恢复尚未确认的 cached
+    State,
+    /// 状态说明（离开时为离开信息）
+    Note,
+    /// 最近一次收到报文的时间
+    LastSeen,
+}
+
+impl Column {
+    /// 未指定 --columns 时输出的列
+    pub const DEFAULT: [Column; 6] = [
+        Column::Name,
+        Column::Host,
+        Column::Ip,
+        Column::Port,
+        Column::State,
+        Column::Note,
+    ];
+
+    pub fn key(self) -> &'static str {
+        match self {
+            Column::Name => "name",
+            Column::User => "user",
+            Column::Host => "host",
+            Column::Ip => "ip",
+            Column::Port => "port",
+            Column::Group => "group",
+            Column::State => "state",
+            Column::Note => "note",
+            Column::LastSeen => "last_seen",
+        }
+    }
+
+    fn header(self) -> &'static str {
+        match self {
+            Column::Name => "Username",
+            Column::User => "User",
+            Column::Host => "Host",
+            Column::Ip => "IP",
+            Column::Port => "Port",
+            Column::Group => "Group",
+            Column::State => "State",
+            Column::Note => "Note",
+            Column::LastSeen => "Last seen",
+        }
+    }
+
+    fn min_width(self) -> usize {
+        match self {
+            Column::Port => 4,
+            Column::State => 6,
+            Column::Note => NOTE_WIDTH,
+            _ => 12,
+        }
+    }
+
+    fn cell(self, user: &OnlineUser, now: SystemTime) -> String {
+        match self {
+            Column::Name => user.username.clone(),
+            Column::User => user.login.clone(),
+            Column::Host => user.hostname.clone(),
+            Column::Ip => user.ip().to_string(),
+            Column::Port => user.port().to_string(),
+            Column::Group if user.group.is_empty() => NO_GROUP.to_string(),
+            Column::Group => user.group.clone(),
+            Column::State => state(user).to_string(),
+            Column::Note => user.note.chars().take(NOTE_WIDTH).collect(),
+            Column::LastSeen => {
+                let elapsed = now.duration_since(user.last_seen).unwrap_or_default();
+                format!("{} ago", format_elapsed(elapsed))
+            }
+        }
+    }
+
+    fn value(self, user: &OnlineUser) -> Value {
+        match self {
+            Column::Name => user.username.clone().into(),
+            Column::User => user.login.clone().into(),
+            Column::Host => user.hostname.clone().into(),
+            Column::Ip => user.ip().to_string().into(),
+            Column::Port => user.port().into(),
+            // 分组为空时为 null
+            Column::Group if user.group.is_empty() => Value::Null,
+            Column::Group => user.group.clone().into(),
+            Column::State => state(user).into(),
+            Column::Note => user.note.clone().into(),
+            // Unix 秒
+            Column::LastSeen => unix_secs(user.last_seen).into(),
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "name" => Ok(Column::Name),
+            "user" => Ok(Column::User),
+            "host" => Ok(Column::Host),
+            "ip" => Ok(Column::Ip),
+            "port" => Ok(Column::Port),
+            "group" => Ok(Column::Group),
+            "state" => Ok(Column::State),
+            "note" => Ok(Column::Note),
+            "last_seen" => Ok(Column::LastSeen),
+            other => Err(anyhow::anyhow!(
+                "Unknown column '{}', expected name, user, host, ip, port, group, state, note or last_seen",
+                other
+            )),
+        }
+    }
+}
+
+/// 排序依据，相同时再按昵称、主机名排序
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortKey {
+    #[default]
+    Name,
+    User,
+    Host,
+    Ip,
+    Group,
+    LastSeen,
+}
+
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "name" => Ok(SortKey::Name),
+            "user" => Ok(SortKey::User),
+            "host" => Ok(SortKey::Host),
+            "ip" => Ok(SortKey::Ip),
+            "group" => Ok(SortKey::Group),
+            "last_seen" => Ok(SortKey::LastSeen),
+            other => Err(anyhow::anyhow!(
+                "Unknown sort key '{}', expected name, user, host, ip, group or last_seen",
+                other
+            )),
+        }
+    }
+}
+
+impl SortKey {
+    fn compare(self, a: &OnlineUser, b: &OnlineUser) -> Ordering {
+        let primary = match self {
+            SortKey::Name => Ordering::Equal,
+            SortKey::User => a.login.cmp(&b.login),
+            SortKey::Host => a.hostname.cmp(&b.hostname),
+            SortKey::Ip => a.addr.cmp(&b.addr),
+            SortKey::Group => a.group.cmp(&b.group),
+            SortKey::LastSeen => a.last_seen.cmp(&b.last_seen),
+        };
+        primary
+            .then_with(|| a.username.cmp(&b.username))
+            .then_with(|| a.hostname.cmp(&b.hostname))
+    }
+}
+
+/// list 的筛选与排序条件
+#[derive(Debug, Clone, Default)]
+pub struct UserQuery {
+    /// 分组名，逐字匹配；`(no group)` 匹配分组为空的用户
+    pub group: Option<String>,
+    /// 昵称、登录名或主机名包含该文本（不区分大小写）
+    pub filter: Option<String>,
+    pub sort: SortKey,
+    /// 降序
+    pub desc: bool,
+}
+
+impl UserQuery {
+    pub fn matches(&self, user: &OnlineUser) -> bool {
+        let group_ok = match self.group.as_deref() {
+            None => true,
+            Some(NO_GROUP) => user.group.is_empty(),
+            Some(group) => user.group == group,
+        };
+        let filter_ok = self.filter.as_deref().is_none_or(|filter| {
+            let filter = filter.to_lowercase();
+            [&user.username, &user.login, &user.hostname]
+                .iter()
+                .any(|field| field.to_lowercase().contains(&filter))
+        });
+        group_ok && filter_ok
+    }
+
+    /// 筛选并排序
+    pub fn apply(&self, users: Vec<OnlineUser>) -> Vec<OnlineUser> {
+        let mut users: Vec<OnlineUser> = users.into_iter().filter(|user| self.matches(user)).collect();
+        users.sort_by(|a, b| self.sort.compare(a, b));
+        if self.desc {
+            users.reverse();
+        }
+        users
+    }
+}
+
+fn state(user: &OnlineUser) -> &'static str {
+    // 缓存恢复且本次未收到报文的用户
+    if user.confirmed { "online" } else { "cached" }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+/// 按所选的列渲染用户表，max_width 为终端宽度
+pub fn render(users: &[OnlineUser], columns: &[Column], style: TableStyle, max_width: Option<usize>) -> String {
+    let now = SystemTime::now();
+    let headers: Vec<&str> = columns.iter().map(|column| column.header()).collect();
+    let min_widths: Vec<usize> = columns.iter().map(|column| column.min_width()).collect();
+    let rows: Vec<Vec<String>> = users
+        .iter()
+        .map(|user| columns.iter().map(|column| column.cell(user, now)).collect())
+        .collect();
+    table::render_fit(&headers, &min_widths, &rows, style, max_width)
+}
+
+/// 按所选的列输出 JSON 数组，顺序与表格相同
+pub fn users_json(users: &[OnlineUser], columns: &[Column]) -> serde_json::Result<String> {
+    let rows: Vec<Map<String, Value>> = users
+        .iter()
+        .map(|user| {
+            columns
+                .iter()
+                .map(|column| (column.key().to_string(), column.value(user)))
+                .collect()
+        })
+        .collect();
+    serde_json::to_string_pretty(&rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn user(name: &str, host: &str, group: &str, seen_secs: u64) -> OnlineUser {
+        OnlineUser {
+            username: name.into(),
+            hostname: host.into(),
+            addr: "192.168.1.5:2425".parse().unwrap(),
+            via_port: 2425,
+            confirmed: true,
+            note: String::new(),
+            login: name.to_lowercase(),
+            group: group.into(),
+            absent: false,
+            absence: None,
+            last_seen: UNIX_EPOCH + Duration::from_secs(seen_secs),
+        }
+    }
+
+    #[test]
+    fn test_query_filters_and_sorts() {
+        let users = vec![
+            user("Alice", "PC-1", "backend", 30),
+            user("bob", "ALI-LAPTOP", "backend", 20),
+            user("carol", "PC-3", "backend", 10),
+            user("alicia", "PC-4", "", 40),
+        ];
+        let query = UserQuery {
+            group: Some("backend".to_string()),
+            filter: Some("ali".to_string()),
+            sort: "last_seen".parse().unwrap(),
+            desc: true,
+        };
+        let names: Vec<String> = query.apply(users.clone()).into_iter().map(|user| user.username).collect();
+        assert_eq!(names, ["Alice", "bob"]);
+
+        let no_group = UserQuery {
+            group: Some(NO_GROUP.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(no_group.apply(users.clone())[0].username, "alicia");
+        // 默认按昵称排序（区分大小写，与 get_online_users 相同）
+        let names: Vec<String> = UserQuery::default().apply(users).into_iter().map(|user| user.username).collect();
+        assert_eq!(names, ["Alice", "alicia", "bob", "carol"]);
+
+        assert!("seen".parse::<SortKey>().unwrap_err().to_string().contains("last_seen"));
+        let error = "name,mac".parse::<Column>().unwrap_err().to_string();
+        assert!(error.contains("'name,mac'"), "{}", error);
+        assert!("mac".parse::<Column>().is_err());
+    }
+
+    #[test]
+    fn test_columns_select_table_and_json_fields() {
+        let users = [user("alice", "PC-1", "", 30)];
+        let columns: Vec<Column> = "name,ip,last_seen"
+            .split(',')
+            .map(|column| column.parse().unwrap())
+            .collect();
+        let table = render(&users, &columns, TableStyle::Ascii, None);
+        assert!(table.contains("| Username     | IP           | Last seen"), "{}", table);
+        assert!(table.contains(" ago |"), "{}", table);
+        assert!(!table.contains("PC-1"));
+
+        let json: Value = serde_json::from_str(&users_json(&users, &columns).unwrap()).unwrap();
+        assert_eq!(json, serde_json::json!([{ "name": "alice", "ip": "192.168.1.5", "last_seen": 30 }]));
+        let json: Value = serde_json::from_str(&users_json(&users, &[Column::Group, Column::Port]).unwrap()).unwrap();
+        assert_eq!(json, serde_json::json!([{ "group": null, "port": 2425 }]));
+    }
+}
@@ -0,0 +1,116 @@
+use crate::protocol::hex_dump;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+/// 签名扩展字段前缀，附加在报文末尾：`\0HMAC:<十六进制>`
+pub const HMAC_FIELD_PREFIX: &[u8] = b"\0HMAC:";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 签名校验失败的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    /// 报文没有签名字段
+    Missing,
+    /// 签名字段不是合法的十六进制
+    Malformed,
+    /// 签名与内容不符
+    Mismatch,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "missing HMAC field"),
+            SignatureError::Malformed => write!(f, "malformed HMAC field"),
+            SignatureError::Mismatch => write!(f, "HMAC mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// 使用共享密钥对报文做 HMAC-SHA256 签名与校验
+#[derive(Clone)]
+pub struct PacketSigner {
+    key: Vec<u8>,
+}
+
+impl PacketSigner {
+    pub fn new(psk: &str) -> Self {
+        Self {
+            key: psk.as_bytes().to_vec(),
+        }
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        // HMAC 接受任意长度的密钥
+        <HmacSha256 as KeyInit>::new_from_slice(&self.key).expect("HMAC accepts any key length")
+    }
+
+    /// 在报文末尾附加签名字段
+    pub fn sign(&self, payload: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(payload);
+        let tag = hex_dump(&mac.finalize().into_bytes());
+
+        let mut signed = Vec::with_capacity(payload.len() + HMAC_FIELD_PREFIX.len() + tag.len());
+        signed.extend_from_slice(payload);
+        signed.extend_from_slice(HMAC_FIELD_PREFIX);
+        signed.extend_from_slice(tag.as_bytes());
+        signed
+    }
+
+    /// 校验签名，成功时返回去掉签名字段后的原始报文
+    pub fn verify<'a>(&self, data: &'a [u8]) -> Result<&'a [u8], SignatureError> {
+        let split = data
+            .windows(HMAC_FIELD_PREFIX.len())
+            .rposition(|window| window == HMAC_FIELD_PREFIX)
+            .ok_or(SignatureError::Missing)?;
+        let payload = &data[..split];
+        let tag = parse_hex(&data[split + HMAC_FIELD_PREFIX.len()..])
+            .ok_or(SignatureError::Malformed)?;
+
+        let mut mac = self.mac();
+        mac.update(payload);
+        mac.verify_slice(&tag).map_err(|_| SignatureError::Mismatch)?;
+        Ok(payload)
+    }
+}
+
+fn parse_hex(data: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(data).ok()?;
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tampered_body_fails_verification() {
+        let signer = PacketSigner::new("secret");
+        let signed = signer.sign(b"1:100:alice:PC:32:hello");
+        assert_eq!(signer.verify(&signed), Ok(&b"1:100:alice:PC:32:hello"[..]));
+
+        let mut tampered = signed.clone();
+        tampered[19] = b'j';
+        assert_eq!(signer.verify(&tampered), Err(SignatureError::Mismatch));
+
+        assert_eq!(
+            PacketSigner::new("other").verify(&signed),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            signer.verify(b"1:100:alice:PC:32:hello"),
+            Err(SignatureError::Missing)
+        );
+    }
+}
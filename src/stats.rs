@@ -0,0 +1,76 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 服务器运行计数器
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    users_evicted: AtomicU64,
+    packets_blocked: AtomicU64,
+    broadcasts_throttled: AtomicU64,
+    user_collisions: AtomicU64,
+    replies_evicted: AtomicU64,
+}
+
+impl ServerStats {
+    pub(crate) fn record_user_evicted(&self) {
+        self.users_evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn users_evicted(&self) -> u64 {
+        self.users_evicted.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_packet_blocked(&self) {
+        self.packets_blocked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn packets_blocked(&self) -> u64 {
+        self.packets_blocked.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_broadcast_throttled(&self) {
+        self.broadcasts_throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn broadcasts_throttled(&self) -> u64 {
+        self.broadcasts_throttled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_user_collision(&self) {
+        self.user_collisions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn user_collisions(&self) -> u64 {
+        self.user_collisions.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_reply_evicted(&self) {
+        self.replies_evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn replies_evicted(&self) -> u64 {
+        self.replies_evicted.load(Ordering::Relaxed)
+    }
+}
+
+/// 统计快照
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// 当前用户表条目数
+    pub users: usize,
+    /// 用户表已满时被淘汰的用户数
+    pub users_evicted: u64,
+    /// 当前去重缓存条目数
+    pub dedup_entries: usize,
+    /// 去重缓存已满时被淘汰的条目数
+    pub dedup_evicted: u64,
+    /// 当前等待应答的条目数
+    pub pending_replies: usize,
+    /// 等待应答的条目已满时被放弃的条目数
+    pub pending_replies_evicted: u64,
+    /// 被黑名单/白名单丢弃的报文数
+    pub packets_blocked: u64,
+    /// 因 min_broadcast_interval_ms 被延后或跳过的广播数
+    pub broadcasts_throttled: u64,
+    /// 发现的同名用户（用户名@主机名相同而 IP 不同）次数
+    pub user_collisions: u64,
+}
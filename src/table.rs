@@ -0,0 +1,203 @@
+//! 命令行表格输出：Unicode 框线或兼容旧控制台的 ASCII 框线
+
+use crate::net::OnlineUser;
+use crate::roster::{self, Column};
+
+/// 表格边框样式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TableStyle {
+    /// Unicode 框线字符
+    #[default]
+    Unicode,
+    /// 只用 `+`、`-`、`|`
+    Ascii,
+}
+
+impl TableStyle {
+    pub fn ascii(ascii: bool) -> Self {
+        if ascii { Self::Ascii } else { Self::Unicode }
+    }
+
+    /// 顶、中、底三条分隔线各自的 (左, 交叉, 右) 字符，以及横线与竖线
+    fn glyphs(self) -> ([[char; 3]; 3], char, char) {
+        match self {
+            Self::Unicode => (
+                [['┌', '┬', '┐'], ['├', '┼', '┤'], ['└', '┴', '┘']],
+                '─',
+                '│',
+            ),
+            Self::Ascii => ([['+'; 3]; 3], '-', '|'),
+        }
+    }
+
+    /// 截断内容时使用的省略号
+    fn ellipsis(self) -> &'static str {
+        match self {
+            Self::Unicode => "…",
+            Self::Ascii => "...",
+        }
+    }
+}
+
+/// 标准输出为终端时的列数，输出被重定向时为 None
+pub fn terminal_width() -> Option<usize> {
+    use std::io::IsTerminal;
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    terminal_size::terminal_size().map(|(width, _)| width.0 as usize)
+}
+
+/// 渲染表格：列宽取表头、最小宽度与内容中最长者（按字符计）
+pub fn render(headers: &[&str], min_widths: &[usize], rows: &[Vec<String>], style: TableStyle) -> String {
+    render_fit(headers, min_widths, rows, style, None)
+}
+
+/// 渲染表格，总宽度超过 max_width 时逐次收窄最宽的列（不窄于表头），放不下的内容以省略号结尾
+pub fn render_fit(
+    headers: &[&str],
+    min_widths: &[usize],
+    rows: &[Vec<String>],
+    style: TableStyle,
+    max_width: Option<usize>,
+) -> String {
+    let mut widths: Vec<usize> = headers
+        .iter()
+        .enumerate()
+        .map(|(column, header)| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .chain([header.chars().count(), min_widths.get(column).copied().unwrap_or(0)])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    if let Some(max_width) = max_width {
+        let floors: Vec<usize> = headers.iter().map(|header| header.chars().count()).collect();
+        // 每列占内容宽度加两侧空格和一条竖线，另有最左侧的竖线
+        while widths.iter().map(|width| width + 3).sum::<usize>() + 1 > max_width {
+            let Some(widest) = (0..widths.len())
+                .filter(|&column| widths[column] > floors[column])
+                .max_by_key(|&column| widths[column])
+            else {
+                break;
+            };
+            widths[widest] -= 1;
+        }
+    }
+    let (corners, horizontal, vertical) = style.glyphs();
+    let rule = |[left, cross, right]: [char; 3]| {
+        let segments: Vec<String> = widths
+            .iter()
+            .map(|width| horizontal.to_string().repeat(width + 2))
+            .collect();
+        format!("{}{}{}\n", left, segments.join(&cross.to_string()), right)
+    };
+    let line = |cells: Vec<&str>| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!(" {:<width$} ", truncate(cell, *width, style), width = width))
+            .collect();
+        format!("{}{}{}\n", vertical, cells.join(&vertical.to_string()), vertical)
+    };
+
+    let mut out = rule(corners[0]);
+    out.push_str(&line(headers.to_vec()));
+    out.push_str(&rule(corners[1]));
+    for row in rows {
+        out.push_str(&line(row.iter().map(String::as_str).collect()));
+    }
+    out.push_str(&rule(corners[2]));
+    out
+}
+
+/// 超过 width 个字符的内容截断并以省略号结尾
+fn truncate(cell: &str, width: usize, style: TableStyle) -> String {
+    if cell.chars().count() <= width {
+        return cell.to_string();
+    }
+    let ellipsis = style.ellipsis();
+    match width.checked_sub(ellipsis.chars().count()) {
+        Some(keep) => cell.chars().take(keep).chain(ellipsis.chars()).collect(),
+        None => cell.chars().take(width).collect(),
+    }
+}
+
+/// 在线用户表（默认列）
+pub fn render_users(users: &[OnlineUser], style: TableStyle) -> String {
+    roster::render(users, &Column::DEFAULT, style, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str, addr: &str, confirmed: bool, note: &str) -> OnlineUser {
+        OnlineUser {
+            username: username.into(),
+            hostname: "PC-1".into(),
+            addr: addr.parse().unwrap(),
+            via_port: 2425,
+            confirmed,
+            note: note.into(),
+            login: username.into(),
+            group: String::new(),
+            absent: false,
+            absence: None,
+            last_seen: std::time::SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn test_ascii_table() {
+        let users = [
+            user("alice", "192.168.1.5:2425", true, ""),
+            user("bob-the-builder", "10.0.0.7:2425", false, "at the construction site"),
+        ];
+        assert_eq!(
+            render_users(&users, TableStyle::Ascii),
+            "\
++-----------------+--------------+--------------+------+--------+------------------+
+| Username        | Host         | IP           | Port | State  | Note             |
++-----------------+--------------+--------------+------+--------+------------------+
+| alice           | PC-1         | 192.168.1.5  | 2425 | online |                  |
+| bob-the-builder | PC-1         | 10.0.0.7     | 2425 | cached | at the construct |
++-----------------+--------------+--------------+------+--------+------------------+
+"
+        );
+        let unicode = render_users(&users, TableStyle::Unicode);
+        assert!(unicode.starts_with("┌─────────────────┬"));
+        assert!(unicode.ends_with("┘\n"));
+        // 两种样式的每一行字符数相同
+        for (ascii, unicode) in render_users(&users, TableStyle::Ascii).lines().zip(unicode.lines()) {
+            assert_eq!(ascii.chars().count(), unicode.chars().count());
+        }
+    }
+
+    #[test]
+    fn test_fit_to_width_truncates_widest_column() {
+        let rows = vec![vec!["alice".to_string(), "a very long status note here".to_string()]];
+        let table = render_fit(&["Name", "Note"], &[0, 0], &rows, TableStyle::Unicode, Some(24));
+        assert_eq!(
+            table,
+            "\
+┌───────┬──────────────┐
+│ Name  │ Note         │
+├───────┼──────────────┤
+│ alice │ a very long… │
+└───────┴──────────────┘
+"
+        );
+        assert!(table.lines().all(|line| line.chars().count() <= 24));
+
+        let ascii = render_fit(&["Name", "Note"], &[0, 0], &rows, TableStyle::Ascii, Some(24));
+        assert!(ascii.contains("| a very lo... |"), "{}", ascii);
+        // 表头放不下时不再收窄
+        let narrow = render_fit(&["Name", "Note"], &[0, 0], &rows, TableStyle::Ascii, Some(5));
+        assert!(narrow.contains("| Name | Note |"), "{}", narrow);
+        assert!(narrow.contains("| a... | a... |"), "{}", narrow);
+        assert_eq!(render_fit(&["Name", "Note"], &[0, 0], &rows, TableStyle::Ascii, None), render(&["Name", "Note"], &[0, 0], &rows, TableStyle::Ascii));
+    }
+}
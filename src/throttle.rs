@@ -0,0 +1,107 @@
+//! 广播节流：相邻两次广播至少间隔 network.min_broadcast_interval_ms
+//!
+//! 来得太快的广播按间隔排队依次发出，用户消息不会丢失；排队中的在线状态刷新
+//! 被之后的刷新取代时直接跳过，只发出最新的状态。单播不受影响。
+
+use std::time::{Duration, Instant};
+
+/// 排队的广播达到该数量时打印警告
+pub const QUEUE_WARN_THRESHOLD: usize = 16;
+
+/// 预约到的发送时机
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    /// 可以立即发送
+    Now,
+    /// 需要等到该时刻
+    At(Instant),
+}
+
+/// 广播发送时机的分配器
+#[derive(Debug)]
+pub struct BroadcastPacer {
+    interval: Duration,
+    // 下一次广播最早的发送时间
+    next: Option<Instant>,
+    // 正在等待发送时机的广播数
+    waiting: usize,
+    // 每次在线状态刷新加一，等待结束时不是最新的刷新就跳过
+    presence_generation: u64,
+}
+
+impl BroadcastPacer {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: None,
+            waiting: 0,
+            presence_generation: 0,
+        }
+    }
+
+    /// 为一次广播预约发送时机；需要等待时计入排队数，等待结束后调用 finish_waiting
+    pub fn reserve(&mut self, now: Instant) -> Slot {
+        let slot = self.next.filter(|&next| next > now).unwrap_or(now);
+        self.next = Some(slot + self.interval);
+        if slot > now {
+            self.waiting += 1;
+            Slot::At(slot)
+        } else {
+            Slot::Now
+        }
+    }
+
+    pub fn finish_waiting(&mut self) {
+        self.waiting = self.waiting.saturating_sub(1);
+    }
+
+    /// 正在排队的广播数
+    pub fn waiting(&self) -> usize {
+        self.waiting
+    }
+
+    /// 登记一次在线状态刷新，返回它的序号
+    pub fn presence_refresh(&mut self) -> u64 {
+        self.presence_generation += 1;
+        self.presence_generation
+    }
+
+    /// 该序号是否仍是最新的在线状态刷新
+    pub fn is_latest_presence(&self, generation: u64) -> bool {
+        generation == self.presence_generation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_spaced_by_interval() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(100);
+        let mut pacer = BroadcastPacer::new(interval);
+        assert_eq!(pacer.reserve(start), Slot::Now);
+        assert_eq!(pacer.reserve(start), Slot::At(start + interval));
+        assert_eq!(pacer.reserve(start + Duration::from_millis(10)), Slot::At(start + interval * 2));
+        assert_eq!(pacer.waiting(), 2);
+        pacer.finish_waiting();
+        pacer.finish_waiting();
+        // 空闲足够久之后立即发送
+        assert_eq!(pacer.reserve(start + Duration::from_secs(5)), Slot::Now);
+
+        let mut unthrottled = BroadcastPacer::new(Duration::ZERO);
+        assert_eq!(unthrottled.reserve(start), Slot::Now);
+        assert_eq!(unthrottled.reserve(start), Slot::Now);
+    }
+
+    #[test]
+    fn test_newer_presence_refresh_supersedes() {
+        let mut pacer = BroadcastPacer::new(Duration::from_millis(100));
+        let first = pacer.presence_refresh();
+        assert!(pacer.is_latest_presence(first));
+        let second = pacer.presence_refresh();
+        assert!(!pacer.is_latest_presence(first));
+        assert!(pacer.is_latest_presence(second));
+    }
+}
@@ -0,0 +1,1005 @@
+//! 文件传输：消息中的附件列表、发送方的 TCP 文件服务与接收方下载
+//!
+//! 与 IPMsg 一致，附件列表放在 MSG 报文附加信息的 NUL 之后（带 IPMSG_FILEATTACHOPT），
+//! 接收方连接发送方 UDP 端口号上的 TCP 服务，发送 FILE（GETFILEDATA）报文请求
+//! `报文编号:文件编号:偏移` 对应的数据（均为十六进制）。
+
+use crate::config::AppConfig;
+use crate::net::IpMsgServer;
+use crate::protocol::{IpMsgPacket, commands};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, Semaphore};
+
+/// 附件列表中各条目之间的分隔符
+const ATTACH_SEPARATOR: char = '\u{7}';
+/// 普通文件的属性值
+pub const FILE_ATTR_REGULAR: u32 = 0x1;
+/// 文件请求报文的最大长度
+const MAX_REQUEST_LEN: usize = 1024;
+/// 传输时每次读写的缓冲区大小
+const COPY_BUF_LEN: usize = 64 * 1024;
+
+/// transfer.checksum 可用的取值
+pub const CHECKSUM_ALGORITHMS: &[&str] = &["sha256", "crc32", "none"];
+
+/// 附件的校验算法，发送方计算后写入附件的扩展属性，接收方据此校验
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Crc32,
+}
+
+impl ChecksumAlgorithm {
+    /// 解析配置或扩展属性中的算法名称；none 与不认识的名称返回 None
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha256" => Some(Self::Sha256),
+            "crc32" => Some(Self::Crc32),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Crc32 => "crc32",
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+            Self::Crc32 => Hasher::Crc32(!0),
+        }
+    }
+
+    /// 计算文件的校验值（十六进制）
+    pub fn file_digest(self, path: &Path) -> Result<String> {
+        let mut file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut hasher = self.hasher();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = std::io::Read::read(&mut file, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finish())
+    }
+}
+
+/// 边接收边计算的校验状态
+enum Hasher {
+    Sha256(Sha256),
+    Crc32(u32),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Crc32(crc) => *crc = crc32_update(*crc, data),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Self::Sha256(hasher) => crate::protocol::hex_dump(&hasher.finalize()),
+            Self::Crc32(crc) => format!("{:08x}", !crc),
+        }
+    }
+}
+
+/// CRC-32（IEEE 802.3，反射多项式 0xEDB88320）
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// 附件的校验值
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    /// 十六进制小写
+    pub value: String,
+}
+
+/// 附件列表中的一个文件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    /// 同一报文内唯一的文件编号
+    pub file_id: u32,
+    pub name: String,
+    pub size: u64,
+    /// 修改时间（Unix 秒）
+    pub mtime: u64,
+    pub attr: u32,
+    /// 发送方计算的校验值，以 `算法=值` 扩展属性携带
+    pub checksum: Option<Checksum>,
+}
+
+impl Attachment {
+    /// 读取文件的名称、大小与修改时间
+    pub fn from_path(file_id: u32, path: &Path) -> Result<Self> {
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if !metadata.is_file() {
+            return Err(anyhow::anyhow!("{} is not a regular file", path.display()));
+        }
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| anyhow::anyhow!("{} has no file name", path.display()))?;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |duration| duration.as_secs());
+        Ok(Self {
+            file_id,
+            name,
+            size: metadata.len(),
+            mtime,
+            attr: FILE_ATTR_REGULAR,
+            checksum: None,
+        })
+    }
+
+    /// 按 algorithm 计算文件的校验值并附在附件上
+    pub fn with_checksum(mut self, path: &Path, algorithm: Option<ChecksumAlgorithm>) -> Result<Self> {
+        self.checksum = match algorithm {
+            Some(algorithm) => Some(Checksum {
+                algorithm,
+                value: algorithm.file_digest(path)?,
+            }),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// 编码为 `编号:文件名:大小:修改时间:属性:[算法=校验值:]`，文件名中的冒号写作 `::`
+    fn encode(&self) -> String {
+        let mut entry = format!(
+            "{}:{}:{:x}:{:x}:{:x}:",
+            self.file_id,
+            self.name.replace(':', "::"),
+            self.size,
+            self.mtime,
+            self.attr
+        );
+        if let Some(checksum) = &self.checksum {
+            entry.push_str(&format!("{}={}:", checksum.algorithm.name(), checksum.value));
+        }
+        entry
+    }
+
+    /// 解析一个附件条目，格式不对时返回 None（不认识的扩展属性忽略）
+    fn parse(entry: &str) -> Option<Self> {
+        let entry = entry.trim_start_matches(':');
+        let (file_id, rest) = entry.split_once(':')?;
+        let mut name = String::new();
+        let mut chars = rest.char_indices().peekable();
+        let mut rest_start = None;
+        while let Some((index, c)) = chars.next() {
+            if c != ':' {
+                name.push(c);
+            } else if chars.peek().is_some_and(|&(_, next)| next == ':') {
+                chars.next();
+                name.push(':');
+            } else {
+                rest_start = Some(index + 1);
+                break;
+            }
+        }
+        let mut fields = rest[rest_start?..].split(':');
+        let mut hex = || fields.next().and_then(|field| u64::from_str_radix(field, 16).ok());
+        let size = hex()?;
+        let mtime = hex()?;
+        let attr = hex().and_then(|attr| u32::try_from(attr).ok())?;
+        let checksum = fields.find_map(|field| {
+            let (key, value) = field.split_once('=')?;
+            Some(Checksum {
+                algorithm: ChecksumAlgorithm::parse(key)?,
+                value: value.to_ascii_lowercase(),
+            })
+        });
+        Some(Self {
+            file_id: file_id.parse().ok()?,
+            name,
+            size,
+            mtime,
+            attr,
+            checksum,
+        })
+    }
+
+    /// 人类可读的大小
+    pub fn display_size(&self) -> String {
+        let size = self.size as f64;
+        match self.size {
+            0..1024 => format!("{} B", self.size),
+            1024..1_048_576 => format!("{:.1} KB", size / 1024.0),
+            _ => format!("{:.1} MB", size / 1_048_576.0),
+        }
+    }
+}
+
+/// 编码附件列表，放在报文的扩展部分
+pub fn encode_attachments(files: &[Attachment]) -> String {
+    files
+        .iter()
+        .map(|file| format!("{}{}", file.encode(), ATTACH_SEPARATOR))
+        .collect()
+}
+
+/// 解析报文扩展部分中的附件列表，跳过无法识别的条目
+pub fn parse_attachments(extension: &str) -> Vec<Attachment> {
+    extension
+        .split(ATTACH_SEPARATOR)
+        .filter(|entry| !entry.trim_matches(':').is_empty())
+        .filter_map(Attachment::parse)
+        .collect()
+}
+
+/// 文件请求的附加信息：`报文编号:文件编号:偏移`（十六进制）
+pub fn file_request(packet_no: u32, file_id: u32, offset: u64) -> String {
+    format!("{:x}:{:x}:{:x}", packet_no, file_id, offset)
+}
+
+fn parse_file_request(request: &str) -> Option<(u32, u32, u64)> {
+    let mut fields = request.trim_end_matches(':').split(':');
+    let packet_no = u32::from_str_radix(fields.next()?, 16).ok()?;
+    let file_id = u32::from_str_radix(fields.next()?, 16).ok()?;
+    let offset = match fields.next() {
+        Some(offset) => u64::from_str_radix(offset, 16).ok()?,
+        None => 0,
+    };
+    Some((packet_no, file_id, offset))
+}
+
+/// 收到的一条带附件的消息
+#[derive(Debug, Clone)]
+pub struct FileOffer {
+    pub packet_no: u32,
+    pub from: SocketAddr,
+    /// 发送方 用户名@主机名
+    pub sender: String,
+    pub files: Vec<Attachment>,
+    pub received_at: Instant,
+}
+
+impl FileOffer {
+    /// 从报文中取出附件列表，不带附件时返回 None
+    pub fn from_packet(packet: &IpMsgPacket, from: SocketAddr) -> Option<Self> {
+        if packet.command & commands::IPMSG_FILEATTACHOPT == 0 {
+            return None;
+        }
+        let files = parse_attachments(&packet.extension);
+        if files.is_empty() {
+            return None;
+        }
+        Some(Self {
+            packet_no: packet.packet_no,
+            from,
+            sender: format!("{}@{}", packet.sender_user, packet.sender_host),
+            files,
+            received_at: Instant::now(),
+        })
+    }
+
+    /// 附件总大小
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+
+    /// 按文件编号选择要接收的附件，ids 为空时选择全部
+    pub fn select(&self, ids: &[u32]) -> Result<Vec<&Attachment>> {
+        if ids.is_empty() {
+            return Ok(self.files.iter().collect());
+        }
+        ids.iter()
+            .map(|id| {
+                self.files
+                    .iter()
+                    .find(|file| file.file_id == *id)
+                    .ok_or_else(|| anyhow::anyhow!("Offer #{} has no file {}", self.packet_no, id))
+            })
+            .collect()
+    }
+}
+
+/// 未处理的文件发送请求，超过 transfer.offer_ttl_secs 后丢弃，
+/// 超出 transfer.max_pending_offers 条时丢弃最早的一条
+#[derive(Debug)]
+pub struct PendingOffers {
+    capacity: usize,
+    // 按收到顺序排列
+    offers: Mutex<Vec<FileOffer>>,
+    evicted: AtomicU64,
+}
+
+impl PendingOffers {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            offers: Mutex::new(Vec::new()),
+            evicted: AtomicU64::new(0),
+        }
+    }
+
+    /// 记下请求；已满时丢弃并返回最早的一条
+    pub fn insert(&self, offer: FileOffer) -> Option<FileOffer> {
+        let mut offers = self.offers.lock().unwrap();
+        let dropped = (offers.len() >= self.capacity).then(|| offers.remove(0));
+        if dropped.is_some() {
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        offers.push(offer);
+        dropped
+    }
+
+    /// 因容量不足被丢弃的请求数
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// 取出指定编号的请求
+    pub fn take(&self, packet_no: u32, ttl: Duration) -> Option<FileOffer> {
+        let mut offers = self.offers.lock().unwrap();
+        offers.retain(|offer| offer.received_at.elapsed() < ttl);
+        let index = offers.iter().position(|offer| offer.packet_no == packet_no)?;
+        Some(offers.remove(index))
+    }
+
+    /// 仍然有效的请求
+    pub fn list(&self, ttl: Duration) -> Vec<FileOffer> {
+        let mut offers = self.offers.lock().unwrap();
+        offers.retain(|offer| offer.received_at.elapsed() < ttl);
+        offers.clone()
+    }
+}
+
+/// 按 transfer.max_rate_kbps 限速：已传输的字节数超出按速率应传的量时等待
+struct RateLimit {
+    /// 每秒字节数，0 表示不限
+    bytes_per_sec: u64,
+    started: Instant,
+    transferred: u64,
+}
+
+impl RateLimit {
+    fn new(max_rate_kbps: u64) -> Self {
+        Self {
+            bytes_per_sec: max_rate_kbps * 1024,
+            started: Instant::now(),
+            transferred: 0,
+        }
+    }
+
+    /// 单次读写的上限：限速时约为 0.1 秒的量，避免一次突发整个缓冲区
+    fn chunk_len(&self, buf_len: usize) -> usize {
+        match self.bytes_per_sec {
+            0 => buf_len,
+            rate => buf_len.min((rate / 10).max(1) as usize),
+        }
+    }
+
+    /// 记录传输了 n 字节，超出速率时等到应有的时间
+    async fn pace(&mut self, n: usize) {
+        self.transferred += n as u64;
+        if self.bytes_per_sec == 0 {
+            return;
+        }
+        let due = Duration::from_secs_f64(self.transferred as f64 / self.bytes_per_sec as f64);
+        tokio::time::sleep_until((self.started + due).into()).await;
+    }
+}
+
+/// 发送方提供的一个文件
+#[derive(Debug, Clone)]
+struct OfferedFile {
+    path: PathBuf,
+    attachment: Attachment,
+}
+
+#[derive(Debug, Default)]
+struct OfferTable {
+    /// (报文编号, 文件编号) -> 文件，传输完成或对方放弃后移除
+    files: Mutex<HashMap<(u32, u32), OfferedFile>>,
+    changed: Notify,
+}
+
+/// 发送方的 TCP 文件服务：在 UDP 端口号上监听，响应对端的文件请求
+#[derive(Clone)]
+pub struct FileServer {
+    listener: Arc<TcpListener>,
+    server: IpMsgServer,
+    config: Arc<AppConfig>,
+    offers: Arc<OfferTable>,
+    /// 同时进行的传输数（transfer.max_concurrent），超出的连接等待空位
+    slots: Arc<Semaphore>,
+}
+
+impl FileServer {
+    /// 在 addr 上监听（通常与发出消息的 UDP 地址相同）
+    pub async fn bind(addr: SocketAddr, server: IpMsgServer, config: Arc<AppConfig>) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen for file requests on {}", addr))?;
+        Ok(Self {
+            listener: Arc::new(listener),
+            server,
+            slots: Arc::new(Semaphore::new(config.transfer.max_concurrent)),
+            config,
+            offers: Arc::new(OfferTable::default()),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// 把一组文件登记到报文 packet_no 下，返回按顺序编号的附件列表
+    pub fn offer(&self, packet_no: u32, paths: &[PathBuf]) -> Result<Vec<Attachment>> {
+        let algorithm = self.config.transfer.checksum();
+        let attachments = paths
+            .iter()
+            .enumerate()
+            .map(|(index, path)| Attachment::from_path(index as u32, path)?.with_checksum(path, algorithm))
+            .collect::<Result<Vec<_>>>()?;
+        let mut files = self.offers.files.lock().unwrap();
+        for (path, attachment) in paths.iter().zip(&attachments) {
+            files.insert(
+                (packet_no, attachment.file_id),
+                OfferedFile {
+                    path: path.clone(),
+                    attachment: attachment.clone(),
+                },
+            );
+        }
+        Ok(attachments)
+    }
+
+    /// 对方放弃接收（RELEASEFILES）时撤回该报文下剩余的文件
+    pub fn release(&self, packet_no: u32) {
+        self.offers.files.lock().unwrap().retain(|(offered, _), _| *offered != packet_no);
+        self.offers.changed.notify_waiters();
+    }
+
+    /// 尚未传输完成的文件数
+    pub fn pending(&self) -> usize {
+        self.offers.files.lock().unwrap().len()
+    }
+
+    /// 响应文件请求，直到全部文件传输完成（或被撤回）或超过 ttl，返回传输完成的文件数
+    ///
+    /// 每传完一个文件以文件名调用一次 on_sent
+    pub async fn serve_until_done(&self, ttl: Duration, mut on_sent: impl FnMut(&str)) -> usize {
+        let deadline = tokio::time::Instant::now() + ttl;
+        let mut sent = 0;
+        let mut transfers = tokio::task::JoinSet::new();
+        while self.pending() > 0 {
+            tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, peer)) => {
+                        let this = self.clone();
+                        transfers.spawn(async move {
+                            let _slot = this.slots.clone().acquire_owned().await?;
+                            this.handle(stream, peer).await
+                        });
+                    }
+                    Err(e) => log::warn!("Accepting file request failed: {}", e),
+                },
+                Some(done) = transfers.join_next() => match done {
+                    Ok(Ok(name)) => {
+                        on_sent(&name);
+                        sent += 1;
+                    }
+                    Ok(Err(e)) => log::warn!("File transfer failed: {:#}", e),
+                    Err(e) => log::warn!("File transfer task failed: {}", e),
+                },
+                _ = self.offers.changed.notified() => {}
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+        // 等待进行中的传输结束
+        while let Some(done) = transfers.join_next().await {
+            if let Ok(Ok(name)) = done {
+                on_sent(&name);
+                sent += 1;
+            }
+        }
+        sent
+    }
+
+    /// 处理一个文件请求连接，传输完成后返回文件名
+    async fn handle(&self, mut stream: TcpStream, peer: SocketAddr) -> Result<String> {
+        let mut buf = vec![0; MAX_REQUEST_LEN];
+        let len = stream.read(&mut buf).await?;
+        let request = self.server.decode_stream(&buf[..len], peer.ip(), &self.config)?;
+        if request.command & 0xff != commands::FILE {
+            return Err(anyhow::anyhow!("Unexpected command {:#x} from {}", request.command, peer));
+        }
+        let (packet_no, file_id, offset) = parse_file_request(&request.additional_msg)
+            .ok_or_else(|| anyhow::anyhow!("Malformed file request '{}' from {}", request.additional_msg, peer))?;
+        let offered = self
+            .offers
+            .files
+            .lock()
+            .unwrap()
+            .get(&(packet_no, file_id))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("{} requested unknown file {}/{}", peer, packet_no, file_id))?;
+
+        let mut file = tokio::fs::File::open(&offered.path)
+            .await
+            .with_context(|| format!("Failed to open {}", offered.path.display()))?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        let mut remaining = offered.attachment.size.saturating_sub(offset);
+        let mut rate = RateLimit::new(self.config.transfer.max_rate_kbps);
+        let mut buf = vec![0; COPY_BUF_LEN];
+        while remaining > 0 {
+            let want = rate.chunk_len(buf.len()).min(remaining as usize);
+            let n = file.read(&mut buf[..want]).await?;
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buf[..n]).await?;
+            remaining -= n as u64;
+            rate.pace(n).await;
+        }
+        stream.shutdown().await?;
+
+        self.offers.files.lock().unwrap().remove(&(packet_no, file_id));
+        self.offers.changed.notify_waiters();
+        Ok(offered.attachment.name)
+    }
+}
+
+/// 发送前检查路径：都必须存在，目录只能打包发送（尚不支持逐个传输目录）
+pub fn check_paths(paths: &[PathBuf], as_archive: bool) -> Result<()> {
+    for path in paths {
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("Cannot send {}", path.display()))?;
+        if metadata.is_dir() && !as_archive {
+            return Err(anyhow::anyhow!(
+                "{} is a directory, use --as-archive to send it as a tar archive",
+                path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 把文件与目录打包成 dest 处的 tar 文件，目录保留其下的相对路径
+pub fn build_archive(paths: &[PathBuf], dest: &Path) -> Result<()> {
+    let file = std::fs::File::create(dest)
+        .with_context(|| format!("Failed to create {}", dest.display()))?;
+    let mut builder = tar::Builder::new(file);
+    for path in paths {
+        let name = path
+            .canonicalize()?
+            .file_name()
+            .map(PathBuf::from)
+            .ok_or_else(|| anyhow::anyhow!("{} has no file name", path.display()))?;
+        if path.is_dir() {
+            builder.append_dir_all(&name, path)?;
+        } else {
+            builder.append_path_with_name(path, &name)?;
+        }
+    }
+    builder
+        .into_inner()?
+        .sync_all()
+        .with_context(|| format!("Failed to write {}", dest.display()))
+}
+
+/// 下载一个附件到 dest_dir，同名文件已存在时改用 `名称 (n).扩展名`，返回保存路径
+///
+/// 先写入 .part 临时文件，接收完整后改名；中途失败时删除临时文件（keep_partial_on_cancel 除外）
+pub async fn download(
+    server: &IpMsgServer,
+    config: &AppConfig,
+    offer: &FileOffer,
+    file: &Attachment,
+    dest_dir: &Path,
+) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(dest_dir)
+        .await
+        .with_context(|| format!("Failed to create {}", dest_dir.display()))?;
+    let dest = unique_path(dest_dir, &safe_file_name(&file.name));
+    let part = dest.with_extension(match dest.extension() {
+        Some(ext) => format!("{}.part", ext.to_string_lossy()),
+        None => "part".to_string(),
+    });
+
+    let result = receive_into(server, config, offer, file, &part).await;
+    match result {
+        Ok(()) => {
+            tokio::fs::rename(&part, &dest)
+                .await
+                .with_context(|| format!("Failed to move {} into place", part.display()))?;
+            Ok(dest)
+        }
+        Err(e) => {
+            if !config.transfer.keep_partial_on_cancel {
+                let _ = tokio::fs::remove_file(&part).await;
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn receive_into(
+    server: &IpMsgServer,
+    config: &AppConfig,
+    offer: &FileOffer,
+    file: &Attachment,
+    part: &Path,
+) -> Result<()> {
+    let mut stream = TcpStream::connect(offer.from)
+        .await
+        .with_context(|| format!("Failed to connect to {}", offer.from))?;
+    let request = server.build_packet(commands::FILE, &file_request(offer.packet_no, file.file_id, 0));
+    stream.write_all(&server.encode_for(&request, &offer.from).await).await?;
+
+    let mut out = tokio::fs::File::create(part)
+        .await
+        .with_context(|| format!("Failed to create {}", part.display()))?;
+    let mut hasher = file.checksum.as_ref().map(|checksum| checksum.algorithm.hasher());
+    let mut received = 0;
+    let mut rate = RateLimit::new(config.transfer.max_rate_kbps);
+    let mut buf = vec![0; COPY_BUF_LEN];
+    while received < file.size {
+        let want = rate.chunk_len(buf.len()).min((file.size - received) as usize);
+        let n = stream.read(&mut buf[..want]).await?;
+        if n == 0 {
+            return Err(anyhow::anyhow!(
+                "{}: connection closed after {} of {} bytes",
+                file.name,
+                received,
+                file.size
+            ));
+        }
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        out.write_all(&buf[..n]).await?;
+        received += n as u64;
+        rate.pace(n).await;
+    }
+    out.flush().await?;
+    if let (Some(hasher), Some(expected)) = (hasher, &file.checksum) {
+        let actual = hasher.finish();
+        if actual != expected.value {
+            return Err(anyhow::anyhow!(
+                "{}: {} checksum mismatch (expected {}, got {})",
+                file.name,
+                expected.algorithm.name(),
+                expected.value,
+                actual
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 只保留文件名部分，防止对端通过 ../ 写到下载目录之外
+fn safe_file_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    match name {
+        "" | "." | ".." => "unnamed".to_string(),
+        name => name.to_string(),
+    }
+}
+
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let candidate = dir.join(name);
+    if !candidate.exists() {
+        return candidate;
+    }
+    let path = Path::new(name);
+    let stem = path.file_stem().map_or(name.into(), |stem| stem.to_string_lossy());
+    let ext = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, ext)))
+        .find(|path| !path.exists())
+        .expect("unbounded range")
+}
+
+/// 按编号接收一条请求中的附件（ids 为空时全部接收），返回每个文件的结果
+pub async fn accept(
+    server: &IpMsgServer,
+    config: &AppConfig,
+    offer: &FileOffer,
+    ids: &[u32],
+) -> Result<Vec<(Attachment, Result<PathBuf>)>> {
+    let selected = offer.select(ids)?;
+    let dest_dir = config.transfer.download_dir();
+    let mut results = Vec::with_capacity(selected.len());
+    for file in selected {
+        let result = download(server, config, offer, file, &dest_dir).await;
+        results.push((file.clone(), result));
+    }
+    // 没有选择的文件告知发送方不再等待
+    if selected_all(offer, ids) {
+        return Ok(results);
+    }
+    let release = server.build_packet(commands::IPMSG_RELEASEFILES, &offer.packet_no.to_string());
+    server.send_to(&release, &offer.from).await?;
+    Ok(results)
+}
+
+fn selected_all(offer: &FileOffer, ids: &[u32]) -> bool {
+    ids.is_empty() || offer.files.iter().all(|file| ids.contains(&file.file_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_offers_cap_drops_oldest() {
+        let offers = PendingOffers::with_capacity(2);
+        let offer = |packet_no| FileOffer {
+            packet_no,
+            from: "127.0.0.1:2425".parse().unwrap(),
+            sender: "alice@PC".into(),
+            files: Vec::new(),
+            received_at: Instant::now(),
+        };
+        assert!(offers.insert(offer(1)).is_none());
+        assert!(offers.insert(offer(2)).is_none());
+        // 不停发来的请求挤掉最早的，保留最新的两条
+        let dropped: Vec<u32> = (3..=6).filter_map(|n| offers.insert(offer(n))).map(|o| o.packet_no).collect();
+        assert_eq!(dropped, [1, 2, 3, 4]);
+        assert_eq!(offers.evicted(), 4);
+        let ttl = Duration::from_secs(60);
+        let kept: Vec<u32> = offers.list(ttl).iter().map(|o| o.packet_no).collect();
+        assert_eq!(kept, [5, 6]);
+        assert!(offers.take(1, ttl).is_none());
+        assert!(offers.take(6, ttl).is_some());
+    }
+
+    #[test]
+    fn test_attachment_list_round_trip() {
+        let files = vec![
+            Attachment { file_id: 0, name: "report.pdf".into(), size: 0x1234, mtime: 0x5f000000, attr: 1, checksum: None },
+            Attachment {
+                file_id: 1,
+                name: "a:b.txt".into(),
+                size: 0,
+                mtime: 0,
+                attr: 1,
+                checksum: Some(Checksum { algorithm: ChecksumAlgorithm::Crc32, value: "00000000".into() }),
+            },
+        ];
+        let encoded = encode_attachments(&files);
+        assert_eq!(encoded, "0:report.pdf:1234:5f000000:1:\u{7}1:a::b.txt:0:0:1:crc32=00000000:\u{7}");
+        assert_eq!(parse_attachments(&encoded), files);
+        // 其他客户端在条目前加冒号、带扩展属性
+        assert_eq!(
+            parse_attachments("0:x.bin:10:0:1:14=abc:\u{7}:1:y:1:0:1:"),
+            [
+                Attachment { file_id: 0, name: "x.bin".into(), size: 16, mtime: 0, attr: 1, checksum: None },
+                Attachment { file_id: 1, name: "y".into(), size: 1, mtime: 0, attr: 1, checksum: None },
+            ]
+        );
+        assert_eq!(parse_file_request(&file_request(0xabc, 2, 0x10)), Some((0xabc, 2, 0x10)));
+        assert_eq!(safe_file_name("../../etc/passwd"), "passwd");
+    }
+
+    #[tokio::test]
+    async fn test_three_queued_files_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = (0..3)
+            .map(|i| {
+                let path = dir.path().join(format!("file{}.txt", i));
+                std::fs::write(&path, format!("contents of file {}", i).repeat(i + 1)).unwrap();
+                path
+            })
+            .collect();
+
+        let config = Arc::new(AppConfig::default());
+        let sender = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sender_addr = sender.local_addr().unwrap();
+        let files = FileServer::bind(sender_addr, sender.clone(), config.clone()).await.unwrap();
+        let mut packet = sender.build_packet(commands::MSG | commands::IPMSG_FILEATTACHOPT, "");
+        let attachments = files.offer(packet.packet_no, &paths).unwrap();
+        packet.extension = encode_attachments(&attachments);
+        let serving = tokio::spawn({
+            let files = files.clone();
+            async move { files.serve_until_done(Duration::from_secs(5), |_| {}).await }
+        });
+
+        let decoded = IpMsgPacket::decode_with_config(packet.encode().as_bytes(), &config).unwrap();
+        let offer = FileOffer::from_packet(&decoded, sender_addr).unwrap();
+        assert_eq!(offer.files.len(), 3);
+        assert_eq!(offer.files.iter().map(|file| file.file_id).collect::<Vec<_>>(), [0, 1, 2]);
+
+        let receiver = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let download_dir = tempfile::tempdir().unwrap();
+        for file in offer.select(&[]).unwrap() {
+            let saved = download(&receiver, &config, &offer, file, download_dir.path()).await.unwrap();
+            let original = std::fs::read(&paths[file.file_id as usize]).unwrap();
+            assert_eq!(std::fs::read(saved).unwrap(), original);
+        }
+        assert_eq!(serving.await.unwrap(), 3);
+        assert_eq!(files.pending(), 0);
+        assert!(offer.select(&[1, 7]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_queues_transfers() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = (0..2)
+            .map(|i| {
+                let path = dir.path().join(format!("file{}.bin", i));
+                std::fs::write(&path, vec![b'x'; 20 * 1024]).unwrap();
+                path
+            })
+            .collect();
+
+        // 发送方每个文件约需 0.2 秒，同时只传一个
+        let mut config = AppConfig::default();
+        config.transfer.max_concurrent = 1;
+        config.transfer.max_rate_kbps = 100;
+        let config = Arc::new(config);
+        let sender = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sender_addr = sender.local_addr().unwrap();
+        let files = FileServer::bind(sender_addr, sender.clone(), config.clone()).await.unwrap();
+        let mut packet = sender.build_packet(commands::MSG | commands::IPMSG_FILEATTACHOPT, "");
+        packet.extension = encode_attachments(&files.offer(packet.packet_no, &paths).unwrap());
+        let serving = tokio::spawn({
+            let files = files.clone();
+            async move { files.serve_until_done(Duration::from_secs(5), |_| {}).await }
+        });
+
+        let offer = FileOffer::from_packet(&packet, sender_addr).unwrap();
+        let receiver = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let receiver_config = AppConfig::default();
+        let download_dir = tempfile::tempdir().unwrap();
+        let timed = |file| {
+            let (receiver, receiver_config, offer) = (&receiver, &receiver_config, &offer);
+            let dest = download_dir.path();
+            async move {
+                download(receiver, receiver_config, offer, file, dest).await.unwrap();
+                Instant::now()
+            }
+        };
+        let (first, second) = tokio::join!(timed(&offer.files[0]), timed(&offer.files[1]));
+        // 两个下载同时发起，第二个要等第一个传完才开始
+        let gap = first.max(second) - first.min(second);
+        assert!(gap >= Duration::from_millis(150), "{:?}", gap);
+        assert_eq!(serving.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_paces_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, vec![b'x'; 30 * 1024]).unwrap();
+
+        let config = Arc::new(AppConfig::default());
+        let sender = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let sender_addr = sender.local_addr().unwrap();
+        let files = FileServer::bind(sender_addr, sender.clone(), config.clone()).await.unwrap();
+        let mut packet = sender.build_packet(commands::MSG | commands::IPMSG_FILEATTACHOPT, "");
+        packet.extension = encode_attachments(&files.offer(packet.packet_no, std::slice::from_ref(&path)).unwrap());
+        let serving = tokio::spawn({
+            let files = files.clone();
+            async move { files.serve_until_done(Duration::from_secs(5), |_| {}).await }
+        });
+
+        // 接收方限速 100 KB/s：30 KB 至少需要约 0.3 秒
+        let mut receiver_config = AppConfig::default();
+        receiver_config.transfer.max_rate_kbps = 100;
+        let offer = FileOffer::from_packet(&packet, sender_addr).unwrap();
+        let receiver = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+        let download_dir = tempfile::tempdir().unwrap();
+        let started = Instant::now();
+        let saved = download(&receiver, &receiver_config, &offer, &offer.files[0], download_dir.path())
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(250), "{:?}", started.elapsed());
+        assert_eq!(std::fs::read(saved).unwrap(), std::fs::read(&path).unwrap());
+        assert_eq!(serving.await.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_folders_need_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().join("photos");
+        std::fs::create_dir_all(folder.join("2024")).unwrap();
+        std::fs::write(folder.join("2024/a.jpg"), "jpeg").unwrap();
+        let notes = dir.path().join("notes.txt");
+        std::fs::write(&notes, "notes").unwrap();
+        let paths = vec![folder.clone(), notes];
+
+        let error = check_paths(&[dir.path().join("missing.txt")], true).unwrap_err();
+        assert!(format!("{:#}", error).contains("missing.txt"), "{:#}", error);
+        assert!(check_paths(&paths, false).unwrap_err().to_string().contains("--as-archive"));
+        check_paths(&paths, true).unwrap();
+
+        let archive = dir.path().join("bundle.tar");
+        build_archive(&paths, &archive).unwrap();
+        let mut entries: Vec<String> = tar::Archive::new(std::fs::File::open(&archive).unwrap())
+            .entries()
+            .unwrap()
+            .map(Result::unwrap)
+            .filter(|entry| entry.header().entry_type().is_file())
+            .map(|entry| entry.path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, ["notes.txt", "photos/2024/a.jpg"]);
+    }
+
+    #[test]
+    fn test_checksum_algorithms() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.txt");
+        std::fs::write(&path, "123456789").unwrap();
+        assert_eq!(ChecksumAlgorithm::Crc32.file_digest(&path).unwrap(), "cbf43926");
+        assert_eq!(
+            ChecksumAlgorithm::Sha256.file_digest(&path).unwrap(),
+            "15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225"
+        );
+        assert_eq!(ChecksumAlgorithm::parse("none"), None);
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_byte_fails_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, b"payload that will be corrupted").unwrap();
+
+        for algorithm in ["sha256", "crc32"] {
+            let mut config = AppConfig::default();
+            config.transfer.checksum = algorithm.to_string();
+            let config = Arc::new(config);
+            let sender = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+            let sender_addr = sender.local_addr().unwrap();
+            let files = FileServer::bind(sender_addr, sender.clone(), config.clone()).await.unwrap();
+            let mut packet = sender.build_packet(commands::MSG | commands::IPMSG_FILEATTACHOPT, "");
+            packet.extension = encode_attachments(&files.offer(packet.packet_no, std::slice::from_ref(&path)).unwrap());
+            let serving = tokio::spawn({
+                let files = files.clone();
+                async move { files.serve_until_done(Duration::from_secs(5), |_| {}).await }
+            });
+            // 登记之后改动一个字节，模拟传输途中的损坏
+            let mut data = std::fs::read(&path).unwrap();
+            data[3] ^= 0x20;
+            std::fs::write(&path, &data).unwrap();
+
+            let offer = FileOffer::from_packet(&packet, sender_addr).unwrap();
+            assert_eq!(offer.files[0].checksum.as_ref().unwrap().algorithm.name(), algorithm);
+            let receiver = IpMsgServer::new(Some("127.0.0.1:0".into())).await.unwrap();
+            let download_dir = tempfile::tempdir().unwrap();
+            let error = download(&receiver, &config, &offer, &offer.files[0], download_dir.path())
+                .await
+                .unwrap_err();
+            assert!(error.to_string().contains("checksum mismatch"), "{}", error);
+            // 校验失败的文件不留在下载目录中
+            assert_eq!(std::fs::read_dir(download_dir.path()).unwrap().count(), 0);
+            serving.await.unwrap();
+            data[3] ^= 0x20;
+            std::fs::write(&path, &data).unwrap();
+        }
+    }
+}
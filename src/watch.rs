@@ -0,0 +1,229 @@
+use crate::event::{NetEvent, PacketView};
+use crate::protocol::commands;
+use crate::transfer::FileOffer;
+use anyhow::Result;
+use serde::Serialize;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// watch 输出的事件类别，用 --types 过滤
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    /// 文本消息
+    Msg,
+    /// 用户上线/下线
+    Presence,
+    /// 文件请求
+    File,
+    /// 消息送达回执（RECVMSG）
+    Receipt,
+}
+
+impl FromStr for WatchKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "msg" => Ok(WatchKind::Msg),
+            "presence" => Ok(WatchKind::Presence),
+            "file" => Ok(WatchKind::File),
+            "receipt" => Ok(WatchKind::Receipt),
+            other => Err(anyhow::anyhow!(
+                "Unknown event type '{}', expected msg, presence, file or receipt",
+                other
+            )),
+        }
+    }
+}
+
+/// 文件请求中的一个附件
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileView {
+    pub id: u32,
+    pub name: String,
+    pub size: u64,
+}
+
+/// 输出的一行事件，`type` 字段区分类别
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WatchEvent {
+    Msg {
+        time_ms: u64,
+        packet: PacketView,
+    },
+    Join {
+        time_ms: u64,
+        user: String,
+        addr: std::net::SocketAddr,
+    },
+    Leave {
+        time_ms: u64,
+        user: String,
+        addr: std::net::SocketAddr,
+    },
+    FileOffer {
+        time_ms: u64,
+        packet: PacketView,
+        files: Vec<FileView>,
+    },
+    /// 对方确认收到了编号为 acked 的消息
+    Receipt {
+        time_ms: u64,
+        acked: u32,
+        packet: PacketView,
+    },
+}
+
+impl WatchEvent {
+    /// 转换服务器事件，不属于任何类别的事件返回 None
+    pub fn from_event(event: &NetEvent, time_ms: u64) -> Option<Self> {
+        match event {
+            NetEvent::Joined { username, addr } => Some(WatchEvent::Join {
+                time_ms,
+                user: username.clone(),
+                addr: *addr,
+            }),
+            NetEvent::Left { username, addr } => Some(WatchEvent::Leave {
+                time_ms,
+                user: username.clone(),
+                addr: *addr,
+            }),
+            NetEvent::Packet { packet, from, .. } => {
+                let view = PacketView::new(packet, *from);
+                match packet.command & 0xff {
+                    commands::MSG => match FileOffer::from_packet(packet, *from) {
+                        Some(offer) => Some(WatchEvent::FileOffer {
+                            time_ms,
+                            packet: view,
+                            files: offer
+                                .files
+                                .iter()
+                                .map(|file| FileView {
+                                    id: file.file_id,
+                                    name: file.name.clone(),
+                                    size: file.size,
+                                })
+                                .collect(),
+                        }),
+                        None => Some(WatchEvent::Msg { time_ms, packet: view }),
+                    },
+                    commands::IPMSG_RECVMSG => Some(WatchEvent::Receipt {
+                        time_ms,
+                        acked: packet.additional_msg.trim().parse().ok()?,
+                        packet: view,
+                    }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> WatchKind {
+        match self {
+            WatchEvent::Msg { .. } => WatchKind::Msg,
+            WatchEvent::Join { .. } | WatchEvent::Leave { .. } => WatchKind::Presence,
+            WatchEvent::FileOffer { .. } => WatchKind::File,
+            WatchEvent::Receipt { .. } => WatchKind::Receipt,
+        }
+    }
+
+    /// 序列化为一行 JSON（不含换行符）
+    pub fn to_line(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// 按 --types 过滤；为空时输出全部类别
+pub fn wanted(types: &[WatchKind], kind: WatchKind) -> bool {
+    types.is_empty() || types.contains(&kind)
+}
+
+/// 当前时间（Unix 毫秒）
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::protocol::IpMsgPacket;
+    use crate::transfer::{Attachment, encode_attachments};
+    use std::net::SocketAddr;
+
+    fn packet(command: u32, text: &str) -> IpMsgPacket {
+        let raw = format!("1:42:alice:PC-A:{}:{}", command, text);
+        IpMsgPacket::decode_with_config(raw.as_bytes(), &AppConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn test_event_shapes() {
+        let from: SocketAddr = "192.168.1.5:2425".parse().unwrap();
+        let line = |event: NetEvent| WatchEvent::from_event(&event, 1000).map(|e| e.to_line().unwrap());
+        let packet_event = |packet| NetEvent::Packet {
+            packet,
+            from,
+            suspect: false,
+        };
+
+        assert_eq!(
+            line(packet_event(packet(commands::MSG | commands::IPMSG_SENDCHECKOPT, "hi"))).unwrap(),
+            r#"{"type":"msg","time_ms":1000,"packet":{"from":"192.168.1.5:2425","packet_no":42,"command":32,"options":256,"user":"alice","host":"PC-A","nickname":"alice","group":"","text":"hi"}}"#
+        );
+        assert_eq!(
+            line(NetEvent::Joined {
+                username: "alice@PC-A".to_string(),
+                addr: from,
+            })
+            .unwrap(),
+            r#"{"type":"join","time_ms":1000,"user":"alice@PC-A","addr":"192.168.1.5:2425"}"#
+        );
+        assert_eq!(
+            line(NetEvent::Left {
+                username: "alice@PC-A".to_string(),
+                addr: from,
+            })
+            .unwrap(),
+            r#"{"type":"leave","time_ms":1000,"user":"alice@PC-A","addr":"192.168.1.5:2425"}"#
+        );
+
+        let mut offer = packet(commands::MSG | commands::IPMSG_FILEATTACHOPT, "");
+        offer.extension = encode_attachments(&[Attachment {
+            file_id: 0,
+            name: "a.txt".to_string(),
+            size: 5,
+            mtime: 0,
+            attr: 1,
+            checksum: None,
+        }]);
+        assert_eq!(
+            line(packet_event(offer)).unwrap(),
+            r#"{"type":"file_offer","time_ms":1000,"packet":{"from":"192.168.1.5:2425","packet_no":42,"command":32,"options":2097152,"user":"alice","host":"PC-A","nickname":"alice","group":"","text":""},"files":[{"id":0,"name":"a.txt","size":5}]}"#
+        );
+        assert_eq!(
+            line(packet_event(packet(commands::IPMSG_RECVMSG, "7"))).unwrap(),
+            r#"{"type":"receipt","time_ms":1000,"acked":7,"packet":{"from":"192.168.1.5:2425","packet_no":42,"command":33,"options":0,"user":"alice","host":"PC-A","nickname":"alice","group":"","text":"7"}}"#
+        );
+
+        assert_eq!(line(packet_event(packet(commands::BR_ENTRY, ""))), None);
+        assert_eq!(line(NetEvent::ConfigReloadFailed { error: "x".to_string() }), None);
+    }
+
+    #[test]
+    fn test_types_filter() {
+        let types: Vec<WatchKind> = "msg, Presence"
+            .split(',')
+            .map(|kind| kind.parse().unwrap())
+            .collect();
+        assert!(wanted(&types, WatchKind::Msg));
+        assert!(wanted(&types, WatchKind::Presence));
+        assert!(!wanted(&types, WatchKind::Receipt));
+        assert!(wanted(&[], WatchKind::File));
+        assert!("joins".parse::<WatchKind>().is_err());
+    }
+}
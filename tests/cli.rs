@@ -0,0 +1,274 @@
+//! 命令行退出码约定
+
+use assert_cmd::Command;
+use std::net::UdpSocket;
+use std::path::Path;
+use std::time::Duration;
+
+/// 只在本机回环上收发的配置，上线后不多等待
+fn write_config(dir: &Path) -> std::path::PathBuf {
+    let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let path = dir.join("config.toml");
+    let config = format!(
+        "[network]\nbind_ip = \"127.0.0.1\"\nport = {}\nbroadcast_ips = [\"127.0.0.1\"]\nentry_settle_ms = 100\ncache_users = false\n\n[user]\nname = \"tester\"\n",
+        port
+    );
+    std::fs::write(&path, config).unwrap();
+    path
+}
+
+fn lanmsg(config: &Path) -> Command {
+    let mut command = Command::cargo_bin("lanMsg").unwrap();
+    command
+        .arg("--config")
+        .arg(config)
+        .arg("--quiet")
+        .env("RUST_BACKTRACE", "0")
+        .timeout(Duration::from_secs(20));
+    for (key, _) in std::env::vars().filter(|(key, _)| key.starts_with("LANMSG_")) {
+        command.env_remove(key);
+    }
+    command
+}
+
+#[test]
+fn test_send_to_unknown_user_exits_not_found() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    let output = lanmsg(&config).args(["send", "ghost", "hi"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Error: User ghost not found"), "{}", stderr);
+}
+
+#[test]
+fn test_send_success_exits_zero() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let target = peer.local_addr().unwrap().to_string();
+
+    lanmsg(&config).args(["send", &target, "hello"]).assert().success();
+    let mut buf = [0; 1024];
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    assert!(String::from_utf8_lossy(&buf[..len]).ends_with(":hello"));
+}
+
+#[test]
+fn test_send_template_expands_identity() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    let mut content = std::fs::read_to_string(&config).unwrap();
+    content.push_str("host = \"PC-T\"\n\n[templates]\nbrb = \"{name}@{host} will be right back\"\n");
+    std::fs::write(&config, content).unwrap();
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let target = peer.local_addr().unwrap().to_string();
+
+    lanmsg(&config).args(["send", &target, "--template", "brb"]).assert().success();
+    let mut buf = [0; 1024];
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    assert!(String::from_utf8_lossy(&buf[..len]).ends_with(":tester@PC-T will be right back"));
+
+    let output = lanmsg(&config).args(["send", &target, "--template", "afk"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(64));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("available templates: brb"));
+}
+
+#[test]
+fn test_usage_error_exits_64() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    lanmsg(&config).args(["send", "alice"]).assert().code(64);
+    lanmsg(&config).args(["send", "--no-such-flag"]).assert().code(64);
+    lanmsg(&config).args(["send", "--help"]).assert().success();
+}
+
+#[test]
+fn test_pipe_sends_each_line_and_exits_zero_on_eof() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let target = peer.local_addr().unwrap().to_string();
+
+    lanmsg(&config)
+        .args(["pipe", "--to", &target, "--prefix", "[CI]"])
+        .write_stdin("build started\n\nbuild passed\n")
+        .assert()
+        .success();
+    let mut buf = [0; 1024];
+    for expected in [":[CI] build started", ":[CI] build passed"] {
+        let (len, _) = peer.recv_from(&mut buf).unwrap();
+        assert!(String::from_utf8_lossy(&buf[..len]).ends_with(expected));
+    }
+    lanmsg(&config).args(["pipe", "--to", &target, "--broadcast"]).assert().code(64);
+}
+
+#[test]
+fn test_chat_with_sends_lines_until_quit() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let target = peer.local_addr().unwrap().to_string();
+
+    let output = lanmsg(&config)
+        .args(["chat", "--with", &target])
+        .write_stdin("hello there\n/quit\nnot sent\n")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("[you -> {}] hello there", target)), "{}", stdout);
+    assert!(stdout.contains("Exiting chat..."), "{}", stdout);
+
+    let mut buf = [0; 1024];
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    assert!(String::from_utf8_lossy(&buf[..len]).ends_with(":hello there"));
+    peer.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    assert!(peer.recv_from(&mut buf).is_err());
+}
+
+#[test]
+fn test_chat_slash_commands_and_escaping() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let target = peer.local_addr().unwrap().to_string();
+
+    let output = lanmsg(&config)
+        .args(["chat", "--with", &target])
+        .write_stdin("/help\n/nope\n//hello\n/quit\n")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("/to <user|all|group:NAME>"), "{}", stdout);
+    assert!(stdout.contains("Unknown command /nope; type /help"), "{}", stdout);
+
+    let mut buf = [0; 1024];
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    assert!(String::from_utf8_lossy(&buf[..len]).ends_with(":/hello"));
+}
+
+/// 把广播目标改到 127.0.0.2，在那里冒充分组 dev 中的一个对端：应答上线通知、不确认消息，
+/// 收到下线通知后返回收到的命令
+fn spawn_silent_member(config: &Path) -> std::thread::JoinHandle<Vec<u32>> {
+    let mut content = std::fs::read_to_string(config).unwrap();
+    let port: u16 = content
+        .lines()
+        .find_map(|line| line.strip_prefix("port = "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    content = content.replace("broadcast_ips = [\"127.0.0.1\"]", "broadcast_ips = [\"127.0.0.2\"]\ntimeout_secs = 1");
+    std::fs::write(config, content).unwrap();
+    let peer = UdpSocket::bind(("127.0.0.2", port)).unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(15))).unwrap();
+    std::thread::spawn(move || {
+        let mut commands = Vec::new();
+        let mut buf = [0; 2048];
+        while let Ok((len, from)) = peer.recv_from(&mut buf) {
+            let text = String::from_utf8_lossy(&buf[..len]).to_string();
+            let command: u32 = text.split(':').nth(4).unwrap().parse().unwrap();
+            commands.push(command & 0xff);
+            match command & 0xff {
+                1 => {
+                    peer.send_to(b"1:7:carol:PC-C:3:carol\0dev\0", from).unwrap();
+                }
+                2 => break,
+                _ => {}
+            }
+        }
+        commands
+    })
+}
+
+#[test]
+fn test_group_send_exit_codes() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+
+    // 分组无人在线：以 2 结束，仍然发出下线通知
+    let member = spawn_silent_member(&config);
+    let output = lanmsg(&config).args(["send", "--group", "ops", "hi"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No online users in group ops"));
+    assert_eq!(member.join().unwrap().last(), Some(&2));
+
+    // 分组成员不确认：与普通 send 一样以 4 结束
+    let member = spawn_silent_member(&config);
+    let output = lanmsg(&config).args(["send", "--group", "dev", "hi"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(4), "{}", String::from_utf8_lossy(&output.stdout));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("carol@PC-C"), "{}", stdout);
+    let commands = member.join().unwrap();
+    assert!(commands.contains(&0x20) && commands.last() == Some(&2), "{:?}", commands);
+}
+
+#[test]
+fn test_overflow_fields_logged_at_debug() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    let content = std::fs::read_to_string(&config).unwrap();
+    let port: u16 = content
+        .lines()
+        .find_map(|line| line.strip_prefix("port = "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    std::fs::write(&config, content.replace("127.0.0.1\"]", "127.0.0.2\"]")).unwrap();
+    // 对端收到上线通知后回一条附加信息中带未转义冒号的消息
+    let peer = UdpSocket::bind(("127.0.0.2", port)).unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(15))).unwrap();
+    let target = peer.local_addr().unwrap().to_string();
+    let member = std::thread::spawn(move || {
+        let mut buf = [0; 2048];
+        while let Ok((len, from)) = peer.recv_from(&mut buf) {
+            match String::from_utf8_lossy(&buf[..len]).split(':').nth(4) {
+                Some("1") => {
+                    peer.send_to(b"1:42:carol:PC-C:32:meet at 10:30", from).unwrap();
+                }
+                Some("2") => break,
+                _ => {}
+            }
+        }
+    });
+
+    let output = lanmsg(&config)
+        .env("RUST_LOG", "debug")
+        .args(["send", &target, "ok"])
+        .output()
+        .unwrap();
+    member.join().unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("packet 42 has 1 extra ':' field(s), reattached to the message body from"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_ignore_config_errors_keeps_overrides() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("config.toml");
+    std::fs::write(&config, "[network]\nttl = 0\nport = 2600\n").unwrap();
+
+    lanmsg(&config).args(["config", "show"]).assert().code(1);
+    // 配置文件作废，但环境变量与命令行覆盖值照常生效
+    let output = lanmsg(&config)
+        .env("LANMSG_NAME", "from-env")
+        .args(["--ignore-config-errors", "--port", "2700", "config", "show"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("port = 2700"), "{}", stdout);
+    assert!(stdout.contains("name = \"from-env\""), "{}", stdout);
+    assert!(stdout.contains("ttl = 1"), "{}", stdout);
+}